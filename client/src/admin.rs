@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use fairy_common::drain::{DrainProgress, DrainState};
+use hyper::{Body, Client, Method, Request};
+
+/// Asks the worker at `host:http_port` to drain, then reports progress every `poll`
+/// until it is empty.
+pub async fn drain(
+    worker: &str,
+    poll: Duration,
+) -> Result<DrainProgress, Box<dyn std::error::Error>> {
+    let client = Client::new();
+    let uri = format!("http://{}/drain", worker);
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(&uri)
+        .body(Body::empty())?;
+    let mut progress = read_progress(client.request(request).await?).await?;
+    loop {
+        println!(
+            "{}: {:?}, {} keys ({} bytes) handed off, {} keys ({} bytes) left, {} failed",
+            worker,
+            progress.state,
+            progress.handed_off,
+            progress.handed_off_bytes,
+            progress.remaining,
+            progress.remaining_bytes,
+            progress.failed
+        );
+        if progress.state == DrainState::Drained {
+            return Ok(progress);
+        }
+        tokio::time::sleep(poll).await;
+        progress = read_progress(client.get(uri.parse()?).await?).await?;
+    }
+}
+
+async fn read_progress(
+    response: hyper::Response<Body>,
+) -> Result<DrainProgress, Box<dyn std::error::Error>> {
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    if !status.is_success() {
+        return Err(format!(
+            "drain failed with {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        )
+        .into());
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
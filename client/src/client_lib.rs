@@ -0,0 +1,4 @@
+pub mod fairy_client;
+pub mod health;
+
+pub use fairy_client::{FairyClient, FairyClientError, ObjectStat};
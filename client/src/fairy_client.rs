@@ -0,0 +1,1103 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use futures::future::{join_all, LocalBoxFuture};
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use h2::client::SendRequest;
+use http::{Method, Request, Response, StatusCode};
+use log::{debug, warn};
+use thiserror::Error;
+
+use fairy_common::discovery::ServiceDiscovery;
+use fairy_common::h2::{encode_key, h2_client};
+use fairy_common::kv_store::object_meta::{ObjectMeta, TOMBSTONE_VERSION_HEADER};
+use fairy_common::ring::HashRing;
+use fairy_common::settings::client_options::FairyClientOptions;
+
+use crate::health::HealthTracker;
+
+#[derive(Error, Debug)]
+pub enum FairyClientError {
+    #[error("no worker available for {0}")]
+    NoWorker(String),
+    #[error("{0} not found")]
+    NotFound(String),
+    /// A replica deleted the key within its tombstone TTL.
+    #[error("{key} was deleted at version {version}")]
+    Deleted { key: String, version: u64 },
+    #[error("invalid request: {0}")]
+    InvalidRequest(#[from] http::Error),
+    #[error("worker {worker} answered {status}")]
+    Status { worker: String, status: StatusCode },
+    #[error("{key}: only {answered} of the {quorum} required replicas answered, last error: {last_error}")]
+    Quorum {
+        key: String,
+        answered: usize,
+        quorum: usize,
+        last_error: String,
+    },
+    #[error("request to worker {0} timed out")]
+    Timeout(String),
+    #[error("transport error with worker {worker}: {message}")]
+    Transport { worker: String, message: String },
+}
+
+impl FairyClientError {
+    /// Whether the worker may answer the same request on a later attempt, such failures
+    /// count against its health. A full store (507) or a value too large (413) is the
+    /// request's fault, not the worker's.
+    fn is_retryable(&self) -> bool {
+        match self {
+            FairyClientError::Timeout(_) | FairyClientError::Transport { .. } => true,
+            FairyClientError::Status { status, .. } => matches!(
+                *status,
+                StatusCode::INTERNAL_SERVER_ERROR
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectStat {
+    pub meta: ObjectMeta,
+    pub len: u64,
+}
+
+impl ObjectStat {
+    fn from_response(response: &Response<Bytes>) -> ObjectStat {
+        let len = response
+            .headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok())
+            .unwrap_or(0);
+        ObjectStat {
+            meta: ObjectMeta::from_headers(response.headers()),
+            len,
+        }
+    }
+}
+
+/// Client of a fairy cluster. Keys are routed to their owning worker through the
+/// hash ring, with one pooled h2 connection per worker. Unhealthy workers are skipped
+/// and their keys routed to the next owner on the ring until they recover.
+///
+/// The client runs on the monoio runtime, the runtime needs the timer enabled.
+/// Clones are cheap and share the ring, the connections and the health of the workers.
+#[derive(Clone)]
+pub struct FairyClient {
+    options: Arc<FairyClientOptions>,
+    ring: Arc<RwLock<HashRing>>,
+    connections: Arc<Mutex<HashMap<String, SendRequest<Bytes>>>>,
+    health: Arc<HealthTracker>,
+    discovery: Arc<RwLock<Option<Arc<dyn ServiceDiscovery>>>>,
+}
+
+type Reply = (String, Result<Response<Bytes>, FairyClientError>);
+
+/// Replica requests still running once a quorum answered.
+type Stragglers = FuturesUnordered<LocalBoxFuture<'static, Reply>>;
+
+impl FairyClient {
+    pub fn new(mut options: FairyClientOptions) -> FairyClient {
+        options.replication_factor = options.replication_factor.max(1);
+        if options.write_quorum > options.replication_factor
+            || options.read_quorum > options.replication_factor
+        {
+            warn!(
+                "Quorums W={} R={} exceed the replication factor {}, capping them",
+                options.write_quorum, options.read_quorum, options.replication_factor
+            );
+        }
+        options.write_quorum = options.write_quorum.clamp(1, options.replication_factor);
+        options.read_quorum = options.read_quorum.clamp(1, options.replication_factor);
+        let ring = HashRing::with_members(options.ring_vnodes, options.workers.iter().cloned());
+        FairyClient {
+            health: Arc::new(HealthTracker::new(options.failure_threshold)),
+            options: Arc::new(options),
+            ring: Arc::new(RwLock::new(ring)),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            discovery: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Replaces the workers keys are routed to, dropping connections to removed ones.
+    pub fn set_workers<I: IntoIterator<Item = String>>(&self, workers: I) {
+        self.set_weighted_workers(workers.into_iter().map(|worker| (worker, 1)));
+    }
+
+    /// Replaces the workers with ones weighted on the ring, a weight of 0 leaves one out.
+    pub fn set_weighted_workers<I: IntoIterator<Item = (String, u32)>>(&self, workers: I) {
+        let mut ring = self.ring.write().unwrap();
+        if ring.set_members(workers) {
+            self.connections
+                .lock()
+                .unwrap()
+                .retain(|worker, _| ring.contains(worker));
+            self.health.retain(|worker| ring.contains(worker));
+        }
+    }
+
+    /// Labels workers with their zone, replicas are spread across zones.
+    pub fn set_zones<I: IntoIterator<Item = (String, Option<String>)>>(&self, zones: I) {
+        self.ring.write().unwrap().set_zones(zones);
+    }
+
+    /// Routes to the members of `discovery` from now on, for as long as the client lives.
+    /// Workers are reached on the h2 port of their descriptor when they published one,
+    /// placed in the zone it names and weighted by its capacity, like on the workers.
+    pub fn follow(self: &Arc<Self>, discovery: Arc<dyn ServiceDiscovery>) {
+        *self.discovery.write().unwrap() = Some(Arc::clone(&discovery));
+        let client = Arc::downgrade(self);
+        let source = Arc::downgrade(&discovery);
+        discovery.subscribe(Box::new(move |change| {
+            let (Some(client), Some(source)) = (client.upgrade(), source.upgrade()) else {
+                return;
+            };
+            client.set_zones(change.members.iter().map(|worker| {
+                let zone = source.descriptor(worker).and_then(|d| d.zone);
+                (worker.clone(), zone)
+            }));
+            client.set_weighted_workers(change.members.iter().map(|worker| {
+                let weight = source.descriptor(worker).map_or(1, |d| d.ring_weight());
+                (worker.clone(), weight)
+            }));
+        }));
+    }
+
+    /// Workers currently ejected from routing.
+    pub fn ejected_workers(&self) -> Vec<String> {
+        self.health.ejected()
+    }
+
+    /// Probes every worker once, ejecting failing ones and re-admitting recovered ones.
+    pub async fn check_health(&self) {
+        let workers: Vec<String> = {
+            let ring = self.ring.read().unwrap();
+            ring.members()
+                .map(|(worker, _)| worker.to_string())
+                .collect()
+        };
+        join_all(workers.iter().map(|worker| async move {
+            let request = Request::builder()
+                .uri(format!("http://{}/health", self.h2_addr(worker)))
+                .body(())
+                .unwrap();
+            match self.send(worker, request, None).await {
+                Ok(response) if response.status().is_success() => {
+                    self.health.record_success(worker)
+                }
+                Ok(response) => {
+                    debug!("Health probe of {} answered {}", worker, response.status());
+                    self.health.record_failure(worker)
+                }
+                Err(e) => {
+                    debug!("Health probe of {} failed: {}", worker, e);
+                    self.health.record_failure(worker)
+                }
+            }
+        }))
+        .await;
+    }
+
+    /// Probes the workers every `health_check_interval`, meant to be spawned next to
+    /// the requests sharing this client.
+    pub async fn run_health_checks(&self) {
+        loop {
+            monoio::time::sleep(self.options.health_check_interval).await;
+            self.check_health().await;
+        }
+    }
+
+    /// Worker owning the key.
+    pub fn owner(&self, key: &str) -> Option<String> {
+        self.owners(key).into_iter().next()
+    }
+
+    /// Workers holding the replicas of the key, primary first. Ejected workers are
+    /// replaced by the next healthy ones on the ring unless no worker is healthy.
+    pub fn owners(&self, key: &str) -> Vec<String> {
+        let ring = self.ring.read().unwrap();
+        let candidates = ring.owners(&key.to_string(), ring.len());
+        let healthy: Vec<String> = candidates
+            .iter()
+            .filter(|worker| self.health.is_healthy(worker))
+            .take(self.options.replication_factor)
+            .map(|worker| worker.to_string())
+            .collect();
+        if !healthy.is_empty() {
+            return healthy;
+        }
+        candidates
+            .into_iter()
+            .take(self.options.replication_factor)
+            .map(String::from)
+            .collect()
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Bytes, FairyClientError> {
+        let (_, value) = self.get_with_meta(key).await?;
+        Ok(value)
+    }
+
+    /// Reads every replica and returns the newest value once `read_quorum` replicas
+    /// answered. Replicas found missing or behind, including the ones answering after
+    /// the quorum, are repaired with it in the background. A replica that deleted the
+    /// key at a version no older than the newest value wins, the key is reported
+    /// missing and nothing is repaired. With a read quorum of one, a replica in the
+    /// client's zone is tried alone first.
+    pub async fn get_with_meta(&self, key: &str) -> Result<(ObjectMeta, Bytes), FairyClientError> {
+        if let Some(worker) = self.nearby_replica(key) {
+            match self
+                .call(&worker, Method::GET, "get", key, None, None)
+                .await
+            {
+                Ok(response) => {
+                    let meta = ObjectMeta::from_headers(response.headers());
+                    return Ok((meta, response.into_body()));
+                }
+                Err(e) => debug!("Zone local read of {} from {} failed: {}", key, worker, e),
+            }
+        }
+        let (replies, stragglers) = self
+            .fan_out(
+                key,
+                self.options.read_quorum,
+                Method::GET,
+                "get",
+                None,
+                None,
+            )
+            .await?;
+        let mut newest: Option<(ObjectMeta, Bytes)> = None;
+        let mut deleted: Option<u64> = None;
+        // the version each replica holds, a value or a tombstone
+        let mut versions = Vec::with_capacity(replies.len());
+        for (worker, reply) in replies {
+            match reply {
+                Ok(response) => {
+                    let meta = ObjectMeta::from_headers(response.headers());
+                    versions.push((worker, Some(meta.version.unwrap_or(0))));
+                    if newest
+                        .as_ref()
+                        .map_or(true, |(newest, _)| newer(&meta, newest))
+                    {
+                        newest = Some((meta, response.into_body()));
+                    }
+                }
+                Err(FairyClientError::NotFound(_)) => versions.push((worker, None)),
+                Err(FairyClientError::Deleted { version, .. }) => {
+                    deleted = deleted.max(Some(version));
+                    versions.push((worker, Some(version)));
+                }
+                Err(_) => {}
+            }
+        }
+        let newest = newest.filter(|(meta, _)| {
+            deleted.map_or(true, |deleted| meta.version.unwrap_or(0) > deleted)
+        });
+        let Some((meta, value)) = newest else {
+            finish_in_background(key, stragglers);
+            return Err(FairyClientError::NotFound(key.to_string()));
+        };
+
+        let newest_version = meta.version.unwrap_or(0);
+        let stale: Vec<String> = versions
+            .into_iter()
+            .filter(|(_, version)| version.map_or(true, |version| version < newest_version))
+            .map(|(worker, _)| worker)
+            .collect();
+        self.repair_in_background(key, &meta, &value, stale, stragglers);
+        Ok((meta, value))
+    }
+
+    /// Writes the newest value to the `stale` replicas and to the stragglers that turn
+    /// out missing or behind once they answer.
+    fn repair_in_background(
+        &self,
+        key: &str,
+        meta: &ObjectMeta,
+        value: &Bytes,
+        mut stale: Vec<String>,
+        mut stragglers: Stragglers,
+    ) {
+        if stale.is_empty() && stragglers.is_empty() {
+            return;
+        }
+        let (client, key, meta, value) =
+            (self.clone(), key.to_string(), meta.clone(), value.clone());
+        monoio::spawn(async move {
+            let newest_version = meta.version.unwrap_or(0);
+            while let Some((worker, reply)) = stragglers.next().await {
+                match reply {
+                    Ok(response) => {
+                        let version = ObjectMeta::from_headers(response.headers()).version;
+                        if version.unwrap_or(0) < newest_version {
+                            stale.push(worker);
+                        }
+                    }
+                    Err(FairyClientError::NotFound(_)) => stale.push(worker),
+                    Err(FairyClientError::Deleted { version, .. }) => {
+                        if version < newest_version {
+                            stale.push(worker);
+                        }
+                    }
+                    Err(e) => warn!("Replica request for {} failed: {}", key, e),
+                }
+            }
+            let repairs = stale.iter().map(|worker| {
+                client.call(
+                    worker,
+                    Method::PUT,
+                    "put",
+                    &key,
+                    Some(&meta),
+                    Some(value.clone()),
+                )
+            });
+            for (worker, result) in stale.iter().zip(join_all(repairs).await) {
+                match result {
+                    Ok(_) => debug!(
+                        "Repaired {} on {} to version {}",
+                        key, worker, newest_version
+                    ),
+                    Err(e) => warn!("Failed to repair {} on {}: {}", key, worker, e),
+                }
+            }
+        });
+    }
+
+    pub async fn put(&self, key: &str, value: Bytes) -> Result<(), FairyClientError> {
+        self.put_with_meta(key, value, &ObjectMeta::default()).await
+    }
+
+    /// Writes every replica, succeeding once `write_quorum` replicas acknowledged.
+    /// The value is versioned with the current time unless `meta` carries a version.
+    pub async fn put_with_meta(
+        &self,
+        key: &str,
+        value: Bytes,
+        meta: &ObjectMeta,
+    ) -> Result<(), FairyClientError> {
+        let mut meta = meta.clone();
+        meta.version.get_or_insert_with(new_version);
+        let (_, stragglers) = self
+            .fan_out(
+                key,
+                self.options.write_quorum,
+                Method::PUT,
+                "put",
+                Some(&meta),
+                Some(value),
+            )
+            .await?;
+        finish_in_background(key, stragglers);
+        Ok(())
+    }
+
+    /// Deletes every replica, succeeding once `write_quorum` replicas acknowledged.
+    /// Replicas keep a tombstone versioned like the writes, reads then tell the delete
+    /// from a replica that missed it and do not repair the value back.
+    pub async fn delete(&self, key: &str) -> Result<(), FairyClientError> {
+        let meta = ObjectMeta {
+            version: Some(new_version()),
+            ..ObjectMeta::default()
+        };
+        let (_, stragglers) = self
+            .fan_out(
+                key,
+                self.options.write_quorum,
+                Method::DELETE,
+                "delete",
+                Some(&meta),
+                None,
+            )
+            .await?;
+        finish_in_background(key, stragglers);
+        Ok(())
+    }
+
+    pub async fn stat(&self, key: &str) -> Result<ObjectStat, FairyClientError> {
+        if let Some(worker) = self.nearby_replica(key) {
+            match self
+                .call(&worker, Method::HEAD, "head", key, None, None)
+                .await
+            {
+                Ok(response) => return Ok(ObjectStat::from_response(&response)),
+                Err(e) => debug!("Zone local stat of {} on {} failed: {}", key, worker, e),
+            }
+        }
+        let (replies, stragglers) = self
+            .fan_out(
+                key,
+                self.options.read_quorum,
+                Method::HEAD,
+                "head",
+                None,
+                None,
+            )
+            .await?;
+        finish_in_background(key, stragglers);
+        let mut newest: Option<ObjectStat> = None;
+        for response in replies.into_iter().filter_map(|(_, reply)| reply.ok()) {
+            let stat = ObjectStat::from_response(&response);
+            if newest
+                .as_ref()
+                .map_or(true, |newest| newer(&stat.meta, &newest.meta))
+            {
+                newest = Some(stat);
+            }
+        }
+        newest.ok_or_else(|| FairyClientError::NotFound(key.to_string()))
+    }
+
+    /// An owner of the key in the client's zone, when a single replica answers reads.
+    fn nearby_replica(&self, key: &str) -> Option<String> {
+        let zone = self.options.zone.as_deref()?;
+        if self.options.read_quorum > 1 {
+            return None;
+        }
+        let owners = self.owners(key);
+        let ring = self.ring.read().unwrap();
+        owners
+            .into_iter()
+            .find(|worker| ring.zone(worker) == Some(zone))
+    }
+
+    /// Sends a request to every owner of the key concurrently and returns as soon as
+    /// `quorum` owners answered, a missing key counts as an answer. Fails once too
+    /// many owners failed for the quorum to be reached, with the status of a refused
+    /// request if any. The requests still running are returned alongside the replies.
+    async fn fan_out(
+        &self,
+        key: &str,
+        quorum: usize,
+        method: Method,
+        op: &'static str,
+        meta: Option<&ObjectMeta>,
+        body: Option<Bytes>,
+    ) -> Result<(Vec<Reply>, Stragglers), FairyClientError> {
+        let owners = self.owners(key);
+        if owners.is_empty() {
+            return Err(FairyClientError::NoWorker(key.to_string()));
+        }
+        // a ring smaller than the replication factor can only offer what it has
+        let quorum = quorum.min(owners.len());
+        let total = owners.len();
+        let mut pending: Stragglers = owners
+            .into_iter()
+            .map(|worker| {
+                let (client, method, key) = (self.clone(), method.clone(), key.to_string());
+                let (meta, body) = (meta.cloned(), body.clone());
+                async move {
+                    let reply = client
+                        .call(&worker, method, op, &key, meta.as_ref(), body)
+                        .await;
+                    (worker, reply)
+                }
+                .boxed_local()
+            })
+            .collect();
+        let mut replies = Vec::with_capacity(total);
+        let (mut answered, mut failed) = (0, 0);
+        let mut last_error = None;
+        let mut rejected = None;
+        while answered < quorum && total - failed >= quorum {
+            let Some((worker, reply)) = pending.next().await else {
+                break;
+            };
+            match &reply {
+                Ok(_) | Err(FairyClientError::NotFound(_) | FairyClientError::Deleted { .. }) => {
+                    answered += 1
+                }
+                Err(e) => {
+                    warn!("Replica request for {} failed: {}", key, e);
+                    last_error = Some(e.to_string());
+                    if let FairyClientError::Status { worker, status } = e {
+                        if !e.is_retryable() {
+                            rejected = Some((worker.clone(), *status));
+                        }
+                    }
+                    failed += 1;
+                }
+            }
+            replies.push((worker, reply));
+        }
+        if answered < quorum {
+            finish_in_background(key, pending);
+            // a request the workers refuse fails the same on every replica
+            if let Some((worker, status)) = rejected {
+                return Err(FairyClientError::Status { worker, status });
+            }
+            return Err(FairyClientError::Quorum {
+                key: key.to_string(),
+                answered,
+                quorum,
+                last_error: last_error.unwrap_or_default(),
+            });
+        }
+        Ok((replies, pending))
+    }
+
+    /// Sends a request to one worker, retrying timeouts, connection errors and 5xx.
+    async fn call(
+        &self,
+        worker: &str,
+        method: Method,
+        op: &str,
+        key: &str,
+        meta: Option<&ObjectMeta>,
+        body: Option<Bytes>,
+    ) -> Result<Response<Bytes>, FairyClientError> {
+        let mut attempt = 0;
+        loop {
+            let mut request = Request::builder()
+                .method(method.clone())
+                .uri(format!(
+                    "http://{}/{}/{}",
+                    self.h2_addr(worker),
+                    op,
+                    encode_key(key)
+                ))
+                .body(())?;
+            if let Some(meta) = meta {
+                meta.to_headers(request.headers_mut());
+            }
+            let result = self.send(worker, request, body.clone()).await;
+            let error = match result {
+                Ok(response) if response.status().is_success() => {
+                    self.health.record_success(worker);
+                    return Ok(response);
+                }
+                Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                    self.health.record_success(worker);
+                    let deleted = response
+                        .headers()
+                        .get(TOMBSTONE_VERSION_HEADER)
+                        .and_then(|version| version.to_str().ok()?.parse().ok());
+                    return Err(match deleted {
+                        Some(version) => FairyClientError::Deleted {
+                            key: key.to_string(),
+                            version,
+                        },
+                        None => FairyClientError::NotFound(key.to_string()),
+                    });
+                }
+                Ok(response) => FairyClientError::Status {
+                    worker: worker.to_string(),
+                    status: response.status(),
+                },
+                Err(e) => e,
+            };
+            if error.is_retryable() {
+                self.health.record_failure(worker);
+            } else {
+                self.health.record_success(worker);
+            }
+            if !error.is_retryable() || attempt >= self.options.max_retries {
+                return Err(error);
+            }
+            attempt += 1;
+            warn!("{} {} failed, retry {}: {}", op, key, attempt, error);
+            monoio::time::sleep(self.options.retry_backoff * attempt).await;
+        }
+    }
+
+    async fn send(
+        &self,
+        worker: &str,
+        request: Request<()>,
+        body: Option<Bytes>,
+    ) -> Result<Response<Bytes>, FairyClientError> {
+        let connection = self.connection(worker).await?;
+        match monoio::time::timeout(
+            self.options.request_timeout,
+            h2_client::send(connection, request, body),
+        )
+        .await
+        {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => {
+                self.connections.lock().unwrap().remove(worker);
+                Err(FairyClientError::Transport {
+                    worker: worker.to_string(),
+                    message: e.to_string(),
+                })
+            }
+            Err(_) => Err(FairyClientError::Timeout(worker.to_string())),
+        }
+    }
+
+    async fn connection(&self, worker: &str) -> Result<SendRequest<Bytes>, FairyClientError> {
+        if let Some(connection) = self.connections.lock().unwrap().get(worker) {
+            return Ok(connection.clone());
+        }
+        let addr = self.h2_addr(worker);
+        debug!("Connecting to worker {} at {}", worker, addr);
+        let connection =
+            match monoio::time::timeout(self.options.connect_timeout, h2_client::connect(&addr))
+                .await
+            {
+                Ok(Ok(connection)) => connection,
+                Ok(Err(e)) => {
+                    return Err(FairyClientError::Transport {
+                        worker: worker.to_string(),
+                        message: e.to_string(),
+                    })
+                }
+                Err(_) => return Err(FairyClientError::Timeout(worker.to_string())),
+            };
+        // a concurrent request may have connected first, keep a single connection
+        let mut connections = self.connections.lock().unwrap();
+        Ok(connections
+            .entry(worker.to_string())
+            .or_insert(connection)
+            .clone())
+    }
+
+    fn h2_addr(&self, worker: &str) -> String {
+        let discovery = self.discovery.read().unwrap();
+        h2_client::resolve_h2_addr(discovery.as_deref(), worker, self.options.h2_port)
+    }
+}
+
+/// Lets the replica requests still running complete, a write that reached its quorum
+/// still lands on every owner that is up.
+fn finish_in_background(key: &str, mut stragglers: Stragglers) {
+    if stragglers.is_empty() {
+        return;
+    }
+    let key = key.to_string();
+    monoio::spawn(async move {
+        while let Some((worker, reply)) = stragglers.next().await {
+            match reply {
+                Ok(_) | Err(FairyClientError::NotFound(_) | FairyClientError::Deleted { .. }) => {}
+                Err(e) => warn!("Replica request for {} to {} failed: {}", key, worker, e),
+            }
+        }
+    });
+}
+
+/// Versions are microseconds since the epoch, the last writer wins.
+fn new_version() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as u64)
+}
+
+fn newer(meta: &ObjectMeta, than: &ObjectMeta) -> bool {
+    meta.version.unwrap_or(0) > than.version.unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use fairy_common::discovery::static_list::StaticDiscovery;
+    use fairy_common::discovery::WorkerDescriptor;
+    use fairy_common::h2::h2_service::H2Service;
+    use fairy_common::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
+    use fairy_common::settings::local_kv_options::StoreLayout;
+    use fairy_common::test_util::{self, free_port};
+
+    use super::*;
+
+    /// Serves a store on `host`, loopback hosts share the port like workers of a cluster.
+    fn serve_on(host: &str, port: u16, root: &std::path::Path) -> &'static LocalFileKVStore {
+        let kv_store = test_util::store(root, StoreLayout::FilePerKey);
+        test_util::spawn_h2(H2Service::new(kv_store, test_util::h2_addr(host, port)));
+        kv_store
+    }
+
+    fn serve(root: &std::path::Path) -> u16 {
+        let port = free_port();
+        serve_on("127.0.0.1", port, root);
+        port
+    }
+
+    /// Lets the replica requests left running after a quorum complete.
+    async fn settle() {
+        monoio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    fn options(h2_port: u16) -> FairyClientOptions {
+        FairyClientOptions {
+            workers: vec![String::from("127.0.0.1:8080")],
+            h2_port,
+            request_timeout: Duration::from_secs(2),
+            ..FairyClientOptions::default()
+        }
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_put_get_stat_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = FairyClient::new(options(serve(dir.path())));
+
+        // larger than the initial h2 window, the value spans many data frames
+        let value = Bytes::from(vec![7u8; 200 * 1024]);
+        let meta = ObjectMeta {
+            content_type: Some(String::from("application/octet-stream")),
+            ..ObjectMeta::default()
+        };
+        client
+            .put_with_meta("models/a.bin", value.clone(), &meta)
+            .await
+            .unwrap();
+        client.put("b", Bytes::from_static(b"small")).await.unwrap();
+
+        let (got_meta, got) = client.get_with_meta("models/a.bin").await.unwrap();
+        assert_eq!(got, value);
+        assert_eq!(got_meta.content_type, meta.content_type);
+        assert_eq!(client.get("b").await.unwrap(), Bytes::from_static(b"small"));
+        // keys are percent encoded in the request path
+        let odd = "dir/a b?c#%é";
+        client.put(odd, Bytes::from_static(b"odd")).await.unwrap();
+        assert_eq!(client.get(odd).await.unwrap(), Bytes::from_static(b"odd"));
+        assert!(matches!(
+            client.get("dir/a b").await,
+            Err(FairyClientError::NotFound(_))
+        ));
+
+        let stat = client.stat("models/a.bin").await.unwrap();
+        assert_eq!(stat.len, value.len() as u64);
+
+        client.delete("models/a.bin").await.unwrap();
+        assert!(matches!(
+            client.get("models/a.bin").await,
+            Err(FairyClientError::NotFound(_))
+        ));
+        assert!(matches!(
+            client.stat("models/a.bin").await,
+            Err(FairyClientError::NotFound(_))
+        ));
+        assert_eq!(client.connections.lock().unwrap().len(), 1);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_unreachable_worker_is_retried() {
+        let port = free_port();
+        let client = FairyClient::new(FairyClientOptions {
+            max_retries: 1,
+            retry_backoff: Duration::from_millis(1),
+            ..options(port)
+        });
+        let result = client.get("a").await;
+        assert!(
+            matches!(result, Err(FairyClientError::Quorum { answered: 0, .. })),
+            "{:?}",
+            result
+        );
+
+        client.set_workers(Vec::new());
+        assert!(matches!(
+            client.get("a").await,
+            Err(FairyClientError::NoWorker(_))
+        ));
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_quorum_writes_and_read_repair() {
+        let port = free_port();
+        let dirs: Vec<_> = (0..3).map(|_| tempfile::tempdir().unwrap()).collect();
+        let stores: Vec<_> = (1..=3)
+            .map(|i| serve_on(&format!("127.0.0.{}", i), port, dirs[i - 1].path()))
+            .collect();
+        let workers: Vec<String> = (1..=3).map(|i| format!("127.0.0.{}:8080", i)).collect();
+        let client = FairyClient::new(FairyClientOptions {
+            workers: workers.clone(),
+            replication_factor: 3,
+            write_quorum: 2,
+            read_quorum: 2,
+            ..options(port)
+        });
+
+        client.put("k", Bytes::from_static(b"v2")).await.unwrap();
+        settle().await;
+        assert!(stores.iter().all(|store| store.exists(String::from("k"))));
+        let version = client.stat("k").await.unwrap().meta.version.unwrap();
+
+        // one replica lost the key, then another one falls behind, any read quorum
+        // still sees the newest version and repairs the replica
+        stores[0].remove(String::from("k")).unwrap();
+        assert_eq!(client.get("k").await.unwrap(), Bytes::from_static(b"v2"));
+        settle().await;
+        let old = ObjectMeta {
+            version: Some(version - 1),
+            ..ObjectMeta::default()
+        };
+        stores[1]
+            .put_with_meta(String::from("k"), Bytes::from_static(b"v1"), &old)
+            .await
+            .unwrap();
+        assert_eq!(client.get("k").await.unwrap(), Bytes::from_static(b"v2"));
+        settle().await;
+        for store in stores.iter() {
+            let (meta, value) = store.get_with_meta(String::from("k")).await.unwrap();
+            assert_eq!(
+                (meta.version, value.as_slice()),
+                (Some(version), &b"v2"[..])
+            );
+        }
+
+        // a fourth owner that is down still leaves a write quorum of 2
+        let mut with_dead = workers.clone();
+        with_dead.push(String::from("127.0.0.4:8080"));
+        let client = FairyClient::new(FairyClientOptions {
+            workers: with_dead,
+            replication_factor: 4,
+            write_quorum: 2,
+            max_retries: 0,
+            ..options(port)
+        });
+        client.put("k2", Bytes::from_static(b"v")).await.unwrap();
+        let strict = FairyClient::new(FairyClientOptions {
+            write_quorum: 4,
+            ..(*client.options).clone()
+        });
+        assert!(matches!(
+            strict.put("k3", Bytes::from_static(b"v")).await,
+            Err(FairyClientError::Quorum { quorum: 4, .. })
+        ));
+        settle().await;
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_read_repair_does_not_resurrect_deletes() {
+        let port = free_port();
+        let dirs: Vec<_> = (0..3).map(|_| tempfile::tempdir().unwrap()).collect();
+        let stores: Vec<_> = (1..=3)
+            .map(|i| serve_on(&format!("127.0.0.{}", i), port, dirs[i - 1].path()))
+            .collect();
+        let client = FairyClient::new(FairyClientOptions {
+            workers: (1..=3).map(|i| format!("127.0.0.{}:8080", i)).collect(),
+            replication_factor: 3,
+            write_quorum: 2,
+            read_quorum: 3,
+            ..options(port)
+        });
+
+        client.put("k", Bytes::from_static(b"v1")).await.unwrap();
+        settle().await;
+        let version = client.stat("k").await.unwrap().meta.version.unwrap();
+
+        // the first replica missed the delete, its value is older than the tombstones
+        for store in &stores[1..] {
+            store.delete_at(String::from("k"), version + 1).unwrap();
+        }
+        assert!(matches!(
+            client.get("k").await,
+            Err(FairyClientError::NotFound(_))
+        ));
+        settle().await;
+        assert!(stores[1..]
+            .iter()
+            .all(|store| !store.exists(String::from("k"))));
+
+        // a write newer than the delete is repaired over the tombstones
+        let newer = ObjectMeta {
+            version: Some(version + 2),
+            ..ObjectMeta::default()
+        };
+        stores[0]
+            .put_with_meta(String::from("k"), Bytes::from_static(b"v2"), &newer)
+            .await
+            .unwrap();
+        assert_eq!(client.get("k").await.unwrap(), Bytes::from_static(b"v2"));
+        settle().await;
+        assert!(stores.iter().all(|store| store.exists(String::from("k"))));
+
+        // deletes from the client are versioned like its writes
+        client.delete("k").await.unwrap();
+        settle().await;
+        assert!(stores.iter().all(|store| store
+            .deleted_version("k")
+            .is_some_and(|deleted| deleted > version + 2)));
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_quorum_does_not_wait_for_hung_replica() {
+        let port = free_port();
+        let dirs: Vec<_> = (0..2).map(|_| tempfile::tempdir().unwrap()).collect();
+        for (i, dir) in dirs.iter().enumerate() {
+            serve_on(&format!("127.0.0.{}", i + 1), port, dir.path());
+        }
+        // accepts connections but never answers
+        let hung = std::net::TcpListener::bind(("127.0.0.3", port)).unwrap();
+        monoio::time::sleep(Duration::from_millis(10)).await;
+        let client = FairyClient::new(FairyClientOptions {
+            workers: (1..=3).map(|i| format!("127.0.0.{}:8080", i)).collect(),
+            replication_factor: 3,
+            write_quorum: 2,
+            read_quorum: 2,
+            max_retries: 0,
+            request_timeout: Duration::from_secs(10),
+            ..options(port)
+        });
+
+        let start = std::time::Instant::now();
+        client.put("k", Bytes::from_static(b"v")).await.unwrap();
+        assert_eq!(client.get("k").await.unwrap(), Bytes::from_static(b"v"));
+        client.delete("k").await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // resets the hung connections, the requests still waiting on it fail
+        drop(hung);
+        settle().await;
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_refused_request_is_not_a_health_failure() {
+        let port = free_port();
+        let dir = tempfile::tempdir().unwrap();
+        let kv_store = serve_on("127.0.0.1", port, dir.path());
+        kv_store.pin_key(String::from("pinned")).unwrap();
+        monoio::time::sleep(Duration::from_millis(10)).await;
+        let client = FairyClient::new(FairyClientOptions {
+            failure_threshold: 1,
+            ..options(port)
+        });
+
+        // the pin budget is 0, the worker has no room for a pinned value
+        let result = client.put("pinned", Bytes::from_static(b"v")).await;
+        assert!(
+            matches!(
+                result,
+                Err(FairyClientError::Status {
+                    status: StatusCode::INSUFFICIENT_STORAGE,
+                    ..
+                })
+            ),
+            "{:?}",
+            result
+        );
+        assert!(client.ejected_workers().is_empty());
+        client.put("other", Bytes::from_static(b"v")).await.unwrap();
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_failover_and_recovery() {
+        let port = free_port();
+        let up = tempfile::tempdir().unwrap();
+        serve_on("127.0.0.1", port, up.path());
+        let client = FairyClient::new(FairyClientOptions {
+            workers: vec![
+                String::from("127.0.0.1:8080"),
+                String::from("127.0.0.2:8080"),
+            ],
+            failure_threshold: 1,
+            max_retries: 0,
+            ..options(port)
+        });
+        let key = (0..)
+            .map(|i| format!("key{}", i))
+            .find(|key| client.owner(key).unwrap() == "127.0.0.2:8080")
+            .unwrap();
+
+        // the owner is down, the first request fails and ejects it
+        assert!(client.put(&key, Bytes::from_static(b"v")).await.is_err());
+        assert_eq!(
+            client.ejected_workers(),
+            vec![String::from("127.0.0.2:8080")]
+        );
+        assert_eq!(client.owner(&key).unwrap(), "127.0.0.1:8080");
+        client.put(&key, Bytes::from_static(b"v")).await.unwrap();
+        assert_eq!(client.get(&key).await.unwrap(), Bytes::from_static(b"v"));
+
+        client.check_health().await;
+        assert_eq!(client.ejected_workers().len(), 1);
+
+        let recovered = tempfile::tempdir().unwrap();
+        serve_on("127.0.0.2", port, recovered.path());
+        monoio::time::sleep(Duration::from_millis(10)).await;
+        client.check_health().await;
+        assert!(client.ejected_workers().is_empty());
+        assert_eq!(client.owner(&key).unwrap(), "127.0.0.2:8080");
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_reads_prefer_same_zone_replica() {
+        let port = free_port();
+        let dirs: Vec<_> = (0..3).map(|_| tempfile::tempdir().unwrap()).collect();
+        let stores: Vec<_> = (1..=3)
+            .map(|i| serve_on(&format!("127.0.0.{}", i), port, dirs[i - 1].path()))
+            .collect();
+        let workers: Vec<String> = (1..=3).map(|i| format!("127.0.0.{}:8080", i)).collect();
+        let client = FairyClient::new(FairyClientOptions {
+            workers: workers.clone(),
+            zone: Some(String::from("zone-2")),
+            replication_factor: 3,
+            ..options(port)
+        });
+        client.set_zones(
+            workers
+                .iter()
+                .enumerate()
+                .map(|(i, worker)| (worker.clone(), Some(format!("zone-{}", i + 1)))),
+        );
+        client.put("k", Bytes::from_static(b"v2")).await.unwrap();
+        settle().await;
+
+        // an older value in zone-2 is what a zone local read returns
+        let old = ObjectMeta {
+            version: Some(1),
+            ..ObjectMeta::default()
+        };
+        stores[1]
+            .put_with_meta(String::from("k"), Bytes::from_static(b"v1"), &old)
+            .await
+            .unwrap();
+        assert_eq!(client.get("k").await.unwrap(), Bytes::from_static(b"v1"));
+        assert_eq!(client.stat("k").await.unwrap().meta.version, Some(1));
+
+        // without a local copy a read of every replica repairs the local one
+        stores[1].remove(String::from("k")).unwrap();
+        let client = FairyClient::new(FairyClientOptions {
+            read_quorum: 3,
+            ..(*client.options).clone()
+        });
+        assert_eq!(client.get("k").await.unwrap(), Bytes::from_static(b"v2"));
+        settle().await;
+        assert!(stores[1].exists(String::from("k")));
+    }
+
+    #[test]
+    fn test_routes_to_ring_owner() {
+        let client = FairyClient::new(FairyClientOptions {
+            workers: vec![String::from("10.0.0.1:8080"), String::from("10.0.0.2:8080")],
+            ..FairyClientOptions::default()
+        });
+        let owner = client.owner("models/a.bin").unwrap();
+        assert!(owner.starts_with("10.0.0."));
+        assert_eq!(client.h2_addr(&owner), owner.replace(":8080", ":5928"));
+    }
+
+    #[test]
+    fn test_follows_discovery() {
+        let client = Arc::new(FairyClient::new(FairyClientOptions::default()));
+        let discovery: Arc<dyn ServiceDiscovery> =
+            Arc::new(StaticDiscovery::new(vec![String::from("10.0.0.3:8080")]));
+        discovery.update_local(WorkerDescriptor {
+            node_id: String::from("10.0.0.3:8080"),
+            host: String::from("10.0.0.3"),
+            h2_port: 6000,
+            capacity_bytes: 8 << 30,
+            ..WorkerDescriptor::default()
+        });
+        client.follow(Arc::clone(&discovery));
+        assert_eq!(
+            client.ring.read().unwrap().members().collect::<Vec<_>>(),
+            [("10.0.0.3:8080", 8)]
+        );
+        assert_eq!(client.owner("models/a.bin").unwrap(), "10.0.0.3:8080");
+        // the descriptor's port wins over the configured one
+        assert_eq!(client.h2_addr("10.0.0.3:8080"), "10.0.0.3:6000");
+        assert_eq!(client.h2_addr("10.0.0.4:8080"), "10.0.0.4:5928");
+    }
+}
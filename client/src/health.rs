@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+#[derive(Clone, Copy, Debug, Default)]
+struct WorkerHealth {
+    consecutive_failures: u32,
+    ejected: bool,
+}
+
+/// Health of the workers as seen by one client. A worker is ejected from routing
+/// after `failure_threshold` consecutive failed requests or probes and re-admitted
+/// by the first request or probe that succeeds.
+#[derive(Debug)]
+pub struct HealthTracker {
+    failure_threshold: u32,
+    workers: Mutex<HashMap<String, WorkerHealth>>,
+}
+
+impl HealthTracker {
+    pub fn new(failure_threshold: u32) -> HealthTracker {
+        HealthTracker {
+            failure_threshold: failure_threshold.max(1),
+            workers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_healthy(&self, worker: &str) -> bool {
+        self.workers
+            .lock()
+            .unwrap()
+            .get(worker)
+            .map_or(true, |health| !health.ejected)
+    }
+
+    pub fn ejected(&self) -> Vec<String> {
+        let workers = self.workers.lock().unwrap();
+        let mut ejected: Vec<String> = workers
+            .iter()
+            .filter(|(_, health)| health.ejected)
+            .map(|(worker, _)| worker.clone())
+            .collect();
+        ejected.sort();
+        ejected
+    }
+
+    pub fn record_success(&self, worker: &str) {
+        let mut workers = self.workers.lock().unwrap();
+        if let Some(health) = workers.remove(worker) {
+            if health.ejected {
+                info!("Worker {} recovered, re-admitting it", worker);
+            }
+        }
+    }
+
+    pub fn record_failure(&self, worker: &str) {
+        let mut workers = self.workers.lock().unwrap();
+        let health = workers.entry(worker.to_string()).or_default();
+        health.consecutive_failures += 1;
+        if !health.ejected && health.consecutive_failures >= self.failure_threshold {
+            health.ejected = true;
+            warn!(
+                "Worker {} failed {} times in a row, ejecting it",
+                worker, health.consecutive_failures
+            );
+        }
+    }
+
+    /// Forgets workers that left the cluster.
+    pub fn retain<F: Fn(&str) -> bool>(&self, keep: F) {
+        self.workers
+            .lock()
+            .unwrap()
+            .retain(|worker, _| keep(worker));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eject_and_readmit() {
+        let health = HealthTracker::new(3);
+        health.record_failure("a");
+        health.record_failure("a");
+        assert!(health.is_healthy("a"));
+        // a success resets the streak
+        health.record_success("a");
+        health.record_failure("a");
+        health.record_failure("a");
+        assert!(health.is_healthy("a"));
+        health.record_failure("a");
+        assert!(!health.is_healthy("a"));
+        assert_eq!(health.ejected(), vec![String::from("a")]);
+        assert!(health.is_healthy("b"));
+
+        health.record_success("a");
+        assert!(health.is_healthy("a"));
+        assert!(health.ejected().is_empty());
+    }
+}
@@ -1,10 +1,18 @@
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::Bytes;
 use clap::{Parser, Subcommand};
-use monoio::net::TcpStream;
-use monoio_compat::StreamWrapper;
 
+use fairy_client::FairyClient;
+use fairy_common::discovery;
+use fairy_common::kv_store::local_kv_store::migration;
+use fairy_common::settings;
+use fairy_common::settings::client_options::FairyClientOptions;
+use fairy_common::settings::local_kv_options::{LocalFileKVStoreOptions, StoreLayout};
+
+mod admin;
 mod ufs;
 
 #[derive(Parser)]
@@ -27,6 +35,26 @@ enum Commands {
         mountpoint: PathBuf,
         source: PathBuf,
     },
+    /// Rebucket a stopped worker's local store into the current on-disk format
+    MigrateStore {
+        /// Store directory, defaults to the worker's local_kv_root_path
+        #[arg(long)]
+        root_path: Option<String>,
+        /// Target bucket count, defaults to the worker's local_kv_num_bucket
+        #[arg(long)]
+        num_bucket: Option<u16>,
+        /// Target layout, `file` or `segment`
+        #[arg(long)]
+        layout: Option<String>,
+    },
+    /// Take a worker out of service, waiting until it has handed off all its keys
+    Drain {
+        /// The worker as `host:http_port`
+        worker: String,
+        /// Milliseconds between progress reports
+        #[arg(long, default_value_t = 1000)]
+        poll_ms: u64,
+    },
 }
 
 #[tokio::main]
@@ -40,6 +68,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Some(Commands::MountPassthrough { mountpoint, source }) => {
             fairy_fuse::mount_passthrough(mountpoint, source);
         }
+        Some(Commands::MigrateStore {
+            root_path,
+            num_bucket,
+            layout,
+        }) => {
+            let mut options: LocalFileKVStoreOptions = settings::parse_with_prefix("worker");
+            if let Some(root_path) = root_path {
+                options.root_path = root_path.clone();
+            }
+            if let Some(num_bucket) = num_bucket {
+                options.num_bucket = *num_bucket;
+            }
+            if let Some(layout) = layout {
+                options.layout = StoreLayout::parse(layout)
+                    .ok_or_else(|| format!("unknown layout {}", layout))?;
+            }
+            let report = migration::migrate(&options).map_err(|e| e.to_string())?;
+            println!(
+                "Migrated {} from {:?} to {:?}: {} values moved, {} unchanged",
+                options.root_path, report.from, report.to, report.moved, report.unchanged
+            );
+            return Ok(());
+        }
+        Some(Commands::Drain { worker, poll_ms }) => {
+            admin::drain(worker, Duration::from_millis(*poll_ms)).await?;
+            println!("{} is drained and can be stopped", worker);
+            return Ok(());
+        }
         None => {}
     }
     // let s3_client = ufs::create_s3_client().await;
@@ -51,69 +107,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .enable_timer()
         .build()
         .unwrap();
+    let options: FairyClientOptions = settings::parse_with_prefix("client");
+    let discovery = discovery::from_options(&options.discovery, None);
+    let client = Arc::new(FairyClient::new(options));
+    client.follow(Arc::clone(&discovery));
+    tokio::spawn(discovery.run());
     rt.block_on(async {
-        let tcp = TcpStream::connect("127.0.0.1:5928").await.unwrap();
-        let tcp_wrapper = StreamWrapper::new(tcp);
-        let (client, h2) = h2::client::handshake(tcp_wrapper).await.unwrap();
-
-        // Spawn a task to run the conn...
-        monoio::spawn(async move {
-            if let Err(e) = h2.await {
-                println!("GOT ERR={e:?}");
-            }
-        });
-
-        let mut client = client.ready().await.unwrap();
-        let _ = put(&mut client).await;
-
-        let _ = get(&mut client).await;
+        match client.put("1111", Bytes::from_static(b"world\n")).await {
+            Ok(()) => println!("PUT 1111 to {:?}", client.owner("1111")),
+            Err(e) => println!("PUT ERR={e:?}"),
+        }
+        match client.get("1111").await {
+            Ok(value) => println!("GOT 1111 = {value:?}"),
+            Err(e) => println!("GET ERR={e:?}"),
+        }
     });
     Ok(())
 }
-
-#[allow(clippy::needless_pass_by_ref_mut)]
-async fn get(client: &mut h2::client::SendRequest<bytes::Bytes>) {
-    let request = http::Request::builder().uri("/get/1111").body(()).unwrap();
-
-    let mut trailers = http::HeaderMap::new();
-    trailers.insert("zomg", "hello".parse().unwrap());
-
-    let (response, mut stream) = client.send_request(request, false).unwrap();
-
-    // send trailers
-    stream.send_trailers(trailers).unwrap();
-
-    let response = response.await.unwrap();
-    println!("GOT GET RESPONSE: {response:?}");
-
-    // Get the body
-    let mut body = response.into_body();
-
-    while let Some(chunk) = body.data().await {
-        println!("GOT CHUNK = {:?}", chunk.unwrap());
-    }
-
-    if let Some(trailers) = body.trailers().await.unwrap() {
-        println!("GOT TRAILERS: {trailers:?}");
-    }
-}
-#[allow(clippy::needless_pass_by_ref_mut)]
-async fn put(
-    client: &mut h2::client::SendRequest<Bytes>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    //let mut client = client.ready().await.unwrap();
-    let request = http::Request::builder().uri("/put/1111").body(()).unwrap();
-
-    let (response, mut stream) = client.send_request(request, false).unwrap();
-
-    stream.send_data(bytes::Bytes::from_static(b"world\n"), false)?;
-
-    let mut trailers = http::HeaderMap::new();
-    trailers.insert("zomg", "hello".parse().unwrap());
-    stream.send_trailers(trailers).unwrap();
-
-    let response = response.await.unwrap();
-    println!("GOT PUT RESPONSE: {response:?}");
-
-    Ok(())
-}
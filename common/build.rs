@@ -0,0 +1,10 @@
+fn main() {
+    println!("cargo:rerun-if-changed=proto");
+    // values are handed to the store and sent back without copies
+    let mut config = prost_build::Config::new();
+    config.bytes(["."]);
+    tonic_build::configure()
+        .build_server(false)
+        .compile_with_config(config, &["proto/cache.proto"], &["proto"])
+        .expect("Failed to compile proto files");
+}
@@ -0,0 +1,703 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http::{Method, Request, StatusCode};
+use log::{debug, info, warn};
+
+use crate::discovery::ServiceDiscovery;
+use crate::h2::encode_key;
+use crate::h2::h2_client::{self, ConnectionPool, LOCAL_ONLY_HEADER};
+use crate::kv_store::local_kv_store::index::{KeyDigest, KeyState};
+use crate::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
+use crate::kv_store::object_meta::ObjectMeta;
+use crate::kv_store::{stable_hash, Key};
+use crate::metrics::{
+    ANTI_ENTROPY_BYTES_REPAIRED, ANTI_ENTROPY_KEYS_DIFFERING, ANTI_ENTROPY_KEYS_REPAIRED,
+    ANTI_ENTROPY_ROUNDS,
+};
+use crate::ring::HashRing;
+
+/// Levels below the root, the tree has `2^depth` leaves.
+pub const MERKLE_DEPTH: usize = 10;
+/// How long a tree built for a peer answers its requests. An exchange starts at the
+/// root, which is always answered from a fresh tree, and walks down the levels of it.
+const TREE_TTL: Duration = Duration::from_secs(10);
+
+/// Merkle tree over the keys a worker shares with one peer. Keys fall into leaves by
+/// hash, a leaf hashes the keys and digests in it, inner nodes hash their children.
+pub struct MerkleTree {
+    /// `levels[0]` is the root, `levels[depth]` the leaves.
+    levels: Vec<Vec<u64>>,
+    leaves: Vec<Vec<(String, KeyDigest)>>,
+}
+
+impl MerkleTree {
+    pub fn build<I: IntoIterator<Item = (String, KeyDigest)>>(entries: I, depth: usize) -> Self {
+        let mut leaves: Vec<Vec<(String, KeyDigest)>> = vec![Vec::new(); 1 << depth];
+        for (key, digest) in entries {
+            leaves[MerkleTree::leaf_of(&key, depth)].push((key, digest));
+        }
+        let mut level: Vec<u64> = leaves
+            .iter_mut()
+            .map(|leaf| {
+                leaf.sort_by(|a, b| a.0.cmp(&b.0));
+                leaf.iter().fold(0, |hash, (key, digest)| {
+                    let entry = format!(
+                        "{}\0{}\0{}\0{:?}",
+                        key, digest.version, digest.checksum, digest.state
+                    );
+                    combine(hash, stable_hash(entry.as_bytes()))
+                })
+            })
+            .collect();
+        let mut levels = vec![level.clone()];
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| combine(pair[0], pair[1]))
+                .collect();
+            levels.push(level.clone());
+        }
+        levels.reverse();
+        MerkleTree { levels, leaves }
+    }
+
+    pub fn leaf_of(key: &str, depth: usize) -> usize {
+        (key.to_string().stable_hash() % (1 << depth)) as usize
+    }
+
+    pub fn depth(&self) -> usize {
+        self.levels.len() - 1
+    }
+
+    pub fn root(&self) -> u64 {
+        self.levels[0][0]
+    }
+
+    /// Hashes of the given nodes of a level, 0 for nodes out of range.
+    pub fn nodes(&self, level: usize, nodes: &[usize]) -> Vec<u64> {
+        let level = self.levels.get(level);
+        nodes
+            .iter()
+            .map(|node| {
+                level
+                    .and_then(|level| level.get(*node))
+                    .copied()
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
+
+    pub fn leaf_entries(&self, leaves: &[usize]) -> Vec<(String, KeyDigest)> {
+        leaves
+            .iter()
+            .filter_map(|leaf| self.leaves.get(*leaf))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+fn combine(left: u64, right: u64) -> u64 {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&left.to_le_bytes());
+    bytes[8..].copy_from_slice(&right.to_le_bytes());
+    stable_hash(&bytes)
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    pub differing: usize,
+    pub pulled: usize,
+    pub pushed: usize,
+    pub deleted: usize,
+    pub bytes: u64,
+    pub failed: usize,
+}
+
+/// What brings a key shared with a peer back in sync.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Repair {
+    Pull,
+    Push,
+    /// Delete the local copy, at the version of the peer's tombstone.
+    DeleteLocal(u64),
+    /// Delete the peer's copy, at the version of the local tombstone.
+    DeleteRemote(u64),
+}
+
+/// The newer side wins, by version then by checksum so that both ends of an exchange
+/// agree on a conflict. A tombstone beats a value of the same version. An evicted key
+/// only takes a value newer than the one it dropped, and a key missing without a
+/// tombstone takes the value, as after a lost disk.
+fn resolve(mine: Option<&KeyDigest>, theirs: Option<&KeyDigest>) -> Option<Repair> {
+    let newer = |a: &KeyDigest, b: &KeyDigest| {
+        (a.version, !a.is_live(), a.checksum) > (b.version, !b.is_live(), b.checksum)
+    };
+    match (mine, theirs) {
+        (Some(mine), None) if mine.is_live() => Some(Repair::Push),
+        (None, Some(theirs)) if theirs.is_live() => Some(Repair::Pull),
+        (Some(mine), Some(theirs)) => match (mine.state, theirs.state) {
+            (KeyState::Live, KeyState::Live) if newer(theirs, mine) => Some(Repair::Pull),
+            (KeyState::Live, KeyState::Live) if newer(mine, theirs) => Some(Repair::Push),
+            (KeyState::Live, KeyState::Deleted) if newer(theirs, mine) => {
+                Some(Repair::DeleteLocal(theirs.version))
+            }
+            (KeyState::Live, KeyState::Deleted | KeyState::Evicted) if newer(mine, theirs) => {
+                Some(Repair::Push)
+            }
+            (KeyState::Deleted, KeyState::Live) if newer(mine, theirs) => {
+                Some(Repair::DeleteRemote(mine.version))
+            }
+            (KeyState::Deleted | KeyState::Evicted, KeyState::Live) if newer(theirs, mine) => {
+                Some(Repair::Pull)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Reconciles the replicas this worker shares with its peers. Both sides build a
+/// Merkle tree over the keys they share, the initiator walks down the levels where the
+/// hashes differ and repairs only the keys of differing leaves, in both directions.
+/// Deletes and evictions take part as tombstones for `TOMBSTONE_TTL`, see `resolve`.
+pub struct AntiEntropy {
+    kv_store: &'static LocalFileKVStore,
+    worker_id: String,
+    /// h2 port of peers without a descriptor.
+    h2_port: u16,
+    replication_factor: usize,
+    ring: Arc<RwLock<HashRing>>,
+    discovery: Option<Arc<dyn ServiceDiscovery>>,
+    /// Repaired bytes per second, 0 is unlimited.
+    rate_bytes: u64,
+    trees: Mutex<HashMap<String, (Instant, Arc<MerkleTree>)>>,
+    connections: ConnectionPool,
+}
+
+impl AntiEntropy {
+    pub fn new(
+        kv_store: &'static LocalFileKVStore,
+        worker_id: String,
+        h2_port: u16,
+        replication_factor: usize,
+        ring: Arc<RwLock<HashRing>>,
+        rate_bytes: u64,
+    ) -> AntiEntropy {
+        AntiEntropy {
+            kv_store,
+            worker_id,
+            h2_port,
+            replication_factor: replication_factor.max(1),
+            ring,
+            discovery: None,
+            rate_bytes,
+            trees: Mutex::new(HashMap::new()),
+            connections: ConnectionPool::new(),
+        }
+    }
+
+    /// Peers are reached on the h2 port they publish.
+    pub fn with_discovery(mut self, discovery: Arc<dyn ServiceDiscovery>) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    /// Exchanges with every peer holding replicas of this worker's keys.
+    pub async fn run_round(&self) -> RepairReport {
+        let mut report = RepairReport::default();
+        if self.replication_factor < 2 {
+            return report;
+        }
+        let peers: Vec<String> = self
+            .ring
+            .read()
+            .unwrap()
+            .members()
+            .map(|(peer, _)| peer.to_string())
+            .filter(|peer| *peer != self.worker_id)
+            .collect();
+        for peer in peers {
+            match self.exchange(&peer).await {
+                Ok(peer_report) => {
+                    report.differing += peer_report.differing;
+                    report.pulled += peer_report.pulled;
+                    report.pushed += peer_report.pushed;
+                    report.deleted += peer_report.deleted;
+                    report.bytes += peer_report.bytes;
+                    report.failed += peer_report.failed;
+                }
+                Err(e) => warn!("Anti-entropy with {} failed: {}", peer, e),
+            }
+        }
+        ANTI_ENTROPY_ROUNDS.inc();
+        self.connections.clear();
+        info!("Anti-entropy round finished: {:?}", report);
+        report
+    }
+
+    pub async fn exchange(&self, peer: &str) -> Result<RepairReport, Box<dyn Error + Send + Sync>> {
+        let local = self.build_tree(peer).await;
+        let mut differing = vec![0];
+        for level in 0..=local.depth() {
+            if level > 0 {
+                differing = differing
+                    .iter()
+                    .flat_map(|node| [node * 2, node * 2 + 1])
+                    .collect();
+            }
+            let remote = self.remote_nodes(peer, level, &differing).await?;
+            let mine = local.nodes(level, &differing);
+            differing = differing
+                .into_iter()
+                .zip(mine.into_iter().zip(remote))
+                .filter(|(_, (mine, theirs))| mine != theirs)
+                .map(|(node, _)| node)
+                .collect();
+            if differing.is_empty() {
+                debug!("Replicas shared with {} are in sync", peer);
+                return Ok(RepairReport::default());
+            }
+        }
+
+        let mine: BTreeMap<String, KeyDigest> =
+            local.leaf_entries(&differing).into_iter().collect();
+        let theirs: BTreeMap<String, KeyDigest> = self
+            .remote_leaves(peer, &differing)
+            .await?
+            .into_iter()
+            .collect();
+        let mut report = RepairReport::default();
+        let started = Instant::now();
+        let keys: BTreeSet<&String> = mine.keys().chain(theirs.keys()).collect();
+        for key in keys {
+            let Some(repair) = resolve(mine.get(key), theirs.get(key)) else {
+                continue;
+            };
+            report.differing += 1;
+            ANTI_ENTROPY_KEYS_DIFFERING.inc();
+            let result = match repair {
+                Repair::Pull => self.pull(peer, key).await,
+                Repair::Push => self.push(peer, key).await,
+                Repair::DeleteLocal(version) => self
+                    .kv_store
+                    .delete_at(key.clone(), version)
+                    .map(|_| 0)
+                    .map_err(|e| e.into()),
+                Repair::DeleteRemote(version) => self.push_delete(peer, key, version).await,
+            };
+            match result {
+                Ok(bytes) => {
+                    match repair {
+                        Repair::Pull => report.pulled += 1,
+                        Repair::Push => report.pushed += 1,
+                        Repair::DeleteLocal(_) | Repair::DeleteRemote(_) => report.deleted += 1,
+                    }
+                    report.bytes += bytes;
+                    ANTI_ENTROPY_KEYS_REPAIRED.inc();
+                    ANTI_ENTROPY_BYTES_REPAIRED.inc_by(bytes);
+                }
+                Err(e) => {
+                    warn!("Failed to repair {} with {}: {}", key, peer, e);
+                    report.failed += 1;
+                }
+            }
+            if self.rate_bytes > 0 {
+                let due = Duration::from_secs_f64(report.bytes as f64 / self.rate_bytes as f64);
+                let elapsed = started.elapsed();
+                if due > elapsed {
+                    monoio::time::sleep(due - elapsed).await;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Hashes of nodes of the tree shared with `peer`, answering a peer's exchange.
+    pub async fn merkle_nodes(&self, peer: &str, level: usize, nodes: &[usize]) -> Vec<u64> {
+        if level == 0 {
+            self.trees.lock().unwrap().remove(peer);
+        }
+        self.cached_tree(peer).await.nodes(level, nodes)
+    }
+
+    /// Keys and digests in leaves of the tree shared with `peer`.
+    pub async fn merkle_leaves(&self, peer: &str, leaves: &[usize]) -> Vec<(String, KeyDigest)> {
+        self.cached_tree(peer).await.leaf_entries(leaves)
+    }
+
+    async fn cached_tree(&self, peer: &str) -> Arc<MerkleTree> {
+        let cached = self.trees.lock().unwrap().get(peer).cloned();
+        if let Some((built, tree)) = cached {
+            if built.elapsed() < TREE_TTL {
+                return tree;
+            }
+        }
+        let tree = Arc::new(self.build_tree(peer).await);
+        self.trees
+            .lock()
+            .unwrap()
+            .insert(peer.to_string(), (Instant::now(), Arc::clone(&tree)));
+        tree
+    }
+
+    /// Tree over the local keys and tombstones `peer` holds replicas of as well.
+    async fn build_tree(&self, peer: &str) -> MerkleTree {
+        let shared = |key: &String| {
+            let ring = self.ring.read().unwrap();
+            let owners = ring.owners(key, self.replication_factor);
+            owners.contains(&peer) && owners.contains(&self.worker_id.as_str())
+        };
+        let keys: Vec<String> = self
+            .kv_store
+            .list("")
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(shared)
+            .collect();
+        let mut entries: Vec<(String, KeyDigest)> = self
+            .kv_store
+            .tombstones()
+            .into_iter()
+            .filter(|(key, _)| shared(key))
+            .collect();
+        for key in keys {
+            match self.kv_store.digest(&key).await {
+                Ok(digest) => entries.push((key, digest)),
+                Err(e) => debug!("Skipping {} in the Merkle tree: {}", key, e),
+            }
+        }
+        MerkleTree::build(entries, MERKLE_DEPTH)
+    }
+
+    async fn remote_nodes(
+        &self,
+        peer: &str,
+        level: usize,
+        nodes: &[usize],
+    ) -> Result<Vec<u64>, Box<dyn Error + Send + Sync>> {
+        let uri = format!(
+            "http://{}/merkle/{}?level={}&nodes={}",
+            self.addr(peer),
+            encode_key(&self.worker_id),
+            level,
+            join(nodes)
+        );
+        let hashes: Vec<u64> = self.request_json(peer, uri).await?;
+        if hashes.len() != nodes.len() {
+            return Err(format!(
+                "{} answered {} of {} nodes",
+                peer,
+                hashes.len(),
+                nodes.len()
+            )
+            .into());
+        }
+        Ok(hashes)
+    }
+
+    async fn remote_leaves(
+        &self,
+        peer: &str,
+        leaves: &[usize],
+    ) -> Result<Vec<(String, KeyDigest)>, Box<dyn Error + Send + Sync>> {
+        let uri = format!(
+            "http://{}/merkle-leaves/{}?leaves={}",
+            self.addr(peer),
+            encode_key(&self.worker_id),
+            join(leaves)
+        );
+        self.request_json(peer, uri).await
+    }
+
+    async fn request_json<T: serde::de::DeserializeOwned>(
+        &self,
+        peer: &str,
+        uri: String,
+    ) -> Result<T, Box<dyn Error + Send + Sync>> {
+        let request = Request::builder().uri(uri).body(())?;
+        let response = self
+            .connections
+            .send(&self.addr(peer), request, None)
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", peer, response.status()).into());
+        }
+        Ok(serde_json::from_slice(response.body())?)
+    }
+
+    async fn pull(&self, peer: &str, key: &str) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let request = Request::builder()
+            .uri(format!(
+                "http://{}/get/{}",
+                self.addr(peer),
+                encode_key(key)
+            ))
+            .header(LOCAL_ONLY_HEADER, "1")
+            .body(())?;
+        let response = self
+            .connections
+            .send(&self.addr(peer), request, None)
+            .await?;
+        if response.status() != StatusCode::OK {
+            return Err(format!("{} answered {}", peer, response.status()).into());
+        }
+        let meta = ObjectMeta::from_headers(response.headers());
+        let value = response.into_body();
+        let bytes = value.len() as u64;
+        self.kv_store
+            .put_with_meta(key.to_string(), value, &meta)
+            .await?;
+        debug!("Pulled {} ({} bytes) from {}", key, bytes, peer);
+        Ok(bytes)
+    }
+
+    async fn push_delete(
+        &self,
+        peer: &str,
+        key: &str,
+        version: u64,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let mut request = Request::builder()
+            .method(Method::DELETE)
+            .uri(format!(
+                "http://{}/delete/{}",
+                self.addr(peer),
+                encode_key(key)
+            ))
+            .body(())?;
+        let meta = ObjectMeta {
+            version: Some(version),
+            ..ObjectMeta::default()
+        };
+        meta.to_headers(request.headers_mut());
+        let response = self
+            .connections
+            .send(&self.addr(peer), request, None)
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", peer, response.status()).into());
+        }
+        debug!("Deleted {} on {} at version {}", key, peer, version);
+        Ok(0)
+    }
+
+    async fn push(&self, peer: &str, key: &str) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let (meta, value) = self.kv_store.get_with_meta(key.to_string()).await?;
+        let mut request = Request::builder()
+            .method(Method::PUT)
+            .uri(format!(
+                "http://{}/put/{}",
+                self.addr(peer),
+                encode_key(key)
+            ))
+            .body(())?;
+        meta.to_headers(request.headers_mut());
+        let bytes = value.len() as u64;
+        let response = self
+            .connections
+            .send(&self.addr(peer), request, Some(Bytes::from(value)))
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", peer, response.status()).into());
+        }
+        debug!("Pushed {} ({} bytes) to {}", key, bytes, peer);
+        Ok(bytes)
+    }
+
+    fn addr(&self, worker: &str) -> String {
+        h2_client::resolve_h2_addr(self.discovery.as_deref(), worker, self.h2_port)
+    }
+}
+
+fn join(nodes: &[usize]) -> String {
+    nodes
+        .iter()
+        .map(|node| node.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h2::h2_service::H2Service;
+    use crate::settings::local_kv_options::StoreLayout;
+    use crate::test_util::{free_port, h2_addr, leak, spawn_h2, store};
+
+    const A: &str = "127.0.0.1:8080";
+    const B: &str = "127.0.0.2:8080";
+
+    fn serve(
+        worker: &str,
+        port: u16,
+        kv_store: &'static LocalFileKVStore,
+        ring: &Arc<RwLock<HashRing>>,
+    ) -> &'static AntiEntropy {
+        let anti_entropy: &'static AntiEntropy = leak(AntiEntropy::new(
+            kv_store,
+            worker.to_string(),
+            port,
+            2,
+            Arc::clone(ring),
+            0,
+        ));
+        spawn_h2(H2Service::new(kv_store, h2_addr(worker, port)).with_anti_entropy(anti_entropy));
+        anti_entropy
+    }
+
+    async fn put(kv_store: &LocalFileKVStore, key: &str, value: &str, version: u64) {
+        let meta = ObjectMeta {
+            version: Some(version),
+            ..Default::default()
+        };
+        kv_store
+            .put_with_meta(key.to_string(), Bytes::from(value.to_string()), &meta)
+            .await
+            .unwrap();
+    }
+
+    async fn get(kv_store: &LocalFileKVStore, key: &str) -> (Option<u64>, Vec<u8>) {
+        let (meta, value) = kv_store.get_with_meta(key.to_string()).await.unwrap();
+        (meta.version, value.to_vec())
+    }
+
+    #[test]
+    fn test_resolve() {
+        let live = |version| KeyDigest::new(version, b"value");
+        let deleted = |version| KeyDigest::tombstone(version, KeyState::Deleted);
+        let evicted = |version| KeyDigest::tombstone(version, KeyState::Evicted);
+        let cases = [
+            (Some(live(1)), None, Some(Repair::Push)),
+            (None, Some(live(1)), Some(Repair::Pull)),
+            (Some(live(1)), Some(live(2)), Some(Repair::Pull)),
+            (Some(live(2)), Some(deleted(1)), Some(Repair::Push)),
+            (
+                Some(live(1)),
+                Some(deleted(1)),
+                Some(Repair::DeleteLocal(1)),
+            ),
+            (
+                Some(deleted(2)),
+                Some(live(1)),
+                Some(Repair::DeleteRemote(2)),
+            ),
+            (Some(deleted(2)), None, None),
+            (None, Some(evicted(2)), None),
+            (Some(live(1)), Some(evicted(2)), None),
+            (Some(live(3)), Some(evicted(2)), Some(Repair::Push)),
+            (Some(evicted(2)), Some(live(1)), None),
+            (Some(deleted(1)), Some(evicted(2)), None),
+        ];
+        for (mine, theirs, repair) in cases {
+            assert_eq!(
+                resolve(mine.as_ref(), theirs.as_ref()),
+                repair,
+                "{:?} {:?}",
+                mine,
+                theirs
+            );
+        }
+    }
+
+    #[test]
+    fn test_merkle_tree_localizes_differences() {
+        let entries = |changed: &str| -> Vec<(String, KeyDigest)> {
+            (0..100)
+                .map(|i| {
+                    let key = format!("key/{}", i);
+                    let value = if key == changed { "changed" } else { "value" };
+                    (key, KeyDigest::new(1, value.as_bytes()))
+                })
+                .collect()
+        };
+        let (left, right) = (
+            MerkleTree::build(entries(""), 4),
+            MerkleTree::build(entries("key/7"), 4),
+        );
+        assert_eq!(left.depth(), 4);
+        assert_eq!(left.root(), MerkleTree::build(entries(""), 4).root());
+        assert_ne!(left.root(), right.root());
+
+        let all: Vec<usize> = (0..16).collect();
+        let differing: Vec<usize> = all
+            .iter()
+            .copied()
+            .filter(|leaf| left.nodes(4, &[*leaf]) != right.nodes(4, &[*leaf]))
+            .collect();
+        assert_eq!(differing, vec![MerkleTree::leaf_of("key/7", 4)]);
+        assert!(right
+            .leaf_entries(&differing)
+            .iter()
+            .any(|(key, _)| key == "key/7"));
+        assert_eq!(left.leaf_entries(&all).len(), 100);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_exchange_repairs_differing_replicas() {
+        let port = free_port();
+        let (a_dir, b_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (a_store, b_store) = (
+            store(a_dir.path(), StoreLayout::FilePerKey),
+            store(b_dir.path(), StoreLayout::FilePerKey),
+        );
+        let ring = Arc::new(RwLock::new(HashRing::with_members(16, [A, B])));
+        let a = serve(A, port, a_store, &ring);
+        serve(B, port, b_store, &ring);
+        monoio::time::sleep(Duration::from_millis(10)).await;
+
+        for i in 0..50 {
+            let key = format!("same/{}", i);
+            put(a_store, &key, &key, 1).await;
+            put(b_store, &key, &key, 1).await;
+        }
+        put(a_store, "only/a", "a", 1).await;
+        put(b_store, "only/b", "b", 1).await;
+        put(a_store, "newer/a", "new", 2).await;
+        put(b_store, "newer/a", "old", 1).await;
+        put(a_store, "newer/b", "old", 1).await;
+        put(b_store, "newer/b", "new", 3).await;
+        put(a_store, "conflict", "a", 4).await;
+        put(b_store, "conflict", "b", 4).await;
+
+        put(a_store, "gone", "value", 1).await;
+        put(b_store, "gone", "value", 1).await;
+        a_store.delete("gone".to_string()).unwrap();
+
+        // the same version with different contents resolves to the higher checksum
+        let a_wins = KeyDigest::new(4, b"a").checksum > KeyDigest::new(4, b"b").checksum;
+        let report = a.exchange(B).await.unwrap();
+        assert_eq!(
+            report,
+            RepairReport {
+                differing: 6,
+                pulled: if a_wins { 2 } else { 3 },
+                pushed: if a_wins { 3 } else { 2 },
+                deleted: 1,
+                bytes: 9,
+                failed: 0,
+            }
+        );
+        let winner = if a_wins { b"a".to_vec() } else { b"b".to_vec() };
+        for store in [a_store, b_store] {
+            assert_eq!(get(store, "only/a").await, (Some(1), b"a".to_vec()));
+            assert_eq!(get(store, "only/b").await, (Some(1), b"b".to_vec()));
+            assert_eq!(get(store, "newer/a").await, (Some(2), b"new".to_vec()));
+            assert_eq!(get(store, "newer/b").await, (Some(3), b"new".to_vec()));
+            assert_eq!(get(store, "conflict").await, (Some(4), winner.clone()));
+            // the delete wins over the older value instead of being undone
+            assert!(!store.exists("gone".to_string()));
+        }
+        // both sides hold the same tombstone now
+        assert_eq!(a.exchange(B).await.unwrap(), RepairReport::default());
+
+        // deletes on both sides are not restored from either
+        a_store.delete("conflict".to_string()).unwrap();
+        b_store.delete("conflict".to_string()).unwrap();
+        assert_eq!(a.exchange(B).await.unwrap(), RepairReport::default());
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use bytes::Bytes;
+use monoio::io::{AsyncWriteRentExt, BufReader, Splitable};
+use monoio::net::tcp::{TcpOwnedReadHalf, TcpOwnedWriteHalf};
+use monoio::net::TcpStream;
+
+use crate::binary::protocol::{read_frame, Op, Request, Response, Status};
+
+/// Version and value length of a stored key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stat {
+    pub version: Option<u64>,
+    pub len: u64,
+}
+
+/// Client of the binary protocol over a single connection.
+pub struct BinaryClient {
+    reader: BufReader<TcpOwnedReadHalf>,
+    writer: TcpOwnedWriteHalf,
+    next_id: u64,
+}
+
+impl BinaryClient {
+    pub async fn connect(addr: &str) -> Result<BinaryClient, Box<dyn Error + Send + Sync>> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        Ok(BinaryClient {
+            reader: BufReader::new(reader),
+            writer,
+            next_id: 0,
+        })
+    }
+
+    /// The value and its version, None if the key is not stored.
+    pub async fn get(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<(Option<u64>, Bytes)>, Box<dyn Error + Send + Sync>> {
+        let response = self.call(Request::new(Op::Get, key)).await?;
+        Ok(found(response)?.map(|response| (response.version, response.body)))
+    }
+
+    pub async fn put(
+        &mut self,
+        key: &str,
+        value: Bytes,
+        version: Option<u64>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let response = self.call(Request::put(key, value, version)).await?;
+        found(response)?.ok_or("put answered not found")?;
+        Ok(())
+    }
+
+    pub async fn delete(&mut self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let response = self.call(Request::new(Op::Delete, key)).await?;
+        found(response)?;
+        Ok(())
+    }
+
+    pub async fn stat(&mut self, key: &str) -> Result<Option<Stat>, Box<dyn Error + Send + Sync>> {
+        let response = self.call(Request::new(Op::Stat, key)).await?;
+        let Some(response) = found(response)? else {
+            return Ok(None);
+        };
+        let len = response.body.as_ref().try_into().map(u64::from_be_bytes)?;
+        Ok(Some(Stat {
+            version: response.version,
+            len,
+        }))
+    }
+
+    async fn call(&mut self, request: Request) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        Ok(self.pipeline(vec![request]).await?.pop().unwrap())
+    }
+
+    /// Sends every request before reading any response, and returns the responses in
+    /// the order of the requests. The ids of the requests are assigned here.
+    pub async fn pipeline(
+        &mut self,
+        requests: Vec<Request>,
+    ) -> Result<Vec<Response>, Box<dyn Error + Send + Sync>> {
+        let mut order = HashMap::with_capacity(requests.len());
+        let mut frames = Vec::new();
+        for (position, mut request) in requests.into_iter().enumerate() {
+            if request.key.len() > u16::MAX as usize {
+                return Err(format!("key of {} bytes is too long", request.key.len()).into());
+            }
+            self.next_id += 1;
+            request.id = self.next_id;
+            order.insert(request.id, position);
+            frames.extend_from_slice(&request.encode());
+        }
+        let (res, _) = self.writer.write_all(frames).await;
+        res?;
+
+        let mut responses: Vec<Option<Response>> = vec![None; order.len()];
+        for _ in 0..order.len() {
+            let frame = read_frame(&mut self.reader)
+                .await?
+                .ok_or("connection closed by the worker")?;
+            let response = Response::decode(frame)?;
+            let position = *order
+                .get(&response.id)
+                .ok_or_else(|| format!("response to unknown request {}", response.id))?;
+            responses[position] = Some(response);
+        }
+        Ok(responses.into_iter().flatten().collect())
+    }
+}
+
+/// The successful response, None for a missing key.
+fn found(response: Response) -> Result<Option<Response>, Box<dyn Error + Send + Sync>> {
+    match response.status {
+        Status::Ok => Ok(Some(response)),
+        Status::NotFound => Ok(None),
+        status => Err(format!("{:?}: {}", status, String::from_utf8_lossy(&response.body)).into()),
+    }
+}
@@ -0,0 +1,324 @@
+use std::error::Error;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use log::{debug, error};
+use monoio::io::{BufReader, Splitable};
+use monoio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, Semaphore};
+
+use crate::binary::protocol::{
+    read_frame_body, read_frame_len, Op, Request, Response, Status, MAX_BODY_LEN, MAX_FRAME_LEN,
+};
+use crate::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
+use crate::kv_store::object_meta::ObjectMeta;
+use crate::kv_store::read_through::{self, ReadThroughLoader};
+use crate::kv_store::{is_not_found, validate_key};
+use crate::metrics::{INCOMING_REQUESTS, RESPONSE_TIME_COLLECTOR};
+use crate::rebalance::Rebalancer;
+use crate::shutdown::{self, Shutdown};
+
+/// Requests of one connection handled at the same time, reading pauses beyond that.
+const MAX_IN_FLIGHT: usize = 128;
+/// Request bytes of one connection held at the same time, reading pauses beyond that.
+const MAX_IN_FLIGHT_BYTES: usize = MAX_FRAME_LEN;
+
+/// Serves the binary protocol of `binary::protocol`. Requests of a connection are
+/// handled concurrently and answered as they complete. Values are read whole from the
+/// store and written out of that buffer, there is no zero-copy path to the socket.
+#[derive(Clone, Copy)]
+pub struct BinaryService {
+    kv_store: &'static LocalFileKVStore,
+    addr: &'static str,
+    rebalancer: Option<&'static Rebalancer>,
+    loader: Option<&'static ReadThroughLoader>,
+    shutdown: Option<&'static Shutdown>,
+}
+
+impl BinaryService {
+    pub fn new(kv_store: &'static LocalFileKVStore, addr: &'static str) -> BinaryService {
+        BinaryService {
+            kv_store,
+            addr,
+            rebalancer: None,
+            loader: None,
+            shutdown: None,
+        }
+    }
+
+    pub fn with_rebalancer(mut self, rebalancer: &'static Rebalancer) -> Self {
+        self.rebalancer = Some(rebalancer);
+        self
+    }
+
+    pub fn with_loader(mut self, loader: &'static ReadThroughLoader) -> Self {
+        self.loader = Some(loader);
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: &'static Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Serves until the shutdown is triggered.
+    pub async fn serve(&self) {
+        let listener = TcpListener::bind(self.addr).unwrap();
+        loop {
+            let accepted = monoio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown::triggered(self.shutdown) => break,
+            };
+            match accepted {
+                Ok((socket, peer_addr)) => {
+                    let service = *self;
+                    monoio::spawn(async move {
+                        debug!("binary connection received from {}", peer_addr);
+                        if let Err(e) = service.serve_connection(socket).await {
+                            debug!("binary connection from {} failed: {}", peer_addr, e);
+                        }
+                    });
+                }
+                Err(e) => error!("binary connection failed: {}", e),
+            }
+        }
+        debug!(
+            "binary service on {} stopped accepting connections",
+            self.addr
+        );
+    }
+
+    async fn serve_connection(self, socket: TcpStream) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // responses are small writes that must not wait for more data
+        socket.set_nodelay(true)?;
+        let (reader, writer) = socket.into_split();
+        let mut reader = BufReader::new(reader);
+        let writer = Rc::new(Mutex::new(writer));
+        let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+        let in_flight_bytes = Arc::new(Semaphore::new(MAX_IN_FLIGHT_BYTES));
+        while let Some(len) = read_frame_len(&mut reader).await? {
+            // frames are at most MAX_FRAME_LEN, which fits in u32
+            let bytes = Arc::clone(&in_flight_bytes)
+                .acquire_many_owned(len as u32)
+                .await?;
+            let frame = read_frame_body(&mut reader, len).await?;
+            let response = match Request::decode(frame.clone()) {
+                Ok(request) => Ok(request),
+                Err(e) => match Request::frame_id(&frame) {
+                    Some(id) => Err(Response::message(Status::BadRequest, id, e.to_string())),
+                    None => return Err(e.into()),
+                },
+            };
+            let permit = Arc::clone(&in_flight).acquire_owned().await?;
+            let tracked = self.shutdown.map(|shutdown| shutdown.track());
+            let writer = Rc::clone(&writer);
+            monoio::spawn(async move {
+                let _permit = permit;
+                let _bytes = bytes;
+                let _tracked = tracked;
+                let _timer = RESPONSE_TIME_COLLECTOR.start_timer();
+                INCOMING_REQUESTS.inc();
+                let response = match response {
+                    Ok(request) => self.handle(request).await,
+                    Err(response) => response,
+                };
+                let mut writer = writer.lock().await;
+                if let Err(e) = response.write(&mut *writer).await {
+                    debug!("Failed to write binary response: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    async fn handle(self, request: Request) -> Response {
+        let id = request.id;
+        if let Err(e) = validate_key(&request.key) {
+            return Response::message(Status::BadRequest, id, e.to_string());
+        }
+        let result = match request.op {
+            Op::Get => self.get(request).await,
+            Op::Put => self.put(request).await,
+            Op::Delete => self
+                .kv_store
+                .delete(request.key)
+                .map(|_| Response::new(Status::Ok, id))
+                .map_err(Into::into),
+            Op::Stat => self.stat(request).await,
+        };
+        result.unwrap_or_else(|e| {
+            if is_not_found(&*e) {
+                Response::new(Status::NotFound, id)
+            } else {
+                error!("binary request {} failed: {}", id, e);
+                Response::message(Status::Error, id, e.to_string())
+            }
+        })
+    }
+
+    /// A miss is served from the old owner while the key is being handed over, then
+    /// read through the loader.
+    async fn get(self, request: Request) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        let (meta, value) =
+            read_through::lookup(self.kv_store, self.rebalancer, self.loader, &request.key).await?;
+        if value.len() > MAX_BODY_LEN {
+            return Err(format!("value of {} bytes is too large to send", value.len()).into());
+        }
+        Ok(Response {
+            status: Status::Ok,
+            id: request.id,
+            version: meta.version,
+            body: value,
+        })
+    }
+
+    async fn put(self, request: Request) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        let meta = ObjectMeta {
+            version: request.version,
+            ..Default::default()
+        };
+        self.kv_store
+            .put_with_meta(request.key, request.value, &meta)
+            .await?;
+        Ok(Response::new(Status::Ok, request.id))
+    }
+
+    async fn stat(self, request: Request) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        let (meta, len) = self.kv_store.head(request.key).await?;
+        Ok(Response {
+            status: Status::Ok,
+            id: request.id,
+            version: meta.version,
+            body: Bytes::copy_from_slice(&len.to_be_bytes()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use monoio::io::AsyncWriteRentExt;
+
+    use super::*;
+    use crate::binary::binary_client::{BinaryClient, Stat};
+    use crate::settings::local_kv_options::StoreLayout;
+    use crate::test_util::{self, leak};
+
+    async fn serve(layout: StoreLayout) -> (&'static str, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = test_util::store(dir.path(), layout);
+        let addr =
+            test_util::serve_on_free_port(|addr| leak(BinaryService::new(store, addr)).serve())
+                .await;
+        (addr, dir)
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_get_put_delete_stat() {
+        for layout in [StoreLayout::FilePerKey, StoreLayout::Segment] {
+            let (addr, _dir) = serve(layout).await;
+            let mut client = BinaryClient::connect(addr).await.unwrap();
+            assert_eq!(client.get("key").await.unwrap(), None);
+            assert_eq!(client.stat("key").await.unwrap(), None);
+
+            client
+                .put("key", Bytes::from_static(b"value"), Some(3))
+                .await
+                .unwrap();
+            assert_eq!(
+                client.get("key").await.unwrap(),
+                Some((Some(3), Bytes::from_static(b"value")))
+            );
+            assert_eq!(
+                client.stat("key").await.unwrap(),
+                Some(Stat {
+                    version: Some(3),
+                    len: 5
+                })
+            );
+
+            client.delete("key").await.unwrap();
+            assert_eq!(client.get("key").await.unwrap(), None);
+            assert!(client.get("").await.is_err());
+        }
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_pipelined_requests() {
+        let (addr, _dir) = serve(StoreLayout::FilePerKey).await;
+        let mut client = BinaryClient::connect(addr).await.unwrap();
+        // large enough to be written from the read buffer
+        let large = Bytes::from(vec![7; 1024 * 1024]);
+        let puts = (0..10)
+            .map(|i| {
+                let value = if i == 0 {
+                    large.clone()
+                } else {
+                    Bytes::from(format!("value{}", i))
+                };
+                Request::put(&format!("key{}", i), value, None)
+            })
+            .collect();
+        let responses = client.pipeline(puts).await.unwrap();
+        assert!(responses.iter().all(|r| r.status == Status::Ok));
+
+        let gets = (0..11)
+            .map(|i| Request::new(Op::Get, &format!("key{}", i)))
+            .collect();
+        let responses = client.pipeline(gets).await.unwrap();
+        assert_eq!(responses[0].body, large);
+        for (i, response) in responses.iter().enumerate().take(10).skip(1) {
+            assert_eq!(response.body, Bytes::from(format!("value{}", i)));
+        }
+        assert_eq!(responses[10].status, Status::NotFound);
+        let ids: Vec<u64> = responses.iter().map(|r| r.id).collect();
+        assert_eq!(ids, (11..22).collect::<Vec<u64>>());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_bad_request_is_answered() {
+        let (addr, _dir) = serve(StoreLayout::FilePerKey).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut request = Request::new(Op::Get, "key");
+        request.id = 5;
+        let mut frame = request.encode();
+        frame[4] = 42;
+        let (res, _) = stream.write_all(frame).await;
+        res.unwrap();
+        let frame = crate::binary::protocol::read_frame(&mut stream)
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Response::decode(frame).unwrap();
+        assert_eq!((response.status, response.id), (Status::BadRequest, 5));
+        assert_eq!(response.body.as_ref(), b"unknown op 42");
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_keys_escaping_the_store_are_rejected() {
+        let (addr, _dir) = serve(StoreLayout::FilePerKey).await;
+        let mut client = BinaryClient::connect(addr).await.unwrap();
+        let requests = vec![
+            Request::put("/etc/passwd", Bytes::from_static(b"x"), None),
+            Request::new(Op::Get, "../x"),
+            Request::new(Op::Delete, "a/../../x"),
+        ];
+        for response in client.pipeline(requests).await.unwrap() {
+            assert_eq!(response.status, Status::BadRequest);
+        }
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_truncated_large_frame_is_dropped() {
+        let (addr, _dir) = serve(StoreLayout::FilePerKey).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut frame = (MAX_FRAME_LEN as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&[0; 1024]);
+        let (res, _) = stream.write_all(frame).await;
+        res.unwrap();
+        drop(stream);
+
+        let mut client = BinaryClient::connect(addr).await.unwrap();
+        assert!(client.get("key").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,3 @@
+pub mod binary_client;
+pub mod binary_service;
+pub mod protocol;
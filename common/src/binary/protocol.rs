@@ -0,0 +1,328 @@
+use bytes::Bytes;
+use monoio::buf::IoBufMut;
+use monoio::io::{AsyncReadRent, AsyncReadRentExt, AsyncWriteRent, AsyncWriteRentExt};
+use thiserror::Error;
+
+/// Largest frame accepted after the length prefix, bounding what a put can carry.
+pub const MAX_FRAME_LEN: usize = 256 * 1024 * 1024;
+/// Op, request id, version and key length.
+const REQUEST_HEADER_LEN: usize = 1 + 8 + 8 + 2;
+/// Status, request id and version.
+const RESPONSE_HEADER_LEN: usize = 1 + 8 + 8;
+/// Largest body a response frame can carry.
+pub const MAX_BODY_LEN: usize = u32::MAX as usize - RESPONSE_HEADER_LEN;
+/// Bodies up to this size are copied behind the header to go out in a single write.
+const INLINE_BODY_LEN: usize = 4 * 1024;
+/// Most bytes of a frame read at once.
+const READ_CHUNK_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("frame of {0} bytes is larger than the limit")]
+    FrameTooLarge(usize),
+    #[error("frame of {0} bytes is truncated")]
+    Truncated(usize),
+    #[error("unknown op {0}")]
+    UnknownOp(u8),
+    #[error("unknown status {0}")]
+    UnknownStatus(u8),
+    #[error("key is not valid UTF-8")]
+    InvalidKey,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Get = 1,
+    Put = 2,
+    Delete = 3,
+    /// Version and value length without the value.
+    Stat = 4,
+}
+
+impl TryFrom<u8> for Op {
+    type Error = ProtocolError;
+
+    fn try_from(op: u8) -> Result<Self, ProtocolError> {
+        match op {
+            1 => Ok(Op::Get),
+            2 => Ok(Op::Put),
+            3 => Ok(Op::Delete),
+            4 => Ok(Op::Stat),
+            _ => Err(ProtocolError::UnknownOp(op)),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    NotFound = 1,
+    /// The request could not be decoded, the body says why.
+    BadRequest = 2,
+    /// The request failed on the worker, the body says why.
+    Error = 3,
+}
+
+impl TryFrom<u8> for Status {
+    type Error = ProtocolError;
+
+    fn try_from(status: u8) -> Result<Self, ProtocolError> {
+        match status {
+            0 => Ok(Status::Ok),
+            1 => Ok(Status::NotFound),
+            2 => Ok(Status::BadRequest),
+            3 => Ok(Status::Error),
+            _ => Err(ProtocolError::UnknownStatus(status)),
+        }
+    }
+}
+
+/// A request frame, all integers big endian:
+///
+/// `len u32 | op u8 | id u64 | version u64 | key_len u16 | key | value`
+///
+/// `len` counts the bytes after itself. The id is chosen by the client and echoed in
+/// the response, so that requests can be pipelined and answered out of order. The
+/// version, 0 for none, and the value are only used by puts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub op: Op,
+    pub id: u64,
+    pub version: Option<u64>,
+    pub key: String,
+    pub value: Bytes,
+}
+
+impl Request {
+    pub fn new(op: Op, key: &str) -> Request {
+        Request {
+            op,
+            id: 0,
+            version: None,
+            key: key.to_string(),
+            value: Bytes::new(),
+        }
+    }
+
+    pub fn put(key: &str, value: Bytes, version: Option<u64>) -> Request {
+        Request {
+            version,
+            value,
+            ..Request::new(Op::Put, key)
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let len = REQUEST_HEADER_LEN + self.key.len() + self.value.len();
+        let mut frame = Vec::with_capacity(4 + len);
+        frame.extend_from_slice(&(len as u32).to_be_bytes());
+        frame.push(self.op as u8);
+        frame.extend_from_slice(&self.id.to_be_bytes());
+        frame.extend_from_slice(&self.version.unwrap_or(0).to_be_bytes());
+        frame.extend_from_slice(&(self.key.len() as u16).to_be_bytes());
+        frame.extend_from_slice(self.key.as_bytes());
+        frame.extend_from_slice(&self.value);
+        frame
+    }
+
+    /// Decodes a frame read by `read_frame`, the value is a view into it.
+    pub fn decode(frame: Bytes) -> Result<Request, ProtocolError> {
+        if frame.len() < REQUEST_HEADER_LEN {
+            return Err(ProtocolError::Truncated(frame.len()));
+        }
+        let op = Op::try_from(frame[0])?;
+        let id = u64::from_be_bytes(frame[1..9].try_into().unwrap());
+        let version = u64::from_be_bytes(frame[9..17].try_into().unwrap());
+        let key_len = u16::from_be_bytes(frame[17..19].try_into().unwrap()) as usize;
+        let key_end = REQUEST_HEADER_LEN + key_len;
+        if frame.len() < key_end {
+            return Err(ProtocolError::Truncated(frame.len()));
+        }
+        let key = std::str::from_utf8(&frame[REQUEST_HEADER_LEN..key_end])
+            .map_err(|_| ProtocolError::InvalidKey)?
+            .to_string();
+        Ok(Request {
+            op,
+            id,
+            version: (version != 0).then_some(version),
+            key,
+            value: frame.slice(key_end..),
+        })
+    }
+
+    /// The id of a frame that failed to decode, to answer it anyway.
+    pub fn frame_id(frame: &[u8]) -> Option<u64> {
+        Some(u64::from_be_bytes(frame.get(1..9)?.try_into().unwrap()))
+    }
+}
+
+/// A response frame, all integers big endian:
+///
+/// `len u32 | status u8 | id u64 | version u64 | body`
+///
+/// The body of a get is the value, of a stat the value length as u64, of a bad request
+/// or an error the message, and empty otherwise.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: Status,
+    pub id: u64,
+    pub version: Option<u64>,
+    pub body: Bytes,
+}
+
+impl Response {
+    pub fn new(status: Status, id: u64) -> Response {
+        Response {
+            status,
+            id,
+            version: None,
+            body: Bytes::new(),
+        }
+    }
+
+    pub fn message(status: Status, id: u64, message: String) -> Response {
+        Response {
+            body: Bytes::from(message),
+            ..Response::new(status, id)
+        }
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let len = RESPONSE_HEADER_LEN + self.body.len();
+        let mut header = Vec::with_capacity(4 + RESPONSE_HEADER_LEN + INLINE_BODY_LEN);
+        header.extend_from_slice(&(len as u32).to_be_bytes());
+        header.push(self.status as u8);
+        header.extend_from_slice(&self.id.to_be_bytes());
+        header.extend_from_slice(&self.version.unwrap_or(0).to_be_bytes());
+        header
+    }
+
+    pub fn decode(frame: Bytes) -> Result<Response, ProtocolError> {
+        if frame.len() < RESPONSE_HEADER_LEN {
+            return Err(ProtocolError::Truncated(frame.len()));
+        }
+        let status = Status::try_from(frame[0])?;
+        let id = u64::from_be_bytes(frame[1..9].try_into().unwrap());
+        let version = u64::from_be_bytes(frame[9..17].try_into().unwrap());
+        Ok(Response {
+            status,
+            id,
+            version: (version != 0).then_some(version),
+            body: frame.slice(RESPONSE_HEADER_LEN..),
+        })
+    }
+
+    /// Writes the frame, large bodies straight from their buffer after the header.
+    pub async fn write<W: AsyncWriteRent>(self, writer: &mut W) -> std::io::Result<()> {
+        let mut header = self.header();
+        if self.body.len() <= INLINE_BODY_LEN {
+            header.extend_from_slice(&self.body);
+            let (res, _) = writer.write_all(header).await;
+            return res.map(|_| ());
+        }
+        let (res, _) = writer.write_all(header).await;
+        res?;
+        let (res, _) = writer.write_all(self.body).await;
+        res.map(|_| ())
+    }
+}
+
+/// Reads the next frame without its length prefix, None once the peer closed the
+/// connection between frames.
+pub async fn read_frame<R: AsyncReadRent>(reader: &mut R) -> Result<Option<Bytes>, ProtocolError> {
+    match read_frame_len(reader).await? {
+        Some(len) => Ok(Some(read_frame_body(reader, len).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Reads the length prefix of the next frame, None once the peer closed the connection
+/// between frames.
+pub async fn read_frame_len<R: AsyncReadRent>(
+    reader: &mut R,
+) -> Result<Option<usize>, ProtocolError> {
+    let (res, prefix) = reader.read_exact(Vec::with_capacity(4)).await;
+    match res {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(prefix[..4].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge(len));
+    }
+    Ok(Some(len))
+}
+
+/// Reads a frame of `len` bytes in chunks, the buffer grows with what actually arrives
+/// rather than with what the prefix announced.
+pub async fn read_frame_body<R: AsyncReadRent>(
+    reader: &mut R,
+    len: usize,
+) -> Result<Bytes, ProtocolError> {
+    let mut frame = Vec::with_capacity(len.min(READ_CHUNK_LEN));
+    while frame.len() < len {
+        let start = frame.len();
+        let chunk = (len - start).min(READ_CHUNK_LEN);
+        frame.reserve(chunk);
+        let (res, slice) = reader
+            .read_exact(frame.slice_mut(start..start + chunk))
+            .await;
+        frame = slice.into_inner();
+        res?;
+    }
+    Ok(Bytes::from(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_round_trip() {
+        let mut request = Request::put("dir/key", Bytes::from_static(b"value"), Some(7));
+        request.id = 42;
+        let frame = request.encode();
+        assert_eq!(
+            u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize,
+            frame.len() - 4
+        );
+        let frame = Bytes::from(frame).slice(4..);
+        assert_eq!(Request::frame_id(&frame), Some(42));
+        assert_eq!(Request::decode(frame).unwrap(), request);
+
+        let get = Request::new(Op::Get, "key");
+        let decoded = Request::decode(Bytes::from(get.encode()).slice(4..)).unwrap();
+        assert_eq!((decoded.version, decoded.value.len()), (None, 0));
+
+        let mut unknown = get.encode();
+        unknown[4] = 9;
+        assert!(matches!(
+            Request::decode(Bytes::from(unknown).slice(4..)),
+            Err(ProtocolError::UnknownOp(9))
+        ));
+        let truncated = Bytes::from(get.encode()).slice(4..20);
+        assert!(matches!(
+            Request::decode(truncated),
+            Err(ProtocolError::Truncated(16))
+        ));
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let response = Response {
+            status: Status::Ok,
+            id: 3,
+            version: Some(2),
+            body: Bytes::from_static(b"value"),
+        };
+        let mut frame = response.header();
+        frame.extend_from_slice(&response.body);
+        let decoded = Response::decode(Bytes::from(frame).slice(4..)).unwrap();
+        assert_eq!(decoded, response);
+    }
+}
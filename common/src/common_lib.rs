@@ -1,5 +1,20 @@
+pub mod anti_entropy;
+pub mod binary;
+pub mod discovery;
+pub mod drain;
+pub mod grpc;
 pub mod h2;
 pub mod kv_store;
 pub mod logging;
+pub mod memcache;
 pub mod metrics;
+pub mod peer_fetch;
+pub mod pipeline;
+pub mod rebalance;
+pub mod resp;
+pub mod ring;
 pub mod settings;
+pub mod shutdown;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod ufs;
@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ring;
+
+/// What a worker publishes about itself when it registers, stored as JSON. The static
+/// fields are fixed for the life of the process, `used_bytes` is refreshed periodically.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkerDescriptor {
+    /// The worker's member id, `host:http_port`.
+    pub node_id: String,
+    pub host: String,
+    pub http_port: u16,
+    pub h2_port: u16,
+    pub socket_port: u16,
+    #[serde(default)]
+    pub zone: Option<String>,
+    /// Bytes the local store may hold, 0 is unbounded.
+    pub capacity_bytes: u64,
+    pub used_bytes: u64,
+    /// Build version of the worker binary.
+    pub version: String,
+    /// The worker is handing its keys off before leaving, it owns no keys meanwhile.
+    #[serde(default)]
+    pub draining: bool,
+}
+
+impl WorkerDescriptor {
+    pub fn h2_addr(&self) -> String {
+        format!("{}:{}", self.host, self.h2_port)
+    }
+
+    /// Weight of the worker on the ring, following the capacity of its store.
+    pub fn ring_weight(&self) -> u32 {
+        ring::capacity_weight(self.capacity_bytes)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// None for anything else, workers of older versions registered a bare `host:port`.
+    pub fn from_json(value: &str) -> Option<WorkerDescriptor> {
+        serde_json::from_str(value).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_descriptor_json() {
+        let descriptor = WorkerDescriptor {
+            node_id: String::from("10.0.0.1:8080"),
+            host: String::from("10.0.0.1"),
+            http_port: 8080,
+            h2_port: 5928,
+            socket_port: 19090,
+            zone: Some(String::from("us-east-1a")),
+            capacity_bytes: 1 << 30,
+            used_bytes: 1 << 20,
+            version: String::from("0.1.0"),
+            draining: false,
+        };
+        let json = descriptor.to_json();
+        assert_eq!(WorkerDescriptor::from_json(&json), Some(descriptor.clone()));
+        assert_eq!(descriptor.h2_addr(), "10.0.0.1:5928");
+        assert_eq!(WorkerDescriptor::from_json("10.0.0.1:8080"), None);
+        // descriptors published before draining existed
+        let json = json.replace(",\"draining\":false", "");
+        assert_eq!(WorkerDescriptor::from_json(&json), Some(descriptor));
+    }
+}
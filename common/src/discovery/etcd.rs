@@ -0,0 +1,666 @@
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use etcd_client::{
+    Client, EventType, GetOptions, LeaseKeepAliveStream, LeaseKeeper, PutOptions, WatchOptions,
+    WatchStream, Watcher,
+};
+use log::{debug, info, warn};
+use tokio::sync::Notify;
+
+use crate::discovery::{
+    DiscoveryError, DiscoveryFuture, Membership, ServiceDiscovery, WorkerDescriptor,
+};
+
+const SERVICE_PREFIX: &str = "services/";
+const LEASE_TTL: Duration = Duration::from_secs(40);
+
+/// A change to a watched key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// Key and value.
+    Put(String, String),
+    Delete(String),
+}
+
+/// The parts of the etcd KV, lease and watch APIs discovery relies on, so it can run
+/// against an in-process etcd in tests.
+pub trait EtcdApi: Send + Sync + 'static {
+    type KeepAlive: EtcdKeepAlive;
+    type Watch: EtcdWatch;
+
+    /// Keys and values under `prefix` and the revision they were read at.
+    fn list(
+        &self,
+        prefix: &str,
+    ) -> impl Future<Output = Result<(Vec<(String, String)>, i64), DiscoveryError>> + Send;
+
+    fn grant(&self, ttl: Duration) -> impl Future<Output = Result<i64, DiscoveryError>> + Send;
+
+    fn put(
+        &self,
+        key: &str,
+        value: &str,
+        lease: i64,
+    ) -> impl Future<Output = Result<(), DiscoveryError>> + Send;
+
+    fn keep_alive(
+        &self,
+        lease: i64,
+    ) -> impl Future<Output = Result<Self::KeepAlive, DiscoveryError>> + Send;
+
+    /// Ends the lease, deleting the keys attached to it.
+    fn revoke(&self, lease: i64) -> impl Future<Output = Result<(), DiscoveryError>> + Send;
+
+    /// Changes under `prefix` from `revision` on.
+    fn watch(
+        &self,
+        prefix: &str,
+        revision: i64,
+    ) -> impl Future<Output = Result<Self::Watch, DiscoveryError>> + Send;
+}
+
+pub trait EtcdKeepAlive: Send {
+    /// Refreshes the lease, returning its remaining ttl in seconds, 0 once it expired.
+    fn keep_alive(&mut self) -> impl Future<Output = Result<i64, DiscoveryError>> + Send;
+}
+
+pub trait EtcdWatch: Send {
+    /// The next batch of changes, None once the watch is closed.
+    fn next(
+        &mut self,
+    ) -> impl Future<Output = Result<Option<Vec<WatchEvent>>, DiscoveryError>> + Send;
+}
+
+/// etcd over gRPC, connecting on first use.
+pub struct EtcdClient {
+    endpoints: Vec<String>,
+    client: Mutex<Option<Client>>,
+}
+
+impl EtcdClient {
+    pub fn new(endpoints: Vec<String>) -> EtcdClient {
+        EtcdClient {
+            endpoints,
+            client: Mutex::new(None),
+        }
+    }
+
+    async fn client(&self) -> Result<Client, DiscoveryError> {
+        let cached = self.client.lock().unwrap().clone();
+        match cached {
+            Some(client) => Ok(client),
+            None => {
+                let client = Client::connect(&self.endpoints, None).await?;
+                *self.client.lock().unwrap() = Some(client.clone());
+                Ok(client)
+            }
+        }
+    }
+}
+
+impl EtcdApi for EtcdClient {
+    type KeepAlive = (LeaseKeeper, LeaseKeepAliveStream);
+    type Watch = (Watcher, WatchStream);
+
+    async fn list(&self, prefix: &str) -> Result<(Vec<(String, String)>, i64), DiscoveryError> {
+        let options = GetOptions::new().with_prefix();
+        let response = self.client().await?.get(prefix, Some(options)).await?;
+        let kvs = response
+            .kvs()
+            .iter()
+            .filter_map(|kv| Some((kv.key_str().ok()?.into(), kv.value_str().ok()?.into())))
+            .collect();
+        let revision = response.header().map_or(0, |header| header.revision());
+        Ok((kvs, revision))
+    }
+
+    async fn grant(&self, ttl: Duration) -> Result<i64, DiscoveryError> {
+        let ttl = ttl.as_secs().max(1) as i64;
+        Ok(self.client().await?.lease_grant(ttl, None).await?.id())
+    }
+
+    async fn put(&self, key: &str, value: &str, lease: i64) -> Result<(), DiscoveryError> {
+        let options = PutOptions::new().with_lease(lease);
+        self.client().await?.put(key, value, Some(options)).await?;
+        Ok(())
+    }
+
+    async fn keep_alive(&self, lease: i64) -> Result<Self::KeepAlive, DiscoveryError> {
+        Ok(self.client().await?.lease_keep_alive(lease).await?)
+    }
+
+    async fn revoke(&self, lease: i64) -> Result<(), DiscoveryError> {
+        self.client().await?.lease_revoke(lease).await?;
+        Ok(())
+    }
+
+    async fn watch(&self, prefix: &str, revision: i64) -> Result<Self::Watch, DiscoveryError> {
+        let options = WatchOptions::new()
+            .with_prefix()
+            .with_start_revision(revision);
+        Ok(self.client().await?.watch(prefix, Some(options)).await?)
+    }
+}
+
+impl EtcdKeepAlive for (LeaseKeeper, LeaseKeepAliveStream) {
+    async fn keep_alive(&mut self) -> Result<i64, DiscoveryError> {
+        let (keeper, stream) = self;
+        keeper.keep_alive().await?;
+        Ok(stream.message().await?.map_or(0, |response| response.ttl()))
+    }
+}
+
+impl EtcdWatch for (Watcher, WatchStream) {
+    async fn next(&mut self) -> Result<Option<Vec<WatchEvent>>, DiscoveryError> {
+        let (_, stream) = self;
+        while let Some(response) = stream.message().await? {
+            if response.canceled() {
+                warn!("Watch canceled: {}", response.cancel_reason());
+                return Ok(None);
+            }
+            let events: Vec<WatchEvent> = response
+                .events()
+                .iter()
+                .filter_map(|event| {
+                    let kv = event.kv()?;
+                    let key = kv.key_str().ok()?.to_string();
+                    Some(match event.event_type() {
+                        EventType::Put => WatchEvent::Put(key, kv.value_str().ok()?.to_string()),
+                        EventType::Delete => WatchEvent::Delete(key),
+                    })
+                })
+                .collect();
+            if !events.is_empty() {
+                return Ok(Some(events));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Workers register under `services/` with their descriptor as the value and a lease
+/// kept alive while they run, clients only read the registrations. Membership follows a
+/// watch on the prefix. When the lease is lost or the watch breaks, the worker registers
+/// again with a fresh lease and the members are listed again.
+pub struct EtcdDiscovery<C: EtcdApi = EtcdClient> {
+    api: C,
+    /// Member id of the local worker, fixed for the life of the process.
+    local_id: Option<String>,
+    local: Mutex<Option<WorkerDescriptor>>,
+    lease_ttl: Duration,
+    /// Wait before starting over after etcd failed.
+    retry_interval: Duration,
+    /// Woken when the local worker deregisters.
+    leaving: Notify,
+    /// Whether the local worker holds a live lease.
+    registered: AtomicBool,
+    membership: Membership,
+}
+
+impl EtcdDiscovery {
+    pub fn new(
+        endpoints: Vec<String>,
+        local: Option<WorkerDescriptor>,
+        retry_interval: Duration,
+    ) -> EtcdDiscovery {
+        EtcdDiscovery::with_api(EtcdClient::new(endpoints), local, LEASE_TTL, retry_interval)
+    }
+}
+
+impl<C: EtcdApi> EtcdDiscovery<C> {
+    pub fn with_api(
+        api: C,
+        local: Option<WorkerDescriptor>,
+        lease_ttl: Duration,
+        retry_interval: Duration,
+    ) -> EtcdDiscovery<C> {
+        EtcdDiscovery {
+            api,
+            local_id: local.as_ref().map(|local| local.node_id.clone()),
+            local: Mutex::new(local),
+            lease_ttl,
+            retry_interval,
+            leaving: Notify::new(),
+            registered: AtomicBool::new(false),
+            membership: Membership::default(),
+        }
+    }
+
+    /// Registers, then follows the members until the registration is lost or etcd fails.
+    /// Changes to the local descriptor are published with the keep-alives.
+    async fn session(&self) -> Result<(), DiscoveryError> {
+        let local = self.local.lock().unwrap().clone();
+        let mut registration = match local {
+            Some(local) => Some(self.register_service(local).await?),
+            None => None,
+        };
+        self.registered
+            .store(registration.is_some(), Ordering::SeqCst);
+
+        let (kvs, revision) = self.api.list(SERVICE_PREFIX).await?;
+        let mut services = BTreeSet::new();
+        for (key, value) in kvs {
+            self.apply_put(&mut services, &key, &value);
+        }
+        self.membership.set(services.iter().cloned());
+        let mut watch = self.api.watch(SERVICE_PREFIX, revision + 1).await?;
+
+        let mut keep_alive = tokio::time::interval(self.lease_ttl / 4);
+        loop {
+            tokio::select! {
+                _ = keep_alive.tick(), if registration.is_some() => {
+                    if let Some((lease_id, keeper, published)) = registration.as_mut() {
+                        let ttl = keeper.keep_alive().await?;
+                        if ttl <= 0 {
+                            return Err(self.registration_lost());
+                        }
+                        debug!("Lease is still alive for {}s", ttl);
+                        let current = self.local.lock().unwrap().clone();
+                        if let Some(current) = current.filter(|current| current != published) {
+                            self.api.put(&service_key(&current.node_id), &current.to_json(), *lease_id).await?;
+                            *published = current;
+                        }
+                    }
+                }
+                _ = self.leaving.notified(), if registration.is_some() => {
+                    if let Some((lease_id, _, _)) = registration.take() {
+                        self.api.revoke(lease_id).await?;
+                        self.registered.store(false, Ordering::SeqCst);
+                        info!("Deregistered {}", self.local_id.as_deref().unwrap_or_default());
+                    }
+                }
+                events = watch.next() => {
+                    let events = events?.ok_or(DiscoveryError::WatchClosed)?;
+                    for event in events {
+                        match event {
+                            WatchEvent::Put(key, value) => self.apply_put(&mut services, &key, &value),
+                            WatchEvent::Delete(key) => {
+                                if let Some(service) = key.strip_prefix(SERVICE_PREFIX) {
+                                    services.remove(service);
+                                    self.membership.remove_descriptor(service);
+                                    if registration.is_some() && Some(service) == self.local_id.as_deref() {
+                                        return Err(self.registration_lost());
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    self.membership.set(services.iter().cloned());
+                }
+            }
+        }
+    }
+
+    fn apply_put(&self, services: &mut BTreeSet<String>, key: &str, value: &str) {
+        let Some(service) = key.strip_prefix(SERVICE_PREFIX) else {
+            return;
+        };
+        services.insert(service.to_string());
+        match WorkerDescriptor::from_json(value) {
+            Some(descriptor) if descriptor.node_id == service => {
+                self.membership.set_descriptor(descriptor)
+            }
+            _ => debug!("{} registered without a descriptor", service),
+        }
+    }
+
+    async fn register_service(
+        &self,
+        descriptor: WorkerDescriptor,
+    ) -> Result<(i64, C::KeepAlive, WorkerDescriptor), DiscoveryError> {
+        let lease_id = self.api.grant(self.lease_ttl).await?;
+        self.api
+            .put(
+                &service_key(&descriptor.node_id),
+                &descriptor.to_json(),
+                lease_id,
+            )
+            .await?;
+        info!(
+            "Registered service with ID: {}, lease ID: {}",
+            descriptor.node_id, lease_id
+        );
+        let keeper = self.api.keep_alive(lease_id).await?;
+        Ok((lease_id, keeper, descriptor))
+    }
+
+    fn registration_lost(&self) -> DiscoveryError {
+        DiscoveryError::RegistrationLost(self.local_id.clone().unwrap_or_default())
+    }
+}
+
+fn service_key(service: &str) -> String {
+    format!("{}{}", SERVICE_PREFIX, service)
+}
+
+impl<C: EtcdApi> ServiceDiscovery for EtcdDiscovery<C> {
+    fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
+    fn run(self: Arc<Self>) -> DiscoveryFuture {
+        Box::pin(async move {
+            loop {
+                let result = self.session().await;
+                self.registered.store(false, Ordering::SeqCst);
+                if let Err(e) = result {
+                    warn!(
+                        "etcd discovery failed: {}, starting over in {:?}",
+                        e, self.retry_interval
+                    );
+                }
+                tokio::time::sleep(self.retry_interval).await;
+            }
+        })
+    }
+
+    fn update_local(&self, descriptor: WorkerDescriptor) {
+        if Some(&descriptor.node_id) != self.local_id.as_ref() {
+            warn!(
+                "Ignoring descriptor of {}, not the local worker",
+                descriptor.node_id
+            );
+            return;
+        }
+        *self.local.lock().unwrap() = Some(descriptor.clone());
+        self.membership.set_descriptor(descriptor);
+    }
+
+    /// Revokes the lease of the registration, which is not renewed after that.
+    fn leave(&self) {
+        *self.local.lock().unwrap() = None;
+        self.leaving.notify_one();
+    }
+
+    fn publishes_local(&self) -> bool {
+        self.local_id.is_some()
+    }
+
+    fn is_registered(&self) -> bool {
+        self.registered.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashSet};
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    use super::*;
+    use crate::discovery::MembershipChange;
+
+    #[derive(Default)]
+    struct MockState {
+        revision: i64,
+        /// Keys with their value and the lease they are attached to.
+        keys: BTreeMap<String, (String, i64)>,
+        leases: HashSet<i64>,
+        granted: usize,
+        log: Vec<(i64, WatchEvent)>,
+        watchers: Vec<(String, UnboundedSender<Vec<WatchEvent>>)>,
+    }
+
+    impl MockState {
+        fn apply(&mut self, event: WatchEvent) {
+            self.revision += 1;
+            self.log.push((self.revision, event.clone()));
+            self.watchers.retain(|(prefix, watcher)| {
+                let (WatchEvent::Put(key, _) | WatchEvent::Delete(key)) = &event;
+                !key.starts_with(prefix.as_str()) || watcher.send(vec![event.clone()]).is_ok()
+            });
+        }
+    }
+
+    /// etcd KV, lease and watch semantics in memory.
+    #[derive(Clone, Default)]
+    struct MockEtcd(Arc<Mutex<MockState>>);
+
+    impl MockEtcd {
+        fn lease_of(&self, key: &str) -> Option<i64> {
+            self.0
+                .lock()
+                .unwrap()
+                .keys
+                .get(key)
+                .map(|(_, lease)| *lease)
+        }
+
+        fn granted(&self) -> usize {
+            self.0.lock().unwrap().granted
+        }
+
+        /// Expires a lease as etcd does when keep-alives stop, deleting its keys.
+        fn expire(&self, lease: i64) {
+            let mut state = self.0.lock().unwrap();
+            state.leases.remove(&lease);
+            let keys: Vec<String> = state
+                .keys
+                .iter()
+                .filter(|(_, (_, key_lease))| *key_lease == lease)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in keys {
+                state.keys.remove(&key);
+                state.apply(WatchEvent::Delete(key));
+            }
+        }
+    }
+
+    struct MockKeepAlive(MockEtcd, i64);
+
+    struct MockWatch(UnboundedReceiver<Vec<WatchEvent>>);
+
+    impl EtcdApi for MockEtcd {
+        type KeepAlive = MockKeepAlive;
+        type Watch = MockWatch;
+
+        async fn list(&self, prefix: &str) -> Result<(Vec<(String, String)>, i64), DiscoveryError> {
+            let state = self.0.lock().unwrap();
+            let kvs = state
+                .keys
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .map(|(key, (value, _))| (key.clone(), value.clone()))
+                .collect();
+            Ok((kvs, state.revision))
+        }
+
+        async fn grant(&self, _ttl: Duration) -> Result<i64, DiscoveryError> {
+            let mut state = self.0.lock().unwrap();
+            state.granted += 1;
+            let lease = state.granted as i64;
+            state.leases.insert(lease);
+            Ok(lease)
+        }
+
+        async fn put(&self, key: &str, value: &str, lease: i64) -> Result<(), DiscoveryError> {
+            let mut state = self.0.lock().unwrap();
+            if !state.leases.contains(&lease) {
+                return Err(DiscoveryError::RegistrationLost(key.to_string()));
+            }
+            state
+                .keys
+                .insert(key.to_string(), (value.to_string(), lease));
+            state.apply(WatchEvent::Put(key.to_string(), value.to_string()));
+            Ok(())
+        }
+
+        async fn keep_alive(&self, lease: i64) -> Result<MockKeepAlive, DiscoveryError> {
+            Ok(MockKeepAlive(self.clone(), lease))
+        }
+
+        async fn revoke(&self, lease: i64) -> Result<(), DiscoveryError> {
+            self.expire(lease);
+            Ok(())
+        }
+
+        async fn watch(&self, prefix: &str, revision: i64) -> Result<MockWatch, DiscoveryError> {
+            let mut state = self.0.lock().unwrap();
+            let (sender, receiver) = unbounded_channel();
+            let missed: Vec<WatchEvent> = state
+                .log
+                .iter()
+                .filter(|(event_revision, _)| *event_revision >= revision)
+                .map(|(_, event)| event.clone())
+                .collect();
+            if !missed.is_empty() {
+                sender.send(missed).unwrap();
+            }
+            state.watchers.push((prefix.to_string(), sender));
+            Ok(MockWatch(receiver))
+        }
+    }
+
+    impl EtcdKeepAlive for MockKeepAlive {
+        async fn keep_alive(&mut self) -> Result<i64, DiscoveryError> {
+            let alive = self.0 .0.lock().unwrap().leases.contains(&self.1);
+            Ok(if alive { LEASE_TTL.as_secs() as i64 } else { 0 })
+        }
+    }
+
+    impl EtcdWatch for MockWatch {
+        async fn next(&mut self) -> Result<Option<Vec<WatchEvent>>, DiscoveryError> {
+            Ok(self.0.recv().await)
+        }
+    }
+
+    fn descriptor(node_id: &str) -> WorkerDescriptor {
+        let (host, _) = node_id.split_once(':').unwrap();
+        WorkerDescriptor {
+            node_id: node_id.to_string(),
+            host: host.to_string(),
+            http_port: 8080,
+            h2_port: 5928,
+            ..WorkerDescriptor::default()
+        }
+    }
+
+    fn discovery(etcd: &MockEtcd, local: Option<&str>) -> Arc<EtcdDiscovery<MockEtcd>> {
+        Arc::new(EtcdDiscovery::with_api(
+            etcd.clone(),
+            local.map(descriptor),
+            Duration::from_millis(200),
+            Duration::from_millis(20),
+        ))
+    }
+
+    async fn eventually<F: Fn() -> bool>(condition: F) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    #[test]
+    fn test_etcd_error() {
+        let err = DiscoveryError::EtcdError(etcd_client::Error::InvalidArgs("0".to_string()));
+        assert_eq!(format!("{}", err), "etcd error: invalid arguments: 0");
+    }
+
+    #[tokio::test]
+    async fn test_watch_membership() {
+        let etcd = MockEtcd::default();
+        let observer = discovery(&etcd, None);
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&changes);
+        observer.subscribe(Box::new(move |change| {
+            seen.lock().unwrap().push(change.clone());
+        }));
+        tokio::spawn(Arc::clone(&observer).run());
+        tokio::spawn(discovery(&etcd, Some("a:8080")).run());
+        let b = tokio::spawn(discovery(&etcd, Some("b:8080")).run());
+        eventually(|| observer.members() == vec!["a:8080", "b:8080"]).await;
+
+        // b stops keeping its lease alive
+        b.abort();
+        etcd.expire(etcd.lease_of("services/b:8080").unwrap());
+        eventually(|| observer.members() == vec!["a:8080"]).await;
+        assert_eq!(
+            changes.lock().unwrap().last(),
+            Some(&MembershipChange {
+                members: vec![String::from("a:8080")],
+                joined: Vec::new(),
+                left: vec![String::from("b:8080")],
+            })
+        );
+        // only the two registrations, the observer never took a lease
+        assert_eq!(etcd.granted(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_register_again_after_lease_loss() {
+        let etcd = MockEtcd::default();
+        let worker = discovery(&etcd, Some("a:8080"));
+        tokio::spawn(Arc::clone(&worker).run());
+        eventually(|| etcd.lease_of("services/a:8080").is_some()).await;
+        let lease = etcd.lease_of("services/a:8080").unwrap();
+
+        etcd.expire(lease);
+        eventually(|| etcd.lease_of("services/a:8080").is_some_and(|l| l != lease)).await;
+        eventually(|| worker.members() == vec!["a:8080"]).await;
+        assert_eq!(etcd.granted(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_descriptors_are_published_and_refreshed() {
+        let etcd = MockEtcd::default();
+        let observer = discovery(&etcd, None);
+        let worker = discovery(&etcd, Some("a:8080"));
+        tokio::spawn(Arc::clone(&observer).run());
+        tokio::spawn(Arc::clone(&worker).run());
+        eventually(|| observer.descriptor("a:8080") == Some(descriptor("a:8080"))).await;
+        assert_eq!(observer.descriptor("a:8080").unwrap().h2_addr(), "a:5928");
+
+        worker.update_local(WorkerDescriptor {
+            used_bytes: 42,
+            ..descriptor("a:8080")
+        });
+        eventually(|| {
+            observer
+                .descriptor("a:8080")
+                .is_some_and(|d| d.used_bytes == 42)
+        })
+        .await;
+        // a refresh is not a membership change and keeps the lease
+        assert_eq!(observer.members(), vec!["a:8080"]);
+        assert_eq!(etcd.granted(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_draining_worker_leaves_members_then_deregisters() {
+        let etcd = MockEtcd::default();
+        let observer = discovery(&etcd, None);
+        let worker = discovery(&etcd, Some("a:8080"));
+        tokio::spawn(Arc::clone(&observer).run());
+        tokio::spawn(discovery(&etcd, Some("b:8080")).run());
+        tokio::spawn(Arc::clone(&worker).run());
+        eventually(|| observer.members() == vec!["a:8080", "b:8080"]).await;
+        assert!(worker.publishes_local() && !observer.publishes_local());
+
+        worker.update_local(WorkerDescriptor {
+            draining: true,
+            ..descriptor("a:8080")
+        });
+        // the draining worker stops owning keys right away, on its own ring too
+        assert_eq!(worker.members(), vec!["b:8080"]);
+        eventually(|| observer.members() == vec!["b:8080"]).await;
+        assert_eq!(observer.membership().registered(), vec!["a:8080", "b:8080"]);
+
+        assert!(worker.is_registered());
+        worker.leave();
+        eventually(|| !worker.is_registered()).await;
+        eventually(|| observer.membership().registered() == vec!["b:8080"]).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(etcd.lease_of("services/a:8080").is_none());
+        assert_eq!(etcd.granted(), 2);
+        assert_eq!(worker.members(), vec!["b:8080"]);
+    }
+}
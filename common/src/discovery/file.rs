@@ -0,0 +1,84 @@
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+
+use crate::discovery::{DiscoveryError, DiscoveryFuture, Membership, ServiceDiscovery};
+
+/// Members listed in a file, one `host:http_port` per line or comma separated, `#`
+/// starts a comment. The file is re-read every refresh interval, so a deployment tool
+/// can change the membership by rewriting it.
+pub struct FileDiscovery {
+    path: String,
+    refresh_interval: Duration,
+    membership: Membership,
+}
+
+impl FileDiscovery {
+    pub fn new(path: String, refresh_interval: Duration) -> FileDiscovery {
+        let discovery = FileDiscovery {
+            path,
+            refresh_interval,
+            membership: Membership::default(),
+        };
+        if let Err(e) = discovery.reload() {
+            warn!("Failed to read service list {}: {}", discovery.path, e);
+        }
+        discovery
+    }
+
+    /// Re-reads the file, true if the members changed.
+    pub fn reload(&self) -> Result<bool, DiscoveryError> {
+        let content = fs::read_to_string(&self.path)?;
+        let members = content
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .flat_map(|line| line.split(','))
+            .map(String::from);
+        Ok(self.membership.set(members))
+    }
+}
+
+impl ServiceDiscovery for FileDiscovery {
+    fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
+    fn run(self: Arc<Self>) -> DiscoveryFuture {
+        Box::pin(async move {
+            loop {
+                tokio::time::sleep(self.refresh_interval).await;
+                // keep the last members while the file is being replaced
+                if let Err(e) = self.reload() {
+                    warn!("Failed to read service list {}: {}", self.path, e);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_service_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("workers");
+        fs::write(&path, "# workers\nw1:8080\nw2:8080, w3:8080 # rack 2\n\n").unwrap();
+
+        let discovery =
+            FileDiscovery::new(path.to_string_lossy().to_string(), Duration::from_secs(1));
+        assert_eq!(discovery.members(), vec!["w1:8080", "w2:8080", "w3:8080"]);
+        assert!(!discovery.reload().unwrap());
+
+        fs::write(&path, "w1:8080\nw3:8080\n").unwrap();
+        assert!(discovery.reload().unwrap());
+        assert_eq!(discovery.members(), vec!["w1:8080", "w3:8080"]);
+
+        fs::remove_file(&path).unwrap();
+        assert!(discovery.reload().is_err());
+        assert_eq!(discovery.members(), vec!["w1:8080", "w3:8080"]);
+    }
+}
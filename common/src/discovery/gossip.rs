@@ -0,0 +1,839 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+use crate::discovery::{
+    DiscoveryError, DiscoveryFuture, Membership, ServiceDiscovery, WorkerDescriptor,
+};
+
+/// Members asked to probe a member that did not answer a direct ping.
+const INDIRECT_PROBES: usize = 3;
+/// Updates piggybacked on a single message.
+const MAX_PIGGYBACK: usize = 8;
+/// An update is piggybacked `RETRANSMIT_MULT * log2(members + 1)` times.
+const RETRANSMIT_MULT: usize = 3;
+const MAX_PACKET: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+/// What a node claims about a member, the higher incarnation wins. Only the member
+/// itself raises its incarnation, to refute a suspicion or to publish a new descriptor.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Update {
+    pub id: String,
+    /// Gossip address of the member.
+    pub addr: String,
+    pub state: MemberState,
+    pub incarnation: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub descriptor: Option<WorkerDescriptor>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Message {
+    /// `from` is None for nodes following the membership without being a member.
+    Ping {
+        seq: u64,
+        from: Option<String>,
+        updates: Vec<Update>,
+    },
+    /// Asks the receiver to ping `target` and relay its ack.
+    PingReq {
+        seq: u64,
+        target: String,
+        updates: Vec<Update>,
+    },
+    Ack {
+        seq: u64,
+        updates: Vec<Update>,
+    },
+}
+
+struct Node {
+    addr: String,
+    state: MemberState,
+    incarnation: u64,
+    descriptor: Option<WorkerDescriptor>,
+    /// When the state last changed.
+    since: Instant,
+}
+
+impl Node {
+    fn update(&self, id: &str) -> Update {
+        Update {
+            id: id.to_string(),
+            addr: self.addr.clone(),
+            state: self.state,
+            incarnation: self.incarnation,
+            descriptor: self.descriptor.clone(),
+        }
+    }
+
+    /// Whether `update` is newer than what is known about the member.
+    fn is_overridden_by(&self, update: &Update) -> bool {
+        match (update.state, self.state) {
+            (MemberState::Alive, _) => update.incarnation > self.incarnation,
+            (MemberState::Suspect, MemberState::Alive) => update.incarnation >= self.incarnation,
+            (MemberState::Suspect, MemberState::Suspect) => update.incarnation > self.incarnation,
+            (MemberState::Dead, MemberState::Dead) | (MemberState::Suspect, MemberState::Dead) => {
+                false
+            }
+            (MemberState::Dead, _) => update.incarnation >= self.incarnation,
+        }
+    }
+}
+
+enum Pending {
+    /// A probe of this node waiting for the ack.
+    Probe(oneshot::Sender<()>),
+    /// A ping sent for another node, its ack is relayed as `seq` to `to`.
+    Relay { seq: u64, to: SocketAddr },
+}
+
+#[derive(Default)]
+struct State {
+    /// Every member heard of, this node included. Dead members are kept for a while
+    /// so stale gossip can't bring them back.
+    nodes: BTreeMap<String, Node>,
+    /// Updates still to piggyback, with the transmissions left.
+    broadcasts: Vec<(Update, usize)>,
+    /// Members left to probe in this round, a round probes every member once.
+    probe_order: Vec<String>,
+    seq: u64,
+    pending: HashMap<u64, (Instant, Pending)>,
+}
+
+/// Membership gossiped between the workers themselves over UDP, SWIM style. Every
+/// interval a node pings one member, asks a few others to ping it on its behalf if it
+/// doesn't answer, and suspects it if none of them got an ack. A suspect that doesn't
+/// refute the suspicion within the suspect timeout is declared dead. Membership
+/// updates ride along on pings and acks. New nodes join through any of the seeds.
+pub struct GossipDiscovery {
+    bind: String,
+    /// Where the other nodes reach this one.
+    advertise: String,
+    seeds: Vec<String>,
+    local_id: Option<String>,
+    interval: Duration,
+    suspect_timeout: Duration,
+    /// The local worker left, its death is gossiped instead of refuted.
+    left: AtomicBool,
+    /// The death of the local worker was sent to every member.
+    announced: AtomicBool,
+    state: Mutex<State>,
+    membership: Membership,
+}
+
+impl GossipDiscovery {
+    /// Binds to `bind` and advertises the local worker, if any, at its host and the
+    /// bound port. Without a local worker the node only follows the membership.
+    pub fn new(
+        local: Option<WorkerDescriptor>,
+        bind: String,
+        seeds: Vec<String>,
+        interval: Duration,
+        suspect_timeout: Duration,
+    ) -> GossipDiscovery {
+        let port = bind.rsplit_once(':').map_or("0", |(_, port)| port);
+        let advertise = match local.as_ref() {
+            Some(local) => format!("{}:{}", local.host, port),
+            None => bind.clone(),
+        };
+        let discovery = GossipDiscovery {
+            bind,
+            advertise,
+            seeds,
+            local_id: local.as_ref().map(|local| local.node_id.clone()),
+            interval,
+            suspect_timeout,
+            left: AtomicBool::new(false),
+            announced: AtomicBool::new(false),
+            state: Mutex::new(State::default()),
+            membership: Membership::default(),
+        };
+        if let Some(local) = local {
+            let node = Node {
+                addr: discovery.advertise.clone(),
+                state: MemberState::Alive,
+                incarnation: 0,
+                descriptor: Some(local.clone()),
+                since: Instant::now(),
+            };
+            let mut state = discovery.state.lock().unwrap();
+            let update = node.update(&local.node_id);
+            state.nodes.insert(local.node_id, node);
+            discovery.broadcast(&mut state, update);
+            discovery.publish(&state);
+        }
+        discovery
+    }
+
+    pub fn incarnation(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        self.local_id
+            .as_ref()
+            .and_then(|id| state.nodes.get(id))
+            .map_or(0, |node| node.incarnation)
+    }
+
+    pub fn state_of(&self, member: &str) -> Option<MemberState> {
+        self.state
+            .lock()
+            .unwrap()
+            .nodes
+            .get(member)
+            .map(|node| node.state)
+    }
+
+    /// Merges updates heard from other nodes.
+    pub fn apply(&self, updates: Vec<Update>) {
+        let mut state = self.state.lock().unwrap();
+        let mut changed = false;
+        for update in updates {
+            changed |= self.apply_one(&mut state, update);
+        }
+        if changed {
+            self.publish(&state);
+        }
+    }
+
+    fn apply_one(&self, state: &mut State, update: Update) -> bool {
+        if Some(&update.id) == self.local_id.as_ref() {
+            if self.left.load(Ordering::Relaxed) {
+                return false;
+            }
+            let node = state.nodes.get_mut(&update.id).unwrap();
+            let current =
+                update.state == MemberState::Alive && update.incarnation == node.incarnation;
+            if current || update.incarnation < node.incarnation {
+                return false;
+            }
+            // refute the suspicion, or gossip left from before a restart, with a higher
+            // incarnation
+            info!(
+                "Refuting {:?} about this node at incarnation {}",
+                update.state, update.incarnation
+            );
+            node.incarnation = update.incarnation + 1;
+            let refutation = node.update(&update.id);
+            self.broadcast(state, refutation);
+            return false;
+        }
+        let newer = match state.nodes.get(&update.id) {
+            Some(node) => node.is_overridden_by(&update),
+            None => update.state != MemberState::Dead,
+        };
+        if !newer {
+            return false;
+        }
+        debug!(
+            "{} is {:?} at incarnation {}",
+            update.id, update.state, update.incarnation
+        );
+        let descriptor = match (update.descriptor.clone(), state.nodes.get(&update.id)) {
+            (Some(descriptor), _) => Some(descriptor),
+            (None, Some(node)) => node.descriptor.clone(),
+            (None, None) => None,
+        };
+        state.nodes.insert(
+            update.id.clone(),
+            Node {
+                addr: update.addr.clone(),
+                state: update.state,
+                incarnation: update.incarnation,
+                descriptor,
+                since: Instant::now(),
+            },
+        );
+        self.broadcast(state, update);
+        true
+    }
+
+    fn broadcast(&self, state: &mut State, update: Update) {
+        let members = state.nodes.len() + 1;
+        let transmits = RETRANSMIT_MULT * (usize::BITS - members.leading_zeros()) as usize;
+        state
+            .broadcasts
+            .retain(|(queued, _)| queued.id != update.id);
+        state.broadcasts.push((update, transmits.max(1)));
+    }
+
+    /// The updates to piggyback on the next message, least sent first.
+    fn piggyback(&self, state: &mut State) -> Vec<Update> {
+        state
+            .broadcasts
+            .sort_by_key(|(_, transmits)| std::cmp::Reverse(*transmits));
+        let updates = state
+            .broadcasts
+            .iter_mut()
+            .take(MAX_PIGGYBACK)
+            .map(|(update, transmits)| {
+                *transmits -= 1;
+                update.clone()
+            })
+            .collect();
+        state.broadcasts.retain(|(_, transmits)| *transmits > 0);
+        updates
+    }
+
+    fn full_state(&self, state: &State) -> Vec<Update> {
+        state
+            .nodes
+            .iter()
+            .map(|(id, node)| node.update(id))
+            .collect()
+    }
+
+    /// Live and suspected members are members, descriptors are published first.
+    fn publish(&self, state: &State) {
+        let mut members = Vec::new();
+        for (id, node) in state.nodes.iter() {
+            if node.state == MemberState::Dead {
+                continue;
+            }
+            if let Some(descriptor) = node.descriptor.as_ref() {
+                self.membership.set_descriptor(descriptor.clone());
+            }
+            members.push(id.clone());
+        }
+        self.membership.set(members);
+    }
+
+    fn expect_ack(&self, pending: Pending) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.seq += 1;
+        let seq = state.seq;
+        state.pending.insert(seq, (Instant::now(), pending));
+        seq
+    }
+
+    async fn send(&self, socket: &UdpSocket, to: &str, message: &Message) {
+        let result = match serde_json::to_vec(message) {
+            Ok(packet) => socket.send_to(&packet, to).await.map(|_| ()),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            debug!("Failed to send gossip to {}: {}", to, e);
+        }
+    }
+
+    async fn receive(&self, socket: &UdpSocket) -> Result<(), DiscoveryError> {
+        let mut buf = vec![0; MAX_PACKET];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("Failed to receive gossip: {}", e);
+                    continue;
+                }
+            };
+            match serde_json::from_slice(&buf[..len]) {
+                Ok(message) => self.handle(socket, message, from).await,
+                Err(e) => warn!("Invalid gossip from {}: {}", from, e),
+            }
+        }
+    }
+
+    async fn handle(&self, socket: &UdpSocket, message: Message, from: SocketAddr) {
+        match message {
+            Message::Ping {
+                seq,
+                from: member,
+                updates,
+            } => {
+                let known = member.map_or(false, |member| {
+                    let state = self.state.lock().unwrap();
+                    let node = state.nodes.get(&member);
+                    node.map_or(false, |node| node.state != MemberState::Dead)
+                });
+                self.apply(updates);
+                // new and restarted nodes learn the whole membership from the first node
+                // they reach
+                let updates = {
+                    let mut state = self.state.lock().unwrap();
+                    if known {
+                        self.piggyback(&mut state)
+                    } else {
+                        self.full_state(&state)
+                    }
+                };
+                let ack = Message::Ack { seq, updates };
+                self.send(socket, &from.to_string(), &ack).await;
+            }
+            Message::PingReq {
+                seq,
+                target,
+                updates,
+            } => {
+                self.apply(updates);
+                let relay_seq = self.expect_ack(Pending::Relay { seq, to: from });
+                let ping = self.ping(relay_seq);
+                self.send(socket, &target, &ping).await;
+            }
+            Message::Ack { seq, updates } => {
+                self.apply(updates);
+                let pending = self.state.lock().unwrap().pending.remove(&seq);
+                match pending {
+                    Some((_, Pending::Probe(acked))) => {
+                        let _ = acked.send(());
+                    }
+                    Some((_, Pending::Relay { seq, to })) => {
+                        let updates = self.piggyback(&mut self.state.lock().unwrap());
+                        let ack = Message::Ack { seq, updates };
+                        self.send(socket, &to.to_string(), &ack).await;
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+
+    fn ping(&self, seq: u64) -> Message {
+        let updates = self.piggyback(&mut self.state.lock().unwrap());
+        Message::Ping {
+            seq,
+            from: self.local_id.clone(),
+            updates,
+        }
+    }
+
+    /// The next member to probe, members are probed in a random order, each once a round.
+    fn next_target(&self) -> Option<(String, String)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.probe_order.is_empty() {
+                let mut order: Vec<String> = state
+                    .nodes
+                    .iter()
+                    .filter(|(id, node)| {
+                        node.state != MemberState::Dead && Some(*id) != self.local_id.as_ref()
+                    })
+                    .map(|(id, _)| id.clone())
+                    .collect();
+                if order.is_empty() {
+                    return None;
+                }
+                order.shuffle(&mut rand::thread_rng());
+                state.probe_order = order;
+            }
+            let id = state.probe_order.pop().unwrap();
+            match state.nodes.get(&id) {
+                Some(node) if node.state != MemberState::Dead => {
+                    return Some((id, node.addr.clone()))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn probe(&self, socket: &UdpSocket, id: &str, addr: &str) {
+        let timeout = self.interval / 3;
+        let (acked, ack) = oneshot::channel();
+        let seq = self.expect_ack(Pending::Probe(acked));
+        self.send(socket, addr, &self.ping(seq)).await;
+        if tokio::time::timeout(timeout, ack).await.is_ok() {
+            return;
+        }
+
+        let helpers: Vec<String> = {
+            let state = self.state.lock().unwrap();
+            let candidates: Vec<String> = state
+                .nodes
+                .iter()
+                .filter(|(other, node)| {
+                    node.state == MemberState::Alive
+                        && *other != id
+                        && Some(*other) != self.local_id.as_ref()
+                })
+                .map(|(_, node)| node.addr.clone())
+                .collect();
+            candidates
+                .choose_multiple(&mut rand::thread_rng(), INDIRECT_PROBES)
+                .cloned()
+                .collect()
+        };
+        let (acked, ack) = oneshot::channel();
+        let seq = self.expect_ack(Pending::Probe(acked));
+        for helper in helpers.iter() {
+            let updates = self.piggyback(&mut self.state.lock().unwrap());
+            let request = Message::PingReq {
+                seq,
+                target: addr.to_string(),
+                updates,
+            };
+            self.send(socket, helper, &request).await;
+        }
+        if tokio::time::timeout(self.interval - timeout, ack)
+            .await
+            .is_ok()
+        {
+            return;
+        }
+        debug!("{} did not answer, suspecting it", id);
+        let mut state = self.state.lock().unwrap();
+        let Some(node) = state.nodes.get(id) else {
+            return;
+        };
+        let mut suspicion = node.update(id);
+        suspicion.state = MemberState::Suspect;
+        suspicion.descriptor = None;
+        if self.apply_one(&mut state, suspicion) {
+            self.publish(&state);
+        }
+    }
+
+    /// Declares suspects past the timeout dead and forgets members dead for long.
+    fn expire(&self) {
+        let mut state = self.state.lock().unwrap();
+        let expired: Vec<Update> = state
+            .nodes
+            .iter()
+            .filter(|(_, node)| {
+                node.state == MemberState::Suspect && node.since.elapsed() >= self.suspect_timeout
+            })
+            .map(|(id, node)| {
+                let mut update = node.update(id);
+                update.state = MemberState::Dead;
+                update.descriptor = None;
+                update
+            })
+            .collect();
+        let mut changed = false;
+        for update in expired {
+            warn!(
+                "{} did not refute its suspicion, declaring it dead",
+                update.id
+            );
+            changed |= self.apply_one(&mut state, update);
+        }
+        let retention = self.suspect_timeout * 10;
+        let local_id = self.local_id.as_ref();
+        state.nodes.retain(|id, node| {
+            node.state != MemberState::Dead
+                || node.since.elapsed() < retention
+                || Some(id) == local_id
+        });
+        let interval = self.interval;
+        state
+            .pending
+            .retain(|_, (sent, _)| sent.elapsed() < interval * 2);
+        if changed {
+            self.publish(&state);
+        }
+    }
+
+    /// Pings the seeds while no other member is known.
+    async fn join(&self, socket: &UdpSocket) {
+        let alone = self.state.lock().unwrap().nodes.iter().all(|(id, node)| {
+            node.state == MemberState::Dead || Some(id) == self.local_id.as_ref()
+        });
+        if !alone {
+            return;
+        }
+        for seed in self.seeds.iter().filter(|seed| **seed != self.advertise) {
+            let updates = {
+                let state = self.state.lock().unwrap();
+                self.full_state(&state)
+            };
+            let ping = Message::Ping {
+                seq: 0,
+                from: self.local_id.clone(),
+                updates,
+            };
+            self.send(socket, seed, &ping).await;
+        }
+    }
+
+    /// Tells every member right away that the local worker left, rather than waiting
+    /// for the gossip to reach them.
+    async fn announce_leave(&self, socket: &UdpSocket) {
+        if !self.left.load(Ordering::Relaxed) || self.announced.load(Ordering::Relaxed) {
+            return;
+        }
+        let (update, addrs) = {
+            let state = self.state.lock().unwrap();
+            let Some(local_id) = self.local_id.as_ref() else {
+                return;
+            };
+            let addrs: Vec<String> = state
+                .nodes
+                .iter()
+                .filter(|(id, node)| node.state != MemberState::Dead && *id != local_id)
+                .map(|(_, node)| node.addr.clone())
+                .collect();
+            (state.nodes[local_id].update(local_id), addrs)
+        };
+        for addr in addrs.iter() {
+            let ping = Message::Ping {
+                seq: 0,
+                from: self.local_id.clone(),
+                updates: vec![update.clone()],
+            };
+            self.send(socket, addr, &ping).await;
+        }
+        self.announced.store(true, Ordering::Relaxed);
+    }
+
+    async fn gossip(&self, socket: &UdpSocket) -> Result<(), DiscoveryError> {
+        loop {
+            let started = Instant::now();
+            self.announce_leave(socket).await;
+            self.join(socket).await;
+            if let Some((id, addr)) = self.next_target() {
+                self.probe(socket, &id, &addr).await;
+            }
+            self.expire();
+            if let Some(left) = self.interval.checked_sub(started.elapsed()) {
+                tokio::time::sleep(left).await;
+            }
+        }
+    }
+}
+
+impl ServiceDiscovery for GossipDiscovery {
+    fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
+    fn run(self: Arc<Self>) -> DiscoveryFuture {
+        Box::pin(async move {
+            let socket = UdpSocket::bind(&self.bind).await?;
+            info!("Gossiping on {} as {}", self.bind, self.advertise);
+            tokio::select! {
+                result = self.receive(&socket) => result,
+                result = self.gossip(&socket) => result,
+            }
+        })
+    }
+
+    /// A changed descriptor is gossiped with a new incarnation.
+    fn update_local(&self, descriptor: WorkerDescriptor) {
+        let Some(local_id) = self.local_id.as_ref() else {
+            return;
+        };
+        if self.left.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let node = state.nodes.get_mut(local_id).unwrap();
+        if node.descriptor.as_ref() == Some(&descriptor) {
+            return;
+        }
+        node.incarnation += 1;
+        node.descriptor = Some(descriptor.clone());
+        let update = node.update(local_id);
+        self.broadcast(&mut state, update);
+        self.membership.set_descriptor(descriptor);
+    }
+
+    /// Gossips the death of the local worker, which keeps following the members.
+    fn leave(&self) {
+        let Some(local_id) = self.local_id.as_ref() else {
+            return;
+        };
+        self.left.store(true, Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        let node = state.nodes.get_mut(local_id).unwrap();
+        node.state = MemberState::Dead;
+        node.since = Instant::now();
+        let update = node.update(local_id);
+        self.broadcast(&mut state, update);
+        self.publish(&state);
+        info!("Left the gossip membership as {}", local_id);
+    }
+
+    fn publishes_local(&self) -> bool {
+        self.local_id.is_some()
+    }
+
+    fn is_registered(&self) -> bool {
+        self.local_id.is_some() && !self.announced.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(port: u16) -> WorkerDescriptor {
+        WorkerDescriptor {
+            node_id: format!("127.0.0.1:{}", port),
+            host: String::from("127.0.0.1"),
+            http_port: port,
+            ..WorkerDescriptor::default()
+        }
+    }
+
+    fn free_port() -> u16 {
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn node(port: u16, seeds: &[u16]) -> Arc<GossipDiscovery> {
+        Arc::new(GossipDiscovery::new(
+            Some(descriptor(port)),
+            format!("127.0.0.1:{}", port),
+            seeds
+                .iter()
+                .map(|seed| format!("127.0.0.1:{}", seed))
+                .collect(),
+            Duration::from_millis(30),
+            Duration::from_millis(150),
+        ))
+    }
+
+    async fn eventually<F: Fn() -> bool>(condition: F) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("condition not reached in time");
+    }
+
+    #[test]
+    fn test_suspicion_is_refuted() {
+        let discovery = node(7001, &[]);
+        let other = descriptor(7002);
+        discovery.apply(vec![Update {
+            id: other.node_id.clone(),
+            addr: String::from("127.0.0.1:7002"),
+            state: MemberState::Alive,
+            incarnation: 3,
+            descriptor: Some(other.clone()),
+        }]);
+        assert_eq!(
+            discovery.members(),
+            vec!["127.0.0.1:7001", "127.0.0.1:7002"]
+        );
+
+        // stale gossip is ignored, a suspicion at the current incarnation is not
+        let suspect = |incarnation| Update {
+            id: other.node_id.clone(),
+            addr: String::from("127.0.0.1:7002"),
+            state: MemberState::Suspect,
+            incarnation,
+            descriptor: None,
+        };
+        discovery.apply(vec![suspect(2)]);
+        assert_eq!(discovery.state_of(&other.node_id), Some(MemberState::Alive));
+        discovery.apply(vec![suspect(3)]);
+        assert_eq!(
+            discovery.state_of(&other.node_id),
+            Some(MemberState::Suspect)
+        );
+        // suspects stay members until declared dead
+        assert_eq!(discovery.descriptor(&other.node_id), Some(other));
+
+        let local = descriptor(7001);
+        discovery.apply(vec![Update {
+            id: local.node_id.clone(),
+            addr: String::from("127.0.0.1:7001"),
+            state: MemberState::Suspect,
+            incarnation: 0,
+            descriptor: None,
+        }]);
+        assert_eq!(discovery.incarnation(), 1);
+        assert_eq!(discovery.state_of(&local.node_id), Some(MemberState::Alive));
+        let updates = discovery.piggyback(&mut discovery.state.lock().unwrap());
+        assert!(updates.iter().any(|update| update.id == local.node_id
+            && update.state == MemberState::Alive
+            && update.incarnation == 1));
+    }
+
+    #[tokio::test]
+    async fn test_nodes_join_detect_failures_and_rejoin() {
+        let ports = [free_port(), free_port(), free_port()];
+        let nodes = [
+            node(ports[0], &[]),
+            node(ports[1], &[ports[0]]),
+            node(ports[2], &[ports[1]]),
+        ];
+        let mut tasks: Vec<_> = nodes
+            .iter()
+            .map(|node| tokio::spawn(Arc::clone(node).run()))
+            .collect();
+        let ids: Vec<String> = ports.iter().map(|port| descriptor(*port).node_id).collect();
+        let sorted = |ids: &[String]| {
+            let mut ids = ids.to_vec();
+            ids.sort();
+            ids
+        };
+        let all = sorted(&ids);
+        eventually(|| nodes.iter().all(|node| node.members() == all)).await;
+        assert_eq!(
+            nodes[0].descriptor(&ids[2]).map(|d| d.http_port),
+            Some(ports[2])
+        );
+
+        // published descriptors reach the other nodes
+        let mut updated = descriptor(ports[1]);
+        updated.used_bytes = 42;
+        nodes[1].update_local(updated);
+        eventually(|| {
+            nodes[0].descriptor(&ids[1]).map(|d| d.used_bytes) == Some(42)
+                && nodes[2].descriptor(&ids[1]).map(|d| d.used_bytes) == Some(42)
+        })
+        .await;
+
+        // a node that stops answering is suspected, then removed
+        tasks.pop().unwrap().abort();
+        eventually(|| {
+            nodes[..2]
+                .iter()
+                .all(|node| node.members() == sorted(&ids[..2]))
+        })
+        .await;
+        assert_eq!(nodes[0].state_of(&ids[2]), Some(MemberState::Dead));
+
+        // restarted, it refutes its death with a higher incarnation
+        let restarted = node(ports[2], &[ports[0]]);
+        tasks.push(tokio::spawn(Arc::clone(&restarted).run()));
+        eventually(|| {
+            nodes[..2].iter().all(|node| node.members() == all) && restarted.members() == all
+        })
+        .await;
+        assert!(restarted.incarnation() > 0);
+        for task in tasks {
+            task.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_leave_is_announced_to_every_member() {
+        let ports = [free_port(), free_port()];
+        let nodes = [node(ports[0], &[]), node(ports[1], &[ports[0]])];
+        let tasks: Vec<_> = nodes
+            .iter()
+            .map(|node| tokio::spawn(Arc::clone(node).run()))
+            .collect();
+        let ids: Vec<String> = ports.iter().map(|port| descriptor(*port).node_id).collect();
+        eventually(|| nodes[0].members().len() == 2 && nodes[1].members().len() == 2).await;
+
+        assert!(nodes[1].is_registered());
+        nodes[1].leave();
+        eventually(|| !nodes[1].is_registered()).await;
+        eventually(|| nodes[0].members() == vec![ids[0].clone()]).await;
+        assert_eq!(nodes[0].state_of(&ids[1]), Some(MemberState::Dead));
+        for task in tasks {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+
+use log::info;
+use thiserror::Error;
+
+use crate::settings::discovery_options::{DiscoveryOptions, DiscoveryType};
+pub use descriptor::WorkerDescriptor;
+
+pub mod descriptor;
+pub mod etcd;
+pub mod file;
+pub mod gossip;
+pub mod static_list;
+
+#[derive(Error, Debug)]
+pub enum DiscoveryError {
+    #[error("etcd error: {0}")]
+    EtcdError(#[from] etcd_client::Error),
+    #[error("io error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("registration of {0} was lost")]
+    RegistrationLost(String),
+    #[error("watch closed")]
+    WatchClosed,
+}
+
+pub type DiscoveryFuture = Pin<Box<dyn Future<Output = Result<(), DiscoveryError>> + Send>>;
+
+/// A membership update, members are sorted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MembershipChange {
+    pub members: Vec<String>,
+    pub joined: Vec<String>,
+    pub left: Vec<String>,
+}
+
+pub type MembershipListener = Box<dyn Fn(&MembershipChange) + Send + Sync>;
+
+/// Membership of the cluster, shared by workers and clients whatever the source is.
+/// Members are workers as `host:http_port`.
+pub trait ServiceDiscovery: Send + Sync {
+    fn membership(&self) -> &Membership;
+
+    /// Registers the local worker, if any, and follows membership changes. Runs on tokio
+    /// and only returns for sources that never change or on an unrecoverable error.
+    fn run(self: Arc<Self>) -> DiscoveryFuture;
+
+    fn members(&self) -> Vec<String> {
+        self.membership().members()
+    }
+
+    /// Calls `listener` with the current members, all of them joined, and again after
+    /// every change.
+    fn subscribe(&self, listener: MembershipListener) {
+        self.membership().subscribe(listener)
+    }
+
+    /// What the member published about itself, None for sources without descriptors.
+    fn descriptor(&self, member: &str) -> Option<WorkerDescriptor> {
+        self.membership().descriptor(member)
+    }
+
+    fn descriptors(&self) -> Vec<WorkerDescriptor> {
+        self.membership().descriptors()
+    }
+
+    /// Publishes fresh dynamic fields of the local worker.
+    fn update_local(&self, descriptor: WorkerDescriptor) {
+        self.membership().set_descriptor(descriptor)
+    }
+
+    /// Deregisters the local worker for good, the discovery keeps following the members.
+    /// Sources the workers don't register with ignore it.
+    fn leave(&self) {}
+
+    /// Whether what the local worker publishes, draining or leaving, reaches the other
+    /// members. Sources with a fixed list of members can't tell them.
+    fn publishes_local(&self) -> bool {
+        false
+    }
+
+    /// Whether other workers may still see the local worker as registered, false once
+    /// `leave` took effect.
+    fn is_registered(&self) -> bool {
+        false
+    }
+}
+
+/// Picks the discovery configured in `options`. `local` is this worker, registered
+/// with sources that support it, clients pass None.
+pub fn from_options(
+    options: &DiscoveryOptions,
+    local: Option<WorkerDescriptor>,
+) -> Arc<dyn ServiceDiscovery> {
+    info!("Using {:?} service discovery", options.discovery_type);
+    let discovery: Arc<dyn ServiceDiscovery> = match options.discovery_type {
+        DiscoveryType::Static => Arc::new(static_list::StaticDiscovery::new(
+            options.static_service_list.clone(),
+        )),
+        DiscoveryType::Etcd => Arc::new(etcd::EtcdDiscovery::new(
+            options.etcd_uris.clone(),
+            local.clone(),
+            options.refresh_interval,
+        )),
+        DiscoveryType::File => Arc::new(file::FileDiscovery::new(
+            options.service_list_file.clone().unwrap_or_default(),
+            options.refresh_interval,
+        )),
+        DiscoveryType::Gossip => {
+            // followers without a worker of their own take any free port
+            let port = local.as_ref().map_or(0, |_| options.gossip_port);
+            Arc::new(gossip::GossipDiscovery::new(
+                local.clone(),
+                format!("0.0.0.0:{}", port),
+                options.gossip_seeds.clone(),
+                options.gossip_interval,
+                options.gossip_suspect_timeout,
+            ))
+        }
+    };
+    if let Some(local) = local {
+        discovery.update_local(local);
+    }
+    discovery
+}
+
+/// Members are the registered workers that are not draining, a draining worker keeps
+/// its registration and descriptor until it deregisters.
+#[derive(Default)]
+pub struct Membership {
+    registered: RwLock<Vec<String>>,
+    members: RwLock<Vec<String>>,
+    descriptors: RwLock<BTreeMap<String, WorkerDescriptor>>,
+    listeners: Mutex<Vec<MembershipListener>>,
+}
+
+impl Membership {
+    pub fn new<I: IntoIterator<Item = String>>(members: I) -> Membership {
+        let membership = Membership::default();
+        membership.set(members);
+        membership
+    }
+
+    pub fn members(&self) -> Vec<String> {
+        self.members.read().unwrap().clone()
+    }
+
+    /// Registered workers, draining ones included.
+    pub fn registered(&self) -> Vec<String> {
+        self.registered.read().unwrap().clone()
+    }
+
+    /// Replaces the registered workers, notifying the listeners if the members changed.
+    pub fn set<I: IntoIterator<Item = String>>(&self, registered: I) -> bool {
+        let mut registered: Vec<String> = registered
+            .into_iter()
+            .map(|member| member.trim().to_string())
+            .filter(|member| !member.is_empty())
+            .collect();
+        registered.sort();
+        registered.dedup();
+        self.descriptors
+            .write()
+            .unwrap()
+            .retain(|member, _| registered.binary_search(member).is_ok());
+        *self.registered.write().unwrap() = registered;
+        self.refresh()
+    }
+
+    fn refresh(&self) -> bool {
+        let members: Vec<String> = {
+            let descriptors = self.descriptors.read().unwrap();
+            self.registered
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|member| !descriptors.get(*member).map_or(false, |d| d.draining))
+                .cloned()
+                .collect()
+        };
+        let change = {
+            let mut current = self.members.write().unwrap();
+            if *current == members {
+                return false;
+            }
+            let change = MembershipChange {
+                joined: difference(&members, &current),
+                left: difference(&current, &members),
+                members,
+            };
+            info!(
+                "Membership changed to {:?}, joined {:?}, left {:?}",
+                change.members, change.joined, change.left
+            );
+            *current = change.members.clone();
+            change
+        };
+        for listener in self.listeners.lock().unwrap().iter() {
+            listener(&change);
+        }
+        true
+    }
+
+    pub fn subscribe(&self, listener: MembershipListener) {
+        let members = self.members();
+        listener(&MembershipChange {
+            joined: members.clone(),
+            members,
+            left: Vec::new(),
+        });
+        self.listeners.lock().unwrap().push(listener);
+    }
+
+    pub fn descriptor(&self, member: &str) -> Option<WorkerDescriptor> {
+        self.descriptors.read().unwrap().get(member).cloned()
+    }
+
+    pub fn descriptors(&self) -> Vec<WorkerDescriptor> {
+        self.descriptors.read().unwrap().values().cloned().collect()
+    }
+
+    /// A worker starting or stopping to drain leaves or rejoins the members.
+    pub fn set_descriptor(&self, descriptor: WorkerDescriptor) {
+        let draining = descriptor.draining;
+        let was_draining = self
+            .descriptors
+            .write()
+            .unwrap()
+            .insert(descriptor.node_id.clone(), descriptor)
+            .map_or(false, |previous| previous.draining);
+        if draining != was_draining {
+            self.refresh();
+        }
+    }
+
+    pub fn remove_descriptor(&self, member: &str) {
+        self.descriptors.write().unwrap().remove(member);
+    }
+}
+
+fn difference(members: &[String], other: &[String]) -> Vec<String> {
+    members
+        .iter()
+        .filter(|member| other.binary_search(member).is_err())
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_membership_notifies_changes() {
+        let membership = Membership::new(["b:8080".to_string(), "a:8080".to_string()]);
+        assert_eq!(membership.members(), vec!["a:8080", "b:8080"]);
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&changes);
+        membership.subscribe(Box::new(move |change| {
+            seen.lock().unwrap().push(change.clone());
+        }));
+        assert_eq!(changes.lock().unwrap()[0].joined, vec!["a:8080", "b:8080"]);
+
+        // same members in another order and with duplicates is no change
+        assert!(!membership.set(["a:8080", "b:8080", "a:8080"].map(String::from)));
+        assert_eq!(changes.lock().unwrap().len(), 1);
+        assert!(membership.set(["a:8080", "c:8080"].map(String::from)));
+        assert_eq!(
+            changes.lock().unwrap()[1],
+            MembershipChange {
+                members: vec![String::from("a:8080"), String::from("c:8080")],
+                joined: vec![String::from("c:8080")],
+                left: vec![String::from("b:8080")],
+            }
+        );
+    }
+
+    #[test]
+    fn test_static_discovery_from_options() {
+        let options = DiscoveryOptions {
+            static_service_list: vec![String::from("localhost:8080")],
+            ..Default::default()
+        };
+        let local = WorkerDescriptor {
+            node_id: String::from("localhost:8080"),
+            host: String::from("localhost"),
+            h2_port: 5929,
+            ..WorkerDescriptor::default()
+        };
+        let discovery = from_options(&options, Some(local.clone()));
+        assert_eq!(discovery.members(), vec!["localhost:8080"]);
+        assert_eq!(discovery.descriptor("localhost:8080"), Some(local));
+        // the other workers read the same configured list, a drain can't reach them
+        assert!(!discovery.publishes_local());
+    }
+}
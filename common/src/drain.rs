@@ -0,0 +1,324 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http::{Method, Request, StatusCode};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::h2::encode_key;
+use crate::h2::h2_client::{self, ConnectionPool, LOCAL_ONLY_HEADER};
+use crate::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
+use crate::kv_store::object_meta::ObjectMeta;
+use crate::ring::HashRing;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrainState {
+    #[default]
+    Serving,
+    Draining,
+    /// Every key was handed off, the worker can be stopped.
+    Drained,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DrainProgress {
+    pub state: DrainState,
+    pub handed_off: usize,
+    pub handed_off_bytes: u64,
+    pub remaining: usize,
+    pub remaining_bytes: u64,
+    /// Keys the last pass failed to hand off, they are retried.
+    pub failed: usize,
+}
+
+type AddrResolver = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Takes a worker out of service. Once draining, the worker owns no keys on the ring,
+/// and every key it still stores is copied to its owners and then deleted locally,
+/// until the store is empty.
+pub struct Drainer {
+    kv_store: &'static LocalFileKVStore,
+    /// This worker as it appears on the ring.
+    worker_id: String,
+    h2_port: u16,
+    replication_factor: usize,
+    ring: Arc<RwLock<HashRing>>,
+    /// The h2 address of a worker, by default on `h2_port`.
+    resolve: Option<AddrResolver>,
+    /// Bytes per second, 0 is unlimited.
+    rate_bytes: u64,
+    /// Wait before another pass over keys that could not be handed off.
+    retry_interval: Duration,
+    progress: Mutex<DrainProgress>,
+    connections: ConnectionPool,
+}
+
+impl Drainer {
+    pub fn new(
+        kv_store: &'static LocalFileKVStore,
+        worker_id: String,
+        h2_port: u16,
+        replication_factor: usize,
+        ring: Arc<RwLock<HashRing>>,
+        rate_bytes: u64,
+        retry_interval: Duration,
+    ) -> Drainer {
+        Drainer {
+            kv_store,
+            worker_id,
+            h2_port,
+            replication_factor: replication_factor.max(1),
+            ring,
+            resolve: None,
+            rate_bytes,
+            retry_interval,
+            progress: Mutex::new(DrainProgress::default()),
+            connections: ConnectionPool::new(),
+        }
+    }
+
+    /// Owners are reached on the address `resolve` gives. A closure rather than the
+    /// discovery handle, as discovery publishes the drain state of this worker.
+    pub fn with_resolver<F>(mut self, resolve: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.resolve = Some(Box::new(resolve));
+        self
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.progress.lock().unwrap().state != DrainState::Serving
+    }
+
+    /// Marks the worker as draining, false if it already was.
+    pub fn start(&self) -> bool {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.state != DrainState::Serving {
+            return false;
+        }
+        info!("Draining {}", self.worker_id);
+        progress.state = DrainState::Draining;
+        true
+    }
+
+    pub fn progress(&self) -> DrainProgress {
+        DrainProgress {
+            remaining: self.kv_store.key_count(),
+            remaining_bytes: self.kv_store.used_bytes(),
+            ..self.progress.lock().unwrap().clone()
+        }
+    }
+
+    /// Hands off keys in passes until none is left.
+    pub async fn run(&self) -> DrainProgress {
+        let started = Instant::now();
+        loop {
+            let keys = self.kv_store.list("");
+            if keys.is_empty() {
+                break;
+            }
+            let mut failed = 0;
+            for (key, _) in keys {
+                match self.hand_off(&key).await {
+                    Ok(bytes) => {
+                        let mut progress = self.progress.lock().unwrap();
+                        progress.handed_off += 1;
+                        progress.handed_off_bytes += bytes;
+                    }
+                    Err(e) => {
+                        warn!("Failed to hand off {}: {}", key, e);
+                        failed += 1;
+                    }
+                }
+                if self.rate_bytes > 0 {
+                    let bytes = self.progress.lock().unwrap().handed_off_bytes;
+                    let due = Duration::from_secs_f64(bytes as f64 / self.rate_bytes as f64);
+                    let elapsed = started.elapsed();
+                    if due > elapsed {
+                        monoio::time::sleep(due - elapsed).await;
+                    }
+                }
+            }
+            self.progress.lock().unwrap().failed = failed;
+            if failed > 0 {
+                monoio::time::sleep(self.retry_interval).await;
+            }
+        }
+        self.connections.clear();
+        self.progress.lock().unwrap().state = DrainState::Drained;
+        let progress = self.progress();
+        info!("Drained {}: {:?}", self.worker_id, progress);
+        progress
+    }
+
+    /// Copies the key to every owner missing it, then deletes it here.
+    async fn hand_off(&self, key: &str) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let targets = self.targets(key);
+        if targets.is_empty() {
+            return Err("no other worker to hand off to".into());
+        }
+        let (meta, value) = self.kv_store.get_with_meta(key.to_string()).await?;
+        let value = Bytes::from(value);
+        for target in targets.iter() {
+            if self.has(target, key, &meta).await? {
+                debug!("{} already has {}", target, key);
+                continue;
+            }
+            self.push(target, key, &meta, value.clone()).await?;
+        }
+        self.kv_store.remove(key.to_string())?;
+        Ok(value.len() as u64)
+    }
+
+    fn targets(&self, key: &str) -> Vec<String> {
+        let key = key.to_string();
+        self.ring
+            .read()
+            .unwrap()
+            .owners(&key, self.replication_factor + 1)
+            .into_iter()
+            .filter(|owner| *owner != self.worker_id)
+            .take(self.replication_factor)
+            .map(String::from)
+            .collect()
+    }
+
+    /// Whether `target` holds the key at the same version or a newer one.
+    async fn has(
+        &self,
+        target: &str,
+        key: &str,
+        meta: &ObjectMeta,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let request = Request::builder()
+            .uri(format!(
+                "http://{}/head/{}",
+                self.addr(target),
+                encode_key(key)
+            ))
+            .header(LOCAL_ONLY_HEADER, "1")
+            .body(())?;
+        let response = self
+            .connections
+            .send(&self.addr(target), request, None)
+            .await?;
+        match response.status() {
+            StatusCode::OK => {
+                let theirs = ObjectMeta::from_headers(response.headers()).version;
+                Ok(theirs.unwrap_or(0) >= meta.version.unwrap_or(0))
+            }
+            StatusCode::NOT_FOUND => Ok(false),
+            status => Err(format!("{} answered {}", target, status).into()),
+        }
+    }
+
+    async fn push(
+        &self,
+        target: &str,
+        key: &str,
+        meta: &ObjectMeta,
+        value: Bytes,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut request = Request::builder()
+            .method(Method::PUT)
+            .uri(format!(
+                "http://{}/put/{}",
+                self.addr(target),
+                encode_key(key)
+            ))
+            .body(())?;
+        meta.to_headers(request.headers_mut());
+        let response = self
+            .connections
+            .send(&self.addr(target), request, Some(value))
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", target, response.status()).into());
+        }
+        Ok(())
+    }
+
+    fn addr(&self, worker: &str) -> String {
+        match &self.resolve {
+            Some(resolve) => resolve(worker),
+            None => h2_client::h2_addr(worker, self.h2_port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h2::h2_service::H2Service;
+    use crate::settings::local_kv_options::StoreLayout;
+    use crate::test_util::{free_port, h2_addr, spawn_h2, store};
+
+    const DRAINING: &str = "127.0.0.1:8080";
+    const STAYING: &str = "127.0.0.2:8080";
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_drain_hands_off_every_key() {
+        let port = free_port();
+        let (draining_dir, staying_dir) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (draining_store, staying_store) = (
+            store(draining_dir.path(), StoreLayout::FilePerKey),
+            store(staying_dir.path(), StoreLayout::FilePerKey),
+        );
+        spawn_h2(H2Service::new(staying_store, h2_addr(STAYING, port)));
+        monoio::time::sleep(Duration::from_millis(10)).await;
+
+        // the draining worker is already out of the ring
+        let ring = Arc::new(RwLock::new(HashRing::with_members(16, [STAYING])));
+        let drainer = Drainer::new(
+            draining_store,
+            DRAINING.to_string(),
+            port,
+            1,
+            ring,
+            0,
+            Duration::from_millis(10),
+        );
+        for i in 0..20 {
+            let key = format!("data/{}", i);
+            let meta = ObjectMeta {
+                version: Some(2),
+                ..Default::default()
+            };
+            draining_store
+                .put_with_meta(key.clone(), Bytes::from(key.clone()), &meta)
+                .await
+                .unwrap();
+        }
+        // a newer copy on the owner is kept
+        let newer = ObjectMeta {
+            version: Some(3),
+            ..Default::default()
+        };
+        staying_store
+            .put_with_meta(String::from("data/0"), Bytes::from_static(b"newer"), &newer)
+            .await
+            .unwrap();
+
+        assert_eq!(drainer.progress().remaining, 20);
+        assert!(drainer.start());
+        assert!(!drainer.start());
+        let progress = drainer.run().await;
+        assert_eq!(progress.state, DrainState::Drained);
+        assert_eq!((progress.handed_off, progress.remaining), (20, 0));
+        assert!(draining_store.list("").is_empty());
+        for i in 1..20 {
+            let key = format!("data/{}", i);
+            let (meta, value) = staying_store.get_with_meta(key.clone()).await.unwrap();
+            assert_eq!((meta.version, value), (Some(2), key.into_bytes()));
+        }
+        assert_eq!(
+            staying_store.get(String::from("data/0")).await.unwrap(),
+            b"newer"
+        );
+    }
+}
@@ -0,0 +1,491 @@
+use std::future::poll_fn;
+use std::time::Duration;
+
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use http::{HeaderMap, HeaderValue, Request, StatusCode};
+use log::{debug, error};
+use tonic::Code;
+
+use crate::grpc::proto::{
+    DeleteRequest, DeleteResponse, Entry, GetRequest, GetResponse, ListRequest, ListResponse,
+    PutRequest, PutResponse, StatRequest, StatResponse,
+};
+use crate::grpc::{decode_message, encode_message, PREFIX_LEN};
+use crate::h2::h2_service::{read_body_limited, H2Service, RequestError, MAX_PUT_LEN};
+use crate::kv_store::object_meta::ObjectMeta;
+use crate::kv_store::validate_key;
+
+/// Path prefix of the methods of the `fairy.cache.v1.Cache` service, requests under it
+/// are routed here by the h2 service.
+pub const SERVICE_PATH: &str = "/fairy.cache.v1.Cache/";
+/// Largest value chunk of a `GetStream` message.
+const CHUNK_LEN: usize = 64 * 1024;
+/// Entries per `List` message.
+const LIST_BATCH: usize = 1000;
+
+/// Answers a gRPC call on an h2 stream. Failures are answered with a trailers only
+/// response carrying the status of the error, calls running past their `grpc-timeout`
+/// are cut short with DEADLINE_EXCEEDED.
+pub(crate) async fn handle(
+    service: H2Service,
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+) {
+    let method = request.uri().path()[SERVICE_PATH.len()..].to_string();
+    let deadline = match request.headers().get("grpc-timeout").map(parse_timeout) {
+        Some(Some(deadline)) => Some(deadline),
+        Some(None) => {
+            let error = RequestError::BadRequest(String::from("malformed grpc-timeout"));
+            send_status(&mut respond, &method, error);
+            return;
+        }
+        None => None,
+    };
+    let result = match deadline {
+        Some(deadline) => {
+            match monoio::time::timeout(deadline, call(service, &method, request, &mut respond))
+                .await
+            {
+                Ok(result) => result,
+                Err(_) => {
+                    debug!("gRPC {} exceeded its deadline of {:?}", method, deadline);
+                    send_code(&mut respond, Code::DeadlineExceeded, "deadline exceeded");
+                    return;
+                }
+            }
+        }
+        None => call(service, &method, request, &mut respond).await,
+    };
+    if let Err(e) = result {
+        send_status(&mut respond, &method, e);
+    }
+}
+
+/// Parses a `grpc-timeout` value, at most 8 digits followed by one of the units
+/// H, M, S, m, u or n.
+fn parse_timeout(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?;
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = digits.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 3600)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+async fn call(
+    service: H2Service,
+    method: &str,
+    request: Request<RecvStream>,
+    respond: &mut SendResponse<Bytes>,
+) -> Result<(), RequestError> {
+    let grpc = request
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"));
+    if !grpc {
+        return Err(RequestError::BadRequest(String::from(
+            "content type is not application/grpc",
+        )));
+    }
+    let body = read_body_limited(request.into_body(), PREFIX_LEN + MAX_PUT_LEN).await?;
+    let kv_store = service.kv_store();
+    match method {
+        "Get" => {
+            let request: GetRequest = decode_message(body)?;
+            let (meta, value) = service.lookup(key(request.key)?, false).await?;
+            let response = GetResponse {
+                metadata: Some(meta.into()),
+                value,
+            };
+            send_messages(respond, [encode_message(&response)]).await
+        }
+        "GetStream" => {
+            let request: GetRequest = decode_message(body)?;
+            let (meta, value) = service.lookup(key(request.key)?, false).await?;
+            let mut metadata = Some(meta.into());
+            let chunks = (0..value.len().max(1)).step_by(CHUNK_LEN).map(|start| {
+                let end = value.len().min(start + CHUNK_LEN);
+                encode_message(&GetResponse {
+                    metadata: metadata.take(),
+                    value: value.slice(start..end),
+                })
+            });
+            send_messages(respond, chunks).await
+        }
+        "Put" => {
+            let request: PutRequest = decode_message(body)?;
+            let meta = request.metadata.map(ObjectMeta::from).unwrap_or_default();
+            kv_store
+                .put_with_meta(key(request.key)?, request.value, &meta)
+                .await?;
+            send_messages(respond, [encode_message(&PutResponse {})]).await
+        }
+        "Delete" => {
+            let request: DeleteRequest = decode_message(body)?;
+            kv_store.delete(key(request.key)?)?;
+            send_messages(respond, [encode_message(&DeleteResponse {})]).await
+        }
+        "Stat" => {
+            let request: StatRequest = decode_message(body)?;
+            let (meta, len) = kv_store.head(key(request.key)?).await?;
+            let response = StatResponse {
+                metadata: Some(meta.into()),
+                len,
+            };
+            send_messages(respond, [encode_message(&response)]).await
+        }
+        "List" => {
+            let request: ListRequest = decode_message(body)?;
+            let entries: Vec<Entry> = kv_store
+                .list(&request.prefix)
+                .into_iter()
+                .map(|(key, size)| Entry { key, size })
+                .collect();
+            let batches = entries.chunks(LIST_BATCH).map(|batch| {
+                encode_message(&ListResponse {
+                    entries: batch.to_vec(),
+                })
+            });
+            send_messages(respond, batches).await
+        }
+        _ => Err(RequestError::BadRoute(format!(
+            "{}{}",
+            SERVICE_PATH, method
+        ))),
+    }
+}
+
+fn key(key: String) -> Result<String, RequestError> {
+    validate_key(&key)?;
+    Ok(key)
+}
+
+/// Sends the framed messages followed by an OK status, each message waits for the
+/// peer's flow control window instead of queueing in the connection.
+async fn send_messages(
+    respond: &mut SendResponse<Bytes>,
+    messages: impl IntoIterator<Item = Bytes>,
+) -> Result<(), RequestError> {
+    let mut send = respond.send_response(response_head(), false)?;
+    for message in messages {
+        send_flow_controlled(&mut send, message).await?;
+    }
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(Code::Ok as i32));
+    send.send_trailers(trailers)?;
+    Ok(())
+}
+
+async fn send_flow_controlled(
+    send: &mut SendStream<Bytes>,
+    mut data: Bytes,
+) -> Result<(), RequestError> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        while send.capacity() == 0 {
+            match poll_fn(|cx| send.poll_capacity(cx)).await {
+                Some(capacity) => capacity?,
+                None => return Err(h2::Error::from(h2::Reason::CANCEL).into()),
+            };
+        }
+        let chunk = data.split_to(send.capacity().min(data.len()));
+        send.send_data(chunk, false)?;
+    }
+    Ok(())
+}
+
+fn response_head() -> http::Response<()> {
+    let mut response = http::Response::new(());
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    response
+}
+
+fn code(error: &RequestError) -> Code {
+    match error {
+        RequestError::BadRoute(_) => Code::Unimplemented,
+        RequestError::BadRequest(_) => Code::InvalidArgument,
+        error => match error.status() {
+            StatusCode::BAD_REQUEST => Code::InvalidArgument,
+            StatusCode::NOT_FOUND => Code::NotFound,
+            StatusCode::PAYLOAD_TOO_LARGE | StatusCode::INSUFFICIENT_STORAGE => {
+                Code::ResourceExhausted
+            }
+            _ => Code::Internal,
+        },
+    }
+}
+
+/// Answers with the status and message of the error, if the stream is still usable.
+fn send_status(respond: &mut SendResponse<Bytes>, method: &str, error: RequestError) {
+    let code = code(&error);
+    if code == Code::Internal {
+        error!("gRPC {} failed: {}", method, error);
+    } else {
+        debug!("gRPC {} answered {:?}: {}", method, code, error);
+    }
+    if matches!(error, RequestError::Stream(_)) {
+        return;
+    }
+    send_code(respond, code, &error.to_string());
+}
+
+/// Trailers only response with the code and message. Once headers went out the
+/// stream is reset instead.
+fn send_code(respond: &mut SendResponse<Bytes>, code: Code, message: &str) {
+    let mut response = response_head();
+    let headers = response.headers_mut();
+    headers.insert("grpc-status", HeaderValue::from(code as i32));
+    if let Ok(message) = HeaderValue::from_str(&percent_encode(message)) {
+        headers.insert("grpc-message", message);
+    }
+    if let Err(e) = respond.send_response(response, true) {
+        debug!("Failed to answer {:?}: {}", code, e);
+        respond.send_reset(h2::Reason::CANCEL);
+    }
+}
+
+/// gRPC messages are percent encoded outside of printable ASCII.
+fn percent_encode(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (0x20..0x7f).contains(&byte) && byte != b'%' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tonic::transport::Channel;
+
+    use super::*;
+    use crate::grpc::proto::cache_client::CacheClient;
+    use crate::grpc::proto::Metadata;
+    use crate::settings::local_kv_options::StoreLayout;
+    use crate::test_util;
+
+    /// Serves the h2 service on its own monoio thread, the tonic client runs on tokio.
+    fn serve(layout: StoreLayout) -> (String, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        let addr = test_util::serve_on_free_port_in_thread(move |addr| {
+            test_util::leak(H2Service::new(test_util::store(&root, layout), addr)).serve_h2()
+        });
+        (format!("http://{}", addr), dir)
+    }
+
+    async fn connect(endpoint: String) -> CacheClient<Channel> {
+        CacheClient::connect(endpoint).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_unary_calls() {
+        for layout in [StoreLayout::FilePerKey, StoreLayout::Segment] {
+            let (endpoint, _dir) = serve(layout);
+            let mut client = connect(endpoint).await;
+            let get = |key: &str| GetRequest {
+                key: key.to_string(),
+            };
+            let missing = client.get(get("key")).await.unwrap_err();
+            assert_eq!(missing.code(), Code::NotFound);
+
+            let metadata = Metadata {
+                content_type: String::from("text/plain"),
+                version: Some(2),
+                ..Default::default()
+            };
+            client
+                .put(PutRequest {
+                    key: String::from("key"),
+                    value: Bytes::from_static(b"value"),
+                    metadata: Some(metadata.clone()),
+                })
+                .await
+                .unwrap();
+            let found = client.get(get("key")).await.unwrap().into_inner();
+            assert_eq!(found.value, Bytes::from_static(b"value"));
+            assert_eq!(found.metadata, Some(metadata.clone()));
+
+            let stat = client
+                .stat(StatRequest {
+                    key: String::from("key"),
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!((stat.metadata, stat.len), (Some(metadata), 5));
+
+            client
+                .delete(DeleteRequest {
+                    key: String::from("key"),
+                })
+                .await
+                .unwrap();
+            let missing = client.get(get("key")).await.unwrap_err();
+            assert_eq!(missing.code(), Code::NotFound);
+            let empty = client.get(get("")).await.unwrap_err();
+            assert_eq!(
+                (empty.code(), empty.message()),
+                (Code::InvalidArgument, "invalid key \"\": empty")
+            );
+            for key in ["/etc/passwd", "../x"] {
+                let escaping = client.get(get(key)).await.unwrap_err();
+                assert_eq!(escaping.code(), Code::InvalidArgument);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streaming_calls() {
+        let (endpoint, _dir) = serve(StoreLayout::FilePerKey);
+        let mut client = connect(endpoint).await;
+        let large = Bytes::from((0..CHUNK_LEN * 2 + 10).map(|i| i as u8).collect::<Vec<_>>());
+        for (key, value) in [("dir/large", large.clone()), ("dir/small", Bytes::new())] {
+            client
+                .put(PutRequest {
+                    key: key.to_string(),
+                    value,
+                    metadata: None,
+                })
+                .await
+                .unwrap();
+        }
+        client
+            .put(PutRequest {
+                key: String::from("other"),
+                value: Bytes::from_static(b"value"),
+                metadata: None,
+            })
+            .await
+            .unwrap();
+
+        let mut stream = client
+            .get_stream(GetRequest {
+                key: String::from("dir/large"),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut chunks = Vec::new();
+        while let Some(chunk) = stream.message().await.unwrap() {
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[0].metadata.is_some() && chunks[1].metadata.is_none());
+        let value: Vec<u8> = chunks.iter().flat_map(|c| c.value.to_vec()).collect();
+        assert_eq!(value, large);
+
+        let mut stream = client
+            .list(ListRequest {
+                prefix: String::from("dir/"),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut entries = Vec::new();
+        while let Some(batch) = stream.message().await.unwrap() {
+            entries.extend(batch.entries);
+        }
+        let keys: Vec<&str> = entries.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, ["dir/large", "dir/small"]);
+        assert!(entries[0].size > large.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_stream_flow_control_and_deadline() {
+        let (endpoint, _dir) = serve(StoreLayout::FilePerKey);
+        let channel = tonic::transport::Endpoint::from_shared(endpoint.clone())
+            .unwrap()
+            .initial_stream_window_size(Some(16 * 1024))
+            .connect()
+            .await
+            .unwrap();
+        let mut client = CacheClient::new(channel);
+        let large = Bytes::from((0..CHUNK_LEN * 3).map(|i| i as u8).collect::<Vec<_>>());
+        client
+            .put(PutRequest {
+                key: String::from("large"),
+                value: large.clone(),
+                metadata: None,
+            })
+            .await
+            .unwrap();
+        let mut request = tonic::Request::new(GetRequest {
+            key: String::from("large"),
+        });
+        request.set_timeout(Duration::from_secs(5));
+        let mut stream = client.get_stream(request).await.unwrap().into_inner();
+        let mut value = Vec::new();
+        while let Some(chunk) = stream.message().await.unwrap() {
+            value.extend_from_slice(&chunk.value);
+        }
+        assert_eq!(value, large);
+
+        // with a tiny window the stream can't finish before its deadline
+        let channel = tonic::transport::Endpoint::from_shared(endpoint)
+            .unwrap()
+            .initial_stream_window_size(Some(1024))
+            .connect()
+            .await
+            .unwrap();
+        let mut client = CacheClient::new(channel);
+        let mut request = tonic::Request::new(GetRequest {
+            key: String::from("large"),
+        });
+        request.set_timeout(Duration::from_millis(100));
+        let mut stream = client.get_stream(request).await.unwrap().into_inner();
+        let error = loop {
+            match stream.message().await {
+                Ok(Some(_)) => continue,
+                Ok(None) => panic!("stream finished past its deadline"),
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(
+            error.code(),
+            Code::Cancelled | Code::DeadlineExceeded
+        ));
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        let parse = |value: &'static str| parse_timeout(&HeaderValue::from_static(value));
+        assert_eq!(parse("5S"), Some(Duration::from_secs(5)));
+        assert_eq!(parse("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse("99999999n"), Some(Duration::from_nanos(99999999)));
+        assert_eq!(parse("123456789S"), None);
+        assert_eq!(parse("S"), None);
+        assert_eq!(parse("5s"), None);
+        assert_eq!(parse("-5S"), None);
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("100% ok"), "100%25 ok");
+        assert_eq!(percent_encode("clé\n"), "cl%C3%A9%0A");
+    }
+}
@@ -0,0 +1,102 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use prost::Message;
+
+use crate::h2::h2_service::RequestError;
+use crate::kv_store::object_meta::ObjectMeta;
+
+pub mod grpc_service;
+
+/// Messages and the tonic client generated from `proto/cache.proto`.
+pub mod proto {
+    tonic::include_proto!("fairy.cache.v1");
+}
+
+/// Compression flag and big endian length in front of every message.
+const PREFIX_LEN: usize = 5;
+
+/// A message framed for a gRPC body.
+pub fn encode_message<M: Message>(message: &M) -> Bytes {
+    let len = message.encoded_len();
+    let mut buf = BytesMut::with_capacity(PREFIX_LEN + len);
+    buf.put_u8(0);
+    buf.put_u32(len as u32);
+    message.encode(&mut buf).unwrap();
+    buf.freeze()
+}
+
+/// The single message of a request body, bytes fields are views into the body.
+pub fn decode_message<M: Message + Default>(body: Bytes) -> Result<M, RequestError> {
+    if body.len() < PREFIX_LEN {
+        return Err(RequestError::BadRequest(String::from("truncated message")));
+    }
+    if body[0] != 0 {
+        return Err(RequestError::BadRequest(String::from(
+            "compressed messages are not supported",
+        )));
+    }
+    let len = u32::from_be_bytes(body[1..PREFIX_LEN].try_into().unwrap()) as usize;
+    if body.len() != PREFIX_LEN + len {
+        return Err(RequestError::BadRequest(String::from(
+            "expected a single message",
+        )));
+    }
+    M::decode(body.slice(PREFIX_LEN..)).map_err(|e| RequestError::BadRequest(e.to_string()))
+}
+
+impl From<ObjectMeta> for proto::Metadata {
+    fn from(meta: ObjectMeta) -> Self {
+        proto::Metadata {
+            content_type: meta.content_type.unwrap_or_default(),
+            origin_uri: meta.origin_uri.unwrap_or_default(),
+            etag: meta.etag.unwrap_or_default(),
+            version: meta.version,
+            user_metadata: meta.user_headers.into_iter().collect(),
+            expires_at_ms: meta.expires_at_ms,
+        }
+    }
+}
+
+/// Empty strings are unset.
+impl From<proto::Metadata> for ObjectMeta {
+    fn from(meta: proto::Metadata) -> Self {
+        let set = |value: String| (!value.is_empty()).then_some(value);
+        ObjectMeta {
+            content_type: set(meta.content_type),
+            origin_uri: set(meta.origin_uri),
+            etag: set(meta.etag),
+            version: meta.version,
+            user_headers: meta.user_metadata.into_iter().collect(),
+            expires_at_ms: meta.expires_at_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::proto::PutRequest;
+
+    #[test]
+    fn test_message_round_trip() {
+        let meta = ObjectMeta {
+            content_type: Some(String::from("text/plain")),
+            version: Some(3),
+            user_headers: [(String::from("owner"), String::from("fairy"))].into(),
+            ..Default::default()
+        };
+        let request = PutRequest {
+            key: String::from("key"),
+            value: Bytes::from_static(b"value"),
+            metadata: Some(meta.clone().into()),
+        };
+        let body = encode_message(&request);
+        let decoded: PutRequest = decode_message(body.clone()).unwrap();
+        assert_eq!(decoded, request);
+        assert_eq!(ObjectMeta::from(decoded.metadata.unwrap()), meta);
+
+        assert!(decode_message::<PutRequest>(body.slice(..body.len() - 1)).is_err());
+        let mut compressed = body.to_vec();
+        compressed[0] = 1;
+        assert!(decode_message::<PutRequest>(Bytes::from(compressed)).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+use bytes::{Bytes, BytesMut};
+use h2::client::SendRequest;
+use h2::RecvStream;
+use http::{Request, Response};
+use log::debug;
+use monoio::net::TcpStream;
+
+use crate::discovery::ServiceDiscovery;
+use crate::h2::compat_stream;
+
+/// Marks a worker to worker read that must be answered from the local store only,
+/// so a miss is never forwarded again.
+pub const LOCAL_ONLY_HEADER: &str = "x-fairy-local-only";
+
+/// Workers register as `host:http_port`, the data path is served on the h2 port.
+pub fn h2_addr(worker: &str, h2_port: u16) -> String {
+    let host = worker.rsplit_once(':').map_or(worker, |(host, _)| host);
+    format!("{}:{}", host, h2_port)
+}
+
+/// The h2 address a worker published in its descriptor. Workers without one, or found
+/// without discovery, are assumed to listen on `default_port`.
+pub fn resolve_h2_addr(
+    discovery: Option<&dyn ServiceDiscovery>,
+    worker: &str,
+    default_port: u16,
+) -> String {
+    match discovery.and_then(|discovery| discovery.descriptor(worker)) {
+        Some(descriptor) => descriptor.h2_addr(),
+        None => h2_addr(worker, default_port),
+    }
+}
+
+/// Opens an h2 connection to a worker, the connection is driven by a spawned task
+/// until every clone of the returned handle is dropped.
+pub async fn connect(addr: &str) -> Result<SendRequest<Bytes>, Box<dyn Error + Send + Sync>> {
+    let tcp = TcpStream::connect(addr).await?;
+    let (client, connection) = h2::client::handshake(compat_stream(tcp)).await?;
+    let addr = addr.to_string();
+    monoio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("h2 connection to {} closed: {}", addr, e);
+        }
+    });
+    Ok(client)
+}
+
+/// Sends a request with an optional body and reads the whole response body.
+pub async fn send(
+    client: SendRequest<Bytes>,
+    request: Request<()>,
+    body: Option<Bytes>,
+) -> Result<Response<Bytes>, Box<dyn Error + Send + Sync>> {
+    let mut client = client.ready().await?;
+    let (response, mut stream) = client.send_request(request, body.is_none())?;
+    if let Some(body) = body {
+        stream.send_data(body, true)?;
+    }
+    let (head, body) = response.await?.into_parts();
+    Ok(Response::from_parts(head, read_body(body).await?))
+}
+
+/// Reads a whole h2 body, releasing flow control capacity as chunks arrive.
+pub async fn read_body(mut body: RecvStream) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        body.flow_control().release_capacity(chunk.len())?;
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
+
+/// One pooled h2 connection per address for worker to worker traffic, dropped again
+/// when a request over it fails.
+#[derive(Default)]
+pub struct ConnectionPool {
+    connections: Mutex<HashMap<String, SendRequest<Bytes>>>,
+}
+
+impl ConnectionPool {
+    pub fn new() -> ConnectionPool {
+        ConnectionPool::default()
+    }
+
+    pub async fn send(
+        &self,
+        addr: &str,
+        request: Request<()>,
+        body: Option<Bytes>,
+    ) -> Result<Response<Bytes>, Box<dyn Error + Send + Sync>> {
+        let cached = self.connections.lock().unwrap().get(addr).cloned();
+        let connection = match cached {
+            Some(connection) => connection,
+            None => {
+                let connection = connect(addr).await?;
+                self.connections
+                    .lock()
+                    .unwrap()
+                    .insert(addr.to_string(), connection.clone());
+                connection
+            }
+        };
+        let result = send(connection, request, body).await;
+        if result.is_err() {
+            self.connections.lock().unwrap().remove(addr);
+        }
+        result
+    }
+
+    pub fn clear(&self) {
+        self.connections.lock().unwrap().clear();
+    }
+}
@@ -1,52 +1,171 @@
-use bytes::Bytes;
+use std::error::Error;
+
+use bytes::{Bytes, BytesMut};
 use h2::server::SendResponse;
 use h2::RecvStream;
-use http::Request;
+use http::{Method, Request, StatusCode};
 use log::{debug, error};
 use monoio::net::{TcpListener, TcpStream};
-use monoio_compat::StreamWrapper;
+use thiserror::Error;
 
+use crate::anti_entropy::AntiEntropy;
+use crate::grpc::grpc_service;
+use crate::h2::h2_client::LOCAL_ONLY_HEADER;
+use crate::h2::{compat_stream, decode_key};
 use crate::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
+use crate::kv_store::object_meta::{ObjectMeta, TOMBSTONE_VERSION_HEADER};
+use crate::kv_store::read_through::{self, ReadThroughLoader};
+use crate::kv_store::store_error::StoreError;
+use crate::kv_store::validate_key;
+use crate::rebalance::Rebalancer;
+use crate::shutdown::{self, Shutdown};
+
+/// Puts are buffered whole in memory, larger bodies are refused.
+pub const MAX_PUT_LEN: usize = 1024 * 1024 * 1024;
+/// Most keys answered by one `/list` request.
+pub const LIST_PAGE_SIZE: usize = 1000;
+
+/// Why a request failed, answered with the matching status.
+#[derive(Debug, Error)]
+pub enum RequestError {
+    #[error("unsupported route {0}")]
+    BadRoute(String),
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("body is larger than {0} bytes")]
+    BodyTooLarge(usize),
+    #[error("{key} was deleted at version {version}")]
+    Deleted { key: String, version: u64 },
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Encode(#[from] serde_json::Error),
+    /// The stream broke, nothing can be answered anymore.
+    #[error(transparent)]
+    Stream(#[from] h2::Error),
+}
+
+impl RequestError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RequestError::BadRoute(_)
+            | RequestError::BadRequest(_)
+            | RequestError::Store(StoreError::InvalidKey { .. }) => StatusCode::BAD_REQUEST,
+            RequestError::BodyTooLarge(_) | RequestError::Store(StoreError::TooLarge { .. }) => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            RequestError::Store(StoreError::Full(_)) => StatusCode::INSUFFICIENT_STORAGE,
+            RequestError::Deleted { .. } => StatusCode::NOT_FOUND,
+            RequestError::Store(e) if e.is_not_found() => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
 
+/// Errors of the loader and of peers keep their kind.
+impl From<Box<dyn Error + Send + Sync>> for RequestError {
+    fn from(error: Box<dyn Error + Send + Sync>) -> Self {
+        RequestError::Store(StoreError::from(error))
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct H2Service {
     kv_store: &'static LocalFileKVStore,
     addr: &'static str,
+    rebalancer: Option<&'static Rebalancer>,
+    anti_entropy: Option<&'static AntiEntropy>,
+    loader: Option<&'static ReadThroughLoader>,
+    shutdown: Option<&'static Shutdown>,
 }
 
 impl H2Service {
     pub fn new(kv_store: &'static LocalFileKVStore, addr: &'static str) -> Self {
-        H2Service { kv_store, addr }
+        H2Service {
+            kv_store,
+            addr,
+            rebalancer: None,
+            anti_entropy: None,
+            loader: None,
+            shutdown: None,
+        }
     }
 
+    /// Misses on keys still being handed over are fetched from their old owner.
+    pub fn with_rebalancer(mut self, rebalancer: &'static Rebalancer) -> Self {
+        self.rebalancer = Some(rebalancer);
+        self
+    }
+
+    /// Other misses are read through peers and the under file system.
+    pub fn with_loader(mut self, loader: &'static ReadThroughLoader) -> Self {
+        self.loader = Some(loader);
+        self
+    }
+
+    /// Answers the Merkle tree requests of peers running anti-entropy.
+    pub fn with_anti_entropy(mut self, anti_entropy: &'static AntiEntropy) -> Self {
+        self.anti_entropy = Some(anti_entropy);
+        self
+    }
+
+    /// Once shut down, the service stops accepting connections and asks clients to go
+    /// away from the open ones, whose streams still complete.
+    pub fn with_shutdown(mut self, shutdown: &'static Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Serves until the shutdown is triggered.
     pub async fn serve_h2(&self) {
         let listener = TcpListener::bind(self.addr).unwrap();
         loop {
-            if let Ok((socket, peer_addr)) = listener.accept().await {
-                let kv_store = self.kv_store;
+            let accepted = monoio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown::triggered(self.shutdown) => break,
+            };
+            if let Ok((socket, peer_addr)) = accepted {
+                let service = *self;
                 monoio::spawn(async move {
                     debug!("h2 connection received from {}", peer_addr);
-                    if let Err(e) = H2Service::serve(socket, kv_store).await {
+                    if let Err(e) = service.serve(socket).await {
                         error!("h2 serve error  -> err={:?} peer={}", e, peer_addr);
                     }
                 });
             }
         }
+        debug!("h2 service on {} stopped accepting connections", self.addr);
     }
 
     async fn serve(
+        self,
         socket: TcpStream,
-        kv_store: &'static LocalFileKVStore,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let socket_wrapper = StreamWrapper::new(socket);
-        let mut connection = h2::server::handshake(socket_wrapper).await?;
+        let mut connection = h2::server::handshake(compat_stream(socket)).await?;
         debug!("H2 connection bound");
 
-        while let Some(result) = connection.accept().await {
+        let mut closing = false;
+        loop {
+            let next = if closing {
+                connection.accept().await
+            } else {
+                monoio::select! {
+                    next = connection.accept() => next,
+                    _ = shutdown::triggered(self.shutdown) => {
+                        closing = true;
+                        connection.graceful_shutdown();
+                        continue;
+                    }
+                }
+            };
+            let Some(result) = next else {
+                break;
+            };
             let (request, respond) = result?;
+            let in_flight = self.shutdown.map(|shutdown| shutdown.track());
             monoio::spawn(async move {
-                if let Err(e) = H2Service::handle_request(request, respond, kv_store).await {
-                    error!("error while handling request: {e}");
-                }
+                let _in_flight = in_flight;
+                self.handle_request(request, respond).await;
             });
         }
 
@@ -54,71 +173,449 @@ impl H2Service {
         Ok(())
     }
 
-    async fn handle_request(
-        request: http::Request<h2::RecvStream>,
-        respond: h2::server::SendResponse<bytes::Bytes>,
-        kv_store: &LocalFileKVStore,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn handle_request(self, request: Request<RecvStream>, mut respond: SendResponse<Bytes>) {
         debug!("GOT request: {request:?}");
-        let uri_parse_result = H2Service::parse_uri(&request);
-        match uri_parse_result {
-            ("get", id) => H2Service::get_object(id, respond, kv_store).await,
-            ("put", id) => H2Service::put_object(id, request, respond, kv_store).await,
-            _ => {
-                error!("unsupported ops {:?}", uri_parse_result);
-                Ok(())
+        if request.uri().path().starts_with(grpc_service::SERVICE_PATH) {
+            return grpc_service::handle(self, request, respond).await;
+        }
+        let kv_store = self.kv_store;
+        let with_body = request.method() != Method::HEAD;
+        let result = match H2Service::parse_uri(&request) {
+            Err(e) => Err(e),
+            Ok(("get", id)) => {
+                let local_only = request.headers().contains_key(LOCAL_ONLY_HEADER);
+                self.get_object(id, &mut respond, local_only).await
+            }
+            Ok(("head", id)) => H2Service::head_object(id, &mut respond, kv_store).await,
+            Ok(("put", id)) => H2Service::put_object(id, request, &mut respond, kv_store).await,
+            Ok(("delete", id)) => H2Service::delete_object(id, &request, &mut respond, kv_store),
+            Ok(("list", prefix)) => {
+                H2Service::list_objects(&prefix, &request, &mut respond, kv_store)
             }
+            Ok(("merkle", peer)) => self.merkle_nodes(&peer, &request, &mut respond).await,
+            Ok(("merkle-leaves", peer)) => self.merkle_leaves(&peer, &request, &mut respond).await,
+            Ok(("health", _)) => H2Service::health(&mut respond),
+            Ok(_) => Err(RequestError::BadRoute(request.uri().path().to_string())),
+        };
+        if let Err(e) = result {
+            H2Service::send_error(&mut respond, e, with_body);
         }
     }
 
-    fn parse_uri(request: &http::Request<h2::RecvStream>) -> (&str, String) {
-        let rest_uri: Vec<&str> = {
-            let uri = request.uri().path();
-            uri.split('/').collect::<Vec<&str>>()
+    /// The operation and its decoded argument. Keys are checked here, before any of
+    /// them reaches the store.
+    fn parse_uri(
+        request: &http::Request<h2::RecvStream>,
+    ) -> Result<(&'static str, String), RequestError> {
+        // keys may contain `/`, everything after the op is the key
+        let path = request.uri().path();
+        let rest_uri: Vec<&str> = path.splitn(3, '/').collect();
+        let (op, encoded) = match rest_uri.as_slice() {
+            ["", "get", id] => ("get", *id),
+            ["", "head", id] => ("head", *id),
+            ["", "put", id] => ("put", *id),
+            ["", "delete", id] => ("delete", *id),
+            ["", "list", prefix] => ("list", *prefix),
+            ["", "list"] => ("list", ""),
+            ["", "merkle", peer] => ("merkle", *peer),
+            ["", "merkle-leaves", peer] => ("merkle-leaves", *peer),
+            ["", "health"] => ("health", ""),
+            _ => return Err(RequestError::BadRoute(path.to_string())),
         };
-        match rest_uri.as_slice() {
-            ["", "get", id] => ("get", id.to_string()),
-            ["", "put", id] => ("put", id.to_string()),
-            _ => {
-                error!("unsupported ops {:?}", rest_uri);
-                ("none", String::from("n/a"))
-            }
+        let Some(id) = decode_key(encoded) else {
+            return Err(RequestError::BadRequest(String::from(
+                "key is not valid UTF-8",
+            )));
+        };
+        if matches!(op, "get" | "head" | "put" | "delete") {
+            validate_key(&id)?;
         }
+        Ok((op, id))
     }
 
     async fn put_object(
         id: String,
         request: Request<RecvStream>,
-        mut respond: SendResponse<Bytes>,
+        respond: &mut SendResponse<Bytes>,
         kv_store: &LocalFileKVStore,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    ) -> Result<(), RequestError> {
         debug!(">>>> receive {}", id);
-        //let mut body = request.into_body();//request.body_mut();
-        let (_head, mut body) = request.into_parts();
-        if let Some(chunk) = body.data().await {
-            //println!("receive data {:?}{:?}", head, chunk.unwrap());
-            kv_store
-                .put(id, chunk.unwrap())
-                .await
-                .expect("TODO: panic message");
+        let (head, body) = request.into_parts();
+        let declared_len = head
+            .headers
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<u64>().ok());
+        if declared_len.is_some_and(|len| len > MAX_PUT_LEN as u64) {
+            return Err(RequestError::BodyTooLarge(MAX_PUT_LEN));
         }
+        let meta = ObjectMeta::from_headers(&head.headers);
+        let value = read_body_limited(body, MAX_PUT_LEN).await?;
+        kv_store.put_with_meta(id, value, &meta).await?;
         let response = http::Response::new(());
         let mut send = respond.send_response(response, false)?;
         send.send_data(bytes::Bytes::from_static(b"world\n"), true)?;
         Ok(())
     }
 
+    /// A miss is served from the old owner while the key is being handed over, then
+    /// read through the loader, unless the request came from a peer.
     async fn get_object(
+        self,
         id: String,
-        mut respond: h2::server::SendResponse<bytes::Bytes>,
-        kv_store: &LocalFileKVStore,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let response = http::Response::new(());
+        respond: &mut SendResponse<Bytes>,
+        local_only: bool,
+    ) -> Result<(), RequestError> {
+        let (meta, buf) = match self.lookup(id.clone(), local_only).await {
+            Ok(found) => found,
+            Err(e) => return Err(H2Service::or_deleted(self.kv_store, id, e)),
+        };
+        let mut response = http::Response::new(());
+        meta.to_headers(response.headers_mut());
         let mut send = respond.send_response(response, false)?;
         debug!("h2 is sending data {}", id);
 
-        let buf = kv_store.get(id).await.expect("read data failed from local");
-        send.send_data(bytes::Bytes::from(buf), true)?;
+        send.send_data(buf, true)?;
+        Ok(())
+    }
+
+    /// The value of a get, from the store, the old owner or the loader.
+    pub(crate) async fn lookup(
+        self,
+        id: String,
+        local_only: bool,
+    ) -> Result<(ObjectMeta, Bytes), RequestError> {
+        if local_only {
+            return Ok(self.kv_store.get_bytes(id).await?);
+        }
+        Ok(read_through::lookup(self.kv_store, self.rebalancer, self.loader, &id).await?)
+    }
+
+    pub(crate) fn kv_store(&self) -> &'static LocalFileKVStore {
+        self.kv_store
+    }
+
+    async fn head_object(
+        id: String,
+        respond: &mut SendResponse<Bytes>,
+        kv_store: &LocalFileKVStore,
+    ) -> Result<(), RequestError> {
+        let (meta, len) = match kv_store.head(id.clone()).await {
+            Ok(found) => found,
+            Err(e) => return Err(H2Service::or_deleted(kv_store, id, e.into())),
+        };
+        let mut response = http::Response::new(());
+        meta.to_headers(response.headers_mut());
+        response
+            .headers_mut()
+            .insert(http::header::CONTENT_LENGTH, http::HeaderValue::from(len));
+        respond.send_response(response, true)?;
         Ok(())
     }
+
+    /// A miss on a key deleted lately names the version of the delete, so that read
+    /// repair does not bring back the value it removed.
+    fn or_deleted(kv_store: &LocalFileKVStore, key: String, error: RequestError) -> RequestError {
+        if !matches!(&error, RequestError::Store(e) if e.is_not_found()) {
+            return error;
+        }
+        match kv_store.deleted_version(&key) {
+            Some(version) => RequestError::Deleted { key, version },
+            None => error,
+        }
+    }
+
+    /// A delete carrying a version leaves its tombstone at that version.
+    fn delete_object(
+        id: String,
+        request: &Request<RecvStream>,
+        respond: &mut SendResponse<Bytes>,
+        kv_store: &LocalFileKVStore,
+    ) -> Result<(), RequestError> {
+        match ObjectMeta::from_headers(request.headers()).version {
+            Some(version) => kv_store.delete_at(id, version)?,
+            None => kv_store.delete(id)?,
+        }
+        respond.send_response(http::Response::new(()), true)?;
+        Ok(())
+    }
+
+    /// One page of the keys starting with the prefix as a JSON array of `[key, size]`.
+    /// The page starts after the percent encoded `after` key and holds up to `limit`
+    /// keys, capped at `LIST_PAGE_SIZE`.
+    fn list_objects(
+        prefix: &str,
+        request: &Request<RecvStream>,
+        respond: &mut SendResponse<Bytes>,
+        kv_store: &LocalFileKVStore,
+    ) -> Result<(), RequestError> {
+        let after = query_param(request, "after").and_then(decode_key);
+        let limit = query_param(request, "limit")
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(LIST_PAGE_SIZE)
+            .clamp(1, LIST_PAGE_SIZE);
+        H2Service::send_json(
+            respond,
+            &kv_store.list_page(prefix, after.as_deref(), limit),
+        )
+    }
+
+    /// Hashes of the `nodes` at `level` of the tree shared with the peer, as JSON.
+    async fn merkle_nodes(
+        self,
+        peer: &str,
+        request: &Request<RecvStream>,
+        respond: &mut SendResponse<Bytes>,
+    ) -> Result<(), RequestError> {
+        let Some(anti_entropy) = self.anti_entropy else {
+            return Err(RequestError::BadRoute(request.uri().path().to_string()));
+        };
+        let level = query_param(request, "level").and_then(|level| level.parse().ok());
+        let Some(level) = level else {
+            return Err(RequestError::BadRequest(String::from(
+                "missing merkle level",
+            )));
+        };
+        let nodes = indexes(query_param(request, "nodes"));
+        let hashes = anti_entropy.merkle_nodes(peer, level, &nodes).await;
+        H2Service::send_json(respond, &hashes)
+    }
+
+    /// Keys and digests in the `leaves` of the tree shared with the peer, as JSON.
+    async fn merkle_leaves(
+        self,
+        peer: &str,
+        request: &Request<RecvStream>,
+        respond: &mut SendResponse<Bytes>,
+    ) -> Result<(), RequestError> {
+        let Some(anti_entropy) = self.anti_entropy else {
+            return Err(RequestError::BadRoute(request.uri().path().to_string()));
+        };
+        let leaves = indexes(query_param(request, "leaves"));
+        let entries = anti_entropy.merkle_leaves(peer, &leaves).await;
+        H2Service::send_json(respond, &entries)
+    }
+
+    fn send_json<T: serde::Serialize>(
+        respond: &mut SendResponse<Bytes>,
+        value: &T,
+    ) -> Result<(), RequestError> {
+        let body = serde_json::to_vec(value)?;
+        let mut send = respond.send_response(http::Response::new(()), false)?;
+        send.send_data(Bytes::from(body), true)?;
+        Ok(())
+    }
+
+    fn health(respond: &mut SendResponse<Bytes>) -> Result<(), RequestError> {
+        let mut send = respond.send_response(http::Response::new(()), false)?;
+        send.send_data(Bytes::from_static(b"ok"), true)?;
+        Ok(())
+    }
+
+    /// Answers with the status of the error, and its message unless the request was a
+    /// HEAD, if the stream is still usable.
+    fn send_error(respond: &mut SendResponse<Bytes>, error: RequestError, with_body: bool) {
+        let status = error.status();
+        if status.is_server_error() {
+            error!("h2 request failed: {}", error);
+        } else {
+            debug!("h2 request answered {}: {}", status, error);
+        }
+        if matches!(error, RequestError::Stream(_)) {
+            return;
+        }
+        let mut response = http::Response::new(());
+        *response.status_mut() = status;
+        if let RequestError::Deleted { version, .. } = &error {
+            response
+                .headers_mut()
+                .insert(TOMBSTONE_VERSION_HEADER, http::HeaderValue::from(*version));
+        }
+        let sent = match respond.send_response(response, !with_body) {
+            Ok(mut send) if with_body => send.send_data(Bytes::from(error.to_string()), true),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            debug!("Failed to answer {}: {}", status, e);
+        }
+    }
+}
+
+/// Reads the whole body, failing once it grows beyond `limit` bytes.
+pub(crate) async fn read_body_limited(
+    mut body: RecvStream,
+    limit: usize,
+) -> Result<Bytes, RequestError> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        body.flow_control().release_capacity(chunk.len())?;
+        if buf.len() + chunk.len() > limit {
+            return Err(RequestError::BodyTooLarge(limit));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
+
+fn query_param<'a>(request: &'a Request<RecvStream>, name: &str) -> Option<&'a str> {
+    request.uri().query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+/// Comma separated indexes, anything unparsable is skipped.
+fn indexes(list: Option<&str>) -> Vec<usize> {
+    list.unwrap_or_default()
+        .split(',')
+        .filter_map(|index| index.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h2::h2_client;
+    use crate::kv_store::object_meta::VERSION_HEADER;
+    use crate::test_util::{self, leak};
+
+    async fn send(addr: &str, method: Method, path: &str, body: Option<Bytes>) -> StatusCode {
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", addr, path))
+            .body(())
+            .unwrap();
+        let connection = h2_client::connect(addr).await.unwrap();
+        h2_client::send(connection, request, body)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_failures_are_answered_with_their_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = leak(LocalFileKVStore::new(test_util::options(dir.path(), 1024, 0)).unwrap());
+        store.pin_key(String::from("pinned")).unwrap();
+        let addr =
+            test_util::serve_on_free_port(|addr| leak(H2Service::new(store, addr)).serve_h2())
+                .await;
+
+        let small = Some(Bytes::from_static(b"value"));
+        assert_eq!(
+            send(addr, Method::PUT, "/put/key", small.clone()).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(addr, Method::GET, "/get/key", None).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(addr, Method::GET, "/get/missing", None).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            send(addr, Method::HEAD, "/head/missing", None).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            send(addr, Method::GET, "/unknown/key", None).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            send(addr, Method::GET, "/get/", None).await,
+            StatusCode::BAD_REQUEST
+        );
+        for path in ["/get//etc/passwd", "/put/..%2F..%2Fx", "/delete/a/../../x"] {
+            assert_eq!(
+                send(addr, Method::PUT, path, small.clone()).await,
+                StatusCode::BAD_REQUEST,
+                "{}",
+                path
+            );
+        }
+        let large = Some(Bytes::from(vec![0; 2048]));
+        assert_eq!(
+            send(addr, Method::PUT, "/put/large", large).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            send(addr, Method::PUT, "/put/pinned", small).await,
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        // the worker keeps serving after every failure
+        assert_eq!(
+            send(addr, Method::GET, "/health", None).await,
+            StatusCode::OK
+        );
+
+        // listings are paged, a page starts after the last key of the previous one
+        assert_eq!(
+            send(
+                addr,
+                Method::PUT,
+                "/put/key%202",
+                Some(Bytes::from_static(b"v"))
+            )
+            .await,
+            StatusCode::OK
+        );
+        let list = |path: &'static str| async move {
+            let request = Request::builder()
+                .uri(format!("http://{}{}", addr, path))
+                .body(())
+                .unwrap();
+            let connection = h2_client::connect(addr).await.unwrap();
+            let response = h2_client::send(connection, request, None).await.unwrap();
+            let keys: Vec<(String, u64)> = serde_json::from_slice(response.body()).unwrap();
+            keys.into_iter().map(|(key, _)| key).collect::<Vec<_>>()
+        };
+        assert_eq!(list("/list/?limit=1").await, ["key"]);
+        assert_eq!(list("/list/?limit=1&after=key").await, ["key 2"]);
+        assert!(list("/list/?after=key%202").await.is_empty());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_deleted_keys_answer_their_tombstone_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = leak(LocalFileKVStore::new(test_util::options(dir.path(), 1024, 0)).unwrap());
+        store
+            .put(String::from("key"), Bytes::from_static(b"value"))
+            .await
+            .unwrap();
+        let addr =
+            test_util::serve_on_free_port(|addr| leak(H2Service::new(store, addr)).serve_h2())
+                .await;
+
+        let request = Request::builder()
+            .method(Method::DELETE)
+            .uri(format!("http://{}/delete/key", addr))
+            .header(VERSION_HEADER, "7")
+            .body(())
+            .unwrap();
+        let connection = h2_client::connect(addr).await.unwrap();
+        h2_client::send(connection, request, None).await.unwrap();
+
+        for (method, path) in [(Method::GET, "/get/key"), (Method::HEAD, "/head/key")] {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("http://{}{}", addr, path))
+                .body(())
+                .unwrap();
+            let connection = h2_client::connect(addr).await.unwrap();
+            let response = h2_client::send(connection, request, None).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert_eq!(response.headers()[TOMBSTONE_VERSION_HEADER], "7");
+        }
+        // a key never written has nothing to name
+        let request = Request::builder()
+            .uri(format!("http://{}/get/missing", addr))
+            .body(())
+            .unwrap();
+        let connection = h2_client::connect(addr).await.unwrap();
+        let response = h2_client::send(connection, request, None).await.unwrap();
+        assert!(!response.headers().contains_key(TOMBSTONE_VERSION_HEADER));
+    }
 }
@@ -1 +1,58 @@
+use monoio::net::TcpStream;
+use monoio_compat::StreamWrapper;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tokio::io::BufReader;
+
+pub mod h2_client;
 pub mod h2_service;
+
+/// Bytes escaped in keys put into a request path, `/` is kept as keys nest.
+pub const KEY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b'/');
+
+/// Size of the read buffer inside `StreamWrapper::new`.
+const COMPAT_READ_BUFFER: usize = 8 * 1024;
+
+/// Tokio IO over a monoio stream for h2.
+///
+/// `StreamWrapper` replays the start of its buffer when a read is smaller than what it
+/// has buffered, which corrupts h2 frames. Reading through a buffer at least as large
+/// as the wrapper's always drains it in one go.
+pub type CompatStream = BufReader<StreamWrapper<TcpStream>>;
+
+pub fn compat_stream(stream: TcpStream) -> CompatStream {
+    BufReader::with_capacity(COMPAT_READ_BUFFER, StreamWrapper::new(stream))
+}
+
+/// A key as it goes into a request path, e.g. `/get/{key}`.
+pub fn encode_key(key: &str) -> String {
+    utf8_percent_encode(key, KEY_ENCODE_SET).to_string()
+}
+
+/// The key of a request path, None if it does not decode to UTF-8.
+pub fn decode_key(path: &str) -> Option<String> {
+    percent_decode_str(path)
+        .decode_utf8()
+        .ok()
+        .map(|key| key.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_round_trip_through_paths() {
+        for key in ["dir/key", "a b?c#d", "100%", "clé"] {
+            let encoded = encode_key(key);
+            assert!(encoded.is_ascii() && !encoded.contains(['?', '#', ' ']));
+            assert_eq!(decode_key(&encoded).as_deref(), Some(key));
+        }
+        assert_eq!(encode_key("dir/key"), "dir/key");
+        assert_eq!(decode_key("%FF"), None);
+    }
+}
@@ -0,0 +1,80 @@
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use monoio::io::AsyncReadRent;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads running blocking file I/O for the event loop, which awaits the result
+/// instead of blocking on it. The threads exit once the pool is dropped.
+pub struct BlockingPool {
+    jobs: Sender<Job>,
+}
+
+impl BlockingPool {
+    pub fn new(name: &str, threads: usize) -> std::io::Result<BlockingPool> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..threads.max(1) {
+            let queue = Arc::clone(&queue);
+            thread::Builder::new()
+                .name(format!("{}-{}", name, i))
+                .spawn(move || loop {
+                    let job = queue.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })?;
+        }
+        Ok(BlockingPool { jobs })
+    }
+
+    /// Runs `f` on a pool thread, failing if it panicked. The runtime cannot be woken
+    /// from another thread, the job signals that it is done by closing a socket the
+    /// caller awaits.
+    pub async fn run<F, R>(&self, f: F) -> std::io::Result<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (done, signal) = UnixStream::pair()?;
+        done.set_nonblocking(true)?;
+        let mut done = monoio::net::UnixStream::from_std(done)?;
+        let result = Arc::new(Mutex::new(None));
+        let slot = Arc::clone(&result);
+        let job: Job = Box::new(move || {
+            *slot.lock().unwrap() = Some(panic::catch_unwind(AssertUnwindSafe(f)));
+            drop(signal);
+        });
+        self.jobs
+            .send(job)
+            .map_err(|_| std::io::Error::other("blocking pool is gone"))?;
+        let (read, _) = done.read(vec![0; 1]).await;
+        read?;
+        let result = result.lock().unwrap().take();
+        match result {
+            Some(Ok(value)) => Ok(value),
+            _ => Err(std::io::Error::other("blocking job panicked")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[monoio::test]
+    async fn test_jobs_run_off_the_calling_thread() {
+        let pool = BlockingPool::new("test-io", 2).unwrap();
+        let caller = thread::current().id();
+        let ran_on = pool.run(|| thread::current().id()).await.unwrap();
+        assert_ne!(ran_on, caller);
+        assert!(pool.run(|| panic!("job failed")).await.is_err());
+        // a panicking job leaves the threads serving
+        assert_eq!(pool.run(|| 1 + 1).await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::kv_store::stable_hash;
+
+/// Version and checksum of a stored value, what replicas compare during anti-entropy.
+/// A deleted or evicted key is digested as a tombstone versioned at removal time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyDigest {
+    pub version: u64,
+    pub checksum: u64,
+    #[serde(default)]
+    pub state: KeyState,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyState {
+    #[default]
+    Live,
+    Deleted,
+    Evicted,
+}
+
+impl KeyDigest {
+    pub fn new(version: u64, value: &[u8]) -> KeyDigest {
+        KeyDigest {
+            version,
+            checksum: stable_hash(value),
+            state: KeyState::Live,
+        }
+    }
+
+    pub fn tombstone(version: u64, state: KeyState) -> KeyDigest {
+        KeyDigest {
+            version,
+            checksum: 0,
+            state,
+        }
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.state == KeyState::Live
+    }
+}
+
+/// When the value of a key expires. Keys found on disk at startup are Unknown until
+/// their header is read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Expiry {
+    Unknown,
+    Never,
+    At(u64),
+}
+
+/// What the index knows of one key, handed out by `take` and `evict_entries` to be
+/// restored if what replaced it is undone.
+#[derive(Clone, Copy, Debug)]
+pub struct IndexEntry {
+    size: u64,
+    tick: u64,
+    pinned: bool,
+    /// Tick of the write that stored the value, tells a stale digest from a fresh one.
+    written: u64,
+    digest: Option<KeyDigest>,
+    expiry: Expiry,
+}
+
+/// In-memory view of the keys held by a local store, ordered by last access
+/// so that eviction can pick the least recently used unpinned key.
+#[derive(Debug, Default)]
+pub struct KeyIndex {
+    entries: HashMap<String, IndexEntry>,
+    lru: BTreeMap<u64, String>,
+    next_tick: u64,
+    used_bytes: u64,
+    pinned_bytes: u64,
+}
+
+impl KeyIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.used_bytes
+    }
+
+    pub fn pinned_bytes(&self) -> u64 {
+        self.pinned_bytes
+    }
+
+    pub fn unpinned_bytes(&self) -> u64 {
+        self.used_bytes - self.pinned_bytes
+    }
+
+    pub fn size(&self, key: &str) -> Option<u64> {
+        self.entries.get(key).map(|entry| entry.size)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    pub fn insert(&mut self, key: String, size: u64, pinned: bool) {
+        self.remove(&key);
+        let tick = self.next_tick();
+        self.lru.insert(tick, key.clone());
+        self.used_bytes += size;
+        if pinned {
+            self.pinned_bytes += size;
+        }
+        self.entries.insert(
+            key,
+            IndexEntry {
+                size,
+                tick,
+                pinned,
+                written: tick,
+                digest: None,
+                expiry: Expiry::Unknown,
+            },
+        );
+    }
+
+    /// Records the expiry of the value just written for the key.
+    pub fn set_expires_at(&mut self, key: &str, expires_at_ms: Option<u64>) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.expiry = expires_at_ms.map_or(Expiry::Never, Expiry::At);
+        }
+    }
+
+    /// Records the expiry read from the header of a key found at startup, unless the
+    /// key was written since.
+    pub fn resolve_expires_at(&mut self, key: &str, expires_at_ms: Option<u64>) {
+        if self.entries.get(key).map(|entry| entry.expiry) == Some(Expiry::Unknown) {
+            self.set_expires_at(key, expires_at_ms);
+        }
+    }
+
+    /// Whether the key is known to have expired at `now_ms`.
+    pub fn is_expired(&self, key: &str, now_ms: u64) -> bool {
+        self.entries
+            .get(key)
+            .is_some_and(|entry| matches!(entry.expiry, Expiry::At(at) if at <= now_ms))
+    }
+
+    /// Keys known to have expired at `now_ms`.
+    pub fn expired(&self, now_ms: u64) -> Vec<String> {
+        self.entries
+            .keys()
+            .filter(|key| self.is_expired(key, now_ms))
+            .cloned()
+            .collect()
+    }
+
+    /// Keys whose expiry hasn't been read yet.
+    pub fn unknown_expiry(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.expiry == Expiry::Unknown)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// The cached digest of the key, and the write it has to be computed for if missing.
+    pub fn digest(&self, key: &str) -> Option<(Option<KeyDigest>, u64)> {
+        self.entries
+            .get(key)
+            .map(|entry| (entry.digest, entry.written))
+    }
+
+    /// Caches a digest computed for the write `written`, unless the key was written since.
+    pub fn set_digest(&mut self, key: &str, written: u64, digest: KeyDigest) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            if entry.written == written {
+                entry.digest = Some(digest);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<u64> {
+        self.take(key).map(|entry| entry.size)
+    }
+
+    pub fn take(&mut self, key: &str) -> Option<IndexEntry> {
+        let entry = self.entries.remove(key)?;
+        self.lru.remove(&entry.tick);
+        self.used_bytes -= entry.size;
+        if entry.pinned {
+            self.pinned_bytes -= entry.size;
+        }
+        Some(entry)
+    }
+
+    /// Puts back an entry taken out of the index at its place in the LRU order, unless
+    /// the key was written since.
+    pub fn restore(&mut self, key: String, entry: IndexEntry) {
+        if self.entries.contains_key(&key) {
+            return;
+        }
+        self.lru.insert(entry.tick, key.clone());
+        self.used_bytes += entry.size;
+        if entry.pinned {
+            self.pinned_bytes += entry.size;
+        }
+        self.entries.insert(key, entry);
+    }
+
+    /// Marks the key as most recently used.
+    pub fn touch(&mut self, key: &str) {
+        let tick = self.next_tick();
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.tick);
+            entry.tick = tick;
+            self.lru.insert(tick, key.to_string());
+        }
+    }
+
+    /// Re-evaluates the pinned flag of every key and returns the resulting pinned bytes.
+    pub fn update_pinned<F: Fn(&str) -> bool>(&mut self, is_pinned: F) -> u64 {
+        let mut pinned_bytes = 0;
+        for (key, entry) in self.entries.iter_mut() {
+            entry.pinned = is_pinned(key);
+            if entry.pinned {
+                pinned_bytes += entry.size;
+            }
+        }
+        self.pinned_bytes = pinned_bytes;
+        pinned_bytes
+    }
+
+    /// Bytes that would be pinned if `is_pinned` replaced the current pin state.
+    pub fn pinned_bytes_with<F: Fn(&str) -> bool>(&self, is_pinned: F) -> u64 {
+        self.entries
+            .iter()
+            .filter(|(key, _)| is_pinned(key))
+            .map(|(_, entry)| entry.size)
+            .sum()
+    }
+
+    /// Removes least recently used unpinned keys until at least `bytes` are freed,
+    /// returning the evicted keys.
+    pub fn evict(&mut self, bytes: u64) -> Vec<String> {
+        self.evict_entries(bytes)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Like `evict`, with the entries of the evicted keys.
+    pub fn evict_entries(&mut self, bytes: u64) -> Vec<(String, IndexEntry)> {
+        let mut victims = Vec::new();
+        let mut freed = 0;
+        for key in self.lru.values() {
+            if freed >= bytes {
+                break;
+            }
+            let entry = &self.entries[key];
+            if !entry.pinned {
+                freed += entry.size;
+                victims.push(key.clone());
+            }
+        }
+        victims
+            .into_iter()
+            .filter_map(|key| self.take(&key).map(|entry| (key, entry)))
+            .collect()
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.next_tick += 1;
+        self.next_tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evict_skips_pinned_keys() {
+        let mut index = KeyIndex::new();
+        index.insert(String::from("a"), 10, true);
+        index.insert(String::from("b"), 20, false);
+        index.insert(String::from("c"), 30, false);
+        index.touch("b");
+        assert_eq!(index.used_bytes(), 60);
+        assert_eq!(index.pinned_bytes(), 10);
+        assert_eq!(index.unpinned_bytes(), 50);
+
+        assert_eq!(index.evict(25), vec![String::from("c")]);
+        assert_eq!(index.evict(100), vec![String::from("b")]);
+        assert!(index.evict(100).is_empty());
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.used_bytes(), 10);
+    }
+
+    #[test]
+    fn test_restore_keeps_lru_order() {
+        let mut index = KeyIndex::new();
+        index.insert(String::from("a"), 10, false);
+        index.insert(String::from("b"), 20, false);
+        let evicted = index.evict_entries(10);
+        assert_eq!(index.used_bytes(), 20);
+        for (key, entry) in evicted {
+            index.restore(key, entry);
+        }
+        assert_eq!(index.used_bytes(), 30);
+        assert_eq!(index.evict(10), vec![String::from("a")]);
+
+        // a key written since keeps its new entry
+        let b = index.take("b").unwrap();
+        index.insert(String::from("b"), 5, false);
+        index.restore(String::from("b"), b);
+        assert_eq!(index.size("b"), Some(5));
+        assert_eq!(index.used_bytes(), 5);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut index = KeyIndex::new();
+        index.insert(String::from("a"), 10, false);
+        index.insert(String::from("b"), 10, false);
+        index.insert(String::from("c"), 10, false);
+        assert_eq!(index.unknown_expiry().len(), 3);
+
+        index.set_expires_at("a", Some(100));
+        index.set_expires_at("b", None);
+        index.resolve_expires_at("a", None);
+        index.resolve_expires_at("c", Some(200));
+        assert!(index.unknown_expiry().is_empty());
+        assert!(!index.is_expired("a", 99) && index.is_expired("a", 100));
+        assert_eq!(index.expired(150), vec![String::from("a")]);
+        let mut expired = index.expired(200);
+        expired.sort();
+        assert_eq!(expired, vec![String::from("a"), String::from("c")]);
+
+        // a rewrite starts over
+        index.insert(String::from("a"), 10, false);
+        assert_eq!(index.unknown_expiry(), vec![String::from("a")]);
+    }
+
+    #[test]
+    fn test_update_pinned() {
+        let mut index = KeyIndex::new();
+        index.insert(String::from("models/a"), 10, false);
+        index.insert(String::from("models/b"), 20, false);
+        index.insert(String::from("other"), 30, false);
+        assert_eq!(
+            index.pinned_bytes_with(|key| key.starts_with("models/")),
+            30
+        );
+        assert_eq!(index.pinned_bytes(), 0);
+
+        assert_eq!(index.update_pinned(|key| key.starts_with("models/")), 30);
+        assert_eq!(index.unpinned_bytes(), 30);
+
+        index.insert(String::from("models/a"), 15, true);
+        assert_eq!(index.pinned_bytes(), 35);
+    }
+}
@@ -1,53 +1,207 @@
+use std::collections::BTreeSet;
 use std::error::Error;
 use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 use bytes::Bytes;
-use log::trace;
+use log::{debug, info, trace, warn};
 
-use crate::kv_store::Key;
-use crate::settings::local_kv_options::LocalFileKVStoreOptions;
+use crate::kv_store::local_kv_store::blocking_pool::BlockingPool;
+use crate::kv_store::local_kv_store::index::{IndexEntry, KeyDigest, KeyIndex, KeyState};
+use crate::kv_store::local_kv_store::manifest::{LayoutManifest, StoreLock};
+use crate::kv_store::local_kv_store::pin_set::PinSet;
+use crate::kv_store::local_kv_store::segment_store::SegmentStore;
+use crate::kv_store::local_kv_store::tombstones::{Tombstones, TOMBSTONE_TTL};
+use crate::kv_store::object_meta::{now_ms, now_us, ObjectMeta, META_PREFIX_LEN};
+use crate::kv_store::store_error::StoreError;
+use crate::kv_store::{validate_key, Key};
+use crate::settings::local_kv_options::{LocalFileKVStoreOptions, StoreLayout};
 
+const PIN_SET_FILE: &str = ".pins.json";
+/// Threads doing the segment file I/O of requests, off the event loop.
+const SEGMENT_IO_THREADS: usize = 4;
+
+/// Local store of one storage directory. Values are kept either as one file per key
+/// under hashed bucket directories, or appended to segment files for small objects,
+/// depending on the configured layout.
 pub struct LocalFileKVStore {
     options: LocalFileKVStoreOptions,
+    manifest: LayoutManifest,
+    segments: Option<Arc<SegmentStore>>,
+    /// Runs the blocking segment reads and appends of requests, segment layout only.
+    segment_io: Option<BlockingPool>,
+    // lock order: pins before index
+    pins: RwLock<PinSet>,
+    index: Mutex<KeyIndex>,
+    tombstones: Mutex<Tombstones>,
+    /// Keeps migrations away while the store is open.
+    _lock: StoreLock,
 }
 
 impl LocalFileKVStore {
-    pub fn new(options: LocalFileKVStoreOptions) -> LocalFileKVStore {
-        LocalFileKVStore { options }
+    pub fn new(
+        options: LocalFileKVStoreOptions,
+    ) -> Result<LocalFileKVStore, Box<dyn Error + Send + Sync>> {
+        let pins = PinSet::load(&Self::pin_set_path(&options)).unwrap_or_else(|e| {
+            warn!("Failed to load pin set from {}: {}", options.root_path, e);
+            PinSet::default()
+        });
+        let lock = StoreLock::shared(Path::new(&options.root_path))?;
+        let manifest = LayoutManifest::open(&options)?;
+        let segments = match manifest.layout {
+            StoreLayout::FilePerKey => None,
+            StoreLayout::Segment => Some(Arc::new(SegmentStore::open(
+                Path::new(&options.root_path),
+                options.segment_size,
+            )?)),
+        };
+        let segment_io = match segments {
+            Some(_) => Some(BlockingPool::new("segment-io", SEGMENT_IO_THREADS)?),
+            None => None,
+        };
+        let mut index = KeyIndex::new();
+        match &segments {
+            Some(segments) => {
+                for (key, len) in segments.entries() {
+                    let pinned = pins.is_pinned(&key);
+                    index.insert(key, len, pinned);
+                }
+            }
+            None => {
+                if let Err(e) = Self::scan(&options, &pins, &mut index) {
+                    warn!("Failed to scan local store {}: {}", options.root_path, e);
+                }
+            }
+        }
+        info!(
+            "Local store {} opened with {} keys, {} bytes used, {} bytes pinned",
+            options.root_path,
+            index.len(),
+            index.used_bytes(),
+            index.pinned_bytes()
+        );
+        Ok(LocalFileKVStore {
+            options,
+            manifest,
+            segments,
+            segment_io,
+            pins: RwLock::new(pins),
+            index: Mutex::new(index),
+            tombstones: Mutex::new(Tombstones::new(TOMBSTONE_TTL)),
+            _lock: lock,
+        })
     }
 
-    pub async fn put<K: Key>(&self, id: K, buf: Bytes) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let path = self.data_path(id);
+    pub async fn put<K: Key>(&self, id: K, buf: Bytes) -> Result<(), StoreError> {
+        self.put_with_meta(id, buf, &ObjectMeta::default()).await
+    }
+
+    /// Writes the metadata header block followed by the value.
+    pub async fn put_with_meta<K: Key>(
+        &self,
+        id: K,
+        buf: Bytes,
+        meta: &ObjectMeta,
+    ) -> Result<(), StoreError> {
+        let key = id.filename();
+        validate_key(&key)?;
+        let header = meta.encode_header()?;
+        let len = (header.len() + buf.len()) as u64;
+        let reservation = self.reserve(&key, len, meta.expires_at_ms)?;
+        match self.write(&key, header, buf).await {
+            Ok(()) => {
+                self.commit(reservation, meta.expires_at_ms);
+                Ok(())
+            }
+            Err(e) => {
+                self.rollback(reservation);
+                Err(e)
+            }
+        }
+    }
+
+    async fn write(&self, key: &str, header: Vec<u8>, buf: Bytes) -> Result<(), StoreError> {
+        if self.segments.is_some() {
+            let key = key.to_string();
+            self.on_segments(move |segments| segments.put(&key, &[&header, &buf]))
+                .await??;
+            return Ok(());
+        }
+
+        let header_len = header.len();
+        let path = self.data_path(key.to_string())?;
         trace!("Start writing data to {}", path.clone());
         let file = match monoio::fs::File::create(&path).await {
             Ok(file) => file,
-            Err(error) => match error.kind() {
-                ErrorKind::NotFound => {
-                    let path = std::path::Path::new(path.as_str());
-                    let prefix = path.parent().unwrap();
-                    match std::fs::create_dir_all(prefix) {
-                        Ok(_) => match monoio::fs::File::create(&path).await {
-                            Ok(f) => f,
-                            Err(e) => return Err(e.to_string().into()),
-                        },
-                        Err(e) => return Err(e.to_string().into()),
-                    }
-                }
-                _other_error => {
-                    return Err(error.to_string().into());
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                if let Some(prefix) = Path::new(path.as_str()).parent() {
+                    std::fs::create_dir_all(prefix)?;
                 }
-            },
+                monoio::fs::File::create(&path).await?
+            }
+            Err(error) => return Err(error.into()),
         };
 
-        let (res, _) = file.write_all_at(buf, 0).await;
+        let (res, _) = file.write_all_at(header, 0).await;
+        res?;
+        let (res, _) = file.write_all_at(buf, header_len as u64).await;
         res?;
         file.close().await?;
         trace!("Write data to file {}", path);
         Ok(())
     }
 
-    pub async fn get<K: Key>(&self, id: K) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
-        let path = self.data_path(id);
+    pub async fn get<K: Key>(&self, id: K) -> Result<Vec<u8>, StoreError> {
+        let (_, buf) = self.get_with_meta(id).await?;
+        Ok(buf)
+    }
+
+    pub async fn get_with_meta<K: Key>(&self, id: K) -> Result<(ObjectMeta, Vec<u8>), StoreError> {
+        self.index.lock().unwrap().touch(&id.filename());
+        self.read(id).await
+    }
+
+    /// Like `get_with_meta`, the value is a slice of the buffer the stored bytes were
+    /// read into rather than a copy of it. The whole value is still read into memory.
+    pub async fn get_bytes<K: Key>(&self, id: K) -> Result<(ObjectMeta, Bytes), StoreError> {
+        self.index.lock().unwrap().touch(&id.filename());
+        let (meta, buf, offset) = self.read_raw(id).await?;
+        Ok((meta, Bytes::from(buf).slice(offset..)))
+    }
+
+    /// Digest of the stored value, computed on first use and cached until the key is
+    /// written again. Reading for a digest does not count as an access for eviction.
+    pub async fn digest(&self, key: &str) -> Result<KeyDigest, StoreError> {
+        let cached = self.index.lock().unwrap().digest(key);
+        let written = match cached {
+            Some((Some(digest), _)) => return Ok(digest),
+            Some((None, written)) => written,
+            None => return Err(not_found(key)),
+        };
+        let (meta, value) = self.read(key.to_string()).await?;
+        let digest = KeyDigest::new(meta.version.unwrap_or(0), &value);
+        self.index.lock().unwrap().set_digest(key, written, digest);
+        Ok(digest)
+    }
+
+    async fn read<K: Key>(&self, id: K) -> Result<(ObjectMeta, Vec<u8>), StoreError> {
+        let (meta, mut buf, offset) = self.read_raw(id).await?;
+        buf.drain(..offset);
+        Ok((meta, buf))
+    }
+
+    /// The metadata, the stored bytes and the offset of the value in them.
+    async fn read_raw<K: Key>(&self, id: K) -> Result<(ObjectMeta, Vec<u8>, usize), StoreError> {
+        let key = id.filename();
+        if self.segments.is_some() {
+            let buf = self.segment_get(&key).await?;
+            let (meta, offset) = ObjectMeta::decode_header(&buf)?;
+            return Ok((self.unexpired(&key, meta)?, buf, offset));
+        }
+        let path = self.data_path(id)?;
         let f = monoio::fs::File::open(&path).await?;
         let metadata = std::fs::metadata(&path)?;
         let file_size = metadata.len();
@@ -55,17 +209,837 @@ impl LocalFileKVStore {
         let (res, buf) = f.read_exact_at(buf, 0).await;
         res?;
         f.close().await?;
+        let (meta, offset) = ObjectMeta::decode_header(&buf)?;
         trace!("Read data from file {}", path);
-        Ok(buf)
+        Ok((self.unexpired(&key, meta)?, buf, offset))
+    }
+
+    /// Runs `f` on the segment store from a blocking pool thread.
+    async fn on_segments<F, R>(&self, f: F) -> Result<R, StoreError>
+    where
+        F: FnOnce(&SegmentStore) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (Some(segments), Some(segment_io)) = (&self.segments, &self.segment_io) else {
+            unreachable!("segment I/O on a file per key store");
+        };
+        let segments = Arc::clone(segments);
+        Ok(segment_io.run(move || f(&segments)).await?)
+    }
+
+    async fn segment_get(&self, key: &str) -> Result<Vec<u8>, StoreError> {
+        let owned = key.to_string();
+        self.on_segments(move |segments| segments.get(&owned))
+            .await??
+            .ok_or_else(|| not_found(key))
+    }
+
+    /// Expired values are deleted once read and reported as missing.
+    fn unexpired(&self, key: &str, meta: ObjectMeta) -> Result<ObjectMeta, StoreError> {
+        if meta.is_expired() {
+            debug!("{} expired", key);
+            self.remove(key.to_string())?;
+            return Err(not_found(key));
+        }
+        Ok(meta)
+    }
+
+    /// Reads only the metadata header block, returning it with the value length.
+    pub async fn head<K: Key>(&self, id: K) -> Result<(ObjectMeta, u64), StoreError> {
+        let key = id.filename();
+        if self.segments.is_some() {
+            // segment values are small, reading them whole is cheaper than two reads
+            let buf = self.segment_get(&key).await?;
+            let (meta, offset) = ObjectMeta::decode_header(&buf)?;
+            return Ok((self.unexpired(&key, meta)?, (buf.len() - offset) as u64));
+        }
+        let path = self.data_path(id)?;
+        let f = monoio::fs::File::open(&path).await?;
+        let file_size = std::fs::metadata(&path)?.len();
+        let prefix_len = META_PREFIX_LEN.min(file_size as usize);
+        let (res, prefix) = f.read_exact_at(vec![0; prefix_len], 0).await;
+        res?;
+        let header = match ObjectMeta::header_len(&prefix) {
+            Some(header_len) if header_len as u64 <= file_size => {
+                let (res, header) = f.read_exact_at(vec![0; header_len], 0).await;
+                res?;
+                header
+            }
+            _ => prefix,
+        };
+        f.close().await?;
+        let (meta, offset) = ObjectMeta::decode_header(&header)?;
+        Ok((self.unexpired(&key, meta)?, file_size - offset as u64))
     }
 
-    fn data_path<K: Key>(&self, id: K) -> String {
-        let path = format!(
-            "{}/{}/{}",
-            self.options.root_path,
-            id.short_hash() % self.options.num_bucket,
-            id.filename()
+    /// Replaces the metadata of a stored value. With one file per key the header block
+    /// is rewritten in place when the new metadata fits in it, otherwise the value is
+    /// written again.
+    pub async fn update_meta<K: Key>(&self, id: K, meta: &ObjectMeta) -> Result<(), StoreError> {
+        let key = id.filename();
+        if self.segments.is_none() && self.rewrite_header(&key, meta).await? {
+            let mut index = self.index.lock().unwrap();
+            index.touch(&key);
+            index.set_expires_at(&key, meta.expires_at_ms);
+            return Ok(());
+        }
+        let (_, value) = self.get_bytes(key.clone()).await?;
+        self.put_with_meta(key, value, meta).await
+    }
+
+    /// Writes the header of `meta` over the current one, false if it does not fit.
+    async fn rewrite_header(&self, key: &str, meta: &ObjectMeta) -> Result<bool, StoreError> {
+        let path = self.data_path(key.to_string())?;
+        let file = monoio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .await?;
+        let (res, prefix) = file.read_exact_at(vec![0; META_PREFIX_LEN], 0).await;
+        let header = match res {
+            Ok(_) => match ObjectMeta::header_len(&prefix) {
+                Some(header_len) => meta.encode_header_padded(header_len)?,
+                None => None,
+            },
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => None,
+            Err(e) => return Err(e.into()),
+        };
+        let rewritten = match header {
+            Some(header) => {
+                let (res, _) = file.write_all_at(header, 0).await;
+                res?;
+                true
+            }
+            None => false,
+        };
+        file.close().await?;
+        Ok(rewritten)
+    }
+
+    /// Deletes the key, leaving a tombstone versioned now.
+    pub fn delete<K: Key>(&self, id: K) -> Result<(), StoreError> {
+        self.delete_at(id, now_us())
+    }
+
+    /// Deletes the key, leaving a tombstone at `version` for anti-entropy.
+    pub fn delete_at<K: Key>(&self, id: K, version: u64) -> Result<(), StoreError> {
+        self.tombstones.lock().unwrap().insert(
+            id.filename(),
+            KeyDigest::tombstone(version, KeyState::Deleted),
         );
-        path
+        self.remove(id)
+    }
+
+    /// Drops the local copy of the key without a tombstone, for values that live on
+    /// elsewhere or expired everywhere.
+    pub fn remove<K: Key>(&self, id: K) -> Result<(), StoreError> {
+        let key = id.filename();
+        self.index.lock().unwrap().remove(&key);
+        if let Some(segments) = &self.segments {
+            segments.delete(&key)?;
+            return Ok(());
+        }
+        match std::fs::remove_file(self.data_path(id)?) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Version of the delete that removed the key, if that was within the tombstone TTL.
+    /// Evictions do not count, the value lives on elsewhere.
+    pub fn deleted_version(&self, key: &str) -> Option<u64> {
+        self.tombstones
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|digest| digest.state == KeyState::Deleted)
+            .map(|digest| digest.version)
+    }
+
+    /// Keys deleted or evicted within the tombstone TTL.
+    pub fn tombstones(&self) -> Vec<(String, KeyDigest)> {
+        self.tombstones.lock().unwrap().entries()
+    }
+
+    /// Keys starting with `prefix` with their stored size, sorted by key. Values known
+    /// to have expired are left out.
+    pub fn list(&self, prefix: &str) -> Vec<(String, u64)> {
+        let index = self.index.lock().unwrap();
+        let now = now_ms();
+        let mut keys: Vec<(String, u64)> = index
+            .keys()
+            .filter(|key| key.starts_with(prefix) && !index.is_expired(key, now))
+            .map(|key| (key.clone(), index.size(key).unwrap_or(0)))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// At most `limit` keys starting with `prefix` that sort after `after`, sorted by
+    /// key, with their stored size. A page shorter than `limit` is the last one.
+    pub fn list_page(&self, prefix: &str, after: Option<&str>, limit: usize) -> Vec<(String, u64)> {
+        let index = self.index.lock().unwrap();
+        let now = now_ms();
+        // the `limit` smallest matching keys, without collecting every key first
+        let mut page = BTreeSet::new();
+        for key in index.keys() {
+            if !key.starts_with(prefix) || after.is_some_and(|after| key.as_str() <= after) {
+                continue;
+            }
+            if index.is_expired(key, now) {
+                continue;
+            }
+            if page.len() == limit && page.last().is_some_and(|last| key >= *last) {
+                continue;
+            }
+            page.insert(key);
+            if page.len() > limit {
+                page.pop_last();
+            }
+        }
+        page.into_iter()
+            .map(|key| (key.clone(), index.size(key).unwrap_or(0)))
+            .collect()
+    }
+
+    pub fn exists<K: Key>(&self, id: K) -> bool {
+        match &self.segments {
+            Some(segments) => segments.contains(&id.filename()),
+            None => self
+                .data_path(id)
+                .is_ok_and(|path| Path::new(&path).is_file()),
+        }
+    }
+
+    pub fn compaction_interval(&self) -> Duration {
+        Duration::from_secs(self.options.compaction_interval_secs)
+    }
+
+    /// Reclaims space held by overwritten and deleted values, returning the number of
+    /// segments compacted. Nothing to do for the file per key layout. Blocks on file
+    /// I/O, so it runs on a thread of its own rather than on the event loop.
+    pub fn compact(&self) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        match &self.segments {
+            Some(segments) => segments.compact(),
+            None => Ok(0),
+        }
+    }
+
+    /// Removes expired values, returning how many. Keys found on disk at startup have
+    /// their header read once to learn their expiry. Blocks on file I/O like `compact`.
+    pub fn sweep_expired(&self) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let unknown = self.index.lock().unwrap().unknown_expiry();
+        for key in unknown {
+            match self.read_meta_blocking(&key) {
+                Ok(meta) => self
+                    .index
+                    .lock()
+                    .unwrap()
+                    .resolve_expires_at(&key, meta.expires_at_ms),
+                Err(e) if e.is_not_found() => {}
+                Err(e) => warn!("Failed to read the header of {}: {}", key, e),
+            }
+        }
+        let expired = self.index.lock().unwrap().expired(now_ms());
+        let mut removed = 0;
+        for key in expired {
+            // the key may have been rewritten since
+            if self.index.lock().unwrap().is_expired(&key, now_ms()) {
+                debug!("{} expired", key);
+                self.remove(key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn read_meta_blocking(&self, key: &str) -> Result<ObjectMeta, StoreError> {
+        let header = match &self.segments {
+            Some(segments) => segments.get(key)?.ok_or_else(|| not_found(key))?,
+            None => {
+                let file = std::fs::File::open(self.data_path(key.to_string())?)?;
+                let file_size = file.metadata()?.len();
+                let mut prefix = vec![0; META_PREFIX_LEN.min(file_size as usize)];
+                file.read_exact_at(&mut prefix, 0)?;
+                match ObjectMeta::header_len(&prefix) {
+                    Some(header_len) if header_len as u64 <= file_size => {
+                        let mut header = vec![0; header_len];
+                        file.read_exact_at(&mut header, 0)?;
+                        header
+                    }
+                    _ => prefix,
+                }
+            }
+        };
+        Ok(ObjectMeta::decode_header(&header)?.0)
+    }
+
+    /// Persists buffered writes. The file per key layout writes through on every put.
+    pub fn flush(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match &self.segments {
+            Some(segments) => segments.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.index.lock().unwrap().used_bytes()
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.index.lock().unwrap().len()
+    }
+
+    pub fn pinned_bytes(&self) -> u64 {
+        self.index.lock().unwrap().pinned_bytes()
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.options.capacity
+    }
+
+    pub fn pin_budget(&self) -> u64 {
+        self.options.pin_budget
+    }
+
+    pub fn pins(&self) -> PinSet {
+        self.pins.read().unwrap().clone()
+    }
+
+    pub fn pin_key(&self, key: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.update_pins(|pins| {
+            pins.keys.insert(key);
+        })
+    }
+
+    pub fn pin_prefix(&self, prefix: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.update_pins(|pins| {
+            pins.prefixes.insert(prefix);
+        })
+    }
+
+    pub fn unpin_key(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.update_pins(|pins| {
+            pins.keys.remove(key);
+        })
+    }
+
+    pub fn unpin_prefix(&self, prefix: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.update_pins(|pins| {
+            pins.prefixes.remove(prefix);
+        })
+    }
+
+    /// Applies `change` to the pin set if the resulting pinned bytes fit the pin budget,
+    /// then persists it.
+    fn update_pins<F: FnOnce(&mut PinSet)>(
+        &self,
+        change: F,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut pins = self.pins.write().unwrap();
+        let mut updated = pins.clone();
+        change(&mut updated);
+        if updated == *pins {
+            return Ok(());
+        }
+        let mut index = self.index.lock().unwrap();
+        let pinned_bytes = index.pinned_bytes_with(|key| updated.is_pinned(key));
+        if pinned_bytes > index.pinned_bytes() && pinned_bytes > self.options.pin_budget {
+            return Err(format!(
+                "pin budget exceeded: {} bytes would be pinned, budget is {} bytes",
+                pinned_bytes, self.options.pin_budget
+            )
+            .into());
+        }
+        updated.persist(&Self::pin_set_path(&self.options))?;
+        index.update_pinned(|key| updated.is_pinned(key));
+        *pins = updated;
+        debug!("Pin set updated, {} bytes pinned", pinned_bytes);
+        Ok(())
+    }
+
+    /// Takes room for `size` bytes under `key` in the index before the value is written,
+    /// so that concurrent writes see the room taken by each other. Pinned keys are
+    /// charged to the pin budget and never evicted; the rest of the keys share the store
+    /// capacity, least recently used ones are evicted beyond it.
+    fn reserve(
+        &self,
+        key: &str,
+        size: u64,
+        expires_at_ms: Option<u64>,
+    ) -> Result<Reservation, StoreError> {
+        let pins = self.pins.read().unwrap();
+        let mut index = self.index.lock().unwrap();
+        let pinned = pins.is_pinned(key);
+        let previous = index.take(key);
+        let refused = if pinned {
+            let pinned_bytes = index.pinned_bytes() + size;
+            (pinned_bytes > self.options.pin_budget).then(|| {
+                StoreError::Full(format!(
+                    "pin budget exceeded: writing {} would pin {} bytes, budget is {} bytes",
+                    key, pinned_bytes, self.options.pin_budget
+                ))
+            })
+        } else if self.options.capacity != 0 && size > self.options.capacity {
+            Some(StoreError::TooLarge {
+                key: key.to_string(),
+                size,
+                limit: self.options.capacity,
+            })
+        } else {
+            None
+        };
+        if let Some(error) = refused {
+            if let Some(previous) = previous {
+                index.restore(key.to_string(), previous);
+            }
+            return Err(error);
+        }
+        let required = index.unpinned_bytes() + size;
+        let evicted = if pinned || self.options.capacity == 0 || required <= self.options.capacity {
+            Vec::new()
+        } else {
+            let evicted = index.evict_entries(required - self.options.capacity);
+            debug!("Evicting {} keys to store {}", evicted.len(), key);
+            evicted
+        };
+        index.insert(key.to_string(), size, pinned);
+        index.set_expires_at(key, expires_at_ms);
+        Ok(Reservation {
+            key: key.to_string(),
+            size,
+            previous,
+            evicted,
+        })
+    }
+
+    /// Settles the reservation of a value written: the evicted keys are removed.
+    fn commit(&self, reservation: Reservation, expires_at_ms: Option<u64>) {
+        let Reservation {
+            key, size, evicted, ..
+        } = reservation;
+        let evicted: Vec<String> = {
+            let pinned = self.pins.read().unwrap().is_pinned(&key);
+            let mut index = self.index.lock().unwrap();
+            // written anew, a digest of what was read while the write ran is dropped
+            if index.size(&key).is_some() {
+                index.insert(key.clone(), size, pinned);
+                index.set_expires_at(&key, expires_at_ms);
+            }
+            // unless written again meanwhile
+            evicted
+                .into_iter()
+                .map(|(evicted, _)| evicted)
+                .filter(|evicted| index.size(evicted).is_none())
+                .collect()
+        };
+        {
+            let mut tombstones = self.tombstones.lock().unwrap();
+            tombstones.remove(&key);
+            let version = now_us();
+            for evicted in evicted.iter() {
+                tombstones.insert(
+                    evicted.clone(),
+                    KeyDigest::tombstone(version, KeyState::Evicted),
+                );
+            }
+        }
+        self.remove_files(evicted);
+    }
+
+    /// Gives the room of a failed write back along with the keys evicted for it. The
+    /// segment log still holds the value the write was replacing, a file written over
+    /// is gone with the write and is removed.
+    fn rollback(&self, reservation: Reservation) {
+        let Reservation {
+            key,
+            previous,
+            evicted,
+            ..
+        } = reservation;
+        {
+            let mut index = self.index.lock().unwrap();
+            index.remove(&key);
+            if let (Some(previous), Some(_)) = (previous, &self.segments) {
+                index.restore(key.clone(), previous);
+            }
+            for (evicted, entry) in evicted {
+                index.restore(evicted, entry);
+            }
+        }
+        if self.segments.is_none() {
+            let removed = self
+                .data_path(key.clone())
+                .and_then(|path| Ok(std::fs::remove_file(path)?));
+            match removed {
+                Err(e) if !e.is_not_found() => {
+                    warn!("Failed to remove {} after a failed write: {}", key, e)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn remove_files(&self, keys: Vec<String>) {
+        if let Some(segments) = &self.segments {
+            for key in keys {
+                if let Err(e) = segments.delete(&key) {
+                    warn!("Failed to remove evicted key {}: {}", key, e);
+                }
+            }
+            return;
+        }
+        for key in keys {
+            let removed = self
+                .data_path(key.clone())
+                .and_then(|path| Ok(std::fs::remove_file(path)?));
+            if let Err(e) = removed {
+                warn!("Failed to remove evicted key {}: {}", key, e);
+            }
+        }
+    }
+
+    /// Rebuilds the index from the bucket directories under the root path.
+    fn scan(
+        options: &LocalFileKVStoreOptions,
+        pins: &PinSet,
+        index: &mut KeyIndex,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let root = Path::new(&options.root_path);
+        if !root.is_dir() {
+            return Ok(());
+        }
+        for bucket in std::fs::read_dir(root)? {
+            let bucket = bucket?;
+            if bucket.file_type()?.is_dir()
+                && !bucket.file_name().to_string_lossy().starts_with('.')
+            {
+                Self::scan_dir(&bucket.path(), "", pins, index)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_dir(
+        dir: &Path,
+        relative: &str,
+        pins: &PinSet,
+        index: &mut KeyIndex,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let key = if relative.is_empty() {
+                name
+            } else {
+                format!("{}/{}", relative, name)
+            };
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                Self::scan_dir(&entry.path(), &key, pins, index)?;
+            } else if file_type.is_file() {
+                let pinned = pins.is_pinned(&key);
+                index.insert(key, entry.metadata()?.len(), pinned);
+            }
+        }
+        Ok(())
+    }
+
+    fn pin_set_path(options: &LocalFileKVStoreOptions) -> PathBuf {
+        Path::new(&options.root_path).join(PIN_SET_FILE)
+    }
+
+    fn data_path<K: Key>(&self, id: K) -> Result<String, StoreError> {
+        Ok(self
+            .manifest
+            .data_path(Path::new(&self.options.root_path), &id.filename())?
+            .to_string_lossy()
+            .to_string())
+    }
+}
+
+/// Room taken in the index for a value being written, with the entry it replaces and
+/// the keys evicted for it, given back if the write fails.
+struct Reservation {
+    key: String,
+    size: u64,
+    previous: Option<IndexEntry>,
+    evicted: Vec<(String, IndexEntry)>,
+}
+
+fn not_found(key: &str) -> StoreError {
+    StoreError::NotFound(key.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::options;
+
+    // an empty metadata record encodes as `{}` after the 8 byte prefix
+    const ENTRY_LEN: u64 = 10 + 10;
+
+    #[monoio::test]
+    async fn test_pinned_keys_survive_eviction_and_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalFileKVStore::new(options(dir.path(), 2 * ENTRY_LEN, ENTRY_LEN)).unwrap();
+        store.pin_prefix(String::from("models/")).unwrap();
+
+        for key in ["models/a", "b", "c", "d"] {
+            store
+                .put(String::from(key), Bytes::from(vec![1; 10]))
+                .await
+                .unwrap();
+        }
+
+        assert!(store.exists(String::from("models/a")));
+        assert!(!store.exists(String::from("b")));
+        assert!(store.exists(String::from("d")));
+        assert_eq!(store.pinned_bytes(), ENTRY_LEN);
+        assert!(store
+            .put(String::from("models/b"), Bytes::from(vec![5; 1]))
+            .await
+            .is_err());
+        assert!(store.pin_key(String::from("c")).is_err());
+
+        let reopened =
+            LocalFileKVStore::new(options(dir.path(), 2 * ENTRY_LEN, ENTRY_LEN)).unwrap();
+        assert!(reopened.pins().prefixes.contains("models/"));
+        assert_eq!(reopened.pinned_bytes(), ENTRY_LEN);
+        assert_eq!(reopened.used_bytes(), 3 * ENTRY_LEN);
+    }
+
+    #[monoio::test]
+    async fn test_concurrent_puts_share_the_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalFileKVStore::new(options(dir.path(), 2 * ENTRY_LEN, 0)).unwrap();
+        for key in ["a", "b"] {
+            store
+                .put(String::from(key), Bytes::from(vec![1; 10]))
+                .await
+                .unwrap();
+        }
+        let (c, d) = monoio::join!(
+            store.put(String::from("c"), Bytes::from(vec![2; 10])),
+            store.put(String::from("d"), Bytes::from(vec![3; 10]))
+        );
+        c.unwrap();
+        d.unwrap();
+        assert_eq!(store.used_bytes(), 2 * ENTRY_LEN);
+        assert!(!store.exists(String::from("a")) && !store.exists(String::from("b")));
+
+        // a failed write gives its room and the keys evicted for it back
+        std::fs::create_dir_all(store.data_path(String::from("e")).unwrap()).unwrap();
+        assert!(store
+            .put(String::from("e"), Bytes::from(vec![4; 10]))
+            .await
+            .is_err());
+        assert_eq!(store.used_bytes(), 2 * ENTRY_LEN);
+        assert_eq!(store.get(String::from("c")).await.unwrap(), vec![2; 10]);
+        assert_eq!(store.get(String::from("d")).await.unwrap(), vec![3; 10]);
+    }
+
+    #[monoio::test]
+    async fn test_meta_stored_alongside_value() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalFileKVStore::new(options(dir.path(), 0, 0)).unwrap();
+        let meta = ObjectMeta {
+            content_type: Some(String::from("text/plain")),
+            etag: Some(String::from("v1")),
+            ..Default::default()
+        };
+        store
+            .put_with_meta(String::from("k"), Bytes::from_static(b"hello"), &meta)
+            .await
+            .unwrap();
+
+        assert_eq!(store.get(String::from("k")).await.unwrap(), b"hello");
+        assert_eq!(store.head(String::from("k")).await.unwrap(), (meta, 5));
+
+        // values written before metadata existed are served as they are
+        let legacy_path = store.data_path(String::from("legacy")).unwrap();
+        std::fs::create_dir_all(Path::new(&legacy_path).parent().unwrap()).unwrap();
+        std::fs::write(&legacy_path, b"raw").unwrap();
+        assert_eq!(store.get(String::from("legacy")).await.unwrap(), b"raw");
+        assert_eq!(
+            store.head(String::from("legacy")).await.unwrap(),
+            (ObjectMeta::default(), 3)
+        );
+    }
+
+    #[monoio::test]
+    async fn test_segment_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = options(dir.path(), 0, 0);
+        options.layout = StoreLayout::Segment;
+        let store = LocalFileKVStore::new(options.clone()).unwrap();
+        let meta = ObjectMeta {
+            content_type: Some(String::from("text/plain")),
+            ..Default::default()
+        };
+        store
+            .put_with_meta(String::from("k"), Bytes::from_static(b"hello"), &meta)
+            .await
+            .unwrap();
+        store
+            .put(String::from("gone"), Bytes::from_static(b"bye"))
+            .await
+            .unwrap();
+        store.delete(String::from("gone")).unwrap();
+
+        let reopened = LocalFileKVStore::new(options).unwrap();
+        assert!(!reopened.exists(String::from("gone")));
+        assert_eq!(
+            reopened.get_with_meta(String::from("k")).await.unwrap(),
+            (meta.clone(), b"hello".to_vec())
+        );
+        assert_eq!(reopened.head(String::from("k")).await.unwrap(), (meta, 5));
+        assert!(reopened.get(String::from("gone")).await.is_err());
+    }
+
+    #[monoio::test]
+    async fn test_list_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalFileKVStore::new(options(dir.path(), 0, 0)).unwrap();
+        for key in ["a/3", "a/1", "b/1", "a/2", "a/4"] {
+            store
+                .put(String::from(key), Bytes::from(vec![1; 10]))
+                .await
+                .unwrap();
+        }
+
+        let page = |after, limit| -> Vec<String> {
+            store
+                .list_page("a/", after, limit)
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect()
+        };
+        assert_eq!(page(None, 3), ["a/1", "a/2", "a/3"]);
+        assert_eq!(page(Some("a/3"), 3), ["a/4"]);
+        assert_eq!(page(Some("a/4"), 3), Vec::<String>::new());
+        assert_eq!(store.list_page("", None, 10), store.list(""));
+    }
+
+    #[monoio::test]
+    async fn test_expired_values_are_missing() {
+        for layout in [StoreLayout::FilePerKey, StoreLayout::Segment] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut options = options(dir.path(), 0, 0);
+            options.layout = layout;
+            let store = LocalFileKVStore::new(options).unwrap();
+            let now = crate::kv_store::object_meta::now_ms();
+            for (key, expires_at_ms) in [("expired", now - 1), ("live", now + 60_000)] {
+                let meta = ObjectMeta {
+                    expires_at_ms: Some(expires_at_ms),
+                    ..Default::default()
+                };
+                store
+                    .put_with_meta(String::from(key), Bytes::from_static(b"value"), &meta)
+                    .await
+                    .unwrap();
+            }
+
+            let missing = store.get(String::from("expired")).await.unwrap_err();
+            assert!(missing.is_not_found());
+            assert!(store.head(String::from("expired")).await.is_err());
+            assert!(!store.exists(String::from("expired")));
+            assert_eq!(store.get(String::from("live")).await.unwrap(), b"value");
+        }
+    }
+
+    #[monoio::test]
+    async fn test_update_meta() {
+        for layout in [StoreLayout::FilePerKey, StoreLayout::Segment] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut options = options(dir.path(), 0, 0);
+            options.layout = layout;
+            let store = LocalFileKVStore::new(options).unwrap();
+            let meta = ObjectMeta {
+                version: Some(3),
+                expires_at_ms: Some(crate::kv_store::object_meta::now_ms() + 60_000),
+                ..Default::default()
+            };
+            store
+                .put_with_meta(String::from("key"), Bytes::from_static(b"value"), &meta)
+                .await
+                .unwrap();
+            let used_bytes = store.used_bytes();
+
+            // a shorter header is padded in place, the stored size does not change
+            let shorter = ObjectMeta {
+                expires_at_ms: None,
+                ..meta.clone()
+            };
+            store
+                .update_meta(String::from("key"), &shorter)
+                .await
+                .unwrap();
+            let (found, value) = store.get_with_meta(String::from("key")).await.unwrap();
+            assert_eq!((found, value), (shorter.clone(), b"value".to_vec()));
+            if layout == StoreLayout::FilePerKey {
+                assert_eq!(store.used_bytes(), used_bytes);
+            }
+
+            // a longer one rewrites the value
+            let longer = ObjectMeta {
+                content_type: Some(String::from("text/plain")),
+                ..meta
+            };
+            store
+                .update_meta(String::from("key"), &longer)
+                .await
+                .unwrap();
+            let (found, value) = store.get_with_meta(String::from("key")).await.unwrap();
+            assert_eq!((found, value), (longer, b"value".to_vec()));
+            let missing = store.update_meta(String::from("missing"), &shorter).await;
+            assert!(missing.unwrap_err().is_not_found());
+        }
+    }
+
+    #[monoio::test]
+    async fn test_sweep_expired() {
+        for layout in [StoreLayout::FilePerKey, StoreLayout::Segment] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut options = options(dir.path(), 0, 0);
+            options.layout = layout;
+            let store = LocalFileKVStore::new(options.clone()).unwrap();
+            let now = crate::kv_store::object_meta::now_ms();
+            for (key, expires_at_ms) in [("a", now - 1), ("b", now - 1), ("c", now + 60_000)] {
+                let meta = ObjectMeta {
+                    expires_at_ms: Some(expires_at_ms),
+                    ..Default::default()
+                };
+                store
+                    .put_with_meta(String::from(key), Bytes::from_static(b"value"), &meta)
+                    .await
+                    .unwrap();
+            }
+            let keys = |store: &LocalFileKVStore| -> Vec<String> {
+                store.list("").into_iter().map(|(key, _)| key).collect()
+            };
+            assert_eq!(keys(&store), ["c"]);
+            assert_eq!(store.list_page("", None, 10).len(), 1);
+            assert_eq!(store.sweep_expired().unwrap(), 2);
+            assert!(!store.exists(String::from("a")));
+
+            // keys found on disk are swept once their header was read
+            let meta = ObjectMeta {
+                expires_at_ms: Some(now - 1),
+                ..Default::default()
+            };
+            store
+                .put_with_meta(String::from("d"), Bytes::from_static(b"value"), &meta)
+                .await
+                .unwrap();
+            store.flush().unwrap();
+            drop(store);
+            let store = LocalFileKVStore::new(options).unwrap();
+            assert_eq!(keys(&store), ["c", "d"]);
+            assert_eq!(store.sweep_expired().unwrap(), 1);
+            assert_eq!(keys(&store), ["c"]);
+            assert_eq!(store.sweep_expired().unwrap(), 0);
+        }
     }
 }
@@ -0,0 +1,284 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::kv_store::store_error::StoreError;
+use crate::kv_store::{legacy_short_hash, stable_hash, validate_key};
+use crate::settings::local_kv_options::{LocalFileKVStoreOptions, StoreLayout};
+
+/// Manifest describing how a storage directory is laid out, kept in its root.
+pub const MANIFEST_FILE: &str = ".layout.json";
+/// Version 0 stores predate the manifest and bucket keys with `DefaultHasher`.
+pub const CURRENT_FORMAT_VERSION: u32 = 1;
+/// Locked shared by a store while it is open and exclusively by a migration.
+pub const LOCK_FILE: &str = ".lock";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyHash {
+    /// `DefaultHasher` of the key string, which is not guaranteed to be stable.
+    LegacyDefaultHasher,
+    /// 64 bit FNV-1a of the key bytes, see `kv_store::stable_hash`.
+    Fnv1a64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayoutManifest {
+    pub format_version: u32,
+    pub hash: KeyHash,
+    pub num_bucket: u16,
+    pub layout: StoreLayout,
+    /// Set while an offline migration to another layout is under way, the store is
+    /// not served until the migration has been run to the end.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migrating_to: Option<StoreLayout>,
+}
+
+impl LayoutManifest {
+    pub fn current(num_bucket: u16, layout: StoreLayout) -> LayoutManifest {
+        LayoutManifest {
+            format_version: CURRENT_FORMAT_VERSION,
+            hash: KeyHash::Fnv1a64,
+            num_bucket,
+            layout,
+            migrating_to: None,
+        }
+    }
+
+    /// Loads the manifest of the store, creating one when the directory is new.
+    /// The on-disk layout always wins over the options, a mismatch only warns until
+    /// the store is migrated offline.
+    pub fn open(
+        options: &LocalFileKVStoreOptions,
+    ) -> Result<LayoutManifest, Box<dyn Error + Send + Sync>> {
+        let root = Path::new(&options.root_path);
+        let configured = LayoutManifest::current(options.num_bucket, options.layout);
+        let manifest = match Self::load(root)? {
+            Some(manifest) => manifest,
+            None => {
+                let manifest = Self::infer(root, options)?.unwrap_or(configured);
+                manifest.persist(root)?;
+                info!(
+                    "Wrote layout manifest {:?} to {}",
+                    manifest, options.root_path
+                );
+                manifest
+            }
+        };
+        if manifest.format_version > CURRENT_FORMAT_VERSION {
+            return Err(format!(
+                "store {} has format version {}, newer than the supported {}",
+                options.root_path, manifest.format_version, CURRENT_FORMAT_VERSION
+            )
+            .into());
+        }
+        if let Some(target) = manifest.migrating_to {
+            return Err(format!(
+                "store {} is being migrated to the {:?} layout, run the migration again to \
+                 finish it",
+                options.root_path, target
+            )
+            .into());
+        }
+        if manifest != configured {
+            warn!(
+                "Store {} is laid out as {:?} but configured as {:?}, keeping the on-disk \
+                 layout until the store is migrated",
+                options.root_path, manifest, configured
+            );
+        }
+        Ok(manifest)
+    }
+
+    pub fn load(root: &Path) -> Result<Option<LayoutManifest>, Box<dyn Error + Send + Sync>> {
+        let path = root.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&std::fs::read(path)?)?))
+    }
+
+    /// Describes a store written before manifests existed, None if the directory is empty.
+    /// The bucket count is read from the highest bucket directory, the configured one
+    /// may differ from the one the store was written with.
+    pub fn infer(
+        root: &Path,
+        options: &LocalFileKVStoreOptions,
+    ) -> Result<Option<LayoutManifest>, Box<dyn Error + Send + Sync>> {
+        if !root.is_dir() {
+            return Ok(None);
+        }
+        let mut has_buckets = false;
+        let mut has_segments = false;
+        let mut highest_bucket: Option<u16> = None;
+        for entry in std::fs::read_dir(root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                has_buckets = true;
+                if let Ok(bucket) = name.parse::<u16>() {
+                    highest_bucket = highest_bucket.max(Some(bucket));
+                }
+            } else if name.ends_with(".seg") {
+                has_segments = true;
+            }
+        }
+        let manifest = match (has_buckets, has_segments) {
+            (false, false) => return Ok(None),
+            (_, true) => LayoutManifest::current(options.num_bucket, StoreLayout::Segment),
+            (true, false) => LayoutManifest {
+                format_version: 0,
+                hash: KeyHash::LegacyDefaultHasher,
+                num_bucket: highest_bucket
+                    .map_or(options.num_bucket, |bucket| bucket.saturating_add(1)),
+                layout: StoreLayout::FilePerKey,
+                migrating_to: None,
+            },
+        };
+        Ok(Some(manifest))
+    }
+
+    /// Writes the manifest atomically.
+    pub fn persist(&self, root: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        std::fs::create_dir_all(root)?;
+        let tmp_path = root.join(format!("{}.tmp", MANIFEST_FILE));
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp_path, root.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+
+    pub fn bucket(&self, key: &str) -> u16 {
+        let short_hash = match self.hash {
+            KeyHash::LegacyDefaultHasher => legacy_short_hash(key),
+            KeyHash::Fnv1a64 => stable_hash(key.as_bytes()) as u16,
+        };
+        short_hash % self.num_bucket
+    }
+
+    /// Path of the file of `key`, always below `root`.
+    pub fn data_path(&self, root: &Path, key: &str) -> Result<PathBuf, StoreError> {
+        validate_key(key)?;
+        Ok(root.join(self.bucket(key).to_string()).join(key))
+    }
+}
+
+/// An advisory lock on the store directory, released when dropped or when the process
+/// holding it dies. Stores being served share it, a migration needs it alone.
+pub struct StoreLock {
+    _file: File,
+}
+
+impl StoreLock {
+    pub fn shared(root: &Path) -> Result<StoreLock, Box<dyn Error + Send + Sync>> {
+        Self::acquire(root, libc::LOCK_SH)
+    }
+
+    pub fn exclusive(root: &Path) -> Result<StoreLock, Box<dyn Error + Send + Sync>> {
+        Self::acquire(root, libc::LOCK_EX)
+    }
+
+    fn acquire(root: &Path, operation: i32) -> Result<StoreLock, Box<dyn Error + Send + Sync>> {
+        std::fs::create_dir_all(root)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(root.join(LOCK_FILE))?;
+        // SAFETY: the descriptor stays open for as long as `file` lives
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } != 0 {
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Err(format!(
+                    "store {} is in use by a worker or a migration",
+                    root.display()
+                )
+                .into());
+            }
+            return Err(error.into());
+        }
+        Ok(StoreLock { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn options(root: &Path) -> LocalFileKVStoreOptions {
+        test_util::options(root, 0, 0)
+    }
+
+    #[test]
+    fn test_new_store_gets_current_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = LayoutManifest::open(&options(dir.path())).unwrap();
+        assert_eq!(
+            manifest,
+            LayoutManifest::current(16, StoreLayout::FilePerKey)
+        );
+        assert_eq!(LayoutManifest::load(dir.path()).unwrap(), Some(manifest));
+    }
+
+    #[test]
+    fn test_existing_layout_wins_over_options() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("3")).unwrap();
+        let legacy = LayoutManifest::open(&options(dir.path())).unwrap();
+        assert_eq!(legacy.format_version, 0);
+        assert_eq!(legacy.hash, KeyHash::LegacyDefaultHasher);
+        assert_eq!(legacy.num_bucket, 4);
+
+        let mut changed = options(dir.path());
+        changed.num_bucket = 32;
+        assert_eq!(LayoutManifest::open(&changed).unwrap(), legacy);
+    }
+
+    #[test]
+    fn test_bucket_count_is_inferred_from_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        for bucket in ["0", "5", "31"] {
+            std::fs::create_dir_all(dir.path().join(bucket)).unwrap();
+        }
+        let inferred = LayoutManifest::infer(dir.path(), &options(dir.path()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(inferred.num_bucket, 32);
+    }
+
+    #[test]
+    fn test_store_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = StoreLock::shared(dir.path()).unwrap();
+        let also_shared = StoreLock::shared(dir.path()).unwrap();
+        assert!(StoreLock::exclusive(dir.path()).is_err());
+        drop((shared, also_shared));
+        let exclusive = StoreLock::exclusive(dir.path()).unwrap();
+        assert!(StoreLock::shared(dir.path()).is_err());
+        assert!(StoreLock::exclusive(dir.path()).is_err());
+        drop(exclusive);
+        StoreLock::shared(dir.path()).unwrap();
+    }
+
+    #[test]
+    fn test_data_path_stays_under_root() {
+        let manifest = LayoutManifest::current(16, StoreLayout::FilePerKey);
+        let root = Path::new("/store");
+        assert!(manifest
+            .data_path(root, "dir/key")
+            .unwrap()
+            .starts_with(root));
+        for key in ["/etc/passwd", "../outside", "dir/../../outside"] {
+            assert!(matches!(
+                manifest.data_path(root, key),
+                Err(StoreError::InvalidKey { .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+
+use crate::kv_store::local_kv_store::manifest::{LayoutManifest, StoreLock};
+use crate::kv_store::local_kv_store::segment_store::SegmentStore;
+use crate::settings::local_kv_options::{LocalFileKVStoreOptions, StoreLayout};
+
+/// Values copied between two syncs when converting layouts.
+const SYNC_BATCH: usize = 256;
+
+#[derive(Debug)]
+pub struct MigrationReport {
+    pub from: LayoutManifest,
+    pub to: LayoutManifest,
+    pub moved: u64,
+    pub unchanged: u64,
+}
+
+/// Rewrites a store that is not being served into the current format with the given
+/// bucket count and layout. The source layout is recorded in the manifest as being
+/// migrated before anything moves and the manifest is only replaced once every value
+/// has been moved, so an interrupted migration can simply be run again. The store is
+/// locked for the whole migration, it fails while the store is open elsewhere.
+pub fn migrate(
+    options: &LocalFileKVStoreOptions,
+) -> Result<MigrationReport, Box<dyn Error + Send + Sync>> {
+    let root = Path::new(&options.root_path);
+    let _lock = StoreLock::exclusive(root)?;
+    let from = match LayoutManifest::load(root)? {
+        Some(manifest) => manifest,
+        None => LayoutManifest::infer(root, options)?
+            .unwrap_or(LayoutManifest::current(options.num_bucket, options.layout)),
+    };
+    let to = LayoutManifest::current(options.num_bucket, options.layout);
+    match from.migrating_to {
+        Some(target) if target != to.layout => {
+            return Err(format!(
+                "store {} is being migrated to the {:?} layout, finish that migration first",
+                options.root_path, target
+            )
+            .into())
+        }
+        Some(_) => info!("Resuming the migration of store {}", options.root_path),
+        None => {}
+    }
+    let from = LayoutManifest {
+        migrating_to: None,
+        ..from
+    };
+    LayoutManifest {
+        migrating_to: Some(to.layout),
+        ..from
+    }
+    .persist(root)?;
+    info!(
+        "Migrating store {} from {:?} to {:?}",
+        options.root_path, from, to
+    );
+
+    let mut report = MigrationReport {
+        from,
+        to,
+        moved: 0,
+        unchanged: 0,
+    };
+    match (from.layout, to.layout) {
+        (StoreLayout::FilePerKey, StoreLayout::FilePerKey) => {
+            for (key, path) in list_files(root)? {
+                let target = to.data_path(root, &key)?;
+                if target == path {
+                    report.unchanged += 1;
+                    continue;
+                }
+                std::fs::create_dir_all(target.parent().unwrap())?;
+                std::fs::rename(&path, &target)?;
+                debug!("Moved {} from {:?} to {:?}", key, path, target);
+                report.moved += 1;
+            }
+            remove_empty_dirs(root)?;
+        }
+        (StoreLayout::FilePerKey, StoreLayout::Segment) => {
+            let segments = SegmentStore::open(root, options.segment_size)?;
+            for batch in list_files(root)?.chunks(SYNC_BATCH) {
+                for (key, path) in batch {
+                    segments.put(key, &[&std::fs::read(path)?])?;
+                }
+                // sources are only removed once their copies are durable
+                segments.sync()?;
+                for (_, path) in batch {
+                    std::fs::remove_file(path)?;
+                    report.moved += 1;
+                }
+            }
+            remove_empty_dirs(root)?;
+        }
+        (StoreLayout::Segment, StoreLayout::FilePerKey) => {
+            let segments = SegmentStore::open(root, options.segment_size)?;
+            for (key, _) in segments.entries() {
+                if let Some(value) = segments.get(&key)? {
+                    let target = to.data_path(root, &key)?;
+                    std::fs::create_dir_all(target.parent().unwrap())?;
+                    let mut file = std::fs::File::create(&target)?;
+                    file.write_all(&value)?;
+                    file.sync_all()?;
+                    report.moved += 1;
+                }
+            }
+            drop(segments);
+            // oldest first, a rerun must not find a stale copy without its newer one
+            let mut segment_files = Vec::new();
+            for entry in std::fs::read_dir(root)? {
+                let path = entry?.path();
+                if path.extension().and_then(|ext| ext.to_str()) == Some("seg") {
+                    segment_files.push(path);
+                }
+            }
+            segment_files.sort();
+            for path in segment_files {
+                std::fs::remove_file(path)?;
+            }
+        }
+        (StoreLayout::Segment, StoreLayout::Segment) => {
+            // segment keys are not bucketed, only the manifest changes
+        }
+    }
+    to.persist(root)?;
+    info!(
+        "Migrated store {}: {} values moved, {} unchanged",
+        options.root_path, report.moved, report.unchanged
+    );
+    Ok(report)
+}
+
+/// Every value file of a file per key store with the key it holds.
+fn list_files(root: &Path) -> Result<Vec<(String, PathBuf)>, Box<dyn Error + Send + Sync>> {
+    let mut files = Vec::new();
+    if !root.is_dir() {
+        return Ok(files);
+    }
+    for bucket in std::fs::read_dir(root)? {
+        let bucket = bucket?;
+        if bucket.file_type()?.is_dir() && !bucket.file_name().to_string_lossy().starts_with('.') {
+            collect_files(&bucket.path(), "", &mut files)?;
+        }
+    }
+    Ok(files)
+}
+
+fn collect_files(
+    dir: &Path,
+    relative: &str,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let key = if relative.is_empty() {
+            name
+        } else {
+            format!("{}/{}", relative, name)
+        };
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), &key, files)?;
+        } else {
+            files.push((key, entry.path()));
+        }
+    }
+    Ok(())
+}
+
+/// Removes directories left empty under the root, returning whether `dir` is empty.
+fn remove_empty_dirs(dir: &Path) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let mut empty = true;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && remove_empty_dirs(&entry.path())? {
+            std::fs::remove_dir(entry.path())?;
+        } else {
+            empty = false;
+        }
+    }
+    Ok(empty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_store::legacy_short_hash;
+    use crate::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
+    use crate::kv_store::local_kv_store::manifest::KeyHash;
+    use crate::test_util;
+
+    fn options(root: &Path, num_bucket: u16, layout: StoreLayout) -> LocalFileKVStoreOptions {
+        LocalFileKVStoreOptions {
+            num_bucket,
+            layout,
+            segment_size: 1024,
+            ..test_util::options(root, 0, 0)
+        }
+    }
+
+    fn write_legacy(root: &Path, key: &str, value: &[u8]) {
+        let path = root
+            .join((legacy_short_hash(key) % 8).to_string())
+            .join(key);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, value).unwrap();
+    }
+
+    #[test]
+    fn test_rebucket_legacy_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys: Vec<String> = (0..50).map(|i| format!("dir/key{}", i)).collect();
+        for key in keys.iter() {
+            write_legacy(dir.path(), key, key.as_bytes());
+        }
+
+        let report = migrate(&options(dir.path(), 5, StoreLayout::FilePerKey)).unwrap();
+        assert_eq!(report.from.hash, KeyHash::LegacyDefaultHasher);
+        assert_eq!(report.moved + report.unchanged, 50);
+
+        let manifest = LayoutManifest::load(dir.path()).unwrap().unwrap();
+        assert_eq!(
+            manifest,
+            LayoutManifest::current(5, StoreLayout::FilePerKey)
+        );
+        for key in keys.iter() {
+            let path = manifest.data_path(dir.path(), key).unwrap();
+            assert_eq!(std::fs::read(path).unwrap(), key.as_bytes());
+        }
+        assert!(std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+            .filter(|name| !name.starts_with('.'))
+            .all(|name| name.parse::<u16>().unwrap() < 5));
+
+        // running it again is a no-op
+        let report = migrate(&options(dir.path(), 5, StoreLayout::FilePerKey)).unwrap();
+        assert_eq!((report.moved, report.unchanged), (0, 50));
+    }
+
+    #[test]
+    fn test_convert_between_layouts() {
+        let dir = tempfile::tempdir().unwrap();
+        write_legacy(dir.path(), "a", b"1");
+        write_legacy(dir.path(), "b/c", b"2");
+
+        let report = migrate(&options(dir.path(), 8, StoreLayout::Segment)).unwrap();
+        assert_eq!(report.moved, 2);
+        let segments = SegmentStore::open(dir.path(), 1024).unwrap();
+        assert_eq!(segments.get("b/c").unwrap().unwrap(), b"2");
+        drop(segments);
+
+        let report = migrate(&options(dir.path(), 8, StoreLayout::FilePerKey)).unwrap();
+        assert_eq!(report.moved, 2);
+        let manifest = LayoutManifest::load(dir.path()).unwrap().unwrap();
+        assert_eq!(
+            std::fs::read(manifest.data_path(dir.path(), "a").unwrap()).unwrap(),
+            b"1"
+        );
+    }
+
+    #[test]
+    fn test_open_store_is_not_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        write_legacy(dir.path(), "a", b"1");
+        let store = LocalFileKVStore::new(options(dir.path(), 8, StoreLayout::FilePerKey)).unwrap();
+        assert!(migrate(&options(dir.path(), 8, StoreLayout::Segment)).is_err());
+        drop(store);
+        let report = migrate(&options(dir.path(), 8, StoreLayout::Segment)).unwrap();
+        assert_eq!(report.moved, 1);
+    }
+
+    #[test]
+    fn test_resume_interrupted_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        for key in ["a", "b", "c/d"] {
+            write_legacy(dir.path(), key, key.as_bytes());
+        }
+        // what a run interrupted after moving `a` leaves behind
+        let legacy =
+            LayoutManifest::infer(dir.path(), &options(dir.path(), 8, StoreLayout::Segment))
+                .unwrap()
+                .unwrap();
+        LayoutManifest {
+            migrating_to: Some(StoreLayout::Segment),
+            ..legacy
+        }
+        .persist(dir.path())
+        .unwrap();
+        let segments = SegmentStore::open(dir.path(), 1024).unwrap();
+        segments.put("a", &[b"a"]).unwrap();
+        drop(segments);
+        let moved = dir
+            .path()
+            .join((legacy_short_hash("a") % 8).to_string())
+            .join("a");
+        std::fs::remove_file(moved).unwrap();
+        assert!(LayoutManifest::open(&options(dir.path(), 8, StoreLayout::Segment)).is_err());
+        assert!(migrate(&options(dir.path(), 8, StoreLayout::FilePerKey)).is_err());
+
+        let report = migrate(&options(dir.path(), 8, StoreLayout::Segment)).unwrap();
+        assert_eq!(report.from, legacy);
+        assert_eq!(report.moved, 2);
+        let report = migrate(&options(dir.path(), 8, StoreLayout::Segment)).unwrap();
+        assert_eq!(report.moved, 0);
+
+        let segments = SegmentStore::open(dir.path(), 1024).unwrap();
+        for key in ["a", "b", "c/d"] {
+            assert_eq!(segments.get(key).unwrap().unwrap(), key.as_bytes());
+        }
+        assert!(list_files(dir.path()).unwrap().is_empty());
+        assert_eq!(
+            LayoutManifest::load(dir.path()).unwrap(),
+            Some(LayoutManifest::current(8, StoreLayout::Segment))
+        );
+    }
+}
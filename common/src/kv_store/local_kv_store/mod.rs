@@ -1 +1,8 @@
+pub mod blocking_pool;
+pub mod index;
 pub mod local_file_kv_store;
+pub mod manifest;
+pub mod migration;
+pub mod pin_set;
+pub mod segment_store;
+pub mod tombstones;
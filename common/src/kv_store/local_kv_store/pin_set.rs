@@ -0,0 +1,67 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// Keys and key prefixes that must never be evicted.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinSet {
+    pub keys: BTreeSet<String>,
+    pub prefixes: BTreeSet<String>,
+}
+
+impl PinSet {
+    pub fn is_pinned(&self, key: &str) -> bool {
+        self.keys.contains(key) || self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    pub fn load(path: &Path) -> Result<PinSet, Box<dyn Error + Send + Sync>> {
+        if !path.exists() {
+            return Ok(PinSet::default());
+        }
+        let content = std::fs::read(path)?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    /// Writes the pin set atomically, so a crash never leaves a truncated file behind.
+    pub fn persist(&self, path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pin_matching() {
+        let mut pins = PinSet::default();
+        pins.keys.insert(String::from("lookup/table"));
+        pins.prefixes.insert(String::from("models/"));
+
+        assert!(pins.is_pinned("lookup/table"));
+        assert!(!pins.is_pinned("lookup/table2"));
+        assert!(pins.is_pinned("models/bert/weights"));
+        assert!(!pins.is_pinned("model"));
+    }
+
+    #[test]
+    fn test_persist_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pins.json");
+        assert_eq!(PinSet::load(&path).unwrap(), PinSet::default());
+
+        let mut pins = PinSet::default();
+        pins.keys.insert(String::from("a"));
+        pins.prefixes.insert(String::from("b/"));
+        pins.persist(&path).unwrap();
+        assert_eq!(PinSet::load(&path).unwrap(), pins);
+    }
+}
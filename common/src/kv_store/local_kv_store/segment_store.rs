@@ -0,0 +1,736 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info, warn};
+
+use crate::kv_store::validate_key;
+
+const SEGMENT_SUFFIX: &str = "seg";
+const FOOTER_MAGIC: &[u8; 8] = b"FRYSEGFT";
+/// Footer length (u64) followed by the footer magic.
+const FOOTER_TRAILER_LEN: u64 = 16;
+/// Key length (u16), record kind (u8) and value length (u32).
+const RECORD_HEADER_LEN: u64 = 7;
+/// Sealed segments with less live data than this ratio get compacted.
+const COMPACTION_LIVE_RATIO: f64 = 0.5;
+/// Records copied per lock hold while compacting.
+const COMPACTION_BATCH: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RecordKind {
+    Put = 0,
+    Delete = 1,
+}
+
+impl RecordKind {
+    fn from_u8(kind: u8) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        match kind {
+            0 => Ok(RecordKind::Put),
+            1 => Ok(RecordKind::Delete),
+            other => Err(format!("unknown segment record kind {}", other).into()),
+        }
+    }
+}
+
+/// Where the value of a key lives inside a segment file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct RecordLocation {
+    segment_id: u64,
+    record_offset: u64,
+    value_offset: u64,
+    value_len: u32,
+    record_len: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FooterEntry {
+    kind: RecordKind,
+    key: String,
+    record_offset: u64,
+    value_len: u32,
+}
+
+/// Data length of a sealed segment and the records listed in its footer.
+type Footer = (u64, Vec<FooterEntry>);
+
+struct Segment {
+    file: Arc<File>,
+    data_len: u64,
+    live_bytes: u64,
+    sealed: bool,
+    entries: Vec<FooterEntry>,
+}
+
+struct SegmentState {
+    index: HashMap<String, RecordLocation>,
+    segments: BTreeMap<u64, Segment>,
+    active_id: u64,
+}
+
+/// Log-structured store for small values. Values are appended to segment files of
+/// roughly `segment_size` bytes; a sealed segment ends with a footer listing its
+/// records so the in-memory index can be rebuilt without scanning the whole file.
+///
+/// Record layout: key_len u16 | kind u8 | value_len u32 | key | value, little endian.
+/// Footer layout: entries (kind u8 | key_len u16 | key | record_offset u64 | value_len u32)
+/// then footer_len u64 and `FRYSEGFT`.
+pub struct SegmentStore {
+    dir: PathBuf,
+    segment_size: u64,
+    state: Mutex<SegmentState>,
+}
+
+impl SegmentStore {
+    pub fn open(
+        dir: &Path,
+        segment_size: u64,
+    ) -> Result<SegmentStore, Box<dyn Error + Send + Sync>> {
+        std::fs::create_dir_all(dir)?;
+        let mut segment_ids = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_SUFFIX) {
+                continue;
+            }
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| u64::from_str_radix(stem, 16).ok());
+            match id {
+                Some(id) => segment_ids.push(id),
+                None => warn!("Ignoring unknown segment file {:?}", path),
+            }
+        }
+        segment_ids.sort_unstable();
+
+        let mut state = SegmentState {
+            index: HashMap::new(),
+            segments: BTreeMap::new(),
+            active_id: 0,
+        };
+        for id in segment_ids.iter() {
+            let (segment, entries) = Self::recover_segment(&Self::segment_path(dir, *id))?;
+            state.segments.insert(*id, segment);
+            for entry in entries {
+                Self::apply(&mut state, *id, entry);
+            }
+        }
+        let live: Vec<RecordLocation> = state.index.values().copied().collect();
+        for location in live {
+            if let Some(segment) = state.segments.get_mut(&location.segment_id) {
+                segment.live_bytes += location.record_len;
+            }
+        }
+
+        let store = SegmentStore {
+            dir: dir.to_path_buf(),
+            segment_size,
+            state: Mutex::new(state),
+        };
+        {
+            let mut state = store.state.lock().unwrap();
+            // every segment but a trailing unsealed one stays read only
+            let last_id = state.segments.keys().next_back().copied();
+            let unsealed: Vec<u64> = state
+                .segments
+                .iter()
+                .filter(|(id, segment)| !segment.sealed && Some(**id) != last_id)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in unsealed {
+                store.seal(&mut state, id)?;
+            }
+            match last_id {
+                Some(id) if !state.segments[&id].sealed => state.active_id = id,
+                Some(id) => store.roll(&mut state, id + 1)?,
+                None => store.roll(&mut state, 1)?,
+            }
+            info!(
+                "Segment store {:?} opened with {} segments and {} keys",
+                store.dir,
+                state.segments.len(),
+                state.index.len()
+            );
+        }
+        Ok(store)
+    }
+
+    pub fn put(
+        &self,
+        key: &str,
+        value_parts: &[&[u8]],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // keys must stay valid file names for a migration to one file per key
+        validate_key(key)?;
+        let mut state = self.state.lock().unwrap();
+        self.append(&mut state, RecordKind::Put, key, value_parts)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let (file, location) = {
+            let state = self.state.lock().unwrap();
+            match state.index.get(key) {
+                Some(location) => (
+                    Arc::clone(&state.segments[&location.segment_id].file),
+                    *location,
+                ),
+                None => return Ok(None),
+            }
+        };
+        // the file handle stays valid even if compaction unlinks the segment meanwhile
+        let mut buf = vec![0; location.value_len as usize];
+        file.read_exact_at(&mut buf, location.value_offset)?;
+        Ok(Some(buf))
+    }
+
+    pub fn delete(&self, key: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.index.contains_key(key) {
+            return Ok(false);
+        }
+        self.append(&mut state, RecordKind::Delete, key, &[])?;
+        Ok(true)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.state.lock().unwrap().index.contains_key(key)
+    }
+
+    /// Keys with the length of their stored value.
+    pub fn entries(&self) -> Vec<(String, u64)> {
+        self.state
+            .lock()
+            .unwrap()
+            .index
+            .iter()
+            .map(|(key, location)| (key.clone(), location.value_len as u64))
+            .collect()
+    }
+
+    /// Makes the records appended so far durable, sealed segments are synced already.
+    pub fn sync(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let state = self.state.lock().unwrap();
+        state.segments[&state.active_id].file.sync_data()?;
+        Ok(())
+    }
+
+    /// Seals the active segment and starts a new one, so that nothing is left to
+    /// recover by scanning records on the next start.
+    pub fn flush(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut state = self.state.lock().unwrap();
+        let active_id = state.active_id;
+        if state.segments[&active_id].data_len == 0 {
+            return Ok(());
+        }
+        self.seal(&mut state, active_id)?;
+        self.roll(&mut state, active_id + 1)
+    }
+
+    /// Rewrites the live records of mostly dead sealed segments into the active
+    /// segment and removes them, returning the number of segments compacted. The lock
+    /// is only held for a batch of records at a time so that foreground requests go on
+    /// meanwhile; this does blocking file I/O and is meant to run on its own thread.
+    pub fn compact(&self) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let candidates: Vec<u64> = {
+            let state = self.state.lock().unwrap();
+            state
+                .segments
+                .iter()
+                .filter(|(id, segment)| {
+                    **id != state.active_id
+                        && segment.sealed
+                        && (segment.live_bytes as f64)
+                            < segment.data_len as f64 * COMPACTION_LIVE_RATIO
+                })
+                .map(|(id, _)| *id)
+                .collect()
+        };
+        for id in candidates.iter() {
+            self.compact_segment(*id)?;
+        }
+        Ok(candidates.len())
+    }
+
+    fn compact_segment(&self, id: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (file, entries) = {
+            let state = self.state.lock().unwrap();
+            let Some(segment) = state.segments.get(&id) else {
+                return Ok(());
+            };
+            (Arc::clone(&segment.file), segment.entries.clone())
+        };
+        let shadowed = self.shadowed_keys(id, &entries);
+        let mut moved = 0;
+        for batch in entries.chunks(COMPACTION_BATCH) {
+            let live: Vec<(&str, RecordLocation)> = {
+                let state = self.state.lock().unwrap();
+                batch
+                    .iter()
+                    .filter(|entry| entry.kind == RecordKind::Put)
+                    .filter_map(|entry| {
+                        let location = state.index.get(&entry.key)?;
+                        (location.segment_id == id && location.record_offset == entry.record_offset)
+                            .then_some((entry.key.as_str(), *location))
+                    })
+                    .collect()
+            };
+            let mut values = Vec::with_capacity(live.len());
+            for (key, location) in live {
+                let mut value = vec![0; location.value_len as usize];
+                file.read_exact_at(&mut value, location.value_offset)?;
+                values.push((key, location, value));
+            }
+
+            let mut state = self.state.lock().unwrap();
+            for (key, location, value) in values {
+                // overwritten or deleted while the value was read
+                if state.index.get(key) != Some(&location) {
+                    continue;
+                }
+                self.append(&mut state, RecordKind::Put, key, &[&value])?;
+                moved += 1;
+            }
+            for entry in batch {
+                // a tombstone is only kept while an older segment holds a copy to hide
+                if entry.kind == RecordKind::Delete
+                    && shadowed.contains(entry.key.as_str())
+                    && !state.index.contains_key(&entry.key)
+                {
+                    self.append(&mut state, RecordKind::Delete, &entry.key, &[])?;
+                }
+            }
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            // the moved records must be durable before the only other copy is gone,
+            // segments rolled over meanwhile were synced when sealed
+            state.segments[&state.active_id].file.sync_data()?;
+            state.segments.remove(&id);
+        }
+        std::fs::remove_file(Self::segment_path(&self.dir, id))?;
+        debug!("Compacted segment {} moving {} live records", id, moved);
+        Ok(())
+    }
+
+    /// Keys deleted in segment `id` that a segment older than it still holds a value
+    /// for, looked up one segment per lock hold.
+    fn shadowed_keys<'a>(&self, id: u64, entries: &'a [FooterEntry]) -> HashSet<&'a str> {
+        let tombstones: HashSet<&str> = entries
+            .iter()
+            .filter(|entry| entry.kind == RecordKind::Delete)
+            .map(|entry| entry.key.as_str())
+            .collect();
+        let mut shadowed = HashSet::new();
+        if tombstones.is_empty() {
+            return shadowed;
+        }
+        let older: Vec<u64> = self
+            .state
+            .lock()
+            .unwrap()
+            .segments
+            .range(..id)
+            .map(|(id, _)| *id)
+            .collect();
+        for older_id in older {
+            let state = self.state.lock().unwrap();
+            let Some(segment) = state.segments.get(&older_id) else {
+                continue;
+            };
+            for entry in segment.entries.iter() {
+                if entry.kind == RecordKind::Put {
+                    if let Some(key) = tombstones.get(entry.key.as_str()) {
+                        shadowed.insert(*key);
+                    }
+                }
+            }
+        }
+        shadowed
+    }
+
+    fn append(
+        &self,
+        state: &mut SegmentState,
+        kind: RecordKind,
+        key: &str,
+        value_parts: &[&[u8]],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let value_len: usize = value_parts.iter().map(|part| part.len()).sum();
+        if key.len() > u16::MAX as usize || value_len > u32::MAX as usize {
+            return Err(format!("record for {} is too large for a segment", key).into());
+        }
+        let record_len = RECORD_HEADER_LEN + key.len() as u64 + value_len as u64;
+        let active_id = state.active_id;
+        if state.segments[&active_id].data_len > 0
+            && state.segments[&active_id].data_len + record_len > self.segment_size
+        {
+            self.seal(state, active_id)?;
+            self.roll(state, active_id + 1)?;
+        }
+
+        let active_id = state.active_id;
+        let segment = state.segments.get_mut(&active_id).unwrap();
+        let record_offset = segment.data_len;
+        let mut record = Vec::with_capacity(record_len as usize);
+        record.extend_from_slice(&(key.len() as u16).to_le_bytes());
+        record.push(kind as u8);
+        record.extend_from_slice(&(value_len as u32).to_le_bytes());
+        record.extend_from_slice(key.as_bytes());
+        for part in value_parts {
+            record.extend_from_slice(part);
+        }
+        segment.file.write_all_at(&record, record_offset)?;
+        segment.data_len += record_len;
+        segment.entries.push(FooterEntry {
+            kind,
+            key: key.to_string(),
+            record_offset,
+            value_len: value_len as u32,
+        });
+        let entry = segment.entries.last().unwrap().clone();
+        Self::apply(state, active_id, entry);
+        if let Some(location) = state.index.get(key).copied() {
+            state.segments.get_mut(&active_id).unwrap().live_bytes += location.record_len;
+        }
+        Ok(())
+    }
+
+    /// Updates the index for a record, releasing the live bytes of the copy it replaces.
+    fn apply(state: &mut SegmentState, segment_id: u64, entry: FooterEntry) {
+        let previous = match entry.kind {
+            RecordKind::Put => {
+                let location = RecordLocation {
+                    segment_id,
+                    record_offset: entry.record_offset,
+                    value_offset: entry.record_offset + RECORD_HEADER_LEN + entry.key.len() as u64,
+                    value_len: entry.value_len,
+                    record_len: RECORD_HEADER_LEN + entry.key.len() as u64 + entry.value_len as u64,
+                };
+                state.index.insert(entry.key, location)
+            }
+            RecordKind::Delete => state.index.remove(&entry.key),
+        };
+        if let Some(previous) = previous {
+            if let Some(segment) = state.segments.get_mut(&previous.segment_id) {
+                segment.live_bytes = segment.live_bytes.saturating_sub(previous.record_len);
+            }
+        }
+    }
+
+    fn seal(&self, state: &mut SegmentState, id: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let segment = state.segments.get_mut(&id).unwrap();
+        let mut footer = Vec::new();
+        for entry in segment.entries.iter() {
+            footer.push(entry.kind as u8);
+            footer.extend_from_slice(&(entry.key.len() as u16).to_le_bytes());
+            footer.extend_from_slice(entry.key.as_bytes());
+            footer.extend_from_slice(&entry.record_offset.to_le_bytes());
+            footer.extend_from_slice(&entry.value_len.to_le_bytes());
+        }
+        let footer_len = footer.len() as u64;
+        footer.extend_from_slice(&footer_len.to_le_bytes());
+        footer.extend_from_slice(FOOTER_MAGIC);
+        segment.file.write_all_at(&footer, segment.data_len)?;
+        segment.file.sync_data()?;
+        segment.sealed = true;
+        debug!(
+            "Sealed segment {} with {} records",
+            id,
+            segment.entries.len()
+        );
+        Ok(())
+    }
+
+    fn roll(&self, state: &mut SegmentState, id: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(Self::segment_path(&self.dir, id))?;
+        state.segments.insert(
+            id,
+            Segment {
+                file: Arc::new(file),
+                data_len: 0,
+                live_bytes: 0,
+                sealed: false,
+                entries: Vec::new(),
+            },
+        );
+        state.active_id = id;
+        Ok(())
+    }
+
+    fn recover_segment(
+        path: &Path,
+    ) -> Result<(Segment, Vec<FooterEntry>), Box<dyn Error + Send + Sync>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let file_len = file.metadata()?.len();
+        if let Some((data_len, entries)) = Self::read_footer(&file, file_len)? {
+            let segment = Segment {
+                file: Arc::new(file),
+                data_len,
+                live_bytes: 0,
+                sealed: true,
+                entries: entries.clone(),
+            };
+            return Ok((segment, entries));
+        }
+
+        // not sealed, the worker stopped while this segment was active
+        let entries = Self::scan_records(&file, file_len)?;
+        let data_len = entries
+            .last()
+            .map(|entry| {
+                entry.record_offset
+                    + RECORD_HEADER_LEN
+                    + entry.key.len() as u64
+                    + entry.value_len as u64
+            })
+            .unwrap_or(0);
+        if data_len < file_len {
+            warn!(
+                "Truncating {} bytes of partial records from {:?}",
+                file_len - data_len,
+                path
+            );
+            file.set_len(data_len)?;
+        }
+        let segment = Segment {
+            file: Arc::new(file),
+            data_len,
+            live_bytes: 0,
+            sealed: false,
+            entries: entries.clone(),
+        };
+        Ok((segment, entries))
+    }
+
+    fn read_footer(
+        file: &File,
+        file_len: u64,
+    ) -> Result<Option<Footer>, Box<dyn Error + Send + Sync>> {
+        if file_len < FOOTER_TRAILER_LEN {
+            return Ok(None);
+        }
+        let mut trailer = [0u8; FOOTER_TRAILER_LEN as usize];
+        file.read_exact_at(&mut trailer, file_len - FOOTER_TRAILER_LEN)?;
+        if &trailer[8..] != FOOTER_MAGIC {
+            return Ok(None);
+        }
+        let footer_len = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+        if footer_len + FOOTER_TRAILER_LEN > file_len {
+            return Ok(None);
+        }
+        let data_len = file_len - FOOTER_TRAILER_LEN - footer_len;
+        let mut footer = vec![0; footer_len as usize];
+        file.read_exact_at(&mut footer, data_len)?;
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < footer.len() {
+            let kind = RecordKind::from_u8(footer[pos])?;
+            let key_len = u16::from_le_bytes(footer[pos + 1..pos + 3].try_into()?) as usize;
+            pos += 3;
+            let key = String::from_utf8(footer[pos..pos + key_len].to_vec())?;
+            pos += key_len;
+            let record_offset = u64::from_le_bytes(footer[pos..pos + 8].try_into()?);
+            let value_len = u32::from_le_bytes(footer[pos + 8..pos + 12].try_into()?);
+            pos += 12;
+            entries.push(FooterEntry {
+                kind,
+                key,
+                record_offset,
+                value_len,
+            });
+        }
+        Ok(Some((data_len, entries)))
+    }
+
+    fn scan_records(
+        file: &File,
+        file_len: u64,
+    ) -> Result<Vec<FooterEntry>, Box<dyn Error + Send + Sync>> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+        while offset + RECORD_HEADER_LEN <= file_len {
+            file.read_exact_at(&mut header, offset)?;
+            let key_len = u16::from_le_bytes(header[..2].try_into()?) as u64;
+            let Ok(kind) = RecordKind::from_u8(header[2]) else {
+                break;
+            };
+            let value_len = u32::from_le_bytes(header[3..].try_into()?);
+            let record_len = RECORD_HEADER_LEN + key_len + value_len as u64;
+            if offset + record_len > file_len {
+                break;
+            }
+            let mut key = vec![0; key_len as usize];
+            file.read_exact_at(&mut key, offset + RECORD_HEADER_LEN)?;
+            let Ok(key) = String::from_utf8(key) else {
+                break;
+            };
+            entries.push(FooterEntry {
+                kind,
+                key,
+                record_offset: offset,
+                value_len,
+            });
+            offset += record_len;
+        }
+        Ok(entries)
+    }
+
+    fn segment_path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("{:016x}.{}", id, SEGMENT_SUFFIX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment_count(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn test_put_get_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SegmentStore::open(dir.path(), 1024).unwrap();
+        store.put("a", &[b"hello ", b"world"]).unwrap();
+        store.put("b", &[b"1"]).unwrap();
+        store.put("b", &[b"2"]).unwrap();
+
+        assert_eq!(store.get("a").unwrap().unwrap(), b"hello world");
+        assert_eq!(store.get("b").unwrap().unwrap(), b"2");
+        assert!(store.delete("a").unwrap());
+        assert!(!store.delete("a").unwrap());
+        assert_eq!(store.get("a").unwrap(), None);
+        assert_eq!(store.entries(), vec![(String::from("b"), 1)]);
+        assert!(store.put("/etc/passwd", &[b"x"]).is_err());
+        assert!(store.put("../x", &[b"x"]).is_err());
+    }
+
+    #[test]
+    fn test_recover_from_footers_and_active_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = SegmentStore::open(dir.path(), 64).unwrap();
+            for i in 0..10 {
+                store.put(&format!("key{}", i), &[&[i as u8; 20]]).unwrap();
+            }
+            store.delete("key3").unwrap();
+            store.put("key5", &[b"updated"]).unwrap();
+        }
+        assert!(segment_count(dir.path()) > 2);
+
+        let store = SegmentStore::open(dir.path(), 64).unwrap();
+        assert_eq!(store.entries().len(), 9);
+        assert_eq!(store.get("key0").unwrap().unwrap(), vec![0; 20]);
+        assert_eq!(store.get("key3").unwrap(), None);
+        assert_eq!(store.get("key5").unwrap().unwrap(), b"updated");
+        assert_eq!(store.get("key9").unwrap().unwrap(), vec![9; 20]);
+    }
+
+    #[test]
+    fn test_flush_seals_active_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = SegmentStore::open(dir.path(), 1024).unwrap();
+            store.put("a", &[b"flushed"]).unwrap();
+            store.flush().unwrap();
+            store.flush().unwrap();
+            assert_eq!(segment_count(dir.path()), 2);
+            store.put("b", &[b"after"]).unwrap();
+        }
+        let sealed = std::fs::read(SegmentStore::segment_path(dir.path(), 1)).unwrap();
+        assert!(sealed.ends_with(FOOTER_MAGIC));
+
+        let store = SegmentStore::open(dir.path(), 1024).unwrap();
+        assert_eq!(store.get("a").unwrap().unwrap(), b"flushed");
+        assert_eq!(store.get("b").unwrap().unwrap(), b"after");
+    }
+
+    #[test]
+    fn test_truncated_record_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = SegmentStore::open(dir.path(), 1024).unwrap();
+            store.put("a", &[b"complete"]).unwrap();
+            store.put("b", &[b"partial value"]).unwrap();
+        }
+        let path = SegmentStore::segment_path(dir.path(), 1);
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+
+        let store = SegmentStore::open(dir.path(), 1024).unwrap();
+        assert_eq!(store.get("a").unwrap().unwrap(), b"complete");
+        assert_eq!(store.get("b").unwrap(), None);
+        store.put("c", &[b"after recovery"]).unwrap();
+        assert_eq!(store.get("c").unwrap().unwrap(), b"after recovery");
+    }
+
+    #[test]
+    fn test_compaction_keeps_live_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SegmentStore::open(dir.path(), 128).unwrap();
+        for round in 0..5u8 {
+            for i in 0..4 {
+                store.put(&format!("key{}", i), &[&[round; 20]]).unwrap();
+            }
+        }
+        store.delete("key0").unwrap();
+        let before = segment_count(dir.path());
+
+        assert!(store.compact().unwrap() > 0);
+        assert!(segment_count(dir.path()) < before);
+        assert_eq!(store.get("key0").unwrap(), None);
+        for i in 1..4 {
+            assert_eq!(
+                store.get(&format!("key{}", i)).unwrap().unwrap(),
+                vec![4; 20]
+            );
+        }
+
+        drop(store);
+        let store = SegmentStore::open(dir.path(), 128).unwrap();
+        assert_eq!(store.get("key0").unwrap(), None);
+        assert_eq!(store.entries().len(), 3);
+    }
+
+    #[test]
+    fn test_compaction_drops_tombstones_of_compacted_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SegmentStore::open(dir.path(), 64).unwrap();
+        store.put("a", &[&[1; 40]]).unwrap();
+        store.put("b", &[&[2; 40]]).unwrap();
+        store.delete("a").unwrap();
+        store.put("b", &[&[3; 40]]).unwrap();
+        store.put("c", &[&[4; 40]]).unwrap();
+
+        // the segment holding `a` goes first, nothing is left for its tombstone to hide
+        assert_eq!(store.compact().unwrap(), 2);
+        let tombstones = store
+            .state
+            .lock()
+            .unwrap()
+            .segments
+            .values()
+            .flat_map(|segment| segment.entries.iter())
+            .filter(|entry| entry.kind == RecordKind::Delete)
+            .count();
+        assert_eq!(tombstones, 0);
+
+        drop(store);
+        let store = SegmentStore::open(dir.path(), 64).unwrap();
+        assert_eq!(store.get("a").unwrap(), None);
+        assert_eq!(store.get("b").unwrap().unwrap(), vec![3; 40]);
+        assert_eq!(store.entries().len(), 2);
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::kv_store::local_kv_store::index::KeyDigest;
+
+/// How long deletes and evictions are remembered.
+pub const TOMBSTONE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Keys deleted or evicted lately, so that anti-entropy neither restores a deleted key
+/// from a replica that missed the delete nor pushes an evicted key back. Tombstones
+/// are kept in memory only, a restart forgets them.
+#[derive(Debug)]
+pub struct Tombstones {
+    ttl: Duration,
+    entries: HashMap<String, (KeyDigest, Instant)>,
+    /// Insertion order, possibly with keys removed or inserted again since.
+    order: VecDeque<(Instant, String)>,
+}
+
+impl Tombstones {
+    pub fn new(ttl: Duration) -> Tombstones {
+        Tombstones {
+            ttl,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: String, digest: KeyDigest) {
+        self.prune();
+        let now = Instant::now();
+        self.order.push_back((now, key.clone()));
+        self.entries.insert(key, (digest, now));
+    }
+
+    /// Forgets the tombstone of a key written again.
+    pub fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<KeyDigest> {
+        self.prune();
+        self.entries.get(key).map(|(digest, _)| *digest)
+    }
+
+    pub fn entries(&mut self) -> Vec<(String, KeyDigest)> {
+        self.prune();
+        self.entries
+            .iter()
+            .map(|(key, (digest, _))| (key.clone(), *digest))
+            .collect()
+    }
+
+    fn prune(&mut self) {
+        while let Some((at, _)) = self.order.front() {
+            if at.elapsed() < self.ttl {
+                break;
+            }
+            let (at, key) = self.order.pop_front().unwrap();
+            if self
+                .entries
+                .get(&key)
+                .is_some_and(|(_, inserted)| *inserted == at)
+            {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_store::local_kv_store::index::KeyState;
+
+    #[test]
+    fn test_tombstones_expire() {
+        let mut tombstones = Tombstones::new(Duration::from_millis(20));
+        tombstones.insert(
+            String::from("a"),
+            KeyDigest::tombstone(1, KeyState::Deleted),
+        );
+        tombstones.insert(
+            String::from("b"),
+            KeyDigest::tombstone(1, KeyState::Evicted),
+        );
+        tombstones.remove("b");
+        assert_eq!(tombstones.entries().len(), 1);
+        assert_eq!(tombstones.get("b"), None);
+
+        std::thread::sleep(Duration::from_millis(30));
+        // deleted again, the first tombstone of the key must not expire the new one
+        tombstones.insert(
+            String::from("a"),
+            KeyDigest::tombstone(2, KeyState::Deleted),
+        );
+        assert_eq!(
+            tombstones.entries(),
+            vec![(
+                String::from("a"),
+                KeyDigest::tombstone(2, KeyState::Deleted)
+            )]
+        );
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(tombstones.get("a"), None);
+        assert!(tombstones.entries().is_empty());
+    }
+}
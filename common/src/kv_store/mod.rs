@@ -1,7 +1,14 @@
 use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
 use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
+
+use crate::kv_store::store_error::StoreError;
 
 pub mod local_kv_store;
+pub mod object_meta;
+pub mod read_through;
+pub mod store_error;
 
 // #[async_trait]
 // pub trait KVStore<K: Key, V: Value> {
@@ -18,22 +25,118 @@ pub mod local_kv_store;
 // }
 
 pub trait Key: Send {
-    fn short_hash(&self) -> u16;
-    fn filename(&self) -> String;
-}
+    /// Stable 64 bit hash of the key: FNV-1a over the UTF-8 bytes of `filename`.
+    /// It is part of the on-disk layout and of key placement, so it must never change.
+    fn stable_hash(&self) -> u64 {
+        stable_hash(self.filename().as_bytes())
+    }
 
-impl Key for String {
+    /// Low 16 bits of `stable_hash`, used to pick the bucket directory of a key.
     fn short_hash(&self) -> u16 {
-        let mut s = DefaultHasher::new();
-        self.hash(&mut s);
-        s.finish() as u16
+        self.stable_hash() as u16
     }
 
+    fn filename(&self) -> String;
+}
+
+impl Key for String {
     fn filename(&self) -> String {
         self.clone()
     }
 }
 
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// 64 bit FNV-1a, see http://www.isthe.com/chongo/tech/comp/fnv/
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Checks that a key names a path below its bucket directory: not empty, not absolute,
+/// without `.` or `..` segments and without NUL. Every key is checked before it reaches
+/// the store, whatever the layout, so that stores can be migrated between layouts.
+pub fn validate_key(key: &str) -> Result<(), StoreError> {
+    let reason = if key.is_empty() {
+        "empty"
+    } else if key.starts_with('/') {
+        "absolute"
+    } else if key.contains('\0') {
+        "contains NUL"
+    } else if key
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        "contains a . or .. segment"
+    } else {
+        return Ok(());
+    };
+    Err(StoreError::InvalidKey {
+        key: key.to_string(),
+        reason,
+    })
+}
+
+/// Bucket hash of stores written before the layout manifest existed, `DefaultHasher`
+/// over the key string. Only kept to read and migrate those stores.
+pub fn legacy_short_hash(key: &str) -> u16 {
+    let mut s = DefaultHasher::new();
+    key.hash(&mut s);
+    s.finish() as u16
+}
+
+/// Whether a store or loader error means the key does not exist.
+pub fn is_not_found(error: &(dyn Error + Send + Sync + 'static)) -> bool {
+    if let Some(e) = error.downcast_ref::<StoreError>() {
+        return e.is_not_found();
+    }
+    matches!(
+        error.downcast_ref::<std::io::Error>(),
+        Some(e) if e.kind() == ErrorKind::NotFound
+    )
+}
+
 pub trait Value: Send {}
 
 impl Value for Vec<u8> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stable_hash() {
+        // reference values of 64 bit FNV-1a
+        assert_eq!(stable_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(stable_hash(b"foobar"), 0x85944171f73967e8);
+        assert_eq!(String::from("foobar").short_hash(), 0x67e8);
+    }
+
+    #[test]
+    fn test_validate_key() {
+        for key in ["a", "dir/key", "a..b", ".hidden/x", "dir/"] {
+            assert!(validate_key(key).is_ok(), "{}", key);
+        }
+        for key in [
+            "",
+            "/etc/passwd",
+            "../x",
+            "a/../../x",
+            "a/./b",
+            "..",
+            "a\0b",
+        ] {
+            assert!(
+                matches!(validate_key(key), Err(StoreError::InvalidKey { .. })),
+                "{}",
+                key
+            );
+        }
+    }
+}
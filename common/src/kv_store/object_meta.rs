@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, ETAG};
+use serde::{Deserialize, Serialize};
+
+/// Magic bytes starting the metadata header block of a stored value.
+pub const META_MAGIC: &[u8; 4] = b"FRYM";
+/// Magic plus the little endian u32 length of the encoded metadata.
+pub const META_PREFIX_LEN: usize = 8;
+
+pub const ORIGIN_URI_HEADER: &str = "x-fairy-origin-uri";
+pub const VERSION_HEADER: &str = "x-fairy-version";
+/// Version of the delete a 404 answers for, on keys deleted within the tombstone TTL.
+pub const TOMBSTONE_VERSION_HEADER: &str = "x-fairy-tombstone-version";
+pub const USER_HEADER_PREFIX: &str = "x-fairy-meta-";
+pub const EXPIRES_AT_HEADER: &str = "x-fairy-expires-at";
+
+/// Small metadata record persisted in front of every value.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_uri: Option<String>,
+    /// ETag of the object in under storage when it was loaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// Version of the value across replicas, the highest version wins on read repair.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub user_headers: BTreeMap<String, String>,
+    /// Unix time in milliseconds from which the value is treated as missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at_ms: Option<u64>,
+}
+
+impl ObjectMeta {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at_ms.is_some_and(|at| at <= now_ms())
+    }
+
+    pub fn encode_header(&self) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let meta = serde_json::to_vec(self)?;
+        let mut header = Vec::with_capacity(META_PREFIX_LEN + meta.len());
+        header.extend_from_slice(META_MAGIC);
+        header.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+        header.extend_from_slice(&meta);
+        Ok(header)
+    }
+
+    /// Like `encode_header`, padded with whitespace to `len` bytes so that it can replace
+    /// a header block of that length in place. None if the metadata does not fit.
+    pub fn encode_header_padded(
+        &self,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let mut header = self.encode_header()?;
+        if header.len() > len {
+            return Ok(None);
+        }
+        header[4..META_PREFIX_LEN].copy_from_slice(&((len - META_PREFIX_LEN) as u32).to_le_bytes());
+        header.resize(len, b' ');
+        Ok(Some(header))
+    }
+
+    /// Returns the length of the whole header block if `prefix` starts with one.
+    /// Values written before metadata existed have no header block.
+    pub fn header_len(prefix: &[u8]) -> Option<usize> {
+        if prefix.len() < META_PREFIX_LEN || &prefix[..4] != META_MAGIC {
+            return None;
+        }
+        let meta_len = u32::from_le_bytes(prefix[4..META_PREFIX_LEN].try_into().unwrap());
+        Some(META_PREFIX_LEN + meta_len as usize)
+    }
+
+    /// Decodes the header block at the start of `buf`, returning the metadata and the
+    /// offset where the value starts.
+    pub fn decode_header(buf: &[u8]) -> Result<(ObjectMeta, usize), Box<dyn Error + Send + Sync>> {
+        match Self::header_len(buf) {
+            Some(header_len) if header_len <= buf.len() => {
+                let meta = serde_json::from_slice(&buf[META_PREFIX_LEN..header_len])?;
+                Ok((meta, header_len))
+            }
+            Some(header_len) => Err(format!(
+                "truncated metadata header, expected {} bytes, got {}",
+                header_len,
+                buf.len()
+            )
+            .into()),
+            None => Ok((ObjectMeta::default(), 0)),
+        }
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> ObjectMeta {
+        let header_str = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(String::from)
+        };
+        let user_headers = headers
+            .iter()
+            .filter_map(|(name, value)| {
+                let name = name.as_str().strip_prefix(USER_HEADER_PREFIX)?;
+                Some((name.to_string(), value.to_str().ok()?.to_string()))
+            })
+            .collect();
+        ObjectMeta {
+            content_type: header_str(CONTENT_TYPE.as_str()),
+            origin_uri: header_str(ORIGIN_URI_HEADER),
+            etag: header_str(ETAG.as_str()),
+            version: header_str(VERSION_HEADER).and_then(|version| version.parse().ok()),
+            user_headers,
+            expires_at_ms: header_str(EXPIRES_AT_HEADER).and_then(|at| at.parse().ok()),
+        }
+    }
+
+    pub fn to_headers(&self, headers: &mut HeaderMap) {
+        let mut insert = |name: HeaderName, value: &str| {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+        };
+        if let Some(content_type) = &self.content_type {
+            insert(CONTENT_TYPE, content_type);
+        }
+        if let Some(origin_uri) = &self.origin_uri {
+            insert(HeaderName::from_static(ORIGIN_URI_HEADER), origin_uri);
+        }
+        if let Some(etag) = &self.etag {
+            insert(ETAG, etag);
+        }
+        if let Some(version) = self.version {
+            insert(
+                HeaderName::from_static(VERSION_HEADER),
+                &version.to_string(),
+            );
+        }
+        if let Some(expires_at_ms) = self.expires_at_ms {
+            insert(
+                HeaderName::from_static(EXPIRES_AT_HEADER),
+                &expires_at_ms.to_string(),
+            );
+        }
+        for (name, value) in self.user_headers.iter() {
+            if let Ok(name) = HeaderName::try_from(format!("{}{}", USER_HEADER_PREFIX, name)) {
+                insert(name, value);
+            }
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Microseconds since the Unix epoch, the scale of versions.
+pub fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_meta() -> ObjectMeta {
+        ObjectMeta {
+            content_type: Some(String::from("application/octet-stream")),
+            origin_uri: Some(String::from("s3://bucket/models/a.bin")),
+            etag: Some(String::from("\"abc\"")),
+            version: Some(42),
+            user_headers: BTreeMap::from([(String::from("owner"), String::from("ml"))]),
+            expires_at_ms: Some(1_700_000_000_000),
+        }
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let meta = sample_meta();
+        let mut buf = meta.encode_header().unwrap();
+        let header_len = buf.len();
+        buf.extend_from_slice(b"value");
+
+        assert_eq!(ObjectMeta::header_len(&buf), Some(header_len));
+        let (decoded, offset) = ObjectMeta::decode_header(&buf).unwrap();
+        assert_eq!(decoded, meta);
+        assert_eq!(&buf[offset..], b"value");
+    }
+
+    #[test]
+    fn test_padded_header() {
+        let meta = sample_meta();
+        let len = meta.encode_header().unwrap().len();
+        let mut buf = ObjectMeta::default()
+            .encode_header_padded(len)
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf.len(), len);
+        buf.extend_from_slice(b"value");
+        let (decoded, offset) = ObjectMeta::decode_header(&buf).unwrap();
+        assert_eq!(
+            (decoded, &buf[offset..]),
+            (ObjectMeta::default(), &b"value"[..])
+        );
+        assert!(meta.encode_header_padded(len - 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_value_without_header() {
+        let (meta, offset) = ObjectMeta::decode_header(b"raw bytes").unwrap();
+        assert_eq!(meta, ObjectMeta::default());
+        assert_eq!(offset, 0);
+    }
+
+    #[test]
+    fn test_http_headers_round_trip() {
+        let meta = sample_meta();
+        let mut headers = HeaderMap::new();
+        meta.to_headers(&mut headers);
+        assert_eq!(headers.get("x-fairy-meta-owner").unwrap(), "ml");
+        assert_eq!(headers.get(VERSION_HEADER).unwrap(), "42");
+        assert_eq!(ObjectMeta::from_headers(&headers), meta);
+    }
+}
@@ -0,0 +1,214 @@
+use std::error::Error;
+use std::io::ErrorKind;
+
+use bytes::Bytes;
+use log::{debug, warn};
+
+use crate::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
+use crate::kv_store::object_meta::ObjectMeta;
+use crate::kv_store::store_error::StoreError;
+use crate::peer_fetch::PeerFetcher;
+use crate::rebalance::Rebalancer;
+use crate::ufs::local_ufs::LocalUfs;
+
+/// Result of pulling a single key through the loader.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoadOutcome {
+    /// The key was already in the local store, nothing was read from ufs.
+    Cached,
+    /// The key was read from a peer or from ufs and written to the local store.
+    Loaded(usize),
+}
+
+/// The value of a key from the local store. A miss is served from the old owner while
+/// the key is being handed over, then read through the loader. Expired values are
+/// reported as missing.
+pub async fn lookup(
+    kv_store: &LocalFileKVStore,
+    rebalancer: Option<&Rebalancer>,
+    loader: Option<&ReadThroughLoader>,
+    key: &str,
+) -> Result<(ObjectMeta, Bytes), StoreError> {
+    let (meta, value) = match kv_store.get_bytes(key.to_string()).await {
+        Ok(found) => found,
+        Err(e) if !e.is_not_found() => return Err(e),
+        Err(e) => {
+            let pending = match rebalancer {
+                Some(rebalancer) => rebalancer.fetch_pending(key).await?,
+                None => None,
+            };
+            match (pending, loader) {
+                (Some(found), _) => found,
+                (None, Some(loader)) => {
+                    let (meta, value) = loader.get(key.to_string()).await?;
+                    (meta, Bytes::from(value))
+                }
+                (None, None) => return Err(e),
+            }
+        }
+    };
+    if meta.is_expired() {
+        return Err(StoreError::NotFound(key.to_string()));
+    }
+    Ok((meta, value))
+}
+
+/// Serves keys from the local store, falling back to peers and then to the under file
+/// system on miss and caching what it reads. Keys are the object paths relative to the
+/// ufs root.
+pub struct ReadThroughLoader {
+    kv_store: &'static LocalFileKVStore,
+    ufs: Option<LocalUfs>,
+    peers: Option<&'static PeerFetcher>,
+}
+
+impl ReadThroughLoader {
+    pub fn new(kv_store: &'static LocalFileKVStore, ufs: Option<LocalUfs>) -> Self {
+        ReadThroughLoader {
+            kv_store,
+            ufs,
+            peers: None,
+        }
+    }
+
+    /// Keys missing locally are looked up on previous owners and replicas before ufs.
+    pub fn with_peers(mut self, peers: &'static PeerFetcher) -> Self {
+        self.peers = Some(peers);
+        self
+    }
+
+    pub fn ufs(&self) -> Result<&LocalUfs, Box<dyn Error + Send + Sync>> {
+        self.ufs
+            .as_ref()
+            .ok_or_else(|| "under file system is not configured".into())
+    }
+
+    pub async fn get(
+        &self,
+        key: String,
+    ) -> Result<(ObjectMeta, Vec<u8>), Box<dyn Error + Send + Sync>> {
+        if self.kv_store.exists(key.clone()) {
+            let (meta, buf) = self.kv_store.get_with_meta(key.clone()).await?;
+            if self.is_fresh(&key, &meta) {
+                return Ok((meta, buf));
+            }
+        } else if let Some((meta, buf)) = self.fetch_from_peers(&key).await? {
+            return Ok((meta, buf.to_vec()));
+        }
+        self.fetch(key).await
+    }
+
+    pub async fn load(&self, key: String) -> Result<LoadOutcome, Box<dyn Error + Send + Sync>> {
+        if self.kv_store.exists(key.clone()) {
+            let (meta, _) = self.kv_store.head(key.clone()).await?;
+            if self.is_fresh(&key, &meta) {
+                return Ok(LoadOutcome::Cached);
+            }
+        } else if let Some((_, buf)) = self.fetch_from_peers(&key).await? {
+            return Ok(LoadOutcome::Loaded(buf.len()));
+        }
+        let (_, buf) = self.fetch(key).await?;
+        Ok(LoadOutcome::Loaded(buf.len()))
+    }
+
+    /// A cached copy is stale when it came from ufs and the ufs etag has changed since.
+    /// Values written directly to the store, or whose origin is gone, are kept.
+    fn is_fresh(&self, key: &str, meta: &ObjectMeta) -> bool {
+        let (Some(ufs), Some(etag)) = (self.ufs.as_ref(), meta.etag.as_ref()) else {
+            return true;
+        };
+        if meta.origin_uri.is_none() {
+            return true;
+        }
+        match ufs.stat(key) {
+            Ok(status) if &status.etag != etag => {
+                debug!("Cached {} is stale, etag {} -> {}", key, etag, status.etag);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Caches a fresh copy found on a peer, a stale one is left for ufs to replace.
+    /// The copy is served even when caching it fails.
+    async fn fetch_from_peers(
+        &self,
+        key: &str,
+    ) -> Result<Option<(ObjectMeta, Bytes)>, Box<dyn Error + Send + Sync>> {
+        let Some(peers) = self.peers else {
+            return Ok(None);
+        };
+        match peers.fetch(key).await {
+            Some((meta, buf)) if self.is_fresh(key, &meta) => {
+                if let Err(e) = self
+                    .kv_store
+                    .put_with_meta(key.to_string(), buf.clone(), &meta)
+                    .await
+                {
+                    warn!("Failed to cache {} fetched from a peer: {}", key, e);
+                }
+                Ok(Some((meta, buf)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn fetch(
+        &self,
+        key: String,
+    ) -> Result<(ObjectMeta, Vec<u8>), Box<dyn Error + Send + Sync>> {
+        let Some(ufs) = self.ufs.as_ref() else {
+            let message = format!("{} not found and no under file system is configured", key);
+            return Err(std::io::Error::new(ErrorKind::NotFound, message).into());
+        };
+        let status = ufs.stat(&key)?;
+        let buf = ufs.read(&key).await?;
+        let meta = ObjectMeta {
+            origin_uri: Some(status.uri),
+            etag: Some(status.etag),
+            ..Default::default()
+        };
+        self.kv_store
+            .put_with_meta(key.clone(), Bytes::from(buf.clone()), &meta)
+            .await?;
+        debug!("Loaded {} ({} bytes) from ufs", key, buf.len());
+        Ok((meta, buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::local_kv_options::StoreLayout;
+    use crate::test_util;
+
+    #[monoio::test]
+    async fn test_reload_when_ufs_changes() {
+        let store_dir = tempfile::tempdir().unwrap();
+        let ufs_dir = tempfile::tempdir().unwrap();
+        let kv_store = test_util::store(store_dir.path(), StoreLayout::FilePerKey);
+        let loader = ReadThroughLoader::new(
+            kv_store,
+            Some(LocalUfs::new(ufs_dir.path().to_string_lossy().to_string())),
+        );
+        std::fs::write(ufs_dir.path().join("a"), b"v1").unwrap();
+
+        assert_eq!(
+            loader.load(String::from("a")).await.unwrap(),
+            LoadOutcome::Loaded(2)
+        );
+        assert_eq!(
+            loader.load(String::from("a")).await.unwrap(),
+            LoadOutcome::Cached
+        );
+
+        std::fs::write(ufs_dir.path().join("a"), b"v2 is longer").unwrap();
+        let (meta, buf) = loader.get(String::from("a")).await.unwrap();
+        assert_eq!(buf, b"v2 is longer");
+        assert!(meta.origin_uri.unwrap().ends_with("/a"));
+        assert_eq!(
+            loader.load(String::from("a")).await.unwrap(),
+            LoadOutcome::Cached
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use std::error::Error;
+use std::io::ErrorKind;
+
+use thiserror::Error;
+
+/// Failures of the local store, told apart so that services can answer with a
+/// matching status.
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("{0} not found")]
+    NotFound(String),
+    /// The key can't name a file under the storage directory, see `validate_key`.
+    #[error("invalid key {key:?}: {reason}")]
+    InvalidKey { key: String, reason: &'static str },
+    /// The value can't be stored, however much is evicted.
+    #[error("{size} bytes of {key} exceed the limit of {limit} bytes")]
+    TooLarge { key: String, size: u64, limit: u64 },
+    /// Nothing more can be evicted for the value, or the disk is full.
+    #[error("no space left: {0}")]
+    Full(String),
+    #[error(transparent)]
+    Io(std::io::Error),
+    #[error(transparent)]
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl StoreError {
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            StoreError::NotFound(_) => true,
+            StoreError::Io(e) => e.kind() == ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(error: std::io::Error) -> Self {
+        if error.raw_os_error() == Some(libc::ENOSPC) {
+            StoreError::Full(error.to_string())
+        } else {
+            StoreError::Io(error)
+        }
+    }
+}
+
+/// Keeps the kind of a boxed store or io error.
+impl From<Box<dyn Error + Send + Sync>> for StoreError {
+    fn from(error: Box<dyn Error + Send + Sync>) -> Self {
+        let error = match error.downcast::<StoreError>() {
+            Ok(error) => return *error,
+            Err(error) => error,
+        };
+        match error.downcast::<std::io::Error>() {
+            Ok(error) => StoreError::from(*error),
+            Err(error) => StoreError::Other(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boxed_errors_keep_their_kind() {
+        let boxed: Box<dyn Error + Send + Sync> = Box::new(StoreError::NotFound("key".into()));
+        assert!(StoreError::from(boxed).is_not_found());
+        let boxed: Box<dyn Error + Send + Sync> =
+            Box::new(std::io::Error::from(ErrorKind::NotFound));
+        assert!(StoreError::from(boxed).is_not_found());
+        let full = std::io::Error::from_raw_os_error(libc::ENOSPC);
+        assert!(matches!(StoreError::from(full), StoreError::Full(_)));
+        let boxed: Box<dyn Error + Send + Sync> = "broken".into();
+        assert!(matches!(StoreError::from(boxed), StoreError::Other(_)));
+    }
+}
@@ -0,0 +1,608 @@
+use std::rc::Rc;
+
+use bytes::{Bytes, BytesMut};
+use log::error;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
+use crate::kv_store::object_meta::{now_ms, ObjectMeta};
+use crate::kv_store::read_through::{self, ReadThroughLoader};
+use crate::kv_store::stable_hash;
+use crate::memcache::protocol::{parse_command, Command, MemcacheError, MetaFlags, StoreMode};
+use crate::metrics::{INCOMING_REQUESTS, RESPONSE_TIME_COLLECTOR};
+use crate::pipeline::{self, CommandHandler, ReplyBuffer};
+use crate::rebalance::Rebalancer;
+use crate::shutdown::Shutdown;
+
+/// User metadata entry holding the client flags of a value, absent when 0.
+pub const FLAGS_META: &str = "memcached-flags";
+/// Expiry times up to 30 days are relative, larger ones are Unix times.
+const MAX_RELATIVE_EXPTIME: i64 = 30 * 24 * 60 * 60;
+/// Stripes of the locks serializing the writes of a key.
+const WRITE_LOCKS: usize = 64;
+
+/// Result of a conditional write or delete.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    /// Stored with the new CAS value.
+    Stored(u64),
+    NotStored,
+    /// The CAS value did not match.
+    Exists,
+    NotFound,
+    Deleted,
+}
+
+/// Serves the memcached text commands get, gets, set, add, cas, delete and touch, and
+/// the meta commands mg, ms, md and mn. CAS values are the versions of the values, every
+/// write through this service gives a key a new version. Writes of a key are serialized
+/// so that comparing and writing is atomic among them, writes through the other services
+/// do not take part.
+#[derive(Clone)]
+pub struct MemcacheService {
+    kv_store: &'static LocalFileKVStore,
+    addr: &'static str,
+    rebalancer: Option<&'static Rebalancer>,
+    loader: Option<&'static ReadThroughLoader>,
+    shutdown: Option<&'static Shutdown>,
+    write_locks: Rc<Vec<Mutex<()>>>,
+}
+
+impl MemcacheService {
+    pub fn new(kv_store: &'static LocalFileKVStore, addr: &'static str) -> MemcacheService {
+        MemcacheService {
+            kv_store,
+            addr,
+            rebalancer: None,
+            loader: None,
+            shutdown: None,
+            write_locks: Rc::new((0..WRITE_LOCKS).map(|_| Mutex::new(())).collect()),
+        }
+    }
+
+    pub fn with_rebalancer(mut self, rebalancer: &'static Rebalancer) -> Self {
+        self.rebalancer = Some(rebalancer);
+        self
+    }
+
+    pub fn with_loader(mut self, loader: &'static ReadThroughLoader) -> Self {
+        self.loader = Some(loader);
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: &'static Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Serves until the shutdown is triggered.
+    pub async fn serve(&self) {
+        pipeline::serve("memcached", self.addr, self.shutdown, self.clone()).await;
+    }
+
+    async fn execute(&self, command: Command, out: &mut ReplyBuffer) {
+        let _tracked = self.shutdown.map(|shutdown| shutdown.track());
+        let _timer = RESPONSE_TIME_COLLECTOR.start_timer();
+        INCOMING_REQUESTS.inc();
+        let result = match command {
+            Command::Get { keys, cas } => self.get(keys, cas, out).await,
+            Command::Store {
+                mode,
+                key,
+                flags,
+                exptime,
+                cas,
+                value,
+                noreply,
+            } => {
+                let outcome = self.store(&key, value, mode, flags, exptime, cas).await;
+                outcome.map(|outcome| {
+                    let reply = match outcome {
+                        Outcome::Stored(_) => "STORED",
+                        Outcome::NotStored => "NOT_STORED",
+                        Outcome::Exists => "EXISTS",
+                        Outcome::NotFound | Outcome::Deleted => "NOT_FOUND",
+                    };
+                    if !noreply {
+                        out.line(reply);
+                    }
+                })
+            }
+            Command::Delete { key, noreply } => self.delete(&key, None).await.map(|outcome| {
+                if !noreply {
+                    out.line(match outcome {
+                        Outcome::Deleted => "DELETED",
+                        _ => "NOT_FOUND",
+                    });
+                }
+            }),
+            Command::Touch {
+                key,
+                exptime,
+                noreply,
+            } => self.touch(&key, exptime).await.map(|touched| {
+                if !noreply {
+                    out.line(if touched { "TOUCHED" } else { "NOT_FOUND" });
+                }
+            }),
+            Command::MetaGet { key, flags } => self.meta_get(&key, &flags, out).await,
+            Command::MetaSet { key, value, flags } => self.meta_set(&key, value, &flags, out).await,
+            Command::MetaDelete { key, flags } => self.meta_delete(&key, &flags, out).await,
+            Command::MetaNoop => {
+                out.line("MN");
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            if matches!(e, MemcacheError::Store(_)) {
+                error!("memcached command failed: {}", e);
+            }
+            out.line(&e.to_string());
+        }
+    }
+
+    async fn get(
+        &self,
+        keys: Vec<String>,
+        cas: bool,
+        out: &mut ReplyBuffer,
+    ) -> Result<(), MemcacheError> {
+        for key in keys {
+            let Some((meta, value)) = self.lookup(&key).await? else {
+                continue;
+            };
+            let mut line = format!("VALUE {} {} {}", key, flags(&meta), value.len());
+            if cas {
+                line.push_str(&format!(" {}", meta.version.unwrap_or(0)));
+            }
+            out.line(&line);
+            out.value(value);
+            out.line("");
+        }
+        out.line("END");
+        Ok(())
+    }
+
+    async fn meta_get(
+        &self,
+        key: &str,
+        flags: &MetaFlags,
+        out: &mut ReplyBuffer,
+    ) -> Result<(), MemcacheError> {
+        if let Some(exptime) = flags.number(b'T')? {
+            self.touch(key, exptime).await?;
+        }
+        let Some((meta, value)) = self.lookup(key).await? else {
+            if !flags.has(b'q') {
+                out.line("EN");
+            }
+            return Ok(());
+        };
+        let mut returned = Vec::new();
+        for (flag, token) in flags.iter() {
+            match flag {
+                b'c' => returned.push(format!("c{}", meta.version.unwrap_or(0))),
+                b'f' => returned.push(format!("f{}", self::flags(&meta))),
+                b's' => returned.push(format!("s{}", value.len())),
+                b't' => returned.push(format!("t{}", ttl(&meta))),
+                b'k' => returned.push(format!("k{}", key)),
+                b'O' => returned.push(format!("O{}", token)),
+                _ => {}
+            }
+        }
+        if flags.has(b'v') {
+            out.line(&meta_line(&format!("VA {}", value.len()), &returned));
+            out.value(value);
+            out.line("");
+        } else {
+            out.line(&meta_line("HD", &returned));
+        }
+        Ok(())
+    }
+
+    async fn meta_set(
+        &self,
+        key: &str,
+        value: Bytes,
+        flags: &MetaFlags,
+        out: &mut ReplyBuffer,
+    ) -> Result<(), MemcacheError> {
+        let mode = match flags.token(b'M') {
+            None | Some("S" | "s") => StoreMode::Set,
+            Some("E" | "e") => StoreMode::Add,
+            Some("R" | "r") => StoreMode::Replace,
+            Some(_) => {
+                return Err(MemcacheError::Client(String::from("invalid mode for ms")));
+            }
+        };
+        let client_flags = flags.number(b'F')?.unwrap_or(0);
+        let exptime = flags.number(b'T')?.unwrap_or(0);
+        let cas = flags.number(b'C')?;
+        let outcome = self
+            .store(key, value, mode, client_flags, exptime, cas)
+            .await?;
+        let (code, version) = match outcome {
+            Outcome::Stored(_) if flags.has(b'q') => return Ok(()),
+            Outcome::Stored(version) => ("HD", Some(version)),
+            Outcome::NotStored => ("NS", None),
+            Outcome::Exists => ("EX", None),
+            Outcome::NotFound | Outcome::Deleted => ("NF", None),
+        };
+        let mut returned = meta_returned(key, flags);
+        if let (Some(version), true) = (version, flags.has(b'c')) {
+            returned.push(format!("c{}", version));
+        }
+        out.line(&meta_line(code, &returned));
+        Ok(())
+    }
+
+    async fn meta_delete(
+        &self,
+        key: &str,
+        flags: &MetaFlags,
+        out: &mut ReplyBuffer,
+    ) -> Result<(), MemcacheError> {
+        let code = match self.delete(key, flags.number(b'C')?).await? {
+            Outcome::Exists => "EX",
+            _ if flags.has(b'q') => return Ok(()),
+            Outcome::Deleted => "HD",
+            _ => "NF",
+        };
+        out.line(&meta_line(code, &meta_returned(key, flags)));
+        Ok(())
+    }
+
+    /// A miss is served from the old owner while the key is being handed over, then
+    /// read through the loader.
+    async fn lookup(&self, key: &str) -> Result<Option<(ObjectMeta, Bytes)>, MemcacheError> {
+        match read_through::lookup(self.kv_store, self.rebalancer, self.loader, key).await {
+            Ok(found) => Ok(Some(found)),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn store(
+        &self,
+        key: &str,
+        value: Bytes,
+        mode: StoreMode,
+        client_flags: u32,
+        exptime: i64,
+        cas: Option<u64>,
+    ) -> Result<Outcome, MemcacheError> {
+        let _lock = self.lock(key).await;
+        let current = self.stat(key).await?;
+        let outcome = match (mode, &current, cas) {
+            (StoreMode::Add, Some(_), _) | (StoreMode::Replace, None, _) => {
+                Some(Outcome::NotStored)
+            }
+            (_, None, Some(_)) => Some(Outcome::NotFound),
+            (_, Some(meta), Some(cas)) if meta.version.unwrap_or(0) != cas => Some(Outcome::Exists),
+            _ => None,
+        };
+        if let Some(outcome) = outcome {
+            return Ok(outcome);
+        }
+        let version = next_version(current.as_ref());
+        let mut meta = ObjectMeta {
+            version: Some(version),
+            expires_at_ms: expires_at_ms(exptime),
+            ..Default::default()
+        };
+        if client_flags != 0 {
+            meta.user_headers
+                .insert(FLAGS_META.to_string(), client_flags.to_string());
+        }
+        self.kv_store
+            .put_with_meta(key.to_string(), value, &meta)
+            .await?;
+        Ok(Outcome::Stored(version))
+    }
+
+    async fn delete(&self, key: &str, cas: Option<u64>) -> Result<Outcome, MemcacheError> {
+        let _lock = self.lock(key).await;
+        let outcome = match (self.stat(key).await?, cas) {
+            (None, _) => Outcome::NotFound,
+            (Some(meta), Some(cas)) if meta.version.unwrap_or(0) != cas => Outcome::Exists,
+            (Some(_), _) => {
+                self.kv_store.delete(key.to_string())?;
+                Outcome::Deleted
+            }
+        };
+        Ok(outcome)
+    }
+
+    /// Sets a new expiry time, keeping the version. False for a missing key.
+    async fn touch(&self, key: &str, exptime: i64) -> Result<bool, MemcacheError> {
+        let _lock = self.lock(key).await;
+        let Some(mut meta) = self.stat(key).await? else {
+            return Ok(false);
+        };
+        meta.expires_at_ms = expires_at_ms(exptime);
+        match self.kv_store.update_meta(key.to_string(), &meta).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.is_not_found() => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The metadata of a locally stored key.
+    async fn stat(&self, key: &str) -> Result<Option<ObjectMeta>, MemcacheError> {
+        match self.kv_store.head(key.to_string()).await {
+            Ok((meta, _)) => Ok(Some(meta)),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        let stripe = stable_hash(key.as_bytes()) as usize % WRITE_LOCKS;
+        self.write_locks[stripe].lock().await
+    }
+}
+
+impl CommandHandler for MemcacheService {
+    type Error = MemcacheError;
+
+    async fn handle(
+        &self,
+        input: &mut BytesMut,
+        out: &mut ReplyBuffer,
+    ) -> Result<(), MemcacheError> {
+        loop {
+            match parse_command(input) {
+                Ok(Some(command)) => self.execute(command, out).await,
+                Ok(None) => return Ok(()),
+                Err(e @ MemcacheError::Fatal(_)) => {
+                    out.line(e.to_string());
+                    return Err(e);
+                }
+                Err(e) => out.line(e.to_string()),
+            }
+        }
+    }
+}
+
+/// Versions are microseconds since the epoch like those written by the client, and
+/// grow with every write so that each gets a new CAS value.
+fn next_version(current: Option<&ObjectMeta>) -> u64 {
+    let previous = current.and_then(|meta| meta.version).unwrap_or(0);
+    now_ms()
+        .saturating_mul(1000)
+        .max(previous.saturating_add(1))
+}
+
+/// Negative expiry times expire right away, 0 never does. Times too far out for
+/// milliseconds are clamped to the largest one.
+fn expires_at_ms(exptime: i64) -> Option<u64> {
+    match exptime {
+        0 => None,
+        exptime if exptime < 0 => Some(0),
+        exptime if exptime <= MAX_RELATIVE_EXPTIME => {
+            Some(now_ms().saturating_add(exptime as u64 * 1000))
+        }
+        exptime => Some((exptime as u64).saturating_mul(1000)),
+    }
+}
+
+fn flags(meta: &ObjectMeta) -> u32 {
+    meta.user_headers
+        .get(FLAGS_META)
+        .and_then(|flags| flags.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Seconds to live rounded to the closest second, -1 without an expiry.
+fn ttl(meta: &ObjectMeta) -> i64 {
+    match meta.expires_at_ms {
+        None => -1,
+        Some(at) => (at.saturating_sub(now_ms()).saturating_add(500) / 1000) as i64,
+    }
+}
+
+/// The key and opaque flags returned by every meta command that asked for them.
+fn meta_returned(key: &str, flags: &MetaFlags) -> Vec<String> {
+    flags
+        .iter()
+        .filter_map(|(flag, token)| match flag {
+            b'k' => Some(format!("k{}", key)),
+            b'O' => Some(format!("O{}", token)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn meta_line(code: &str, returned: &[String]) -> String {
+    let mut line = code.to_string();
+    for flag in returned {
+        line.push(' ');
+        line.push_str(flag);
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use monoio::io::{AsyncReadRentExt, AsyncWriteRentExt};
+    use monoio::net::TcpStream;
+
+    use super::*;
+    use crate::settings::local_kv_options::StoreLayout;
+    use crate::test_util::{self, leak};
+
+    async fn serve(layout: StoreLayout) -> (&'static str, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = test_util::store(dir.path(), layout);
+        let addr =
+            test_util::serve_on_free_port(|addr| leak(MemcacheService::new(store, addr)).serve())
+                .await;
+        (addr, dir)
+    }
+
+    /// Sends the commands at once and checks the replies.
+    async fn check(stream: &mut TcpStream, commands: &str, expected: &str) {
+        let (res, _) = stream.write_all(commands.as_bytes().to_vec()).await;
+        res.unwrap();
+        let (res, replies) = stream.read_exact(Vec::with_capacity(expected.len())).await;
+        res.unwrap();
+        assert_eq!(String::from_utf8(replies).unwrap(), expected);
+    }
+
+    async fn read_line(stream: &mut TcpStream) -> String {
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            let (res, byte) = stream.read_exact(Vec::with_capacity(1)).await;
+            res.unwrap();
+            line.extend_from_slice(&byte);
+        }
+        line.truncate(line.len() - 2);
+        String::from_utf8(line).unwrap()
+    }
+
+    /// The CAS value of a key, as the last token of the answer to the command.
+    async fn cas_of(stream: &mut TcpStream, command: &str) -> u64 {
+        let (res, _) = stream.write_all(command.as_bytes().to_vec()).await;
+        res.unwrap();
+        let line = read_line(stream).await;
+        let cas = line.rsplit(' ').next().unwrap();
+        cas.trim_start_matches('c').parse().unwrap()
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_text_commands() {
+        for layout in [StoreLayout::FilePerKey, StoreLayout::Segment] {
+            let (addr, _dir) = serve(layout).await;
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            check(&mut stream, "get key\r\n", "END\r\n").await;
+            check(
+                &mut stream,
+                "set key 7 0 5\r\nvalue\r\nget key other\r\nadd key 0 0 1\r\nx\r\nadd b 0 0 1 noreply\r\ny\r\nget b\r\n",
+                "STORED\r\nVALUE key 7 5\r\nvalue\r\nEND\r\nNOT_STORED\r\nVALUE b 0 1\r\ny\r\nEND\r\n",
+            )
+            .await;
+
+            let cas = cas_of(&mut stream, "gets key\r\n").await;
+            check(&mut stream, "", "value\r\nEND\r\n").await;
+            check(
+                &mut stream,
+                &format!(
+                    "cas key 0 0 2 {}\r\nv2\r\ncas missing 0 0 1 1\r\nx\r\n",
+                    cas + 1
+                ),
+                "EXISTS\r\nNOT_FOUND\r\n",
+            )
+            .await;
+            check(
+                &mut stream,
+                &format!(
+                    "cas key 0 0 2 {}\r\nv2\r\ncas key 0 0 2 {}\r\nv3\r\n",
+                    cas, cas
+                ),
+                "STORED\r\nEXISTS\r\n",
+            )
+            .await;
+            assert!(cas_of(&mut stream, "gets key\r\n").await > cas);
+            check(&mut stream, "", "v2\r\nEND\r\n").await;
+
+            check(
+                &mut stream,
+                "touch key 100\r\ntouch missing 100\r\ndelete key\r\ndelete key\r\nget key\r\n",
+                "TOUCHED\r\nNOT_FOUND\r\nDELETED\r\nNOT_FOUND\r\nEND\r\n",
+            )
+            .await;
+            check(
+                &mut stream,
+                "incr b 1\r\nset b x 0 1\r\nv\r\nget b\r\n",
+                "ERROR\r\nCLIENT_ERROR bad command line format\r\nVALUE b 0 1\r\ny\r\nEND\r\n",
+            )
+            .await;
+            check(
+                &mut stream,
+                "set /etc/passwd 0 0 1\r\nx\r\nget ../x\r\nmd a/../../x\r\nmn\r\n",
+                "CLIENT_ERROR invalid key \"/etc/passwd\": absolute\r\n\
+                 CLIENT_ERROR invalid key \"../x\": contains a . or .. segment\r\n\
+                 CLIENT_ERROR invalid key \"a/../../x\": contains a . or .. segment\r\nMN\r\n",
+            )
+            .await;
+        }
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_meta_commands() {
+        let (addr, _dir) = serve(StoreLayout::FilePerKey).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        check(
+            &mut stream,
+            "mg key v\r\nmg key v q\r\nmn\r\n",
+            "EN\r\nMN\r\n",
+        )
+        .await;
+        check(
+            &mut stream,
+            "ms key 5 F3 T100 Oabc k\r\nvalue\r\nms key 1 ME\r\nx\r\nms other 1 MR q\r\nx\r\n",
+            "HD Oabc kkey\r\nNS\r\nNS\r\n",
+        )
+        .await;
+        check(
+            &mut stream,
+            "mg key v f s t k\r\n",
+            "VA 5 f3 s5 t100 kkey\r\nvalue\r\n",
+        )
+        .await;
+
+        let cas = cas_of(&mut stream, "mg key c\r\n").await;
+        check(
+            &mut stream,
+            &format!("ms key 2 C{}\r\nv2\r\nmd key C{}\r\n", cas + 1, cas),
+            "EX\r\nHD\r\n",
+        )
+        .await;
+        check(
+            &mut stream,
+            "md key q\r\nmd key\r\nmg key X\r\nmn\r\n",
+            "NF\r\nCLIENT_ERROR invalid flag X\r\nMN\r\n",
+        )
+        .await;
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_values_expire() {
+        let (addr, _dir) = serve(StoreLayout::Segment).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        check(
+            &mut stream,
+            "set key 0 1 5\r\nvalue\r\nset gone 0 -1 1\r\nx\r\nget key gone\r\n",
+            "STORED\r\nSTORED\r\nVALUE key 0 5\r\nvalue\r\nEND\r\n",
+        )
+        .await;
+        monoio::time::sleep(Duration::from_millis(1100)).await;
+        check(
+            &mut stream,
+            "get key\r\ntouch key 10\r\n",
+            "END\r\nNOT_FOUND\r\n",
+        )
+        .await;
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_touch_and_far_expiry() {
+        let (addr, _dir) = serve(StoreLayout::FilePerKey).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        check(
+            &mut stream,
+            "set key 3 100 5\r\nvalue\r\ntouch key 200\r\ntouch missing 10\r\nget key\r\n",
+            "STORED\r\nTOUCHED\r\nNOT_FOUND\r\nVALUE key 3 5\r\nvalue\r\nEND\r\n",
+        )
+        .await;
+        check(
+            &mut stream,
+            "set far 0 9223372036854775807 1\r\nx\r\ntouch key 9223372036854775807\r\nget far\r\n",
+            "STORED\r\nTOUCHED\r\nVALUE far 0 1\r\nx\r\nEND\r\n",
+        )
+        .await;
+        assert_eq!(expires_at_ms(i64::MAX), Some(u64::MAX));
+    }
+}
@@ -0,0 +1,2 @@
+pub mod memcache_service;
+pub mod protocol;
@@ -0,0 +1,324 @@
+use bytes::{Buf, Bytes, BytesMut};
+use thiserror::Error;
+
+use crate::h2::h2_service::MAX_PUT_LEN;
+use crate::kv_store::store_error::StoreError;
+use crate::kv_store::validate_key;
+
+/// Longest key accepted, as in memcached.
+pub const MAX_KEY_LEN: usize = 250;
+/// Longest command line.
+const MAX_LINE_LEN: usize = 8 * 1024;
+
+#[derive(Debug, Error)]
+pub enum MemcacheError {
+    #[error("ERROR")]
+    UnknownCommand,
+    /// The command is answered with the message and the connection goes on.
+    #[error("CLIENT_ERROR {0}")]
+    Client(String),
+    /// The input cannot be framed anymore, the connection is closed after the message.
+    #[error("CLIENT_ERROR {0}")]
+    Fatal(String),
+    #[error("SERVER_ERROR {0}")]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+fn client_error(message: &str) -> MemcacheError {
+    MemcacheError::Client(message.to_string())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreMode {
+    /// Stores unconditionally.
+    Set,
+    /// Stores only if the key is missing.
+    Add,
+    /// Stores only if the key exists.
+    Replace,
+}
+
+/// A command of the text or the meta protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// `get`, or `gets` returning the CAS values.
+    Get {
+        keys: Vec<String>,
+        cas: bool,
+    },
+    /// `set`, `add` and `cas`.
+    Store {
+        mode: StoreMode,
+        key: String,
+        flags: u32,
+        exptime: i64,
+        cas: Option<u64>,
+        value: Bytes,
+        noreply: bool,
+    },
+    Delete {
+        key: String,
+        noreply: bool,
+    },
+    Touch {
+        key: String,
+        exptime: i64,
+        noreply: bool,
+    },
+    /// `mg`
+    MetaGet {
+        key: String,
+        flags: MetaFlags,
+    },
+    /// `ms`
+    MetaSet {
+        key: String,
+        value: Bytes,
+        flags: MetaFlags,
+    },
+    /// `md`
+    MetaDelete {
+        key: String,
+        flags: MetaFlags,
+    },
+    /// `mn`, answered in order to mark the end of pipelined quiet commands.
+    MetaNoop,
+}
+
+/// Flags of a meta command, a letter optionally followed by a token, e.g. `T30`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetaFlags(Vec<(u8, String)>);
+
+impl MetaFlags {
+    fn parse(tokens: &[&str], supported: &[u8]) -> Result<MetaFlags, MemcacheError> {
+        let mut flags = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            let flag = token.as_bytes()[0];
+            if !supported.contains(&flag) {
+                return Err(MemcacheError::Client(format!(
+                    "invalid flag {}",
+                    flag as char
+                )));
+            }
+            flags.push((flag, token[1..].to_string()));
+        }
+        Ok(MetaFlags(flags))
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.0.iter().any(|(f, _)| *f == flag)
+    }
+
+    pub fn token(&self, flag: u8) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(f, _)| *f == flag)
+            .map(|(_, token)| token.as_str())
+    }
+
+    pub fn number<T: std::str::FromStr>(&self, flag: u8) -> Result<Option<T>, MemcacheError> {
+        self.token(flag)
+            .map(|token| {
+                token
+                    .parse()
+                    .map_err(|_| client_error("bad token in command line format"))
+            })
+            .transpose()
+    }
+
+    /// The flags to return, in the order they were asked for.
+    pub fn iter(&self) -> impl Iterator<Item = &(u8, String)> {
+        self.0.iter()
+    }
+}
+
+/// Takes the next command off the start of `buf`, None until it is complete. Values
+/// are views into `buf`.
+pub fn parse_command(buf: &mut BytesMut) -> Result<Option<Command>, MemcacheError> {
+    let Some(line_len) = buf.iter().position(|&b| b == b'\n') else {
+        if buf.len() > MAX_LINE_LEN {
+            return Err(MemcacheError::Fatal(String::from("line too long")));
+        }
+        return Ok(None);
+    };
+    let line = String::from_utf8_lossy(&buf[..line_len]).into_owned();
+    let tokens: Vec<&str> = line.split_ascii_whitespace().collect();
+    let data_len = match tokens.as_slice() {
+        ["set" | "add" | "cas", _, _, _, len, ..] | ["ms", _, len, ..] => {
+            match len.parse::<usize>() {
+                Ok(len) if len <= MAX_PUT_LEN => Some(len),
+                Ok(_) => {
+                    buf.advance(line_len + 1);
+                    return Err(client_error("object too large for cache"));
+                }
+                Err(_) => None,
+            }
+        }
+        _ => None,
+    };
+    let value = match data_len {
+        Some(len) => {
+            let frame_len = line_len + 1 + len + 2;
+            if buf.len() < frame_len {
+                return Ok(None);
+            }
+            let frame = buf.split_to(frame_len).freeze();
+            if &frame[frame_len - 2..] != b"\r\n" {
+                return Err(MemcacheError::Fatal(String::from("bad data chunk")));
+            }
+            frame.slice(line_len + 1..frame_len - 2)
+        }
+        None => {
+            buf.advance(line_len + 1);
+            Bytes::new()
+        }
+    };
+    parse_line(&tokens, value).map(Some)
+}
+
+fn parse_line(tokens: &[&str], value: Bytes) -> Result<Command, MemcacheError> {
+    let Some((&name, args)) = tokens.split_first() else {
+        return Err(MemcacheError::UnknownCommand);
+    };
+    let noreply = args.last() == Some(&"noreply");
+    let command = match (name, args) {
+        ("get" | "gets", keys) if !keys.is_empty() => Command::Get {
+            keys: keys
+                .iter()
+                .map(|key| parse_key(key))
+                .collect::<Result<_, _>>()?,
+            cas: name == "gets",
+        },
+        ("set" | "add", [key, flags, exptime, _, ..])
+        | ("cas", [key, flags, exptime, _, _, ..]) => Command::Store {
+            mode: if name == "add" {
+                StoreMode::Add
+            } else {
+                StoreMode::Set
+            },
+            key: parse_key(key)?,
+            flags: parse_number(flags)?,
+            exptime: parse_number(exptime)?,
+            cas: match name {
+                "cas" => Some(parse_number(args[4])?),
+                _ => None,
+            },
+            value,
+            noreply,
+        },
+        ("delete", [key, ..]) => Command::Delete {
+            key: parse_key(key)?,
+            noreply,
+        },
+        ("touch", [key, exptime, ..]) => Command::Touch {
+            key: parse_key(key)?,
+            exptime: parse_number(exptime)?,
+            noreply,
+        },
+        ("mg", [key, flags @ ..]) => Command::MetaGet {
+            key: parse_key(key)?,
+            flags: MetaFlags::parse(flags, b"vcfstkOqT")?,
+        },
+        ("ms", [key, _, flags @ ..]) => Command::MetaSet {
+            key: parse_key(key)?,
+            value,
+            flags: MetaFlags::parse(flags, b"FTCMqOkc")?,
+        },
+        ("md", [key, flags @ ..]) => Command::MetaDelete {
+            key: parse_key(key)?,
+            flags: MetaFlags::parse(flags, b"CqOk")?,
+        },
+        ("mn", []) => Command::MetaNoop,
+        ("get" | "gets" | "set" | "add" | "cas" | "delete" | "touch" | "mg" | "ms" | "md", _) => {
+            return Err(client_error("bad command line format"))
+        }
+        _ => return Err(MemcacheError::UnknownCommand),
+    };
+    Ok(command)
+}
+
+fn parse_key(key: &str) -> Result<String, MemcacheError> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(client_error("key too long"));
+    }
+    validate_key(key).map_err(|e| MemcacheError::Client(e.to_string()))?;
+    Ok(key.to_string())
+}
+
+fn parse_number<T: std::str::FromStr>(token: &str) -> Result<T, MemcacheError> {
+    token
+        .parse()
+        .map_err(|_| client_error("bad command line format"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        let mut buf = BytesMut::from(
+            &b"set key 5 0 7 noreply\r\nva\r\nlue\r\ngets a b\r\nmg key v c T30\r\nms key 2\r\nv"[..],
+        );
+        assert_eq!(
+            parse_command(&mut buf).unwrap(),
+            Some(Command::Store {
+                mode: StoreMode::Set,
+                key: String::from("key"),
+                flags: 5,
+                exptime: 0,
+                cas: None,
+                value: Bytes::from_static(b"va\r\nlue"),
+                noreply: true,
+            })
+        );
+        assert_eq!(
+            parse_command(&mut buf).unwrap(),
+            Some(Command::Get {
+                keys: vec![String::from("a"), String::from("b")],
+                cas: true,
+            })
+        );
+        let Some(Command::MetaGet { key, flags }) = parse_command(&mut buf).unwrap() else {
+            panic!("expected a meta get");
+        };
+        assert_eq!(key, "key");
+        assert!(flags.has(b'v') && flags.has(b'c') && !flags.has(b'f'));
+        assert_eq!(flags.number::<i64>(b'T').unwrap(), Some(30));
+        // the value of a set is not complete yet
+        assert_eq!(parse_command(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"v\r\n");
+        assert!(matches!(
+            parse_command(&mut buf).unwrap(),
+            Some(Command::MetaSet { value, .. }) if value == "vv"
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_bad_commands_are_consumed() {
+        let mut buf =
+            BytesMut::from(&b"incr key 1\r\nset key x 0 1\r\nv\r\nmg key X\r\nmn\r\n"[..]);
+        assert!(matches!(
+            parse_command(&mut buf),
+            Err(MemcacheError::UnknownCommand)
+        ));
+        assert!(matches!(
+            parse_command(&mut buf),
+            Err(MemcacheError::Client(_))
+        ));
+        assert!(matches!(
+            parse_command(&mut buf),
+            Err(MemcacheError::Client(_))
+        ));
+        assert_eq!(parse_command(&mut buf).unwrap(), Some(Command::MetaNoop));
+
+        let mut buf = BytesMut::from(&b"set key 0 0 1\r\nxy\r\n"[..]);
+        assert!(matches!(
+            parse_command(&mut buf),
+            Err(MemcacheError::Fatal(_))
+        ));
+    }
+}
@@ -4,9 +4,10 @@ use std::time::Duration;
 use lazy_static::lazy_static;
 use log::{error, info, trace};
 use prometheus::{
-    labels, register_counter, register_histogram, register_int_counter_vec, register_int_gauge,
+    labels, register_counter, register_histogram, register_int_counter, register_int_counter_vec,
+    register_int_gauge,
 };
-use prometheus::{Counter, Histogram, IntCounterVec, IntGauge, Opts, Registry};
+use prometheus::{Counter, Histogram, IntCounter, IntCounterVec, IntGauge, Opts, Registry};
 use tokio::time::sleep;
 
 use crate::settings::SETTINGS;
@@ -24,6 +25,45 @@ lazy_static! {
     .unwrap();
     pub static ref RESPONSE_TIME_COLLECTOR: Histogram =
         register_histogram!("response_time", "Response Times").unwrap();
+    pub static ref REBALANCE_KEYS_PENDING: IntGauge = register_int_gauge!(
+        "rebalance_keys_pending",
+        "Keys this worker took over that still have to be copied from their old owner"
+    )
+    .unwrap();
+    pub static ref REBALANCE_KEYS_MOVED: IntCounter =
+        register_int_counter!("rebalance_keys_moved", "Keys copied from their old owner").unwrap();
+    pub static ref REBALANCE_BYTES_MOVED: IntCounter = register_int_counter!(
+        "rebalance_bytes_moved",
+        "Value bytes copied from old owners"
+    )
+    .unwrap();
+    pub static ref ANTI_ENTROPY_ROUNDS: IntCounter = register_int_counter!(
+        "anti_entropy_rounds",
+        "Anti-entropy rounds run against all peers"
+    )
+    .unwrap();
+    pub static ref ANTI_ENTROPY_KEYS_DIFFERING: IntCounter = register_int_counter!(
+        "anti_entropy_keys_differing",
+        "Keys found to differ between replicas"
+    )
+    .unwrap();
+    pub static ref ANTI_ENTROPY_KEYS_REPAIRED: IntCounter = register_int_counter!(
+        "anti_entropy_keys_repaired",
+        "Keys pulled from or pushed to a replica by anti-entropy"
+    )
+    .unwrap();
+    pub static ref ANTI_ENTROPY_BYTES_REPAIRED: IntCounter = register_int_counter!(
+        "anti_entropy_bytes_repaired",
+        "Value bytes transferred by anti-entropy"
+    )
+    .unwrap();
+    pub static ref PEER_FETCH_HITS: IntCounter = register_int_counter!(
+        "peer_fetch_hits",
+        "Local misses served by a previous owner or replica instead of under storage"
+    )
+    .unwrap();
+    pub static ref PEER_FETCH_MISSES: IntCounter =
+        register_int_counter!("peer_fetch_misses", "Local misses no peer had a copy of").unwrap();
     static ref PUSH_COUNTER: Counter =
         register_counter!("push_counter", "Total number of prometheus client pushed.").unwrap();
     static ref PUSH_REQ_HISTOGRAM: Histogram = register_histogram!(
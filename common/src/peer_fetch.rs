@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http::{Request, StatusCode};
+use log::debug;
+
+use crate::discovery::ServiceDiscovery;
+use crate::h2::encode_key;
+use crate::h2::h2_client::{self, ConnectionPool, LOCAL_ONLY_HEADER};
+use crate::kv_store::object_meta::ObjectMeta;
+use crate::metrics::{PEER_FETCH_HITS, PEER_FETCH_MISSES};
+use crate::ring::HashRing;
+
+/// How long a peer gets to answer a fetch.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a peer that failed a fetch is skipped.
+const FAILED_PEER_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Looks for a key missing locally on the workers that may still hold it: its owners
+/// before the last membership change first, then the other replicas on the current ring.
+/// Peers that left the cluster or failed a fetch lately are skipped.
+pub struct PeerFetcher {
+    /// This worker as it appears on the ring.
+    worker_id: String,
+    /// h2 port of peers without a descriptor.
+    h2_port: u16,
+    replication_factor: usize,
+    ring: Arc<RwLock<HashRing>>,
+    /// The ring before the last membership change, until the rebalance is done.
+    previous: RwLock<Option<HashRing>>,
+    discovery: Option<Arc<dyn ServiceDiscovery>>,
+    timeout: Duration,
+    /// Peers that failed a fetch, with when they did.
+    failed: Mutex<HashMap<String, Instant>>,
+    connections: ConnectionPool,
+}
+
+impl PeerFetcher {
+    pub fn new(
+        worker_id: String,
+        h2_port: u16,
+        replication_factor: usize,
+        ring: Arc<RwLock<HashRing>>,
+    ) -> PeerFetcher {
+        PeerFetcher {
+            worker_id,
+            h2_port,
+            replication_factor: replication_factor.max(1),
+            ring,
+            previous: RwLock::new(None),
+            discovery: None,
+            timeout: FETCH_TIMEOUT,
+            failed: Mutex::new(HashMap::new()),
+            connections: ConnectionPool::new(),
+        }
+    }
+
+    /// Only members of `discovery` are asked.
+    pub fn with_discovery(mut self, discovery: Arc<dyn ServiceDiscovery>) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn set_previous(&self, ring: HashRing) {
+        *self.previous.write().unwrap() = Some(ring);
+    }
+
+    /// Forgets the previous ring once the keys it placed elsewhere have been moved.
+    pub fn clear_previous(&self) {
+        *self.previous.write().unwrap() = None;
+    }
+
+    /// Workers to ask for `key`, in order, never this worker.
+    pub fn candidates(&self, key: &str) -> Vec<String> {
+        let key = key.to_string();
+        let mut candidates: Vec<String> = Vec::new();
+        let previous = self.previous.read().unwrap();
+        let ring = self.ring.read().unwrap();
+        let members = self.discovery.as_ref().map(|discovery| discovery.members());
+        let owners = previous
+            .iter()
+            .chain(std::iter::once(&*ring))
+            .flat_map(|ring| ring.owners(&key, self.replication_factor));
+        for owner in owners {
+            if owner != self.worker_id
+                && !candidates.iter().any(|c| c == owner)
+                && members
+                    .as_ref()
+                    .map_or(true, |members| members.iter().any(|m| m == owner))
+                && self.is_healthy(owner)
+            {
+                candidates.push(owner.to_string());
+            }
+        }
+        candidates
+    }
+
+    /// The first copy found on a candidate, None if none of them has the key.
+    pub async fn fetch(&self, key: &str) -> Option<(ObjectMeta, Bytes)> {
+        for peer in self.candidates(key) {
+            let result = match monoio::time::timeout(self.timeout, self.get(&peer, key)).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("{} timed out", peer).into()),
+            };
+            match result {
+                Ok(Some(found)) => {
+                    debug!("Fetched {} from {}", key, peer);
+                    PEER_FETCH_HITS.inc();
+                    self.failed.lock().unwrap().remove(&peer);
+                    return Some(found);
+                }
+                Ok(None) => {
+                    self.failed.lock().unwrap().remove(&peer);
+                }
+                Err(e) => {
+                    debug!("Failed to fetch {} from {}: {}", key, peer, e);
+                    self.failed
+                        .lock()
+                        .unwrap()
+                        .insert(peer.clone(), Instant::now());
+                }
+            }
+        }
+        PEER_FETCH_MISSES.inc();
+        None
+    }
+
+    fn is_healthy(&self, peer: &str) -> bool {
+        let mut failed = self.failed.lock().unwrap();
+        match failed.get(peer) {
+            Some(at) if at.elapsed() < FAILED_PEER_BACKOFF => false,
+            Some(_) => {
+                failed.remove(peer);
+                true
+            }
+            None => true,
+        }
+    }
+
+    async fn get(
+        &self,
+        peer: &str,
+        key: &str,
+    ) -> Result<Option<(ObjectMeta, Bytes)>, Box<dyn Error + Send + Sync>> {
+        let addr = h2_client::resolve_h2_addr(self.discovery.as_deref(), peer, self.h2_port);
+        let request = Request::builder()
+            .uri(format!("http://{}/get/{}", addr, encode_key(key)))
+            .header(LOCAL_ONLY_HEADER, "1")
+            .body(())?;
+        let response = self.connections.send(&addr, request, None).await?;
+        match response.status() {
+            StatusCode::OK => {
+                let meta = ObjectMeta::from_headers(response.headers());
+                Ok(Some((meta, response.into_body())))
+            }
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(format!("{} answered {}", peer, status).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::discovery::static_list::StaticDiscovery;
+    use crate::discovery::WorkerDescriptor;
+    use crate::h2::h2_service::H2Service;
+    use crate::kv_store::read_through::ReadThroughLoader;
+    use crate::settings::local_kv_options::StoreLayout;
+    use crate::test_util::{free_port, h2_addr, leak, spawn_h2, store};
+    use crate::ufs::local_ufs::LocalUfs;
+
+    const OLD_OWNER: &str = "127.0.0.1:8080";
+    const NEW_OWNER: &str = "127.0.0.2:8080";
+
+    async fn get(port: u16, key: &str) -> http::Response<Bytes> {
+        let request = Request::builder()
+            .uri(format!("http://{}/get/{}", h2_addr(NEW_OWNER, port), key))
+            .body(())
+            .unwrap();
+        let connection = h2_client::connect(h2_addr(NEW_OWNER, port)).await.unwrap();
+        h2_client::send(connection, request, None).await.unwrap()
+    }
+
+    #[test]
+    fn test_candidates_start_with_previous_owners() {
+        let ring = Arc::new(RwLock::new(HashRing::with_members(
+            16,
+            [OLD_OWNER, NEW_OWNER],
+        )));
+        let peers = PeerFetcher::new(NEW_OWNER.to_string(), 5928, 2, Arc::clone(&ring));
+        assert_eq!(peers.candidates("key"), vec![OLD_OWNER.to_string()]);
+
+        peers.set_previous(HashRing::with_members(16, ["127.0.0.3:8080"]));
+        assert_eq!(
+            peers.candidates("key"),
+            vec!["127.0.0.3:8080".to_string(), OLD_OWNER.to_string()]
+        );
+        peers.clear_previous();
+        assert_eq!(peers.candidates("key"), vec![OLD_OWNER.to_string()]);
+
+        // workers that left the cluster are not asked
+        let discovery: Arc<dyn ServiceDiscovery> =
+            Arc::new(StaticDiscovery::new(vec![NEW_OWNER.to_string()]));
+        let peers =
+            PeerFetcher::new(NEW_OWNER.to_string(), 5928, 2, ring).with_discovery(discovery);
+        peers.set_previous(HashRing::with_members(16, ["127.0.0.3:8080"]));
+        assert!(peers.candidates("key").is_empty());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_hung_peer_times_out_and_is_skipped() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let ring = Arc::new(RwLock::new(HashRing::with_members(
+            16,
+            [OLD_OWNER, NEW_OWNER],
+        )));
+        let peers = PeerFetcher::new(NEW_OWNER.to_string(), port, 2, ring)
+            .with_timeout(Duration::from_millis(100));
+
+        // the old owner accepts connections but never answers
+        let started = std::time::Instant::now();
+        assert!(peers.fetch("key").await.is_none());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(peers.candidates("key").is_empty());
+        drop(listener);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_peers_are_reached_on_their_published_port() {
+        let (port, local_port) = (free_port(), free_port());
+        let dir = tempfile::tempdir().unwrap();
+        let old_store = store(dir.path(), StoreLayout::FilePerKey);
+        spawn_h2(H2Service::new(old_store, h2_addr(OLD_OWNER, port)));
+        old_store
+            .put(String::from("key"), Bytes::from_static(b"value"))
+            .await
+            .unwrap();
+        let discovery: Arc<dyn ServiceDiscovery> =
+            Arc::new(StaticDiscovery::new(vec![OLD_OWNER.to_string()]));
+        discovery.update_local(WorkerDescriptor {
+            node_id: OLD_OWNER.to_string(),
+            host: String::from("127.0.0.1"),
+            h2_port: port,
+            ..WorkerDescriptor::default()
+        });
+        let ring = Arc::new(RwLock::new(HashRing::with_members(
+            16,
+            [OLD_OWNER, NEW_OWNER],
+        )));
+        // this worker listens on another port than the peer
+        let peers =
+            PeerFetcher::new(NEW_OWNER.to_string(), local_port, 2, ring).with_discovery(discovery);
+        monoio::time::sleep(Duration::from_millis(10)).await;
+
+        let (_, value) = peers.fetch("key").await.unwrap();
+        assert_eq!(value.as_ref(), b"value");
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_miss_tries_previous_owner_before_ufs() {
+        let port = free_port();
+        let (old_dir, new_dir, ufs_dir) = (
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+        );
+        let (old_store, new_store) = (
+            store(old_dir.path(), StoreLayout::FilePerKey),
+            store(new_dir.path(), StoreLayout::FilePerKey),
+        );
+        let ring = Arc::new(RwLock::new(HashRing::with_members(16, [NEW_OWNER])));
+        let peers: &'static PeerFetcher =
+            leak(PeerFetcher::new(NEW_OWNER.to_string(), port, 1, ring));
+        peers.set_previous(HashRing::with_members(16, [OLD_OWNER]));
+        let loader: &'static ReadThroughLoader = leak(
+            ReadThroughLoader::new(
+                new_store,
+                Some(LocalUfs::new(ufs_dir.path().to_string_lossy().to_string())),
+            )
+            .with_peers(peers),
+        );
+        spawn_h2(H2Service::new(old_store, h2_addr(OLD_OWNER, port)));
+        spawn_h2(H2Service::new(new_store, h2_addr(NEW_OWNER, port)).with_loader(loader));
+        monoio::time::sleep(Duration::from_millis(10)).await;
+
+        old_store
+            .put(String::from("moved"), Bytes::from_static(b"from peer"))
+            .await
+            .unwrap();
+        std::fs::write(ufs_dir.path().join("moved"), b"from ufs").unwrap();
+        std::fs::write(ufs_dir.path().join("cold"), b"from ufs").unwrap();
+        let (hits, misses) = (PEER_FETCH_HITS.get(), PEER_FETCH_MISSES.get());
+
+        let response = get(port, "moved").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), b"from peer");
+        assert!(new_store.exists(String::from("moved")));
+        assert_eq!(PEER_FETCH_HITS.get(), hits + 1);
+
+        let response = get(port, "cold").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), b"from ufs");
+        assert_eq!(PEER_FETCH_MISSES.get(), misses + 1);
+        // the old owner only answers from its own store
+        assert!(!old_store.exists(String::from("cold")));
+
+        assert_eq!(get(port, "nowhere").await.status(), StatusCode::NOT_FOUND);
+    }
+}
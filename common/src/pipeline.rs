@@ -0,0 +1,143 @@
+//! Listener and reply buffer of the pipelined text protocols, memcached and RESP:
+//! commands of a connection run in order and the replies to everything read at once
+//! are sent in one write.
+
+use std::fmt::Display;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{debug, error};
+use monoio::io::{AsyncReadRent, AsyncWriteRentExt};
+use monoio::net::{TcpListener, TcpStream};
+
+use crate::shutdown::{self, Shutdown};
+
+const READ_BUFFER_LEN: usize = 64 * 1024;
+/// Values up to this size are copied into the reply buffer rather than sent on their own.
+pub const INLINE_VALUE_LEN: usize = 4 * 1024;
+
+/// Answers the commands of one connection.
+pub(crate) trait CommandHandler: Clone + 'static {
+    type Error: From<std::io::Error> + Display;
+
+    /// Runs the complete commands at the front of `input` and leaves a partial one.
+    /// An error closes the connection once the replies are written.
+    async fn handle(&self, input: &mut BytesMut, out: &mut ReplyBuffer) -> Result<(), Self::Error>;
+}
+
+/// Serves connections on `addr` until the shutdown is triggered, each on a task of its
+/// own. `protocol` names the service in the logs.
+pub(crate) async fn serve<H: CommandHandler>(
+    protocol: &str,
+    addr: &str,
+    shutdown: Option<&'static Shutdown>,
+    handler: H,
+) {
+    let listener = TcpListener::bind(addr).unwrap();
+    loop {
+        let accepted = monoio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown::triggered(shutdown) => break,
+        };
+        match accepted {
+            Ok((socket, peer_addr)) => {
+                let handler = handler.clone();
+                let protocol = protocol.to_string();
+                monoio::spawn(async move {
+                    debug!("{} connection received from {}", protocol, peer_addr);
+                    if let Err(e) = serve_connection(handler, socket).await {
+                        debug!("{} connection from {} failed: {}", protocol, peer_addr, e);
+                    }
+                });
+            }
+            Err(e) => error!("{} connection failed: {}", protocol, e),
+        }
+    }
+    debug!(
+        "{} service on {} stopped accepting connections",
+        protocol, addr
+    );
+}
+
+async fn serve_connection<H: CommandHandler>(
+    handler: H,
+    mut socket: TcpStream,
+) -> Result<(), H::Error> {
+    socket.set_nodelay(true)?;
+    let mut input = BytesMut::new();
+    let mut read_buf = Vec::with_capacity(READ_BUFFER_LEN);
+    loop {
+        let mut replies = ReplyBuffer::default();
+        let handled = handler.handle(&mut input, &mut replies).await;
+        for chunk in replies.finish() {
+            let (res, _) = socket.write_all(chunk).await;
+            res?;
+        }
+        handled?;
+
+        read_buf.clear();
+        let (res, buf) = socket.read(read_buf).await;
+        if res? == 0 {
+            return Ok(());
+        }
+        input.extend_from_slice(&buf);
+        read_buf = buf;
+    }
+}
+
+/// Encoded replies as a list of buffers, large values are not copied.
+#[derive(Default)]
+pub struct ReplyBuffer {
+    chunks: Vec<Bytes>,
+    buf: BytesMut,
+}
+
+impl ReplyBuffer {
+    pub fn put_slice(&mut self, bytes: &[u8]) {
+        self.buf.put_slice(bytes);
+    }
+
+    /// The bytes followed by CRLF.
+    pub fn line(&mut self, line: impl AsRef<[u8]>) {
+        self.buf.put_slice(line.as_ref());
+        self.buf.put_slice(b"\r\n");
+    }
+
+    pub fn value(&mut self, value: Bytes) {
+        if value.len() <= INLINE_VALUE_LEN {
+            self.buf.put_slice(&value);
+            return;
+        }
+        if !self.buf.is_empty() {
+            self.chunks.push(self.buf.split().freeze());
+        }
+        self.chunks.push(value);
+    }
+
+    /// The buffers to write in order.
+    pub fn finish(mut self) -> Vec<Bytes> {
+        if !self.buf.is_empty() {
+            self.chunks.push(self.buf.freeze());
+        }
+        self.chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_large_values_are_not_copied() {
+        let large = Bytes::from(vec![b'x'; INLINE_VALUE_LEN + 1]);
+        let mut out = ReplyBuffer::default();
+        out.line(b"VALUE");
+        out.value(Bytes::from_static(b"small"));
+        out.value(large.clone());
+        out.line(b"");
+        let chunks = out.finish();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].as_ref(), b"VALUE\r\nsmall");
+        assert_eq!(chunks[1].as_ptr(), large.as_ptr());
+        assert_eq!(chunks[2].as_ref(), b"\r\n");
+    }
+}
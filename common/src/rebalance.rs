@@ -0,0 +1,410 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http::{Request, Response, StatusCode};
+use log::{debug, info, warn};
+
+use crate::discovery::ServiceDiscovery;
+use crate::h2::encode_key;
+use crate::h2::h2_client::{self, ConnectionPool, LOCAL_ONLY_HEADER};
+use crate::h2::h2_service::LIST_PAGE_SIZE;
+use crate::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
+use crate::kv_store::object_meta::ObjectMeta;
+use crate::metrics::{REBALANCE_BYTES_MOVED, REBALANCE_KEYS_MOVED, REBALANCE_KEYS_PENDING};
+use crate::ring::HashRing;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RebalanceReport {
+    pub planned: usize,
+    pub moved: usize,
+    pub bytes: u64,
+    pub failed: usize,
+}
+
+/// Pulls the keys a worker took over after a membership change from their old owners.
+///
+/// Old owners keep their copies, so a client still routing to them is served as before.
+/// Until a key has been copied, a miss on it here is answered by fetching it from the
+/// old owner right away, see `fetch_pending`.
+pub struct Rebalancer {
+    kv_store: &'static LocalFileKVStore,
+    /// This worker as it appears on the ring.
+    worker_id: String,
+    /// h2 port of peers without a descriptor.
+    h2_port: u16,
+    replication_factor: usize,
+    discovery: Option<Arc<dyn ServiceDiscovery>>,
+    /// Bytes per second, 0 is unlimited.
+    rate_bytes: u64,
+    /// Keys still to copy, with the worker they are copied from.
+    pending: Mutex<BTreeMap<String, String>>,
+    connections: ConnectionPool,
+}
+
+impl Rebalancer {
+    pub fn new(
+        kv_store: &'static LocalFileKVStore,
+        worker_id: String,
+        h2_port: u16,
+        replication_factor: usize,
+        rate_bytes: u64,
+    ) -> Rebalancer {
+        Rebalancer {
+            kv_store,
+            worker_id,
+            h2_port,
+            replication_factor: replication_factor.max(1),
+            discovery: None,
+            rate_bytes,
+            pending: Mutex::new(BTreeMap::new()),
+            connections: ConnectionPool::new(),
+        }
+    }
+
+    /// Peers are reached on the h2 port they publish.
+    pub fn with_discovery(mut self, discovery: Arc<dyn ServiceDiscovery>) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// The ring to rebalance from now that the ring is `next`, None if there is nothing
+    /// to take over. Changes of zones count like membership changes, they move replicas
+    /// too. `seen` is the ring of the last rebalance, None until this worker
+    /// first shows up on the ring next to other members: it then joins, and owns keys
+    /// the members that were there before it hold.
+    pub fn previous_ring(&self, seen: Option<&HashRing>, next: &HashRing) -> Option<HashRing> {
+        match seen {
+            None => {
+                if !next.contains(&self.worker_id) {
+                    return None;
+                }
+                let mut before = next.clone();
+                before.remove(&self.worker_id);
+                (!before.is_empty()).then_some(before)
+            }
+            Some(seen) if seen.places_like(next) => None,
+            Some(seen) => Some(seen.clone()),
+        }
+    }
+
+    pub async fn rebalance(&self, old: &HashRing, new: &HashRing) -> RebalanceReport {
+        let planned = self.plan(old, new).await;
+        let mut report = self.run().await;
+        report.planned = planned;
+        info!("Rebalance finished: {:?}", report);
+        report
+    }
+
+    /// Lists the keys of every other member page by page and queues the ones this
+    /// worker owns on the new ring but did not own on the old one. Members only on the
+    /// new ring are listed too, discovery may show members that held keys for a while
+    /// only after this worker joined.
+    pub async fn plan(&self, old: &HashRing, new: &HashRing) -> usize {
+        let mut planned = 0;
+        let peers: BTreeSet<&str> = old
+            .members()
+            .chain(new.members())
+            .map(|(peer, _)| peer)
+            .filter(|peer| *peer != self.worker_id)
+            .collect();
+        for peer in peers {
+            let mut after: Option<String> = None;
+            loop {
+                let keys = match self.list(peer, after.as_deref()).await {
+                    Ok(keys) => keys,
+                    Err(e) => {
+                        warn!("Failed to list keys of {}: {}", peer, e);
+                        break;
+                    }
+                };
+                let last_page = keys.len() < LIST_PAGE_SIZE;
+                after = keys.last().map(|(key, _)| key.clone());
+                let mut pending = self.pending.lock().unwrap();
+                for (key, _) in keys {
+                    if self.owns(new, &key)
+                        && !self.owns(old, &key)
+                        && !pending.contains_key(&key)
+                        && !self.kv_store.exists(key.clone())
+                    {
+                        pending.insert(key, peer.to_string());
+                        planned += 1;
+                    }
+                }
+                REBALANCE_KEYS_PENDING.set(pending.len() as i64);
+                if last_page {
+                    break;
+                }
+            }
+        }
+        info!("Planned to take over {} keys", planned);
+        planned
+    }
+
+    /// Copies the queued keys, keeping under the configured rate.
+    pub async fn run(&self) -> RebalanceReport {
+        let mut report = RebalanceReport::default();
+        let started = Instant::now();
+        loop {
+            let next = self
+                .pending
+                .lock()
+                .unwrap()
+                .first_key_value()
+                .map(|(k, v)| (k.clone(), v.clone()));
+            let Some((key, peer)) = next else {
+                break;
+            };
+            match self.move_key(&key, &peer).await {
+                Ok(Some((_, value))) => {
+                    report.moved += 1;
+                    report.bytes += value.len() as u64;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Failed to copy {} from {}: {}", key, peer, e);
+                    report.failed += 1;
+                }
+            }
+            if self.rate_bytes > 0 {
+                let due = Duration::from_secs_f64(report.bytes as f64 / self.rate_bytes as f64);
+                let elapsed = started.elapsed();
+                if due > elapsed {
+                    monoio::time::sleep(due - elapsed).await;
+                }
+            }
+        }
+        self.connections.clear();
+        report
+    }
+
+    /// Copies a key that is still waiting for the rebalance right now, returning it.
+    /// None if the key is not pending.
+    pub async fn fetch_pending(
+        &self,
+        key: &str,
+    ) -> Result<Option<(ObjectMeta, Bytes)>, Box<dyn Error + Send + Sync>> {
+        let peer = self.pending.lock().unwrap().get(key).cloned();
+        match peer {
+            Some(peer) => self.move_key(key, &peer).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn move_key(
+        &self,
+        key: &str,
+        peer: &str,
+    ) -> Result<Option<(ObjectMeta, Bytes)>, Box<dyn Error + Send + Sync>> {
+        let result = self.copy(key, peer).await;
+        let mut pending = self.pending.lock().unwrap();
+        pending.remove(key);
+        REBALANCE_KEYS_PENDING.set(pending.len() as i64);
+        result
+    }
+
+    async fn copy(
+        &self,
+        key: &str,
+        peer: &str,
+    ) -> Result<Option<(ObjectMeta, Bytes)>, Box<dyn Error + Send + Sync>> {
+        let request = Request::builder()
+            .uri(format!(
+                "http://{}/get/{}",
+                self.addr(peer),
+                encode_key(key)
+            ))
+            .header(LOCAL_ONLY_HEADER, "1")
+            .body(())?;
+        let response = self.send(peer, request).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            debug!("{} is gone from {}, skipping it", key, peer);
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", peer, response.status()).into());
+        }
+        let meta = ObjectMeta::from_headers(response.headers());
+        let value = response.into_body();
+        self.kv_store
+            .put_with_meta(key.to_string(), value.clone(), &meta)
+            .await?;
+        REBALANCE_KEYS_MOVED.inc();
+        REBALANCE_BYTES_MOVED.inc_by(value.len() as u64);
+        debug!("Copied {} ({} bytes) from {}", key, value.len(), peer);
+        Ok(Some((meta, value)))
+    }
+
+    /// One page of the keys of the peer, starting after `after`.
+    async fn list(
+        &self,
+        peer: &str,
+        after: Option<&str>,
+    ) -> Result<Vec<(String, u64)>, Box<dyn Error + Send + Sync>> {
+        let mut uri = format!("http://{}/list/?limit={}", self.addr(peer), LIST_PAGE_SIZE);
+        if let Some(after) = after {
+            uri.push_str(&format!("&after={}", encode_key(after)));
+        }
+        let request = Request::builder().uri(uri).body(())?;
+        let response = self.send(peer, request).await?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", peer, response.status()).into());
+        }
+        Ok(serde_json::from_slice(response.body())?)
+    }
+
+    async fn send(
+        &self,
+        peer: &str,
+        request: Request<()>,
+    ) -> Result<Response<Bytes>, Box<dyn Error + Send + Sync>> {
+        self.connections.send(&self.addr(peer), request, None).await
+    }
+
+    fn owns(&self, ring: &HashRing, key: &String) -> bool {
+        ring.owners(key, self.replication_factor)
+            .contains(&self.worker_id.as_str())
+    }
+
+    fn addr(&self, worker: &str) -> String {
+        h2_client::resolve_h2_addr(self.discovery.as_deref(), worker, self.h2_port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h2::h2_service::H2Service;
+    use crate::settings::local_kv_options::StoreLayout;
+    use crate::test_util::{free_port, h2_addr, leak, spawn_h2, store};
+
+    const OLD_OWNER: &str = "127.0.0.1:8080";
+    const NEW_OWNER: &str = "127.0.0.2:8080";
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_new_owner_pulls_its_keys() {
+        let port = free_port();
+        let (old_dir, new_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (old_store, new_store) = (
+            store(old_dir.path(), StoreLayout::FilePerKey),
+            store(new_dir.path(), StoreLayout::FilePerKey),
+        );
+        let rebalancer: &'static Rebalancer = leak(Rebalancer::new(
+            new_store,
+            NEW_OWNER.to_string(),
+            port,
+            1,
+            0,
+        ));
+        spawn_h2(H2Service::new(old_store, h2_addr(OLD_OWNER, port)));
+        spawn_h2(H2Service::new(new_store, h2_addr(NEW_OWNER, port)).with_rebalancer(rebalancer));
+        monoio::time::sleep(Duration::from_millis(10)).await;
+
+        let keys: Vec<String> = (0..100).map(|i| format!("data/{}", i)).collect();
+        for key in keys.iter() {
+            old_store
+                .put(key.clone(), Bytes::from(key.clone()))
+                .await
+                .unwrap();
+        }
+        let old = HashRing::with_members(16, [OLD_OWNER]);
+        let new = HashRing::with_members(16, [OLD_OWNER, NEW_OWNER]);
+        let moving: Vec<&String> = keys
+            .iter()
+            .filter(|key| new.primary(*key) == Some(NEW_OWNER))
+            .collect();
+        assert!(!moving.is_empty() && moving.len() < keys.len());
+
+        assert_eq!(rebalancer.plan(&old, &new).await, moving.len());
+        assert_eq!(rebalancer.pending(), moving.len());
+
+        // a request reaching the new owner before the copy is served from the old one
+        let request = Request::builder()
+            .uri(format!(
+                "http://{}/get/{}",
+                h2_addr(NEW_OWNER, port),
+                moving[0]
+            ))
+            .body(())
+            .unwrap();
+        let connection = h2_client::connect(h2_addr(NEW_OWNER, port)).await.unwrap();
+        let response = h2_client::send(connection, request, None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), moving[0].as_bytes());
+        assert_eq!(rebalancer.pending(), moving.len() - 1);
+
+        let report = rebalancer.run().await;
+        assert_eq!((report.moved, report.failed), (moving.len() - 1, 0));
+        assert_eq!(rebalancer.pending(), 0);
+        for key in keys.iter() {
+            assert_eq!(new_store.exists(key.clone()), moving.contains(&key));
+            // the old owner keeps serving its copies
+            assert!(old_store.exists(key.clone()));
+        }
+        assert_eq!(
+            new_store.get(moving[1].clone()).await.unwrap(),
+            moving[1].as_bytes()
+        );
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_worker_joining_a_populated_cluster_pulls_its_keys() {
+        const MEMBERS: [&str; 2] = ["127.0.0.1:8080", "127.0.0.2:8080"];
+        const JOINING: &str = "127.0.0.3:8080";
+        let port = free_port();
+        let dirs: Vec<_> = (0..3).map(|_| tempfile::tempdir().unwrap()).collect();
+        let stores: Vec<_> = dirs
+            .iter()
+            .map(|dir| store(dir.path(), StoreLayout::FilePerKey))
+            .collect();
+        for (member, store) in MEMBERS.iter().zip(stores.iter()) {
+            spawn_h2(H2Service::new(store, h2_addr(member, port)));
+        }
+        let rebalancer = Rebalancer::new(stores[2], JOINING.to_string(), port, 1, 0);
+        monoio::time::sleep(Duration::from_millis(10)).await;
+
+        let cluster = HashRing::with_members(16, MEMBERS);
+        let keys: Vec<String> = (0..100).map(|i| format!("data/{}", i)).collect();
+        for key in keys.iter() {
+            let owner = MEMBERS
+                .iter()
+                .position(|member| cluster.primary(key) == Some(*member))
+                .unwrap();
+            stores[owner]
+                .put(key.clone(), Bytes::from(key.clone()))
+                .await
+                .unwrap();
+        }
+
+        // nothing to do before the worker is on the ring, or while it is alone on it
+        assert!(rebalancer.previous_ring(None, &cluster).is_none());
+        let alone = HashRing::with_members(16, [JOINING]);
+        assert!(rebalancer.previous_ring(None, &alone).is_none());
+
+        let joined = HashRing::with_members(16, MEMBERS.into_iter().chain([JOINING]));
+        let previous = rebalancer.previous_ring(None, &joined).unwrap();
+        assert!(previous.members().eq(cluster.members()));
+        let report = rebalancer.rebalance(&previous, &joined).await;
+        let moving: Vec<&String> = keys
+            .iter()
+            .filter(|key| joined.primary(*key) == Some(JOINING))
+            .collect();
+        assert!(!moving.is_empty());
+        assert_eq!((report.planned, report.moved), (moving.len(), moving.len()));
+        for key in keys.iter() {
+            assert_eq!(stores[2].exists(key.clone()), moving.contains(&key));
+        }
+        assert!(rebalancer.previous_ring(Some(&joined), &joined).is_none());
+        // moving a member to another zone changes which replicas are where
+        let mut rezoned = joined.clone();
+        rezoned.set_zones([(MEMBERS[0].to_string(), Some(String::from("zone-b")))]);
+        let previous = rebalancer.previous_ring(Some(&joined), &rezoned).unwrap();
+        assert!(previous.places_like(&joined));
+    }
+}
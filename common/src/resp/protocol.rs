@@ -0,0 +1,208 @@
+use bytes::{Bytes, BytesMut};
+use thiserror::Error;
+
+use crate::pipeline::ReplyBuffer;
+
+/// Largest bulk string accepted, the default `proto-max-bulk-len` of Redis.
+pub const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Largest number of arguments of a command.
+const MAX_ARGS: i64 = 1024 * 1024;
+/// Longest inline command or length line.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum RespError {
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+fn protocol_error(message: &str) -> RespError {
+    RespError::Protocol(message.to_string())
+}
+
+/// Takes the next command off the start of `buf`, None until it is complete.
+///
+/// Commands are arrays of bulk strings, `*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n`, or inline
+/// as typed into a terminal, `GET key\r\n`. The arguments are views into `buf`. An
+/// empty array or line is an empty command.
+pub fn parse_command(buf: &mut BytesMut) -> Result<Option<Vec<Bytes>>, RespError> {
+    match buf.first() {
+        None => Ok(None),
+        Some(b'*') => parse_array(buf),
+        Some(_) => parse_inline(buf),
+    }
+}
+
+fn parse_array(buf: &mut BytesMut) -> Result<Option<Vec<Bytes>>, RespError> {
+    let Some((count, mut pos)) = read_number(buf, 1)? else {
+        return Ok(None);
+    };
+    if count > MAX_ARGS {
+        return Err(protocol_error("invalid multibulk length"));
+    }
+    let mut ranges = Vec::with_capacity(count.max(0) as usize);
+    for _ in 0..count {
+        match buf.get(pos) {
+            None => return Ok(None),
+            Some(b'$') => {}
+            Some(&other) => {
+                return Err(RespError::Protocol(format!(
+                    "expected '$', got '{}'",
+                    other as char
+                )))
+            }
+        }
+        let Some((len, start)) = read_number(buf, pos + 1)? else {
+            return Ok(None);
+        };
+        if len < 0 || len as usize > MAX_BULK_LEN {
+            return Err(protocol_error("invalid bulk length"));
+        }
+        let end = start + len as usize;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(protocol_error("bulk string is not terminated by CRLF"));
+        }
+        ranges.push(start..end);
+        pos = end + 2;
+    }
+    let frame = buf.split_to(pos).freeze();
+    Ok(Some(
+        ranges.into_iter().map(|range| frame.slice(range)).collect(),
+    ))
+}
+
+fn parse_inline(buf: &mut BytesMut) -> Result<Option<Vec<Bytes>>, RespError> {
+    let Some(end) = buf.iter().position(|&b| b == b'\n') else {
+        if buf.len() > MAX_LINE_LEN {
+            return Err(protocol_error("too big inline request"));
+        }
+        return Ok(None);
+    };
+    let line = buf.split_to(end + 1).freeze();
+    Ok(Some(
+        line.split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| line.slice_ref(arg))
+            .collect(),
+    ))
+}
+
+/// The number on the line starting at `start`, with the position after the line.
+fn read_number(buf: &[u8], start: usize) -> Result<Option<(i64, usize)>, RespError> {
+    let line = buf.get(start..).unwrap_or_default();
+    let Some(end) = line.windows(2).position(|window| window == b"\r\n") else {
+        if line.len() > MAX_LINE_LEN {
+            return Err(protocol_error("too big length line"));
+        }
+        return Ok(None);
+    };
+    let number = std::str::from_utf8(&line[..end])
+        .ok()
+        .and_then(|number| number.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))?;
+    Ok(Some((number, start + end + 2)))
+}
+
+/// A RESP2 reply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    /// None is the null bulk string of a missing key.
+    Bulk(Option<Bytes>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn encode(self, out: &mut ReplyBuffer) {
+        match self {
+            Reply::Status(status) => typed_line(out, b'+', status.as_bytes()),
+            Reply::Error(message) => {
+                typed_line(out, b'-', message.replace(['\r', '\n'], " ").as_bytes())
+            }
+            Reply::Integer(value) => typed_line(out, b':', value.to_string().as_bytes()),
+            Reply::Bulk(None) => typed_line(out, b'$', b"-1"),
+            Reply::Bulk(Some(value)) => {
+                typed_line(out, b'$', value.len().to_string().as_bytes());
+                out.value(value);
+                out.line(b"");
+            }
+            Reply::Array(replies) => {
+                typed_line(out, b'*', replies.len().to_string().as_bytes());
+                for reply in replies {
+                    reply.encode(out);
+                }
+            }
+        }
+    }
+}
+
+fn typed_line(out: &mut ReplyBuffer, kind: u8, line: &[u8]) {
+    out.put_slice(&[kind]);
+    out.line(line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::INLINE_VALUE_LEN;
+
+    fn encode(reply: Reply) -> Vec<u8> {
+        let mut out = ReplyBuffer::default();
+        reply.encode(&mut out);
+        out.finish().concat()
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let mut buf = BytesMut::from(
+            &b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nva\r\nl\r\nPING\r\n*1\r\n$4\r\nPI"[..],
+        );
+        let set = parse_command(&mut buf).unwrap().unwrap();
+        assert_eq!(set, ["SET", "key", "va\r\nl"]);
+        let ping = parse_command(&mut buf).unwrap().unwrap();
+        assert_eq!(ping, ["PING"]);
+        // incomplete commands are left in the buffer
+        assert_eq!(parse_command(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"NG\r\n");
+        assert_eq!(parse_command(&mut buf).unwrap().unwrap(), ["PING"]);
+        assert!(buf.is_empty());
+
+        let mut bad = BytesMut::from(&b"*1\r\n+GET\r\n"[..]);
+        assert!(matches!(
+            parse_command(&mut bad),
+            Err(RespError::Protocol(_))
+        ));
+        let mut bad = BytesMut::from(&b"*x\r\n"[..]);
+        assert!(parse_command(&mut bad).is_err());
+    }
+
+    #[test]
+    fn test_encode_replies() {
+        assert_eq!(encode(Reply::Status("OK")), b"+OK\r\n");
+        assert_eq!(
+            encode(Reply::Error(String::from("ERR bad\r\nline"))),
+            b"-ERR bad  line\r\n"
+        );
+        assert_eq!(encode(Reply::Integer(-2)), b":-2\r\n");
+        assert_eq!(encode(Reply::Bulk(None)), b"$-1\r\n");
+        let array = Reply::Array(vec![
+            Reply::Bulk(Some(Bytes::from_static(b"value"))),
+            Reply::Bulk(None),
+        ]);
+        assert_eq!(encode(array), b"*2\r\n$5\r\nvalue\r\n$-1\r\n");
+
+        let large = Bytes::from(vec![b'x'; INLINE_VALUE_LEN + 1]);
+        let mut out = ReplyBuffer::default();
+        Reply::Bulk(Some(large.clone())).encode(&mut out);
+        let chunks = out.finish();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[1], large);
+    }
+}
@@ -0,0 +1,389 @@
+use bytes::{Bytes, BytesMut};
+use log::error;
+use thiserror::Error;
+
+use crate::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
+use crate::kv_store::object_meta::{now_ms, ObjectMeta};
+use crate::kv_store::read_through::{self, ReadThroughLoader};
+use crate::kv_store::store_error::StoreError;
+use crate::kv_store::validate_key;
+use crate::metrics::{INCOMING_REQUESTS, RESPONSE_TIME_COLLECTOR};
+use crate::pipeline::{self, CommandHandler, ReplyBuffer};
+use crate::rebalance::Rebalancer;
+use crate::resp::protocol::{parse_command, Reply, RespError};
+use crate::shutdown::Shutdown;
+
+/// Why a command failed, answered as an error reply in the words of Redis.
+#[derive(Debug, Error)]
+enum CommandError {
+    #[error("ERR unknown command '{0}'")]
+    Unknown(String),
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    Arity(String),
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR invalid expire time in '{0}' command")]
+    InvalidExpire(String),
+    #[error("ERR {0}")]
+    InvalidKey(String),
+    #[error("ERR {0}")]
+    Store(#[from] StoreError),
+}
+
+/// Serves the Redis commands GET, SET with EX and PX, DEL, EXISTS, MGET, MSET, TTL and
+/// PING over RESP2. Commands of a connection run in order, pipelined commands are
+/// answered in one write.
+#[derive(Clone, Copy)]
+pub struct RespService {
+    kv_store: &'static LocalFileKVStore,
+    addr: &'static str,
+    rebalancer: Option<&'static Rebalancer>,
+    loader: Option<&'static ReadThroughLoader>,
+    shutdown: Option<&'static Shutdown>,
+}
+
+impl RespService {
+    pub fn new(kv_store: &'static LocalFileKVStore, addr: &'static str) -> RespService {
+        RespService {
+            kv_store,
+            addr,
+            rebalancer: None,
+            loader: None,
+            shutdown: None,
+        }
+    }
+
+    pub fn with_rebalancer(mut self, rebalancer: &'static Rebalancer) -> Self {
+        self.rebalancer = Some(rebalancer);
+        self
+    }
+
+    pub fn with_loader(mut self, loader: &'static ReadThroughLoader) -> Self {
+        self.loader = Some(loader);
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: &'static Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Serves until the shutdown is triggered.
+    pub async fn serve(&self) {
+        pipeline::serve("RESP", self.addr, self.shutdown, *self).await;
+    }
+
+    async fn execute(self, command: Vec<Bytes>) -> Reply {
+        let _tracked = self.shutdown.map(|shutdown| shutdown.track());
+        let _timer = RESPONSE_TIME_COLLECTOR.start_timer();
+        INCOMING_REQUESTS.inc();
+        let name = String::from_utf8_lossy(&command[0]).to_ascii_lowercase();
+        let args = &command[1..];
+        let result = match name.as_str() {
+            "ping" => match args {
+                [] => Ok(Reply::Status("PONG")),
+                [message] => Ok(Reply::Bulk(Some(message.clone()))),
+                _ => Err(CommandError::Arity(name)),
+            },
+            "get" => match args {
+                [key] => self.get(key).await.map(Reply::Bulk),
+                _ => Err(CommandError::Arity(name)),
+            },
+            "set" if args.len() >= 2 => self.set(&args[0], &args[1], &args[2..]).await,
+            "del" if !args.is_empty() => self.delete(args).await,
+            "exists" if !args.is_empty() => self.exists(args).await,
+            "mget" if !args.is_empty() => self.mget(args).await,
+            "mset" if !args.is_empty() && args.len() % 2 == 0 => self.mset(args).await,
+            "ttl" => match args {
+                [key] => self.ttl(key).await,
+                _ => Err(CommandError::Arity(name)),
+            },
+            "set" | "del" | "exists" | "mget" | "mset" => Err(CommandError::Arity(name)),
+            _ => Err(CommandError::Unknown(name)),
+        };
+        result.unwrap_or_else(|e| {
+            if matches!(e, CommandError::Store(_)) {
+                error!("RESP command failed: {}", e);
+            }
+            Reply::Error(e.to_string())
+        })
+    }
+
+    /// A miss is served from the old owner while the key is being handed over, then
+    /// read through the loader.
+    async fn get(self, key: &[u8]) -> Result<Option<Bytes>, CommandError> {
+        let key = key_string(key)?;
+        match read_through::lookup(self.kv_store, self.rebalancer, self.loader, &key).await {
+            Ok((_, value)) => Ok(Some(value)),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn set(
+        self,
+        key: &[u8],
+        value: &Bytes,
+        options: &[Bytes],
+    ) -> Result<Reply, CommandError> {
+        let mut expires_in_ms = None;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let unit_ms = match option.to_ascii_lowercase().as_slice() {
+                b"ex" => 1000,
+                b"px" => 1,
+                _ => return Err(CommandError::Syntax),
+            };
+            let Some(amount) = options.next() else {
+                return Err(CommandError::Syntax);
+            };
+            if expires_in_ms.is_some() {
+                return Err(CommandError::Syntax);
+            }
+            let amount: i64 = parse_integer(amount)?;
+            let ms = amount
+                .checked_mul(unit_ms)
+                .filter(|ms| *ms > 0)
+                .ok_or_else(|| CommandError::InvalidExpire(String::from("set")))?;
+            expires_in_ms = Some(ms as u64);
+        }
+        let meta = ObjectMeta {
+            expires_at_ms: expires_in_ms.map(|ms| now_ms().saturating_add(ms)),
+            ..Default::default()
+        };
+        self.kv_store
+            .put_with_meta(key_string(key)?, value.clone(), &meta)
+            .await?;
+        Ok(Reply::Status("OK"))
+    }
+
+    async fn delete(self, keys: &[Bytes]) -> Result<Reply, CommandError> {
+        let mut deleted = 0;
+        for key in keys {
+            let key = key_string(key)?;
+            if self.stat(&key).await?.is_some() {
+                self.kv_store.delete(key)?;
+                deleted += 1;
+            }
+        }
+        Ok(Reply::Integer(deleted))
+    }
+
+    async fn exists(self, keys: &[Bytes]) -> Result<Reply, CommandError> {
+        let mut found = 0;
+        for key in keys {
+            if self.stat(&key_string(key)?).await?.is_some() {
+                found += 1;
+            }
+        }
+        Ok(Reply::Integer(found))
+    }
+
+    async fn mget(self, keys: &[Bytes]) -> Result<Reply, CommandError> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(Reply::Bulk(self.get(key).await?));
+        }
+        Ok(Reply::Array(values))
+    }
+
+    async fn mset(self, pairs: &[Bytes]) -> Result<Reply, CommandError> {
+        // nothing is written unless every key is valid
+        let keys = pairs
+            .iter()
+            .step_by(2)
+            .map(|key| key_string(key))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (key, pair) in keys.into_iter().zip(pairs.chunks(2)) {
+            self.kv_store.put(key, pair[1].clone()).await?;
+        }
+        Ok(Reply::Status("OK"))
+    }
+
+    /// Seconds to live rounded like Redis, -1 without an expiry and -2 for a missing key.
+    async fn ttl(self, key: &[u8]) -> Result<Reply, CommandError> {
+        let ttl = match self.stat(&key_string(key)?).await? {
+            None => -2,
+            Some(ObjectMeta {
+                expires_at_ms: None,
+                ..
+            }) => -1,
+            Some(ObjectMeta {
+                expires_at_ms: Some(at),
+                ..
+            }) => (at.saturating_sub(now_ms()).saturating_add(500) / 1000) as i64,
+        };
+        Ok(Reply::Integer(ttl))
+    }
+
+    /// The metadata of a locally stored key.
+    async fn stat(self, key: &str) -> Result<Option<ObjectMeta>, CommandError> {
+        match self.kv_store.head(key.to_string()).await {
+            Ok((meta, _)) => Ok(Some(meta)),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl CommandHandler for RespService {
+    type Error = RespError;
+
+    async fn handle(&self, input: &mut BytesMut, out: &mut ReplyBuffer) -> Result<(), RespError> {
+        loop {
+            match parse_command(input) {
+                Ok(Some(command)) if command.is_empty() => {}
+                Ok(Some(command)) => self.execute(command).await.encode(out),
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    // like Redis, a protocol error is answered before the connection is closed
+                    Reply::Error(format!("ERR {}", e)).encode(out);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+fn key_string(key: &[u8]) -> Result<String, CommandError> {
+    let key = std::str::from_utf8(key)
+        .map_err(|_| CommandError::InvalidKey(String::from("keys have to be UTF-8")))?;
+    validate_key(key).map_err(|e| CommandError::InvalidKey(e.to_string()))?;
+    Ok(key.to_string())
+}
+
+fn parse_integer(value: &[u8]) -> Result<i64, CommandError> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or(CommandError::NotInteger)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use monoio::io::{AsyncReadRentExt, AsyncWriteRentExt};
+    use monoio::net::TcpStream;
+
+    use super::*;
+    use crate::settings::local_kv_options::StoreLayout;
+    use crate::test_util::{self, leak};
+
+    async fn serve(layout: StoreLayout) -> (&'static str, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = test_util::store(dir.path(), layout);
+        let addr =
+            test_util::serve_on_free_port(|addr| leak(RespService::new(store, addr)).serve()).await;
+        (addr, dir)
+    }
+
+    fn command(line: &str) -> Vec<u8> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let mut encoded = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            encoded.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        encoded
+    }
+
+    /// Sends the commands at once and checks the replies.
+    async fn call(stream: &mut TcpStream, commands: &[&str], expected: &[u8]) {
+        let requests: Vec<u8> = commands.iter().flat_map(|args| command(args)).collect();
+        let (res, _) = stream.write_all(requests).await;
+        res.unwrap();
+        let (res, replies) = stream.read_exact(Vec::with_capacity(expected.len())).await;
+        res.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&replies),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_commands() {
+        for layout in [StoreLayout::FilePerKey, StoreLayout::Segment] {
+            let (addr, _dir) = serve(layout).await;
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            call(&mut stream, &["PING"], b"+PONG\r\n").await;
+            call(&mut stream, &["GET key"], b"$-1\r\n").await;
+            call(
+                &mut stream,
+                &["SET key value", "GET key", "TTL key", "TTL missing"],
+                b"+OK\r\n$5\r\nvalue\r\n:-1\r\n:-2\r\n",
+            )
+            .await;
+            call(
+                &mut stream,
+                &[
+                    "MSET a 1 b 2",
+                    "MGET a missing b",
+                    "EXISTS a a missing",
+                    "DEL a missing",
+                    "EXISTS a",
+                ],
+                b"+OK\r\n*3\r\n$1\r\n1\r\n$-1\r\n$1\r\n2\r\n:2\r\n:1\r\n:0\r\n",
+            )
+            .await;
+            call(
+                &mut stream,
+                &[
+                    "set key value ex 100",
+                    "TTL key",
+                    "SET key value EX 0",
+                    "SET key value NX",
+                    "GET",
+                    "FLUSHALL",
+                ],
+                b"+OK\r\n:100\r\n\
+                  -ERR invalid expire time in 'set' command\r\n\
+                  -ERR syntax error\r\n\
+                  -ERR wrong number of arguments for 'get' command\r\n\
+                  -ERR unknown command 'flushall'\r\n",
+            )
+            .await;
+            call(
+                &mut stream,
+                &[
+                    "SET /etc/passwd x",
+                    "GET ../x",
+                    "MSET a 1 a/../../x 2",
+                    "EXISTS a",
+                ],
+                b"-ERR invalid key \"/etc/passwd\": absolute\r\n\
+                  -ERR invalid key \"../x\": contains a . or .. segment\r\n\
+                  -ERR invalid key \"a/../../x\": contains a . or .. segment\r\n\
+                  :0\r\n",
+            )
+            .await;
+        }
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_values_expire() {
+        let (addr, _dir) = serve(StoreLayout::FilePerKey).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        call(
+            &mut stream,
+            &["SET key value PX 50", "GET key"],
+            b"+OK\r\n$5\r\nvalue\r\n",
+        )
+        .await;
+        monoio::time::sleep(Duration::from_millis(60)).await;
+        call(
+            &mut stream,
+            &["GET key", "TTL key", "EXISTS key"],
+            b"$-1\r\n:-2\r\n:0\r\n",
+        )
+        .await;
+
+        // inline commands, as typed into a terminal
+        let (res, _) = stream.write_all(b"PING hello\r\n".to_vec()).await;
+        res.unwrap();
+        let (res, reply) = stream.read_exact(Vec::with_capacity(11)).await;
+        res.unwrap();
+        assert_eq!(reply, b"$5\r\nhello\r\n");
+    }
+}
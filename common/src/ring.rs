@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+
+use crate::kv_store::{stable_hash, Key};
+
+/// Virtual nodes placed on the ring for every unit of member weight.
+pub const DEFAULT_VNODES: u32 = 128;
+/// Store capacity worth one unit of member weight.
+pub const WEIGHT_UNIT_BYTES: u64 = 1 << 30;
+/// Heaviest member weight, bounds the virtual nodes of a very large store.
+pub const MAX_WEIGHT: u32 = 64;
+
+/// Ring weight of a worker whose store holds up to `capacity_bytes`, one unit per GiB.
+/// Unbounded stores, published as 0, and stores under a GiB weigh 1.
+pub fn capacity_weight(capacity_bytes: u64) -> u32 {
+    (capacity_bytes / WEIGHT_UNIT_BYTES).clamp(1, MAX_WEIGHT as u64) as u32
+}
+
+/// Consistent hashing ring mapping keys to the workers that own them.
+///
+/// Every member is placed on the ring `weight * vnodes` times, a key is owned by the
+/// members of the first virtual nodes found walking clockwise from the key's hash.
+/// Members labelled with a zone are picked so that owners land in distinct zones
+/// while there are zones left.
+#[derive(Clone, Debug)]
+pub struct HashRing {
+    vnodes: u32,
+    members: BTreeMap<String, u32>,
+    zones: BTreeMap<String, String>,
+    /// Virtual node positions sorted by hash, pointing into `ids`.
+    points: Vec<(u64, usize)>,
+    ids: Vec<String>,
+}
+
+impl Default for HashRing {
+    fn default() -> Self {
+        HashRing::new(DEFAULT_VNODES)
+    }
+}
+
+impl HashRing {
+    pub fn new(vnodes: u32) -> HashRing {
+        HashRing {
+            vnodes: vnodes.max(1),
+            members: BTreeMap::new(),
+            zones: BTreeMap::new(),
+            points: Vec::new(),
+            ids: Vec::new(),
+        }
+    }
+
+    /// Builds a ring of equally weighted members.
+    pub fn with_members<I, S>(vnodes: u32, members: I) -> HashRing
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut ring = HashRing::new(vnodes);
+        ring.set_members(members.into_iter().map(|id| (id.into(), 1)));
+        ring
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn vnodes(&self) -> u32 {
+        self.vnodes
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.members.contains_key(id)
+    }
+
+    pub fn zone(&self, id: &str) -> Option<&str> {
+        self.zones.get(id).map(String::as_str)
+    }
+
+    /// Whether both rings place every key on the same owners: same virtual nodes, same
+    /// weighted members and same zones for them. Labels of non-members do not count.
+    pub fn places_like(&self, other: &HashRing) -> bool {
+        self.vnodes == other.vnodes
+            && self.members == other.members
+            && self
+                .members
+                .keys()
+                .all(|id| self.zone(id) == other.zone(id))
+    }
+
+    /// Labels members with their zone, returning whether any label changed. Labels of
+    /// members that are not on the ring are kept for when they join.
+    pub fn set_zones<I>(&mut self, zones: I) -> bool
+    where
+        I: IntoIterator<Item = (String, Option<String>)>,
+    {
+        let mut changed = false;
+        for (id, zone) in zones {
+            changed |= match zone {
+                Some(zone) => self.zones.insert(id, zone.clone()) != Some(zone),
+                None => self.zones.remove(&id).is_some(),
+            };
+        }
+        changed
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.members
+            .iter()
+            .map(|(id, weight)| (id.as_str(), *weight))
+    }
+
+    /// Adds a member or changes its weight, a weight of 0 removes it.
+    pub fn add(&mut self, id: &str, weight: u32) {
+        if weight == 0 {
+            self.remove(id);
+            return;
+        }
+        if self.members.insert(id.to_string(), weight) != Some(weight) {
+            self.rebuild();
+        }
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        if self.members.remove(id).is_some() {
+            self.rebuild();
+        }
+    }
+
+    /// Replaces the membership, returning whether anything changed.
+    pub fn set_members<I>(&mut self, members: I) -> bool
+    where
+        I: IntoIterator<Item = (String, u32)>,
+    {
+        let members: BTreeMap<String, u32> = members
+            .into_iter()
+            .filter(|(_, weight)| *weight > 0)
+            .collect();
+        if members == self.members {
+            return false;
+        }
+        self.members = members;
+        self.rebuild();
+        true
+    }
+
+    /// Up to `n` distinct members owning the key, the first one being the primary owner.
+    /// Members in a zone already holding a replica are only used once every zone is.
+    pub fn owners<K: Key + ?Sized>(&self, key: &K, n: usize) -> Vec<&str> {
+        self.owners_of_hash(key.stable_hash(), n)
+    }
+
+    pub fn primary<K: Key + ?Sized>(&self, key: &K) -> Option<&str> {
+        self.owners(key, 1).into_iter().next()
+    }
+
+    pub fn owners_of_hash(&self, hash: u64, n: usize) -> Vec<&str> {
+        let n = n.min(self.ids.len());
+        let mut owners: Vec<&str> = Vec::with_capacity(n);
+        if n == 0 {
+            return owners;
+        }
+        // members passed over because their zone already holds a replica, in ring order
+        let mut skipped: Vec<&str> = Vec::new();
+        let mut zones: Vec<&str> = Vec::new();
+        let position = mix(hash);
+        let start = self.points.partition_point(|(point, _)| *point < position);
+        for i in 0..self.points.len() {
+            let (_, member) = self.points[(start + i) % self.points.len()];
+            let id = self.ids[member].as_str();
+            if owners.contains(&id) || skipped.contains(&id) {
+                continue;
+            }
+            match self.zone(id) {
+                Some(zone) if zones.contains(&zone) => skipped.push(id),
+                Some(zone) => {
+                    zones.push(zone);
+                    owners.push(id);
+                }
+                None => owners.push(id),
+            }
+            if owners.len() == n || owners.len() + skipped.len() == self.ids.len() {
+                break;
+            }
+        }
+        let missing = n - owners.len();
+        owners.extend(skipped.into_iter().take(missing));
+        owners
+    }
+
+    fn rebuild(&mut self) {
+        self.ids = self.members.keys().cloned().collect();
+        self.points.clear();
+        for (member, (id, weight)) in self.members.iter().enumerate() {
+            for vnode in 0..weight * self.vnodes {
+                let point = mix(stable_hash(format!("{}#{}", id, vnode).as_bytes()));
+                self.points.push((point, member));
+            }
+        }
+        // ties are broken by member id so every worker builds the same ring
+        self.points.sort_unstable();
+    }
+}
+
+/// Finalizer of splitmix64, spreads FNV hashes of similar strings over the whole ring.
+fn mix(mut hash: u64) -> u64 {
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: usize = 20_000;
+
+    fn workers(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("10.0.0.{}:8080", i)).collect()
+    }
+
+    fn keys() -> Vec<String> {
+        (0..KEYS)
+            .map(|i| format!("datasets/part-{:05}", i))
+            .collect()
+    }
+
+    fn primaries(ring: &HashRing) -> Vec<String> {
+        keys()
+            .iter()
+            .map(|key| ring.primary(key).unwrap().to_string())
+            .collect()
+    }
+
+    fn moved_fraction(before: &[String], after: &[String]) -> f64 {
+        let moved = before.iter().zip(after).filter(|(a, b)| a != b).count();
+        moved as f64 / before.len() as f64
+    }
+
+    #[test]
+    fn test_owners_are_distinct_and_ordered() {
+        let ring = HashRing::with_members(DEFAULT_VNODES, workers(5));
+        let key = String::from("models/a.bin");
+        let owners = ring.owners(&key, 3);
+        assert_eq!(owners.len(), 3);
+        assert_eq!(Some(owners[0]), ring.primary(&key));
+        assert_eq!(ring.owners(&key, 2), owners[..2]);
+        assert_eq!(ring.owners(&key, 10).len(), 5);
+        assert!(HashRing::default().owners(&key, 3).is_empty());
+
+        let mut rebuilt = HashRing::new(DEFAULT_VNODES);
+        for worker in workers(5).iter().rev() {
+            rebuilt.add(worker, 1);
+        }
+        assert_eq!(rebuilt.owners(&key, 3), owners);
+    }
+
+    #[test]
+    fn test_load_follows_weight() {
+        let mut ring = HashRing::with_members(DEFAULT_VNODES, workers(4));
+        ring.add("10.0.0.0:8080", 2);
+        let mut load: BTreeMap<String, usize> = BTreeMap::new();
+        for owner in primaries(&ring) {
+            *load.entry(owner).or_default() += 1;
+        }
+        // 5 weight units over 20000 keys, 4000 keys per unit
+        for (worker, keys) in load {
+            let expected = if worker == "10.0.0.0:8080" {
+                8000
+            } else {
+                4000
+            };
+            let skew = (keys as f64 - expected as f64).abs() / expected as f64;
+            assert!(skew < 0.2, "{} owns {} keys", worker, keys);
+        }
+    }
+
+    #[test]
+    fn test_capacity_weight() {
+        assert_eq!(capacity_weight(0), 1);
+        assert_eq!(capacity_weight(100 << 20), 1);
+        assert_eq!(capacity_weight(4 << 30), 4);
+        assert_eq!(capacity_weight(u64::MAX), MAX_WEIGHT);
+    }
+
+    #[test]
+    fn test_adding_a_worker_moves_its_share_only() {
+        let ring = HashRing::with_members(DEFAULT_VNODES, workers(10));
+        let before = primaries(&ring);
+        let mut grown = ring.clone();
+        grown.add("10.0.0.10:8080", 1);
+        let after = primaries(&grown);
+
+        // ideally 1/11 of the keyspace moves, and all of it to the new worker
+        let moved = moved_fraction(&before, &after);
+        assert!(moved > 0.05 && moved < 0.14, "moved {}", moved);
+        for (a, b) in before.iter().zip(after.iter()) {
+            assert!(a == b || b == "10.0.0.10:8080");
+        }
+    }
+
+    #[test]
+    fn test_removing_a_worker_moves_its_keys_only() {
+        let ring = HashRing::with_members(DEFAULT_VNODES, workers(10));
+        let before = primaries(&ring);
+        let mut shrunk = ring.clone();
+        shrunk.remove("10.0.0.3:8080");
+        assert!(!shrunk.contains("10.0.0.3:8080"));
+        let after = primaries(&shrunk);
+
+        let moved = moved_fraction(&before, &after);
+        assert!(moved > 0.05 && moved < 0.15, "moved {}", moved);
+        for (a, b) in before.iter().zip(after.iter()) {
+            assert!(a == b || a == "10.0.0.3:8080");
+        }
+    }
+
+    #[test]
+    fn test_owners_spread_over_zones() {
+        let mut ring = HashRing::with_members(DEFAULT_VNODES, workers(6));
+        // three workers in a, two in b, one in c
+        let zones = ["a", "a", "a", "b", "b", "c"];
+        let labels = workers(6)
+            .into_iter()
+            .zip(zones.iter().map(|zone| Some(zone.to_string())));
+        assert!(ring.set_zones(labels.clone()));
+        assert!(!ring.set_zones(labels));
+        let unlabelled = HashRing::with_members(DEFAULT_VNODES, workers(6));
+
+        for key in keys().iter().take(1000) {
+            let owners = ring.owners(key, 3);
+            let mut owner_zones: Vec<&str> =
+                owners.iter().map(|id| ring.zone(id).unwrap()).collect();
+            owner_zones.sort();
+            assert_eq!(owner_zones, vec!["a", "b", "c"], "{:?}", owners);
+            // the primary does not depend on zones
+            assert_eq!(owners[0], unlabelled.primary(key).unwrap());
+            // more replicas than zones reuse zones
+            assert_eq!(ring.owners(key, 5).len(), 5);
+            assert_eq!(ring.owners(key, 5)[..3], owners);
+        }
+    }
+
+    #[test]
+    fn test_set_members_reports_changes() {
+        let mut ring = HashRing::with_members(DEFAULT_VNODES, workers(3));
+        let same = workers(3).into_iter().map(|id| (id, 1));
+        assert!(!ring.set_members(same));
+        let more = workers(4).into_iter().map(|id| (id, 1));
+        assert!(ring.set_members(more));
+        assert_eq!(ring.len(), 4);
+    }
+}
@@ -7,6 +7,8 @@ use local_ip_address::local_ip;
 use log::info;
 use serde_derive::Deserialize;
 
+pub mod client_options;
+pub mod discovery_options;
 pub mod local_kv_options;
 
 lazy_static! {
@@ -20,13 +22,39 @@ pub struct Settings {
     pub log_level: String,
     pub hostname: String,
     pub local_ip: String,
+    /// Failure domain of this worker, e.g. the availability zone.
+    pub zone: Option<String>,
     pub http_port: u16,
     pub http2_port: u16,
     pub socket_port: u16,
+    /// Port of the Redis compatible listener, which only runs when set.
+    pub redis_port: Option<u16>,
+    /// Port of the memcached compatible listener, which only runs when set.
+    pub memcached_port: Option<u16>,
     pub service_discovery_type: String,
     pub etcd_uris: Vec<String>,
     pub static_service_list: Vec<String>,
+    /// Worker list watched by file discovery.
+    pub service_list_file: Option<String>,
+    pub discovery_refresh_ms: u64,
+    pub gossip_port: u16,
+    /// Gossip addresses of workers to join through, for gossip discovery.
+    pub gossip_seeds: Vec<String>,
+    pub gossip_interval_ms: u64,
+    pub gossip_suspect_ms: u64,
     pub metrics_push_uri: Option<String>,
+    pub ufs_root_path: Option<String>,
+    pub ring_vnodes: u32,
+    /// Ring owners of every key, has to match the clients' replication factor.
+    pub replication_factor: usize,
+    /// Bytes per second copied from old owners after a membership change, 0 is unlimited.
+    pub rebalance_rate_bytes: u64,
+    /// Seconds between anti-entropy rounds with the other replicas, 0 disables them.
+    pub anti_entropy_interval_secs: u64,
+    /// Bytes per second repaired by anti-entropy, 0 is unlimited.
+    pub anti_entropy_rate_bytes: u64,
+    /// Seconds a stopping worker waits for requests and deregistration before exiting.
+    pub shutdown_timeout_secs: u64,
 }
 
 impl From<Config> for Settings {
@@ -41,52 +69,93 @@ impl From<Config> for Settings {
         let local_ip = config
             .get::<String>("local_ip")
             .unwrap_or(local_ip().unwrap().to_string());
+        let zone = config.get_string("zone").ok();
         let http_port = config.get::<u16>("http_port").unwrap_or(8080);
         let http2_port = config.get::<u16>("http2_port").unwrap_or(5928);
 
         let socket_port = config.get::<u16>("socket_port").unwrap_or(19090);
+        let redis_port = config.get::<u16>("redis_port").ok();
+        let memcached_port = config.get::<u16>("memcached_port").ok();
         let service_discovery_type = config
             .get_string("service_discovery_type")
             .unwrap_or(String::from("static"));
         let static_service_list = if service_discovery_type == "static" {
-            config
-                .get_string("static_service_list")
-                .unwrap_or(format!("localhost:{}", http_port))
-                .split(',')
-                .map(String::from)
-                .collect()
+            get_list(&config, "static_service_list")
+                .unwrap_or_else(|| vec![format!("localhost:{}", http_port)])
         } else {
             Vec::new()
         };
         let etcd_uris = if service_discovery_type == "etcd" {
-            config
-                .get_string("etcd_uris")
-                .unwrap_or(String::from("localhost:2379"))
-                .split(',')
-                .map(String::from)
-                .collect()
+            get_list(&config, "etcd_uris").unwrap_or_else(|| vec![String::from("localhost:2379")])
         } else {
             Vec::new()
         };
+        let service_list_file = config.get_string("service_list_file").ok();
+        let discovery_refresh_ms = config.get::<u64>("discovery_refresh_ms").unwrap_or(5000);
+        let gossip_port = config.get::<u16>("gossip_port").unwrap_or(7946);
+        let gossip_seeds = get_list(&config, "gossip_seeds").unwrap_or_default();
+        let gossip_interval_ms = config.get::<u64>("gossip_interval_ms").unwrap_or(1000);
+        let gossip_suspect_ms = config.get::<u64>("gossip_suspect_ms").unwrap_or(5000);
         let metrics_push_uri = config.get_string("metrics_push_uri").ok();
+        let ufs_root_path = config.get_string("ufs_root_path").ok();
+        let ring_vnodes = config
+            .get::<u32>("ring_vnodes")
+            .unwrap_or(crate::ring::DEFAULT_VNODES);
+        let replication_factor = config.get::<usize>("replication_factor").unwrap_or(1);
+        let rebalance_rate_bytes = config
+            .get::<u64>("rebalance_rate_bytes")
+            .unwrap_or(64 * 1024 * 1024);
+        let anti_entropy_interval_secs = config
+            .get::<u64>("anti_entropy_interval_secs")
+            .unwrap_or(600);
+        let anti_entropy_rate_bytes = config
+            .get::<u64>("anti_entropy_rate_bytes")
+            .unwrap_or(16 * 1024 * 1024);
+        let shutdown_timeout_secs = config.get::<u64>("shutdown_timeout_secs").unwrap_or(30);
         let settings = Settings {
             debug,
             log_level,
             hostname,
             local_ip,
+            zone,
             http_port,
             http2_port,
             socket_port,
+            redis_port,
+            memcached_port,
             service_discovery_type,
             etcd_uris,
             static_service_list,
+            service_list_file,
+            discovery_refresh_ms,
+            gossip_port,
+            gossip_seeds,
+            gossip_interval_ms,
+            gossip_suspect_ms,
             metrics_push_uri,
+            ufs_root_path,
+            ring_vnodes,
+            replication_factor,
+            rebalance_rate_bytes,
+            anti_entropy_interval_secs,
+            anti_entropy_rate_bytes,
+            shutdown_timeout_secs,
         };
         info!("Settings loaded {:?}", settings);
         settings
     }
 }
 
+/// Lists are arrays in the config file and comma separated in the environment.
+fn get_list(config: &Config, key: &str) -> Option<Vec<String>> {
+    config.get::<Vec<String>>(key).ok().or_else(|| {
+        config
+            .get_string(key)
+            .ok()
+            .map(|list| list.split(',').map(String::from).collect())
+    })
+}
+
 impl Settings {
     pub fn new() -> Result<Self> {
         let config_filename = env::var("FAIRY_CONFIG").unwrap_or_else(|_| "fairy_config".into());
@@ -119,3 +188,12 @@ where
         .expect("Config should be loaded");
     T::from_with_prefix(prefix, &config_builder)
 }
+
+pub(crate) fn get_config<'a, T>(config: &Config, prefix: &str, key: &str, default: T) -> T
+where
+    T: serde::Deserialize<'a>,
+{
+    config
+        .get::<T>(format!("{}.{}", prefix, key).as_str())
+        .unwrap_or(default)
+}
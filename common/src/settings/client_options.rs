@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use config::Config;
+use log::info;
+
+use crate::ring::DEFAULT_VNODES;
+use crate::settings::discovery_options::DiscoveryOptions;
+use crate::settings::{get_config, FromConfig};
+
+#[derive(Clone, Debug)]
+pub struct FairyClientOptions {
+    /// Workers as registered in service discovery, `host:http_port`.
+    pub workers: Vec<String>,
+    /// Keeps `workers` up to date, static discovery defaults to `workers`.
+    pub discovery: DiscoveryOptions,
+    /// Port of the h2 data service on every worker.
+    pub h2_port: u16,
+    /// Zone the client runs in, reads go to a replica in the same zone first.
+    pub zone: Option<String>,
+    pub ring_vnodes: u32,
+    /// Number of ring owners each key is written to.
+    pub replication_factor: usize,
+    /// Owners that must acknowledge a put or delete.
+    pub write_quorum: usize,
+    /// Owners that must answer a get or stat.
+    pub read_quorum: usize,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    /// Retries after the first attempt for timeouts, connection errors and 5xx.
+    pub max_retries: u32,
+    /// Backoff before a retry, multiplied by the attempt number.
+    pub retry_backoff: Duration,
+    /// Consecutive failed requests or probes before a worker is ejected from routing.
+    pub failure_threshold: u32,
+    pub health_check_interval: Duration,
+}
+
+impl Default for FairyClientOptions {
+    fn default() -> Self {
+        FairyClientOptions {
+            workers: vec![String::from("localhost:8080")],
+            discovery: DiscoveryOptions::default(),
+            h2_port: 5928,
+            zone: None,
+            ring_vnodes: DEFAULT_VNODES,
+            replication_factor: 1,
+            write_quorum: 1,
+            read_quorum: 1,
+            connect_timeout: Duration::from_millis(1000),
+            request_timeout: Duration::from_millis(5000),
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(50),
+            failure_threshold: 3,
+            health_check_interval: Duration::from_millis(5000),
+        }
+    }
+}
+
+impl FromConfig for FairyClientOptions {
+    fn from_with_prefix(prefix: &str, config: &Config) -> Self {
+        let default = FairyClientOptions::default();
+        let workers = get_config(config, prefix, "workers", default.workers);
+        let mut discovery = DiscoveryOptions::from_with_prefix(prefix, config);
+        if discovery.static_service_list.is_empty() {
+            discovery.static_service_list = workers.clone();
+        }
+        let h2_port = get_config(config, prefix, "h2_port", default.h2_port);
+        let zone = get_config(config, prefix, "zone", default.zone);
+        let ring_vnodes = get_config(config, prefix, "ring_vnodes", default.ring_vnodes);
+        let replication_factor = get_config(
+            config,
+            prefix,
+            "replication_factor",
+            default.replication_factor,
+        );
+        let write_quorum = get_config(config, prefix, "write_quorum", default.write_quorum);
+        let read_quorum = get_config(config, prefix, "read_quorum", default.read_quorum);
+        let connect_timeout_ms = get_config(config, prefix, "connect_timeout_ms", 1000);
+        let request_timeout_ms = get_config(config, prefix, "request_timeout_ms", 5000);
+        let max_retries = get_config(config, prefix, "max_retries", default.max_retries);
+        let retry_backoff_ms = get_config(config, prefix, "retry_backoff_ms", 50);
+        let failure_threshold = get_config(
+            config,
+            prefix,
+            "failure_threshold",
+            default.failure_threshold,
+        );
+        let health_check_interval_ms = get_config(config, prefix, "health_check_interval_ms", 5000);
+
+        let options = FairyClientOptions {
+            workers,
+            discovery,
+            h2_port,
+            zone,
+            ring_vnodes,
+            replication_factor,
+            write_quorum,
+            read_quorum,
+            connect_timeout: Duration::from_millis(connect_timeout_ms),
+            request_timeout: Duration::from_millis(request_timeout_ms),
+            max_retries,
+            retry_backoff: Duration::from_millis(retry_backoff_ms),
+            failure_threshold,
+            health_check_interval: Duration::from_millis(health_check_interval_ms),
+        };
+        info!("FairyClientOptions loaded {:?}", options);
+        options
+    }
+}
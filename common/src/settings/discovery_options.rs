@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use config::Config;
+use log::{info, warn};
+
+use crate::settings::{get_config, FromConfig, Settings};
+
+/// Where the members of the cluster come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiscoveryType {
+    /// A fixed list from the configuration.
+    Static,
+    /// Workers registering themselves in etcd.
+    Etcd,
+    /// A file listing the workers, re-read when it changes.
+    File,
+    /// Workers gossiping membership among themselves, no external service needed.
+    Gossip,
+}
+
+impl DiscoveryType {
+    pub fn parse(discovery_type: &str) -> Option<DiscoveryType> {
+        match discovery_type {
+            "static" => Some(DiscoveryType::Static),
+            "etcd" => Some(DiscoveryType::Etcd),
+            "file" => Some(DiscoveryType::File),
+            "gossip" => Some(DiscoveryType::Gossip),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DiscoveryOptions {
+    pub discovery_type: DiscoveryType,
+    /// Workers as `host:http_port`, for static discovery.
+    pub static_service_list: Vec<String>,
+    pub etcd_uris: Vec<String>,
+    /// File with one worker per line, for file discovery.
+    pub service_list_file: Option<String>,
+    /// How often the service list file is checked, and the wait before reconnecting to etcd.
+    pub refresh_interval: Duration,
+    /// UDP port workers gossip on.
+    pub gossip_port: u16,
+    /// Gossip addresses, `host:gossip_port`, of workers a new node joins through.
+    pub gossip_seeds: Vec<String>,
+    /// How often a node probes one of the others.
+    pub gossip_interval: Duration,
+    /// How long a suspected worker has to refute the suspicion before it is removed.
+    pub gossip_suspect_timeout: Duration,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        DiscoveryOptions {
+            discovery_type: DiscoveryType::Static,
+            static_service_list: Vec::new(),
+            etcd_uris: vec![String::from("localhost:2379")],
+            service_list_file: None,
+            refresh_interval: Duration::from_millis(5000),
+            gossip_port: 7946,
+            gossip_seeds: Vec::new(),
+            gossip_interval: Duration::from_millis(1000),
+            gossip_suspect_timeout: Duration::from_millis(5000),
+        }
+    }
+}
+
+fn parse_type(discovery_type: &str) -> DiscoveryType {
+    DiscoveryType::parse(discovery_type).unwrap_or_else(|| {
+        warn!(
+            "Unknown service_discovery_type {}, using static",
+            discovery_type
+        );
+        DiscoveryType::Static
+    })
+}
+
+impl From<&Settings> for DiscoveryOptions {
+    fn from(settings: &Settings) -> Self {
+        DiscoveryOptions {
+            discovery_type: parse_type(&settings.service_discovery_type),
+            static_service_list: settings.static_service_list.clone(),
+            etcd_uris: settings.etcd_uris.clone(),
+            service_list_file: settings.service_list_file.clone(),
+            refresh_interval: Duration::from_millis(settings.discovery_refresh_ms),
+            gossip_port: settings.gossip_port,
+            gossip_seeds: settings.gossip_seeds.clone(),
+            gossip_interval: Duration::from_millis(settings.gossip_interval_ms),
+            gossip_suspect_timeout: Duration::from_millis(settings.gossip_suspect_ms),
+        }
+    }
+}
+
+impl FromConfig for DiscoveryOptions {
+    fn from_with_prefix(prefix: &str, config: &Config) -> Self {
+        let default = DiscoveryOptions::default();
+        let discovery_type = get_config(
+            config,
+            prefix,
+            "service_discovery_type",
+            String::from("static"),
+        );
+        let static_service_list = get_config(
+            config,
+            prefix,
+            "static_service_list",
+            default.static_service_list,
+        );
+        let etcd_uris = get_config(config, prefix, "etcd_uris", default.etcd_uris);
+        let service_list_file = get_config(config, prefix, "service_list_file", None);
+        let refresh_ms = get_config(config, prefix, "discovery_refresh_ms", 5000);
+        let gossip_port = get_config(config, prefix, "gossip_port", default.gossip_port);
+        let gossip_seeds = get_config(config, prefix, "gossip_seeds", default.gossip_seeds);
+        let gossip_interval_ms = get_config(config, prefix, "gossip_interval_ms", 1000);
+        let gossip_suspect_ms = get_config(config, prefix, "gossip_suspect_ms", 5000);
+
+        let options = DiscoveryOptions {
+            discovery_type: parse_type(&discovery_type),
+            static_service_list,
+            etcd_uris,
+            service_list_file,
+            refresh_interval: Duration::from_millis(refresh_ms),
+            gossip_port,
+            gossip_seeds,
+            gossip_interval: Duration::from_millis(gossip_interval_ms),
+            gossip_suspect_timeout: Duration::from_millis(gossip_suspect_ms),
+        };
+        info!("DiscoveryOptions loaded {:?}", options);
+        options
+    }
+}
@@ -1,8 +1,29 @@
 use config::Config;
-use log::info;
-use serde::Deserialize;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 
-use crate::settings::FromConfig;
+use crate::settings::{get_config, FromConfig};
+
+/// How values are laid out inside a storage directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StoreLayout {
+    /// One file per key under hashed bucket directories.
+    #[serde(rename = "file")]
+    FilePerKey,
+    /// Values appended to large segment files, for many small objects.
+    #[serde(rename = "segment")]
+    Segment,
+}
+
+impl StoreLayout {
+    pub fn parse(layout: &str) -> Option<StoreLayout> {
+        match layout {
+            "file" => Some(StoreLayout::FilePerKey),
+            "segment" => Some(StoreLayout::Segment),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 #[allow(unused)]
@@ -10,6 +31,14 @@ pub struct LocalFileKVStoreOptions {
     pub root_path: String,
     pub num_bucket: u16,
     pub chuck_size: u32,
+    /// Bytes of unpinned data kept before evicting, 0 means unbounded.
+    pub capacity: u64,
+    /// Bytes that may be pinned against eviction, counted separately from `capacity`.
+    pub pin_budget: u64,
+    pub layout: StoreLayout,
+    /// Target size of a segment file for the segment layout.
+    pub segment_size: u64,
+    pub compaction_interval_secs: u64,
 }
 
 impl FromConfig for LocalFileKVStoreOptions {
@@ -22,22 +51,33 @@ impl FromConfig for LocalFileKVStoreOptions {
         );
         let num_bucket = get_config(config, prefix, "local_kv_num_bucket", 1024);
         let chuck_size = get_config(config, prefix, "local_kv_chunk_size", 128 * 1024);
+        let capacity = get_config(config, prefix, "local_kv_capacity_bytes", 0);
+        let pin_budget = get_config(
+            config,
+            prefix,
+            "local_kv_pin_budget_bytes",
+            1024 * 1024 * 1024,
+        );
+        let layout_name = get_config(config, prefix, "local_kv_layout", String::from("file"));
+        let layout = StoreLayout::parse(&layout_name).unwrap_or_else(|| {
+            warn!("Unknown local_kv_layout {}, using file", layout_name);
+            StoreLayout::FilePerKey
+        });
+        let segment_size = get_config(config, prefix, "local_kv_segment_size", 64 * 1024 * 1024);
+        let compaction_interval_secs =
+            get_config(config, prefix, "local_kv_compaction_interval_secs", 60);
 
         let options = LocalFileKVStoreOptions {
             root_path,
             num_bucket,
             chuck_size,
+            capacity,
+            pin_budget,
+            layout,
+            segment_size,
+            compaction_interval_secs,
         };
         info!("LocalFileKVStoreOptions loaded {:?}", options);
         options
     }
 }
-
-fn get_config<'a, T>(config: &Config, prefix: &str, key: &str, default: T) -> T
-where
-    T: Deserialize<'a>,
-{
-    config
-        .get::<T>(format!("{}.{}", prefix, key).as_str())
-        .unwrap_or(default)
-}
@@ -0,0 +1,131 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use tokio::sync::Notify;
+
+/// Signals the services of a process to stop and counts the requests they are still
+/// serving. Usable from both the monoio and the tokio side.
+#[derive(Default)]
+pub struct Shutdown {
+    triggered: AtomicBool,
+    triggered_notify: Notify,
+    in_flight: AtomicUsize,
+    idle_notify: Notify,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+        self.triggered_notify.notify_waiters();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    pub async fn triggered(&self) {
+        loop {
+            let notified = self.triggered_notify.notified();
+            if self.is_triggered() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Counts a request as in flight until the guard is dropped.
+    pub fn track(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Waits until no request is in flight.
+    pub async fn idle(&self) {
+        loop {
+            let notified = self.idle_notify.notified();
+            if self.in_flight() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+pub struct InFlight<'a>(&'a Shutdown);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle_notify.notify_waiters();
+        }
+    }
+}
+
+/// Resolves when `shutdown` is triggered, never without one.
+pub async fn triggered(shutdown: Option<&Shutdown>) {
+    match shutdown {
+        Some(shutdown) => shutdown.triggered().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use http::{Method, Request, StatusCode};
+
+    use super::*;
+    use crate::h2::h2_client;
+    use crate::h2::h2_service::H2Service;
+    use crate::settings::local_kv_options::StoreLayout;
+    use crate::test_util::{self, leak};
+
+    #[monoio::test(timer_enabled = true)]
+    async fn test_shutdown_stops_accepting_and_waits_for_requests() {
+        let addr = test_util::leak_str(format!("127.0.0.1:{}", test_util::free_port()));
+        let dir = tempfile::tempdir().unwrap();
+        let store = test_util::store(dir.path(), StoreLayout::FilePerKey);
+        let shutdown = leak(Shutdown::new());
+        let service = leak(H2Service::new(store, addr).with_shutdown(shutdown));
+        let serving = monoio::spawn(service.serve_h2());
+        monoio::time::sleep(Duration::from_millis(10)).await;
+
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri(format!("http://{}/put/key", addr))
+            .body(())
+            .unwrap();
+        let connection = h2_client::connect(addr).await.unwrap();
+        let response = h2_client::send(connection, request, Some(Bytes::from_static(b"value")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(shutdown.in_flight(), 0);
+
+        // a request still being served
+        let in_flight = shutdown.track();
+        shutdown.trigger();
+        assert!(shutdown.is_triggered());
+        serving.await;
+        // the listener closes asynchronously
+        monoio::time::sleep(Duration::from_millis(10)).await;
+        assert!(h2_client::connect(addr).await.is_err());
+        assert!(
+            monoio::time::timeout(Duration::from_millis(20), shutdown.idle())
+                .await
+                .is_err()
+        );
+        drop(in_flight);
+        shutdown.idle().await;
+        shutdown.triggered().await;
+    }
+}
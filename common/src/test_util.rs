@@ -0,0 +1,91 @@
+//! Fixtures shared by the tests of this crate and of the crates built on it: stores
+//! under temporary directories and services listening on free ports.
+
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::h2::h2_service::H2Service;
+use crate::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
+use crate::settings::local_kv_options::{LocalFileKVStoreOptions, StoreLayout};
+
+/// Options of a file per key store under `root_path`.
+pub fn options(root_path: &Path, capacity: u64, pin_budget: u64) -> LocalFileKVStoreOptions {
+    LocalFileKVStoreOptions {
+        root_path: root_path.to_string_lossy().to_string(),
+        num_bucket: 16,
+        chuck_size: 128 * 1024,
+        capacity,
+        pin_budget,
+        layout: StoreLayout::FilePerKey,
+        segment_size: 64 * 1024 * 1024,
+        compaction_interval_secs: 60,
+    }
+}
+
+/// Services hold their store and address by 'static reference, tests leak them.
+pub fn leak<T>(value: T) -> &'static T {
+    Box::leak(Box::new(value))
+}
+
+pub fn leak_str(value: String) -> &'static str {
+    Box::leak(value.into_boxed_str())
+}
+
+/// An unbounded store with the given layout under `root_path`.
+pub fn store(root_path: &Path, layout: StoreLayout) -> &'static LocalFileKVStore {
+    let mut options = options(root_path, 0, 0);
+    options.layout = layout;
+    leak(LocalFileKVStore::new(options).unwrap())
+}
+
+/// A port nothing listens on at the moment.
+pub fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Spawns the future `serve` returns for a loopback address on a free port, and gives
+/// it a moment to bind before returning the address.
+pub async fn serve_on_free_port<S, F>(serve: S) -> &'static str
+where
+    S: FnOnce(&'static str) -> F,
+    F: Future<Output = ()> + 'static,
+{
+    let addr = leak_str(format!("127.0.0.1:{}", free_port()));
+    monoio::spawn(serve(addr));
+    monoio::time::sleep(Duration::from_millis(10)).await;
+    addr
+}
+
+/// Like `serve_on_free_port` but on a monoio runtime of its own thread, for tests
+/// whose client runs on tokio.
+pub fn serve_on_free_port_in_thread<S, F>(serve: S) -> &'static str
+where
+    S: FnOnce(&'static str) -> F + Send + 'static,
+    F: Future<Output = ()> + 'static,
+{
+    let addr = leak_str(format!("127.0.0.1:{}", free_port()));
+    std::thread::spawn(move || {
+        let mut runtime = monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        runtime.block_on(serve(addr));
+    });
+    std::thread::sleep(Duration::from_millis(50));
+    addr
+}
+
+/// Address of the h2 service of `worker` on `port`.
+pub fn h2_addr(worker: &str, port: u16) -> &'static str {
+    leak_str(crate::h2::h2_client::h2_addr(worker, port))
+}
+
+/// Spawns the h2 service, for workers sharing a port on different loopback hosts.
+pub fn spawn_h2(service: H2Service) {
+    monoio::spawn(leak(service).serve_h2());
+}
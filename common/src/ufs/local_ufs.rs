@@ -0,0 +1,156 @@
+use std::error::Error;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use log::trace;
+
+use crate::kv_store::local_kv_store::blocking_pool::BlockingPool;
+use crate::kv_store::store_error::StoreError;
+use crate::kv_store::validate_key;
+
+/// Status of an object in under storage, used to tell whether a cached copy is stale.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UfsStatus {
+    pub uri: String,
+    pub len: u64,
+    pub etag: String,
+}
+
+/// Under file system backed by a local (or locally mounted) directory.
+/// Object paths are relative to `root_path` and always use `/` as separator.
+pub struct LocalUfs {
+    root_path: String,
+}
+
+impl LocalUfs {
+    pub fn new(root_path: String) -> LocalUfs {
+        LocalUfs { root_path }
+    }
+
+    pub fn root_path(&self) -> &str {
+        &self.root_path
+    }
+
+    pub async fn read(&self, path: &str) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let full_path = self.full_path(path)?;
+        let f = monoio::fs::File::open(&full_path).await?;
+        let file_size = std::fs::metadata(&full_path)?.len();
+        let buf = vec![0; file_size as usize];
+        let (res, buf) = f.read_exact_at(buf, 0).await;
+        res?;
+        f.close().await?;
+        trace!("Read {} bytes from ufs {}", buf.len(), full_path);
+        Ok(buf)
+    }
+
+    /// The etag of a local file is derived from its modification time and length.
+    pub fn stat(&self, path: &str) -> Result<UfsStatus, Box<dyn Error + Send + Sync>> {
+        let full_path = self.full_path(path)?;
+        let metadata = std::fs::metadata(&full_path)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+        Ok(UfsStatus {
+            uri: format!("file://{}", full_path),
+            len: metadata.len(),
+            etag: format!("\"{:x}-{:x}\"", modified.as_nanos(), metadata.len()),
+        })
+    }
+
+    /// Lists all object paths starting with `prefix`, sorted. The tree is walked on a
+    /// thread of its own, a large prefix would otherwise stall the event loop.
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        // only walk the deepest directory fully covered by the prefix
+        let start_dir = match prefix.rfind('/') {
+            Some(pos) => &prefix[..pos],
+            None => "",
+        };
+        if !start_dir.is_empty() {
+            validate_key(start_dir)?;
+        }
+        let start_path = Path::new(&self.root_path).join(start_dir);
+        let start_dir = start_dir.to_string();
+        let prefix = prefix.to_string();
+        let walker = BlockingPool::new("ufs-list", 1)?;
+        walker
+            .run(move || {
+                let mut paths = Vec::new();
+                if start_path.is_dir() {
+                    Self::walk(&start_path, &start_dir, &mut paths)?;
+                }
+                paths.retain(|path| path.starts_with(&prefix));
+                paths.sort();
+                Ok(paths)
+            })
+            .await?
+    }
+
+    fn walk(
+        dir: &Path,
+        relative: &str,
+        paths: &mut Vec<String>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let relative_path = if relative.is_empty() {
+                name
+            } else {
+                format!("{}/{}", relative, name)
+            };
+            if entry.file_type()?.is_dir() {
+                Self::walk(&entry.path(), &relative_path, paths)?;
+            } else {
+                paths.push(relative_path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Object paths are checked like keys, they must not lead out of the root.
+    fn full_path(&self, path: &str) -> Result<String, StoreError> {
+        let path = path.trim_start_matches('/');
+        validate_key(path)?;
+        Ok(format!("{}/{}", self.root_path, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[monoio::test]
+    async fn test_list_with_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("models/v1")).unwrap();
+        std::fs::write(dir.path().join("models/v1/a.bin"), b"a").unwrap();
+        std::fs::write(dir.path().join("models/v1/b.bin"), b"b").unwrap();
+        std::fs::write(dir.path().join("models/v2.bin"), b"c").unwrap();
+        std::fs::write(dir.path().join("tables.bin"), b"d").unwrap();
+
+        let ufs = LocalUfs::new(dir.path().to_string_lossy().to_string());
+        assert_eq!(
+            ufs.list("models/v").await.unwrap(),
+            vec!["models/v1/a.bin", "models/v1/b.bin", "models/v2.bin"]
+        );
+        assert_eq!(
+            ufs.list("models/v1/").await.unwrap(),
+            vec!["models/v1/a.bin", "models/v1/b.bin"]
+        );
+        assert_eq!(ufs.list("").await.unwrap().len(), 4);
+        assert!(ufs.list("missing/").await.unwrap().is_empty());
+    }
+
+    #[monoio::test]
+    async fn test_paths_stay_under_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ufs");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(dir.path().join("secret"), b"s").unwrap();
+        std::fs::write(root.join("a.bin"), b"a").unwrap();
+
+        let ufs = LocalUfs::new(root.to_string_lossy().to_string());
+        assert!(ufs.stat("/a.bin").is_ok());
+        assert!(ufs.stat("../secret").is_err());
+        assert!(ufs.stat("x/../../secret").is_err());
+        assert!(ufs.list("../").await.is_err());
+    }
+}
@@ -8,6 +8,16 @@ use monoio_compat::TcpStreamCompat;
 
 use fairy_common::metrics::metrics_result;
 
+use crate::load_job::{LoadJobRequest, LOAD_JOBS};
+use crate::{drain, DISCOVERY, DRAINER, KV_STORE, LOADER, S3_GATEWAY};
+
+/// Body of `POST /pins` and `DELETE /pins`, exactly one of the fields is expected.
+#[derive(Debug, serde::Deserialize)]
+struct PinRequest {
+    key: Option<String>,
+    prefix: Option<String>,
+}
+
 #[derive(Clone)]
 struct HyperExecutor;
 
@@ -39,15 +49,143 @@ where
     }
 }
 
+/// Serves the admin routes, any other request goes to the S3 gateway. Buckets named
+/// like an admin route, e.g. `pins`, are shadowed by it.
 pub(crate) async fn hyper_handler(
     req: Request<Body>,
 ) -> Result<Response<Body>, std::convert::Infallible> {
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/") => Ok(Response::new(Body::from("Fairy!"))),
+        (&Method::GET, "/health") => Ok(json_response(
+            StatusCode::OK,
+            &serde_json::json!({
+                "status": "ok",
+                "used_bytes": KV_STORE.used_bytes(),
+                "pinned_bytes": KV_STORE.pinned_bytes(),
+            }),
+        )),
+        (&Method::GET, "/workers") => Ok(json_response(
+            StatusCode::OK,
+            &serde_json::json!({
+                "members": DISCOVERY.members(),
+                "descriptors": DISCOVERY.descriptors(),
+            }),
+        )),
+        (&Method::POST, "/drain") if !DISCOVERY.publishes_local() => Ok(text_response(
+            StatusCode::NOT_IMPLEMENTED,
+            String::from(
+                "the discovery source can't tell the other workers, remove this worker from their lists instead",
+            ),
+        )),
+        (&Method::POST, "/drain") => {
+            let status = if drain() {
+                StatusCode::ACCEPTED
+            } else {
+                StatusCode::OK
+            };
+            Ok(json_response(status, &DRAINER.progress()))
+        }
+        (&Method::GET, "/drain") => Ok(json_response(StatusCode::OK, &DRAINER.progress())),
         (&Method::GET, "/metrics") => Ok(Response::new(Body::from(metrics_result()))),
-        _ => Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::from("404 not found"))
-            .unwrap()),
+        (&Method::POST, "/jobs/load") => Ok(submit_load_job(req).await),
+        (&Method::GET, "/jobs/load") => Ok(json_response(StatusCode::OK, &LOAD_JOBS.list())),
+        (&Method::GET, path) if path.starts_with("/jobs/load/") => {
+            let status = path["/jobs/load/".len()..]
+                .parse::<u64>()
+                .ok()
+                .and_then(|id| LOAD_JOBS.status(id));
+            match status {
+                Some(status) => Ok(json_response(StatusCode::OK, &status)),
+                None => Ok(not_found()),
+            }
+        }
+        (&Method::GET, "/pins") => Ok(pin_status()),
+        (&Method::POST, "/pins") => Ok(update_pins(req, true).await),
+        (&Method::DELETE, "/pins") => Ok(update_pins(req, false).await),
+        _ => Ok(S3_GATEWAY.handle(req).await),
+    }
+}
+
+fn pin_status() -> Response<Body> {
+    let pins = KV_STORE.pins();
+    json_response(
+        StatusCode::OK,
+        &serde_json::json!({
+            "keys": pins.keys,
+            "prefixes": pins.prefixes,
+            "pinned_bytes": KV_STORE.pinned_bytes(),
+            "pin_budget_bytes": KV_STORE.pin_budget(),
+        }),
+    )
+}
+
+async fn update_pins(req: Request<Body>, pin: bool) -> Response<Body> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let request: PinRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let result = match (request.key, request.prefix, pin) {
+        (Some(key), None, true) => KV_STORE.pin_key(key),
+        (None, Some(prefix), true) => KV_STORE.pin_prefix(prefix),
+        (Some(key), None, false) => KV_STORE.unpin_key(&key),
+        (None, Some(prefix), false) => KV_STORE.unpin_prefix(&prefix),
+        _ => {
+            return text_response(
+                StatusCode::BAD_REQUEST,
+                String::from("exactly one of key or prefix is required"),
+            )
+        }
+    };
+    match result {
+        Ok(_) => pin_status(),
+        Err(e) => text_response(StatusCode::CONFLICT, e.to_string()),
     }
 }
+
+async fn submit_load_job(req: Request<Body>) -> Response<Body> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let request: LoadJobRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    if request.keys.is_empty() && request.prefix.is_none() {
+        return text_response(
+            StatusCode::BAD_REQUEST,
+            String::from("either keys or prefix is required"),
+        );
+    }
+    let id = LOAD_JOBS.submit(request, &LOADER);
+    json_response(StatusCode::ACCEPTED, &serde_json::json!({ "id": id }))
+}
+
+fn json_response<T: serde::Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => text_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+fn text_response(status: StatusCode, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from("404 not found"))
+        .unwrap()
+}
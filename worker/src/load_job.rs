@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use fairy_common::kv_store::read_through::{LoadOutcome, ReadThroughLoader};
+
+const DEFAULT_CONCURRENCY: usize = 16;
+const MAX_CONCURRENCY: usize = 256;
+const MAX_REPORTED_FAILURES: usize = 100;
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(60 * 60);
+const MAX_FINISHED_JOBS: usize = 100;
+
+lazy_static! {
+    pub static ref LOAD_JOBS: LoadJobManager = LoadJobManager::new();
+}
+
+/// Body of `POST /jobs/load`. Keys and the keys listed under `prefix` are merged.
+#[derive(Debug, Default, Deserialize)]
+pub struct LoadJobRequest {
+    #[serde(default)]
+    pub keys: Vec<String>,
+    pub prefix: Option<String>,
+    pub concurrency: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadJobState {
+    Listing,
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LoadFailure {
+    pub key: String,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoadJobStatus {
+    pub id: u64,
+    pub state: LoadJobState,
+    pub total: u64,
+    pub loaded: u64,
+    pub cached: u64,
+    pub failed: u64,
+    pub loaded_bytes: u64,
+    pub elapsed_secs: f64,
+    pub throughput_bytes_per_sec: f64,
+    pub failures: Vec<LoadFailure>,
+}
+
+pub struct LoadJob {
+    id: u64,
+    state: Mutex<LoadJobState>,
+    total: AtomicU64,
+    loaded: AtomicU64,
+    cached: AtomicU64,
+    failed: AtomicU64,
+    loaded_bytes: AtomicU64,
+    failures: Mutex<Vec<LoadFailure>>,
+    started_at: Instant,
+    finished_at: Mutex<Option<Instant>>,
+}
+
+impl LoadJob {
+    fn new(id: u64) -> Self {
+        LoadJob {
+            id,
+            state: Mutex::new(LoadJobState::Listing),
+            total: AtomicU64::new(0),
+            loaded: AtomicU64::new(0),
+            cached: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            loaded_bytes: AtomicU64::new(0),
+            failures: Mutex::new(Vec::new()),
+            started_at: Instant::now(),
+            finished_at: Mutex::new(None),
+        }
+    }
+
+    fn set_state(&self, state: LoadJobState) {
+        *self.state.lock().unwrap() = state;
+        if matches!(state, LoadJobState::Completed | LoadJobState::Failed) {
+            *self.finished_at.lock().unwrap() = Some(Instant::now());
+        }
+    }
+
+    fn record_failure(&self, key: String, error: String) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() < MAX_REPORTED_FAILURES {
+            failures.push(LoadFailure { key, error });
+        }
+    }
+
+    fn finished_at(&self) -> Option<Instant> {
+        *self.finished_at.lock().unwrap()
+    }
+
+    pub fn status(&self) -> LoadJobStatus {
+        let elapsed = match *self.finished_at.lock().unwrap() {
+            Some(finished_at) => finished_at - self.started_at,
+            None => self.started_at.elapsed(),
+        };
+        let loaded_bytes = self.loaded_bytes.load(Ordering::Relaxed);
+        LoadJobStatus {
+            id: self.id,
+            state: *self.state.lock().unwrap(),
+            total: self.total.load(Ordering::Relaxed),
+            loaded: self.loaded.load(Ordering::Relaxed),
+            cached: self.cached.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            loaded_bytes,
+            elapsed_secs: elapsed.as_secs_f64(),
+            throughput_bytes_per_sec: throughput(loaded_bytes, elapsed),
+            failures: self.failures.lock().unwrap().clone(),
+        }
+    }
+
+    async fn run(&self, request: LoadJobRequest, loader: &'static ReadThroughLoader) {
+        let mut keys = request.keys;
+        if let Some(prefix) = request.prefix.as_deref() {
+            let listed = match loader.ufs() {
+                Ok(ufs) => ufs.list(prefix).await,
+                Err(e) => Err(e),
+            };
+            match listed {
+                Ok(listed) => keys.extend(listed),
+                Err(e) => {
+                    error!(
+                        "Load job {} failed to list prefix {}: {}",
+                        self.id, prefix, e
+                    );
+                    self.record_failure(prefix.to_string(), e.to_string());
+                    self.set_state(LoadJobState::Failed);
+                    return;
+                }
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        self.total.store(keys.len() as u64, Ordering::Relaxed);
+        self.set_state(LoadJobState::Running);
+
+        let concurrency = request
+            .concurrency
+            .unwrap_or(DEFAULT_CONCURRENCY)
+            .clamp(1, MAX_CONCURRENCY);
+        futures::stream::iter(keys)
+            .map(|key| async move {
+                let result = loader.load(key.clone()).await;
+                (key, result)
+            })
+            .buffer_unordered(concurrency)
+            .for_each(|(key, result)| async move {
+                match result {
+                    Ok(LoadOutcome::Cached) => {
+                        self.cached.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(LoadOutcome::Loaded(len)) => {
+                        self.loaded.fetch_add(1, Ordering::Relaxed);
+                        self.loaded_bytes.fetch_add(len as u64, Ordering::Relaxed);
+                    }
+                    Err(e) => self.record_failure(key, e.to_string()),
+                }
+            })
+            .await;
+
+        self.set_state(LoadJobState::Completed);
+        info!("Load job {} finished: {:?}", self.id, self.status());
+    }
+}
+
+pub struct LoadJobManager {
+    next_id: AtomicU64,
+    jobs: RwLock<HashMap<u64, Arc<LoadJob>>>,
+}
+
+impl LoadJobManager {
+    pub fn new() -> Self {
+        LoadJobManager {
+            next_id: AtomicU64::new(1),
+            jobs: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Starts a load job on the current monoio runtime and returns its id.
+    pub fn submit(&self, request: LoadJobRequest, loader: &'static ReadThroughLoader) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let job = Arc::new(LoadJob::new(id));
+        self.prune(FINISHED_JOB_TTL, MAX_FINISHED_JOBS);
+        self.jobs.write().unwrap().insert(id, Arc::clone(&job));
+        info!(
+            "Submitted load job {} with {} keys, prefix {:?}",
+            id,
+            request.keys.len(),
+            request.prefix
+        );
+        monoio::spawn(async move { job.run(request, loader).await });
+        id
+    }
+
+    /// Forgets finished jobs older than `ttl`, and the oldest ones beyond `max_finished`.
+    /// Running jobs are always kept.
+    fn prune(&self, ttl: Duration, max_finished: usize) {
+        let mut jobs = self.jobs.write().unwrap();
+        jobs.retain(|_, job| match job.finished_at() {
+            Some(finished_at) => finished_at.elapsed() < ttl,
+            None => true,
+        });
+        let mut finished: Vec<(Instant, u64)> = jobs
+            .values()
+            .filter_map(|job| job.finished_at().map(|finished_at| (finished_at, job.id)))
+            .collect();
+        if finished.len() > max_finished {
+            finished.sort();
+            for (_, id) in &finished[..finished.len() - max_finished] {
+                jobs.remove(id);
+            }
+        }
+    }
+
+    pub fn status(&self, id: u64) -> Option<LoadJobStatus> {
+        self.jobs.read().unwrap().get(&id).map(|job| job.status())
+    }
+
+    pub fn list(&self) -> Vec<LoadJobStatus> {
+        let mut statuses: Vec<LoadJobStatus> = self
+            .jobs
+            .read()
+            .unwrap()
+            .values()
+            .map(|job| job.status())
+            .collect();
+        statuses.sort_by_key(|status| status.id);
+        statuses
+    }
+}
+
+impl Default for LoadJobManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn throughput(bytes: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        bytes as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_load_request() {
+        let request: LoadJobRequest =
+            serde_json::from_str(r#"{"prefix": "models/", "concurrency": 4}"#).unwrap();
+        assert!(request.keys.is_empty());
+        assert_eq!(request.prefix.as_deref(), Some("models/"));
+        assert_eq!(request.concurrency, Some(4));
+    }
+
+    #[test]
+    fn test_job_status() {
+        let job = LoadJob::new(7);
+        job.total.store(3, Ordering::Relaxed);
+        job.loaded.fetch_add(1, Ordering::Relaxed);
+        job.loaded_bytes.fetch_add(4096, Ordering::Relaxed);
+        job.record_failure(String::from("missing"), String::from("not found"));
+        job.set_state(LoadJobState::Completed);
+
+        let status = job.status();
+        assert_eq!(status.id, 7);
+        assert_eq!(status.state, LoadJobState::Completed);
+        assert_eq!(status.failed, 1);
+        assert_eq!(status.failures[0].key, "missing");
+        assert_eq!(throughput(4096, Duration::from_secs(2)), 2048.0);
+    }
+
+    #[test]
+    fn test_prune_finished_jobs() {
+        let manager = LoadJobManager::new();
+        for id in 1..=4 {
+            let job = LoadJob::new(id);
+            if id > 1 {
+                job.set_state(LoadJobState::Completed);
+            }
+            manager.jobs.write().unwrap().insert(id, Arc::new(job));
+        }
+        let expired = Instant::now() - Duration::from_secs(120);
+        *manager.jobs.read().unwrap()[&2].finished_at.lock().unwrap() = Some(expired);
+
+        manager.prune(Duration::from_secs(60), 10);
+        assert!(manager.status(2).is_none());
+        assert_eq!(manager.list().len(), 3);
+
+        // only the newest finished job is kept, the running one stays
+        manager.prune(Duration::from_secs(60), 1);
+        let ids: Vec<u64> = manager.list().iter().map(|status| status.id).collect();
+        assert_eq!(ids, vec![1, 4]);
+    }
+}
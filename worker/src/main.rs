@@ -1,33 +1,108 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
 use lazy_static::lazy_static;
-use log::{error, info};
-use monoio::io::{AsyncReadRent, AsyncWriteRentExt};
+use log::{error, info, warn};
 use monoio::join;
-use monoio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
 
+use fairy_common::anti_entropy::AntiEntropy;
+use fairy_common::binary::binary_service::BinaryService;
+use fairy_common::discovery::{self, ServiceDiscovery, WorkerDescriptor};
+use fairy_common::drain::Drainer;
+use fairy_common::h2::h2_client;
 use fairy_common::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
-use fairy_common::metrics::{INCOMING_REQUESTS, RESPONSE_TIME_COLLECTOR};
+use fairy_common::kv_store::read_through::ReadThroughLoader;
+use fairy_common::memcache::memcache_service::MemcacheService;
+use fairy_common::peer_fetch::PeerFetcher;
+use fairy_common::rebalance::Rebalancer;
+use fairy_common::resp::resp_service::RespService;
+use fairy_common::ring::HashRing;
 use fairy_common::settings;
+use fairy_common::shutdown::Shutdown;
+use fairy_common::ufs::local_ufs::LocalUfs;
 use hyper_service::{hyper_handler, serve_http};
-use service_registry::etcd::{ServiceRegistry, ServiceRegistryError};
+use s3_gateway::S3Gateway;
 use settings::SETTINGS;
 
 pub mod h2_service;
 pub mod hyper_service;
-
-mod service_registry;
+pub mod load_job;
+pub mod s3_gateway;
 
 lazy_static! {
     static ref KV_STORE: LocalFileKVStore =
-        LocalFileKVStore::new(settings::parse_with_prefix("worker"));
-    static ref H2_ADDR: String = format!("127.0.0.1:{}", SETTINGS.http2_port);
+        LocalFileKVStore::new(settings::parse_with_prefix("worker"))
+            .unwrap_or_else(|e| {
+                error!("Failed to open the local store: {}", e);
+                std::process::exit(1)
+            });
+    static ref LOADER: ReadThroughLoader =
+        ReadThroughLoader::new(&KV_STORE, SETTINGS.ufs_root_path.clone().map(LocalUfs::new))
+            .with_peers(&PEERS);
+    /// This worker as it appears in service discovery and on the ring.
+    static ref WORKER_ID: String = format!("{}:{}", SETTINGS.local_ip, SETTINGS.http_port);
+    static ref DISCOVERY: Arc<dyn ServiceDiscovery> =
+        discovery::from_options(&(&*SETTINGS).into(), Some(descriptor()));
+    /// Placement of keys across workers, kept in sync with service discovery.
+    static ref RING: Arc<RwLock<HashRing>> = Arc::new(RwLock::new(HashRing::new(SETTINGS.ring_vnodes)));
+    static ref PEERS: PeerFetcher = PeerFetcher::new(
+        WORKER_ID.clone(),
+        SETTINGS.http2_port,
+        SETTINGS.replication_factor,
+        Arc::clone(&RING),
+    )
+    .with_discovery(Arc::clone(&DISCOVERY));
+    static ref REBALANCER: Rebalancer = Rebalancer::new(
+        &KV_STORE,
+        WORKER_ID.clone(),
+        SETTINGS.http2_port,
+        SETTINGS.replication_factor,
+        SETTINGS.rebalance_rate_bytes,
+    )
+    .with_discovery(Arc::clone(&DISCOVERY));
+    static ref ANTI_ENTROPY: AntiEntropy = AntiEntropy::new(
+        &KV_STORE,
+        WORKER_ID.clone(),
+        SETTINGS.http2_port,
+        SETTINGS.replication_factor,
+        Arc::clone(&RING),
+        SETTINGS.anti_entropy_rate_bytes,
+    )
+    .with_discovery(Arc::clone(&DISCOVERY));
+    static ref DRAINER: Drainer = Drainer::new(
+        &KV_STORE,
+        WORKER_ID.clone(),
+        SETTINGS.http2_port,
+        SETTINGS.replication_factor,
+        Arc::clone(&RING),
+        SETTINGS.rebalance_rate_bytes,
+        Duration::from_secs(5),
+    )
+    .with_resolver(|worker| {
+        h2_client::resolve_h2_addr(Some(&**DISCOVERY), worker, SETTINGS.http2_port)
+    });
+    static ref SHUTDOWN: Shutdown = Shutdown::new();
+    static ref S3_GATEWAY: S3Gateway = S3Gateway::new(&KV_STORE)
+        .with_rebalancer(&REBALANCER)
+        .with_loader(&LOADER)
+        .with_ring(&RING)
+        .with_shutdown(&SHUTDOWN);
+    static ref H2_ADDR: String = format!("0.0.0.0:{}", SETTINGS.http2_port);
+    static ref SOCKET_ADDR: String = format!("0.0.0.0:{}", SETTINGS.socket_port);
+    static ref REDIS_ADDR: Option<String> = SETTINGS.redis_port.map(|port| format!("0.0.0.0:{}", port));
+    static ref MEMCACHED_ADDR: Option<String> = SETTINGS.memcached_port.map(|port| format!("0.0.0.0:{}", port));
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     fairy_common::logging::setup_logger().unwrap();
+    lazy_static::initialize(&KV_STORE);
 
-    let _ = register().await;
+    discover();
+    handle_signals();
+    start_compaction();
     let _ = fairy_common::metrics::start_push().await;
 
     let mut rt = monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
@@ -41,57 +116,223 @@ async fn main() -> Result<()> {
             let _ = serve_http(([0, 0, 0, 0], SETTINGS.http_port), hyper_handler).await;
         };
 
-        let h2_service = fairy_common::h2::h2_service::H2Service::new(&KV_STORE, H2_ADDR.as_str());
+        let h2_service = fairy_common::h2::h2_service::H2Service::new(&KV_STORE, H2_ADDR.as_str())
+            .with_rebalancer(&REBALANCER)
+            .with_anti_entropy(&ANTI_ENTROPY)
+            .with_loader(&LOADER)
+            .with_shutdown(&SHUTDOWN);
         let h2_service = h2_service.serve_h2();
 
-        let socket_service = async {
-            let listener =
-                TcpListener::bind(format!("127.0.0.1:{}", SETTINGS.socket_port)).unwrap();
-            info!("listening socket {}", SETTINGS.socket_port);
+        info!("Running binary service on {}", *SOCKET_ADDR);
+        let socket_service = BinaryService::new(&KV_STORE, SOCKET_ADDR.as_str())
+            .with_rebalancer(&REBALANCER)
+            .with_loader(&LOADER)
+            .with_shutdown(&SHUTDOWN);
+        let socket_service = socket_service.serve();
+
+        let redis_service = async {
+            let Some(addr) = REDIS_ADDR.as_deref() else {
+                return;
+            };
+            info!("Running redis service on {}", addr);
+            RespService::new(&KV_STORE, addr)
+                .with_rebalancer(&REBALANCER)
+                .with_loader(&LOADER)
+                .with_shutdown(&SHUTDOWN)
+                .serve()
+                .await;
+        };
+
+        let memcached_service = async {
+            let Some(addr) = MEMCACHED_ADDR.as_deref() else {
+                return;
+            };
+            info!("Running memcached service on {}", addr);
+            MemcacheService::new(&KV_STORE, addr)
+                .with_rebalancer(&REBALANCER)
+                .with_loader(&LOADER)
+                .with_shutdown(&SHUTDOWN)
+                .serve()
+                .await;
+        };
+
+        let rebalance_service = async {
+            // the ring of the last rebalance, None until this worker has joined
+            let mut current: Option<HashRing> = None;
+            loop {
+                monoio::time::sleep(Duration::from_secs(1)).await;
+                let next = RING.read().unwrap().clone();
+                let Some(previous) = REBALANCER.previous_ring(current.as_ref(), &next) else {
+                    continue;
+                };
+                info!("Ring changed, rebalancing");
+                PEERS.set_previous(previous.clone());
+                REBALANCER.rebalance(&previous, &next).await;
+                PEERS.clear_previous();
+                current = Some(next);
+            }
+        };
+
+        let anti_entropy_service = async {
+            if SETTINGS.anti_entropy_interval_secs == 0 {
+                return;
+            }
+            let interval = Duration::from_secs(SETTINGS.anti_entropy_interval_secs);
             loop {
-                let incoming = listener.accept().await;
-                match incoming {
-                    Ok((stream, addr)) => {
-                        error!("accepted a connection from {}", addr);
-                        monoio::spawn(echo(stream));
-                    }
-                    Err(e) => {
-                        error!("accepted connection failed: {}", e);
-                        return;
-                    }
-                }
+                monoio::time::sleep(interval).await;
+                ANTI_ENTROPY.run_round().await;
             }
         };
 
-        join!(hyper_service, socket_service, h2_service);
+        let services = async {
+            join!(
+                hyper_service,
+                socket_service,
+                redis_service,
+                memcached_service,
+                h2_service,
+                rebalance_service,
+                anti_entropy_service
+            );
+        };
+        // dropping the services closes their listeners, spawned requests keep running
+        monoio::select! {
+            _ = services => {}
+            _ = SHUTDOWN.triggered() => {}
+        }
+        shut_down().await;
     });
 
     Ok(())
 }
 
-async fn echo(mut stream: TcpStream) -> std::io::Result<()> {
-    let mut buf: Vec<u8> = Vec::with_capacity(8 * 1024);
-    let mut res;
-    loop {
-        let _timer = RESPONSE_TIME_COLLECTOR.start_timer();
-        // read
-        (res, buf) = stream.read(buf).await;
-        if res? == 0 {
-            return Ok(());
-        }
-        INCOMING_REQUESTS.inc();
-        // write all
-        (res, buf) = stream.write_all(buf).await;
-        res?;
+/// This worker as published in service discovery.
+fn descriptor() -> WorkerDescriptor {
+    WorkerDescriptor {
+        node_id: WORKER_ID.clone(),
+        host: SETTINGS.local_ip.clone(),
+        http_port: SETTINGS.http_port,
+        h2_port: SETTINGS.http2_port,
+        socket_port: SETTINGS.socket_port,
+        zone: SETTINGS.zone.clone(),
+        capacity_bytes: KV_STORE.capacity(),
+        used_bytes: KV_STORE.used_bytes(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        draining: DRAINER.is_draining(),
+    }
+}
 
-        // clear
-        buf.clear();
+/// Takes the worker out of the ring, hands its keys off and deregisters once empty.
+/// Only with a discovery source that publishes the local worker, otherwise the other
+/// workers would keep routing to it.
+fn drain() -> bool {
+    if !DRAINER.start() {
+        return false;
     }
+    DISCOVERY.update_local(descriptor());
+    monoio::spawn(async {
+        DRAINER.run().await;
+        DISCOVERY.leave();
+    });
+    true
+}
+
+/// Expiry sweeps and compaction block on file I/O, they run on a thread of their own so
+/// that they do not stall the requests of the event loop.
+fn start_compaction() {
+    std::thread::Builder::new()
+        .name(String::from("compaction"))
+        .spawn(|| loop {
+            std::thread::sleep(KV_STORE.compaction_interval());
+            // expired values first, so that compaction reclaims their space
+            match KV_STORE.sweep_expired() {
+                Ok(0) => {}
+                Ok(swept) => info!("Removed {} expired values", swept),
+                Err(e) => error!("Expired value sweep failed: {}", e),
+            }
+            match KV_STORE.compact() {
+                Ok(0) => {}
+                Ok(compacted) => info!("Compacted {} segments", compacted),
+                Err(e) => error!("Segment compaction failed: {}", e),
+            }
+        })
+        .unwrap();
 }
 
-async fn register() -> Result<(), ServiceRegistryError> {
-    let registry = ServiceRegistry::new(&SETTINGS.etcd_uris).await?;
-    registry.run().await?;
+/// The first SIGTERM or SIGINT shuts the worker down gracefully, a second one exits
+/// right away.
+fn handle_signals() {
+    tokio::spawn(async {
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+        loop {
+            tokio::select! {
+                _ = terminate.recv() => info!("Received SIGTERM"),
+                _ = interrupt.recv() => info!("Received SIGINT"),
+            }
+            if SHUTDOWN.is_triggered() {
+                warn!("Exiting without waiting for the shutdown");
+                std::process::exit(1);
+            }
+            SHUTDOWN.trigger();
+        }
+    });
+}
 
-    Ok(())
+/// Waits for the requests in flight, flushes the store and deregisters, giving up on
+/// the remaining steps once the shutdown timeout passed.
+async fn shut_down() {
+    let deadline = Instant::now() + Duration::from_secs(SETTINGS.shutdown_timeout_secs);
+    let remaining = || deadline.saturating_duration_since(Instant::now());
+    info!("Shutting down, {} requests in flight", SHUTDOWN.in_flight());
+    if monoio::time::timeout(remaining(), SHUTDOWN.idle())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Shutdown timed out with {} requests in flight",
+            SHUTDOWN.in_flight()
+        );
+    }
+    match KV_STORE.flush() {
+        Ok(()) => info!("Flushed the store"),
+        Err(e) => error!("Failed to flush the store: {}", e),
+    }
+    DISCOVERY.leave();
+    while DISCOVERY.is_registered() {
+        if remaining().is_zero() {
+            warn!("Shutdown timed out before deregistering {}", *WORKER_ID);
+            return;
+        }
+        monoio::time::sleep(Duration::from_millis(20)).await;
+    }
+    info!("Worker {} shut down", *WORKER_ID);
+}
+
+fn discover() {
+    DISCOVERY.subscribe(Box::new(|change| {
+        let zones = change.members.iter().map(|member| {
+            let zone = DISCOVERY.descriptor(member).and_then(|d| d.zone);
+            (member.clone(), zone)
+        });
+        let members = change.members.iter().map(|member| {
+            let weight = DISCOVERY.descriptor(member).map_or(1, |d| d.ring_weight());
+            (member.clone(), weight)
+        });
+        let mut ring = RING.write().unwrap();
+        ring.set_zones(zones);
+        ring.set_members(members);
+    }));
+    tokio::spawn(async {
+        if let Err(e) = Arc::clone(&DISCOVERY).run().await {
+            error!("Service discovery stopped: {}", e);
+        }
+    });
+    tokio::spawn(async {
+        let refresh = Duration::from_millis(SETTINGS.discovery_refresh_ms);
+        loop {
+            tokio::time::sleep(refresh).await;
+            DISCOVERY.update_local(descriptor());
+        }
+    });
 }
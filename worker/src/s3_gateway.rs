@@ -0,0 +1,918 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::ops::Range;
+use std::sync::RwLock;
+
+use bytes::{Bytes, BytesMut};
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderName, HeaderValue, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, RANGE,
+};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use log::{debug, error};
+use md5::{Digest, Md5};
+use percent_encoding::percent_decode_str;
+use thiserror::Error;
+
+use fairy_common::h2::encode_key;
+use fairy_common::h2::h2_service::MAX_PUT_LEN;
+use fairy_common::kv_store::local_kv_store::local_file_kv_store::LocalFileKVStore;
+use fairy_common::kv_store::object_meta::ObjectMeta;
+use fairy_common::kv_store::read_through::{self, ReadThroughLoader};
+use fairy_common::kv_store::store_error::StoreError;
+use fairy_common::kv_store::validate_key;
+use fairy_common::metrics::{INCOMING_REQUESTS, RESPONSE_TIME_COLLECTOR};
+use fairy_common::rebalance::Rebalancer;
+use fairy_common::ring::HashRing;
+use fairy_common::shutdown::Shutdown;
+
+/// Headers carrying user metadata, stored without the prefix.
+const AMZ_META_PREFIX: &str = "x-amz-meta-";
+/// Content type of values stored without one, as in S3.
+const DEFAULT_CONTENT_TYPE: &str = "binary/octet-stream";
+/// Largest page of `ListObjectsV2`, as in S3.
+const MAX_KEYS: usize = 1000;
+const XML_DECLARATION: &str = r#"<?xml version="1.0" encoding="UTF-8"?>"#;
+
+/// Why an S3 request failed, answered with the S3 error code and status.
+#[derive(Debug, Error)]
+pub enum S3Error {
+    #[error("The specified key does not exist.")]
+    NoSuchKey,
+    #[error("The requested range is not satisfiable")]
+    InvalidRange,
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[error("Your proposed upload exceeds the maximum allowed size")]
+    EntityTooLarge,
+    #[error("{0} is not implemented")]
+    NotImplemented(String),
+    #[error("{0} is only served by a cluster of a single worker")]
+    SingleWorkerOnly(&'static str),
+    #[error("The specified method is not allowed against this resource.")]
+    MethodNotAllowed,
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Body(#[from] hyper::Error),
+}
+
+impl S3Error {
+    fn code(&self) -> (&'static str, StatusCode) {
+        match self {
+            S3Error::NoSuchKey => ("NoSuchKey", StatusCode::NOT_FOUND),
+            S3Error::InvalidRange => ("InvalidRange", StatusCode::RANGE_NOT_SATISFIABLE),
+            S3Error::InvalidArgument(_) | S3Error::Store(StoreError::InvalidKey { .. }) => {
+                ("InvalidArgument", StatusCode::BAD_REQUEST)
+            }
+            S3Error::EntityTooLarge | S3Error::Store(StoreError::TooLarge { .. }) => {
+                ("EntityTooLarge", StatusCode::BAD_REQUEST)
+            }
+            S3Error::NotImplemented(_) | S3Error::SingleWorkerOnly(_) => {
+                ("NotImplemented", StatusCode::NOT_IMPLEMENTED)
+            }
+            S3Error::MethodNotAllowed => ("MethodNotAllowed", StatusCode::METHOD_NOT_ALLOWED),
+            S3Error::Store(e) if e.is_not_found() => ("NoSuchKey", StatusCode::NOT_FOUND),
+            S3Error::Store(StoreError::Full(_)) => {
+                ("InsufficientStorage", StatusCode::INSUFFICIENT_STORAGE)
+            }
+            S3Error::Store(_) => ("InternalError", StatusCode::INTERNAL_SERVER_ERROR),
+            S3Error::Body(_) => ("IncompleteBody", StatusCode::BAD_REQUEST),
+        }
+    }
+}
+
+/// Serves a subset of the S3 API with path style addressing, `/{bucket}/{key}`:
+/// GetObject, PutObject, HeadObject, DeleteObject and ListObjectsV2.
+///
+/// A bucket is a namespace of the cache, its objects are stored under `{bucket}/{key}`
+/// and it exists as long as it holds keys. Requests are not authenticated, signatures
+/// are ignored.
+///
+/// Reads find keys of other workers like the other services, but writes, deletes and
+/// listings only reach the store of this worker. With a ring they are refused once it
+/// holds more than one worker, so the gateway is for single worker clusters.
+#[derive(Clone, Copy)]
+pub struct S3Gateway {
+    kv_store: &'static LocalFileKVStore,
+    rebalancer: Option<&'static Rebalancer>,
+    loader: Option<&'static ReadThroughLoader>,
+    ring: Option<&'static RwLock<HashRing>>,
+    shutdown: Option<&'static Shutdown>,
+}
+
+impl S3Gateway {
+    pub fn new(kv_store: &'static LocalFileKVStore) -> S3Gateway {
+        S3Gateway {
+            kv_store,
+            rebalancer: None,
+            loader: None,
+            ring: None,
+            shutdown: None,
+        }
+    }
+
+    pub fn with_rebalancer(mut self, rebalancer: &'static Rebalancer) -> Self {
+        self.rebalancer = Some(rebalancer);
+        self
+    }
+
+    pub fn with_loader(mut self, loader: &'static ReadThroughLoader) -> Self {
+        self.loader = Some(loader);
+        self
+    }
+
+    pub fn with_ring(mut self, ring: &'static RwLock<HashRing>) -> Self {
+        self.ring = Some(ring);
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: &'static Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Answers an S3 request, failures are answered with an S3 error document.
+    pub async fn handle(self, req: Request<Body>) -> Response<Body> {
+        let _tracked = self.shutdown.map(|shutdown| shutdown.track());
+        let _timer = RESPONSE_TIME_COLLECTOR.start_timer();
+        INCOMING_REQUESTS.inc();
+        let head = req.method() == Method::HEAD;
+        let resource = req.uri().path().to_string();
+        match self.route(req).await {
+            Ok(response) => response,
+            Err(e) => error_response(&e, &resource, head),
+        }
+    }
+
+    async fn route(self, req: Request<Body>) -> Result<Response<Body>, S3Error> {
+        let path = percent_decode_str(req.uri().path())
+            .decode_utf8()
+            .map_err(|_| S3Error::InvalidArgument(String::from("key is not valid UTF-8")))?
+            .into_owned();
+        let path = path.strip_prefix('/').unwrap_or(&path);
+        let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+        let query: HashMap<String, String> =
+            form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                .into_owned()
+                .collect();
+        if bucket.is_empty() {
+            return Err(S3Error::NotImplemented(String::from("ListBuckets")));
+        }
+        if query.contains_key("uploads") || query.contains_key("uploadId") {
+            return Err(S3Error::NotImplemented(String::from("Multipart upload")));
+        }
+        let method = req.method().clone();
+        if key.is_empty() {
+            return match method {
+                Method::GET if query.get("list-type").is_some_and(|v| v == "2") => {
+                    self.check_single_worker("ListObjectsV2")?;
+                    self.list_objects(bucket, &query).await
+                }
+                Method::GET => Err(S3Error::NotImplemented(String::from("ListObjects"))),
+                _ => Err(S3Error::NotImplemented(format!("{} on a bucket", method))),
+            };
+        }
+        let key = format!("{}/{}", bucket, key);
+        validate_key(&key).map_err(|e| S3Error::InvalidArgument(e.to_string()))?;
+        match method {
+            Method::GET => self.get_object(key, req.headers()).await,
+            Method::HEAD => self.head_object(key).await,
+            Method::PUT => {
+                self.check_single_worker("PutObject")?;
+                self.put_object(key, req).await
+            }
+            Method::DELETE => {
+                self.check_single_worker("DeleteObject")?;
+                self.kv_store.delete(key)?;
+                Ok(empty_response(StatusCode::NO_CONTENT))
+            }
+            _ => Err(S3Error::MethodNotAllowed),
+        }
+    }
+
+    async fn get_object(self, key: String, headers: &HeaderMap) -> Result<Response<Body>, S3Error> {
+        let (meta, value) = self.lookup(&key).await?;
+        let len = value.len() as u64;
+        let range = match headers.get(RANGE).and_then(|range| range.to_str().ok()) {
+            Some(range) => parse_range(range, len)?,
+            None => None,
+        };
+        let Some(range) = range else {
+            return Ok(object_response(
+                &meta,
+                StatusCode::OK,
+                len,
+                Body::from(value),
+            ));
+        };
+        let body = value.slice(range.start as usize..range.end as usize);
+        let mut response = object_response(
+            &meta,
+            StatusCode::PARTIAL_CONTENT,
+            range.end - range.start,
+            Body::from(body),
+        );
+        let content_range = format!(
+            "bytes {}-{}/{}",
+            range.start,
+            range.end.saturating_sub(1),
+            len
+        );
+        response.headers_mut().insert(
+            CONTENT_RANGE,
+            HeaderValue::from_str(&content_range).unwrap(),
+        );
+        Ok(response)
+    }
+
+    async fn head_object(self, key: String) -> Result<Response<Body>, S3Error> {
+        let (meta, len) = match self.kv_store.head(key.clone()).await {
+            Ok(found) => found,
+            Err(e) if e.is_not_found() => {
+                let (meta, value) = self.lookup(&key).await?;
+                (meta, value.len() as u64)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(object_response(&meta, StatusCode::OK, len, Body::empty()))
+    }
+
+    async fn put_object(self, key: String, req: Request<Body>) -> Result<Response<Body>, S3Error> {
+        let (parts, body) = req.into_parts();
+        let headers = &parts.headers;
+        if headers.contains_key("x-amz-copy-source") {
+            return Err(S3Error::NotImplemented(String::from("CopyObject")));
+        }
+        let declared_len = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+        if declared_len.is_some_and(|len| len > MAX_PUT_LEN as u64) {
+            return Err(S3Error::EntityTooLarge);
+        }
+        let mut value = read_body(body).await?;
+        if is_aws_chunked(headers) {
+            value = decode_aws_chunked(&value)?;
+        }
+        let etag = format!("\"{}\"", hex::encode(Md5::digest(&value)));
+        let meta = ObjectMeta {
+            content_type: headers
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from),
+            etag: Some(etag.clone()),
+            user_headers: headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    let name = name.as_str().strip_prefix(AMZ_META_PREFIX)?;
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            ..Default::default()
+        };
+        self.kv_store.put_with_meta(key, value, &meta).await?;
+        let mut response = empty_response(StatusCode::OK);
+        response
+            .headers_mut()
+            .insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+        Ok(response)
+    }
+
+    async fn list_objects(
+        self,
+        bucket: &str,
+        query: &HashMap<String, String>,
+    ) -> Result<Response<Body>, S3Error> {
+        let param = |name: &str| {
+            query
+                .get(name)
+                .map(String::as_str)
+                .filter(|v| !v.is_empty())
+        };
+        let prefix = param("prefix").unwrap_or_default();
+        let delimiter = param("delimiter");
+        let max_keys = match param("max-keys") {
+            Some(max_keys) => max_keys.parse::<usize>().map_err(|_| {
+                S3Error::InvalidArgument(String::from("max-keys is not a valid number"))
+            })?,
+            None => MAX_KEYS,
+        }
+        .min(MAX_KEYS);
+        // the token is the last key or common prefix of the previous page
+        let marker = param("continuation-token").or(param("start-after"));
+        let url_encoded = param("encoding-type") == Some("url");
+        let encode = |value: &str| match url_encoded {
+            true => encode_key(value),
+            false => value.to_string(),
+        };
+
+        let namespace = format!("{}/", bucket);
+        let mut keys = Vec::new();
+        let mut common_prefixes = Vec::new();
+        let mut last: Option<String> = None;
+        let mut truncated = false;
+        for (stored, _) in self.kv_store.list(&format!("{}{}", namespace, prefix)) {
+            let key = &stored[namespace.len()..];
+            let common_prefix = delimiter.and_then(|delimiter| {
+                let end = key[prefix.len()..].find(delimiter)? + prefix.len() + delimiter.len();
+                Some(&key[..end])
+            });
+            let entry = common_prefix.unwrap_or(key);
+            if marker.is_some_and(|marker| entry <= marker) || last.as_deref() == Some(entry) {
+                continue;
+            }
+            if keys.len() + common_prefixes.len() == max_keys {
+                truncated = max_keys > 0;
+                break;
+            }
+            match common_prefix {
+                Some(common_prefix) => common_prefixes.push(common_prefix.to_string()),
+                None => keys.push(key.to_string()),
+            }
+            last = Some(entry.to_string());
+        }
+
+        let mut contents = String::new();
+        let mut key_count = common_prefixes.len();
+        for key in keys {
+            // listed sizes include the metadata, the value length is read from the header
+            let (meta, len) = match self.kv_store.head(format!("{}{}", namespace, key)).await {
+                Ok(found) => found,
+                Err(e) if e.is_not_found() => continue,
+                Err(e) => return Err(e.into()),
+            };
+            key_count += 1;
+            contents.push_str("<Contents>");
+            element(&mut contents, "Key", &encode(&key));
+            element(&mut contents, "Size", &len.to_string());
+            if let Some(etag) = &meta.etag {
+                element(&mut contents, "ETag", etag);
+            }
+            element(&mut contents, "StorageClass", "STANDARD");
+            contents.push_str("</Contents>");
+        }
+
+        let mut xml = String::from(XML_DECLARATION);
+        xml.push_str(r#"<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">"#);
+        element(&mut xml, "Name", bucket);
+        element(&mut xml, "Prefix", &encode(prefix));
+        if let Some(delimiter) = delimiter {
+            element(&mut xml, "Delimiter", &encode(delimiter));
+        }
+        if let Some(token) = param("continuation-token") {
+            element(&mut xml, "ContinuationToken", token);
+        }
+        if let Some(start_after) = param("start-after") {
+            element(&mut xml, "StartAfter", &encode(start_after));
+        }
+        if url_encoded {
+            element(&mut xml, "EncodingType", "url");
+        }
+        element(&mut xml, "MaxKeys", &max_keys.to_string());
+        element(&mut xml, "KeyCount", &key_count.to_string());
+        element(&mut xml, "IsTruncated", &truncated.to_string());
+        if let (true, Some(last)) = (truncated, &last) {
+            element(&mut xml, "NextContinuationToken", last);
+        }
+        xml.push_str(&contents);
+        for common_prefix in common_prefixes {
+            xml.push_str("<CommonPrefixes>");
+            element(&mut xml, "Prefix", &encode(&common_prefix));
+            xml.push_str("</CommonPrefixes>");
+        }
+        xml.push_str("</ListBucketResult>");
+        Ok(xml_response(StatusCode::OK, xml))
+    }
+
+    /// Operations on the local store alone would miss or strand the keys placed on the
+    /// other workers of the ring.
+    fn check_single_worker(self, operation: &'static str) -> Result<(), S3Error> {
+        match self.ring {
+            Some(ring) if ring.read().unwrap().len() > 1 => {
+                Err(S3Error::SingleWorkerOnly(operation))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The value of a key stored here, pending a rebalance, or read through the loader.
+    async fn lookup(self, key: &str) -> Result<(ObjectMeta, Bytes), S3Error> {
+        match read_through::lookup(self.kv_store, self.rebalancer, self.loader, key).await {
+            Ok(found) => Ok(found),
+            Err(e) if e.is_not_found() => Err(S3Error::NoSuchKey),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The byte range asked for by a `Range` header, never empty. As in S3, a header that
+/// can't be parsed or asks for several ranges is ignored and the whole value is returned.
+fn parse_range(header: &str, len: u64) -> Result<Option<Range<u64>>, S3Error> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    let Some((start, end)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        match end.parse::<u64>() {
+            Ok(0) => return Err(S3Error::InvalidRange),
+            Ok(suffix) => len.saturating_sub(suffix)..len,
+            Err(_) => return Ok(None),
+        }
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return Ok(None);
+        };
+        match end {
+            "" => start..len,
+            end => match end.parse::<u64>() {
+                Ok(end) if end >= start => start..len.min(end.saturating_add(1)),
+                _ => return Ok(None),
+            },
+        }
+    };
+    if range.start >= len || range.is_empty() {
+        return Err(S3Error::InvalidRange);
+    }
+    Ok(Some(range))
+}
+
+async fn read_body(mut body: Body) -> Result<Bytes, S3Error> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > MAX_PUT_LEN {
+            return Err(S3Error::EntityTooLarge);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
+
+/// Bodies signed chunk by chunk are framed with the `aws-chunked` encoding.
+fn is_aws_chunked(headers: &HeaderMap) -> bool {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .unwrap_or_default()
+    };
+    header(CONTENT_ENCODING).contains("aws-chunked")
+        || header(HeaderName::from_static("x-amz-content-sha256")).starts_with("STREAMING-")
+}
+
+/// Decodes chunks of `{hex len}[;chunk-signature=..]\r\n{data}\r\n`, ended by an empty
+/// chunk. Signatures and trailers are ignored.
+fn decode_aws_chunked(body: &[u8]) -> Result<Bytes, S3Error> {
+    let invalid = || S3Error::InvalidArgument(String::from("malformed aws-chunked body"));
+    let mut value = BytesMut::new();
+    let mut rest = body;
+    loop {
+        let line_len = rest
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(invalid)?;
+        let line = std::str::from_utf8(&rest[..line_len]).map_err(|_| invalid())?;
+        let len_hex = line.split(';').next().unwrap_or_default().trim();
+        let len = usize::from_str_radix(len_hex, 16).map_err(|_| invalid())?;
+        rest = &rest[line_len + 2..];
+        if len == 0 {
+            return Ok(value.freeze());
+        }
+        if rest.len() < len + 2 || &rest[len..len + 2] != b"\r\n" {
+            return Err(invalid());
+        }
+        value.extend_from_slice(&rest[..len]);
+        rest = &rest[len + 2..];
+    }
+}
+
+fn object_response(meta: &ObjectMeta, status: StatusCode, len: u64, body: Body) -> Response<Body> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    let content_type = meta
+        .content_type
+        .as_deref()
+        .and_then(|content_type| HeaderValue::from_str(content_type).ok());
+    headers.insert(
+        CONTENT_TYPE,
+        content_type.unwrap_or(HeaderValue::from_static(DEFAULT_CONTENT_TYPE)),
+    );
+    if let Some(etag) = meta
+        .etag
+        .as_deref()
+        .and_then(|etag| HeaderValue::from_str(etag).ok())
+    {
+        headers.insert(ETAG, etag);
+    }
+    for (name, value) in meta.user_headers.iter() {
+        let name = HeaderName::try_from(format!("{}{}", AMZ_META_PREFIX, name));
+        if let (Ok(name), Ok(value)) = (name, HeaderValue::from_str(value)) {
+            headers.insert(name, value);
+        }
+    }
+    response
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn xml_response(status: StatusCode, xml: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/xml")
+        .body(Body::from(xml))
+        .unwrap()
+}
+
+/// The S3 error document, answers to HEAD requests only carry the status.
+fn error_response(error: &S3Error, resource: &str, head: bool) -> Response<Body> {
+    let (code, status) = error.code();
+    if status.is_server_error() && status != StatusCode::NOT_IMPLEMENTED {
+        error!("S3 request for {} failed: {}", resource, error);
+    } else {
+        debug!("S3 request for {} answered {}: {}", resource, code, error);
+    }
+    if head {
+        return empty_response(status);
+    }
+    let mut xml = String::from(XML_DECLARATION);
+    xml.push_str("<Error>");
+    element(&mut xml, "Code", code);
+    element(&mut xml, "Message", &error.to_string());
+    element(&mut xml, "Resource", resource);
+    xml.push_str("</Error>");
+    xml_response(status, xml)
+}
+
+fn element(xml: &mut String, name: &str, value: &str) {
+    let _ = write!(xml, "<{0}>{1}</{0}>", name, escape(value));
+}
+
+fn escape(value: &str) -> Cow<str> {
+    if !value.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped = String::with_capacity(value.len() + 16);
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use aws_sdk_s3::config::{Credentials, Region};
+    use aws_sdk_s3::error::ProvideErrorMetadata;
+    use aws_sdk_s3::primitives::ByteStream;
+    use aws_sdk_s3::Client;
+
+    use fairy_common::settings::local_kv_options::StoreLayout;
+    use fairy_common::test_util;
+
+    use super::*;
+    use crate::hyper_service::serve_http;
+
+    fn serve() -> (Client, tempfile::TempDir) {
+        serve_with_ring(None)
+    }
+
+    /// Serves the gateway on its own monoio thread, the S3 client runs on tokio.
+    fn serve_with_ring(ring: Option<&'static RwLock<HashRing>>) -> (Client, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        let addr = test_util::serve_on_free_port_in_thread(move |addr| async move {
+            let mut gateway = S3Gateway::new(test_util::store(&root, StoreLayout::FilePerKey));
+            if let Some(ring) = ring {
+                gateway = gateway.with_ring(ring);
+            }
+            let addr: std::net::SocketAddr = addr.parse().unwrap();
+            let _ = serve_http(addr, move |req| async move {
+                Ok::<_, Infallible>(gateway.handle(req).await)
+            })
+            .await;
+        });
+        let config = aws_sdk_s3::Config::builder()
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("access", "secret", None, None, "test"))
+            .endpoint_url(format!("http://{}", addr))
+            .force_path_style(true)
+            .build();
+        (Client::from_conf(config), dir)
+    }
+
+    #[tokio::test]
+    async fn test_object_operations() {
+        let (client, _dir) = serve();
+        let missing = client
+            .get_object()
+            .bucket("bucket")
+            .key("dir/key")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(missing.into_service_error().is_no_such_key());
+
+        let put = client
+            .put_object()
+            .bucket("bucket")
+            .key("dir/key")
+            .body(ByteStream::from_static(b"hello world"))
+            .content_type("text/plain")
+            .metadata("owner", "fairy")
+            .send()
+            .await
+            .unwrap();
+        let etag = format!("\"{}\"", hex::encode(Md5::digest(b"hello world")));
+        assert_eq!(put.e_tag(), Some(etag.as_str()));
+
+        let found = client
+            .get_object()
+            .bucket("bucket")
+            .key("dir/key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(found.content_type(), Some("text/plain"));
+        assert_eq!(found.e_tag(), Some(etag.as_str()));
+        assert_eq!(
+            found
+                .metadata()
+                .and_then(|m| m.get("owner"))
+                .map(String::as_str),
+            Some("fairy")
+        );
+        let value = found.body.collect().await.unwrap().into_bytes();
+        assert_eq!(value, "hello world");
+
+        let partial = client
+            .get_object()
+            .bucket("bucket")
+            .key("dir/key")
+            .range("bytes=6-")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(partial.content_range(), Some("bytes 6-10/11"));
+        let value = partial.body.collect().await.unwrap().into_bytes();
+        assert_eq!(value, "world");
+        let unsatisfiable = client
+            .get_object()
+            .bucket("bucket")
+            .key("dir/key")
+            .range("bytes=20-30")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            unsatisfiable.into_service_error().code(),
+            Some("InvalidRange")
+        );
+
+        let head = client
+            .head_object()
+            .bucket("bucket")
+            .key("dir/key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(head.content_length(), 11);
+        assert_eq!(head.e_tag(), Some(etag.as_str()));
+        // buckets are namespaces
+        let other = client
+            .head_object()
+            .bucket("other")
+            .key("dir/key")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(other.into_service_error().is_not_found());
+
+        client
+            .delete_object()
+            .bucket("bucket")
+            .key("dir/key")
+            .send()
+            .await
+            .unwrap();
+        let missing = client
+            .head_object()
+            .bucket("bucket")
+            .key("dir/key")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(missing.into_service_error().is_not_found());
+
+        for key in ["../../outside", "dir/../../../outside"] {
+            let escaping = client
+                .put_object()
+                .bucket("bucket")
+                .key(key)
+                .body(ByteStream::from_static(b"x"))
+                .send()
+                .await
+                .unwrap_err();
+            assert_eq!(escaping.code(), Some("InvalidArgument"), "{}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_objects() {
+        let (client, _dir) = serve();
+        for key in ["a", "dir/b", "dir/c", "dir/sub/d", "dir/sub/e", "e & f"] {
+            client
+                .put_object()
+                .bucket("bucket")
+                .key(key)
+                .body(ByteStream::from_static(b"value"))
+                .send()
+                .await
+                .unwrap();
+        }
+        client
+            .put_object()
+            .bucket("other")
+            .key("dir/x")
+            .body(ByteStream::from_static(b"value"))
+            .send()
+            .await
+            .unwrap();
+
+        let all = client
+            .list_objects_v2()
+            .bucket("bucket")
+            .send()
+            .await
+            .unwrap();
+        let keys: Vec<&str> = all
+            .contents()
+            .unwrap()
+            .iter()
+            .filter_map(|o| o.key())
+            .collect();
+        assert_eq!(
+            keys,
+            ["a", "dir/b", "dir/c", "dir/sub/d", "dir/sub/e", "e & f"]
+        );
+        assert_eq!(all.contents().unwrap()[0].size(), 5);
+        assert!(!all.is_truncated());
+
+        let listing = client
+            .list_objects_v2()
+            .bucket("bucket")
+            .prefix("dir/")
+            .delimiter("/")
+            .send()
+            .await
+            .unwrap();
+        let keys: Vec<&str> = listing
+            .contents()
+            .unwrap()
+            .iter()
+            .filter_map(|o| o.key())
+            .collect();
+        assert_eq!(keys, ["dir/b", "dir/c"]);
+        let prefixes: Vec<&str> = listing
+            .common_prefixes()
+            .unwrap()
+            .iter()
+            .filter_map(|p| p.prefix())
+            .collect();
+        assert_eq!(prefixes, ["dir/sub/"]);
+
+        // pages of two entries, a common prefix counts as one
+        let mut pages = Vec::new();
+        let mut token = None;
+        loop {
+            let page = client
+                .list_objects_v2()
+                .bucket("bucket")
+                .delimiter("/")
+                .max_keys(2)
+                .set_continuation_token(token)
+                .send()
+                .await
+                .unwrap();
+            let mut entries: Vec<String> = page
+                .contents()
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|o| o.key().map(String::from))
+                .collect();
+            entries.extend(
+                page.common_prefixes()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|p| p.prefix().map(String::from)),
+            );
+            pages.push(entries);
+            if !page.is_truncated() {
+                break;
+            }
+            token = page.next_continuation_token().map(String::from);
+        }
+        assert_eq!(pages, [vec!["a", "dir/"], vec!["e & f"]]);
+    }
+
+    #[tokio::test]
+    async fn test_writes_need_a_single_worker() {
+        let ring = test_util::leak(RwLock::new(HashRing::with_members(16, ["a:1"])));
+        let (client, _dir) = serve_with_ring(Some(ring));
+        client
+            .put_object()
+            .bucket("bucket")
+            .key("key")
+            .body(ByteStream::from_static(b"value"))
+            .send()
+            .await
+            .unwrap();
+
+        ring.write().unwrap().add("b:1", 1);
+        let put = client
+            .put_object()
+            .bucket("bucket")
+            .key("other")
+            .body(ByteStream::from_static(b"value"))
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(put.code(), Some("NotImplemented"));
+        let delete = client
+            .delete_object()
+            .bucket("bucket")
+            .key("key")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(delete.code(), Some("NotImplemented"));
+        let list = client
+            .list_objects_v2()
+            .bucket("bucket")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(list.code(), Some("NotImplemented"));
+        // reads still find the keys
+        let get = client
+            .get_object()
+            .bucket("bucket")
+            .key("key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(get.body.collect().await.unwrap().into_bytes(), "value");
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-4", 10).unwrap(), Some(0..5));
+        assert_eq!(parse_range("bytes=5-100", 10).unwrap(), Some(5..10));
+        assert_eq!(parse_range("bytes=-3", 10).unwrap(), Some(7..10));
+        assert_eq!(parse_range("bytes=-30", 10).unwrap(), Some(0..10));
+        assert_eq!(parse_range("bytes=0-1,4-5", 10).unwrap(), None);
+        assert_eq!(parse_range("bytes=5-2", 10).unwrap(), None);
+        assert_eq!(parse_range("items=0-1", 10).unwrap(), None);
+        assert_eq!(
+            parse_range(&format!("bytes=5-{}", u64::MAX), 10).unwrap(),
+            Some(5..10)
+        );
+        assert!(matches!(
+            parse_range("bytes=10-", 10),
+            Err(S3Error::InvalidRange)
+        ));
+        assert!(matches!(
+            parse_range("bytes=-5", 0),
+            Err(S3Error::InvalidRange)
+        ));
+    }
+
+    #[test]
+    fn test_decode_aws_chunked() {
+        let body = b"5;chunk-signature=abc\r\nhello\r\n6;chunk-signature=def\r\n world\r\n0;chunk-signature=0\r\n\r\n";
+        assert_eq!(decode_aws_chunked(body).unwrap(), "hello world");
+        assert!(decode_aws_chunked(b"5\r\nhel\r\n").is_err());
+    }
+}